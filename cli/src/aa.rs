@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Client for the CoCo attestation-agent running in the guest
+
+#[cfg(feature = "native-kbs")]
+use crate::kbs::EvidenceProvider;
+use crate::ttrpc::{self, TtrpcClient};
+use anyhow::{Result, anyhow};
+use std::time::Duration;
+#[cfg(feature = "aa-backend")]
+use {
+    crate::KeyFetcher,
+    crate::jose::TeeKey,
+    crate::kbs::{self, Credential, TransportPool},
+    anyhow::Context,
+    clevis_pin_trustee_lib::{Cert, ConnectionSettings, TrusteePinError},
+    serde::Deserialize,
+};
+
+pub(crate) const AA_SOCKET: &str =
+    "/run/confidential-containers/attestation-agent/attestation-agent.sock";
+const AA_SERVICE: &str = "attestation_agent.AttestationAgentService";
+const AA_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(feature = "aa-backend")]
+const AA_TOKEN_TYPE: &str = "kbs";
+
+#[cfg(feature = "aa-backend")]
+/// Token returned by the attestation-agent for the KBS it is configured with
+#[derive(Deserialize)]
+struct KbsToken {
+    token: String,
+    tee_keypair: String,
+}
+
+/// Evidence provider backed by the attestation-agent ttrpc API
+pub(crate) struct AttestationAgent {
+    client: TtrpcClient,
+}
+
+impl AttestationAgent {
+    pub(crate) fn new(socket: &str) -> Self {
+        Self {
+            client: TtrpcClient::new(socket, AA_TIMEOUT),
+        }
+    }
+
+    #[cfg(feature = "aa-backend")]
+    /// Get a KBS attestation token and the TEE key pair it is bound to. The
+    /// agent caches the token, so this does not attest on every call.
+    fn kbs_token(&self) -> Result<KbsToken> {
+        let mut request = Vec::new();
+        ttrpc::put_bytes(&mut request, 1, AA_TOKEN_TYPE.as_bytes());
+        let response = self.client.call(AA_SERVICE, "GetToken", &request)?;
+        let token = ttrpc::bytes_field(&response, 1)?
+            .ok_or_else(|| anyhow!("Attestation agent returned no token"))?;
+        serde_json::from_slice(token).context("Failed to parse attestation agent token")
+    }
+}
+
+#[cfg(feature = "native-kbs")]
+impl EvidenceProvider for AttestationAgent {
+    fn tee(&self) -> Result<String> {
+        let response = self.client.call(AA_SERVICE, "GetTeeType", &[])?;
+        let tee = ttrpc::bytes_field(&response, 1)?
+            .ok_or_else(|| anyhow!("Attestation agent returned no TEE type"))?;
+        Ok(String::from_utf8(tee.to_vec())?)
+    }
+
+    fn evidence(&self, report_data: &[u8]) -> Result<String> {
+        let mut request = Vec::new();
+        ttrpc::put_bytes(&mut request, 1, report_data);
+        let response = self.client.call(AA_SERVICE, "GetEvidence", &request)?;
+        let evidence = ttrpc::bytes_field(&response, 1)?
+            .ok_or_else(|| anyhow!("Attestation agent returned no evidence"))?;
+        String::from_utf8(evidence.to_vec())
+            .map_err(|e| anyhow!("Invalid UTF-8 in TEE evidence: {}", e))
+    }
+}
+
+#[cfg(feature = "aa-backend")]
+/// Key fetcher that lets the attestation-agent attest and only requests the
+/// resource itself. The evidence and initdata are whatever the agent was
+/// launched with, so the `initdata` and `policy_ids` of the binding are not
+/// used by this backend.
+pub(crate) struct AttestationAgentExecutor {
+    agent: AttestationAgent,
+    transports: TransportPool,
+}
+
+#[cfg(feature = "aa-backend")]
+impl AttestationAgentExecutor {
+    pub(crate) fn new(socket: &str, timeout: Option<Duration>) -> Self {
+        Self {
+            agent: AttestationAgent::new(socket),
+            transports: TransportPool::new(timeout),
+        }
+    }
+}
+
+#[cfg(feature = "aa-backend")]
+impl KeyFetcher for AttestationAgentExecutor {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        _initdata: Option<String>,
+        _policy_ids: &[String],
+    ) -> Result<String> {
+        let token = self.agent.kbs_token()?;
+        let body = self
+            .transports
+            .with_transport(url, cert, connection, |transport| {
+                kbs::get_resource(transport, url, path, &Credential::Bearer(&token.token))
+            })?;
+        let tee_key = TeeKey::from_pem(&token.tee_keypair).map_err(|e| {
+            TrusteePinError::Crypto(format!(
+                "Failed to load TEE key pair from attestation agent: {}",
+                e
+            ))
+        })?;
+        kbs::decrypt_resource(&body, &tee_key)
+    }
+}
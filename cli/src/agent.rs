@@ -0,0 +1,307 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Resident agent serving decrypt requests over a unix socket. The key
+//! fetchers, and the attestation sessions they hold, outlive a request, so
+//! unlocking many volumes does not pay for a full attestation every time.
+//!
+//! A client connects, writes a compact JWE, shuts down its writing half and
+//! reads back `{"payload": BASE64}` or `{"error": MESSAGE}`.
+
+use crate::{ExecutorCache, NoEvents, check_private_dir, decrypt_with};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::RuntimeConfig;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, DirBuilder};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Socket of the agent unless given with `--socket`
+pub const AGENT_SOCKET: &str = "/run/clevis-trustee/agent.sock";
+/// Largest request read, far above the size of a JWE protecting a LUKS key
+const MAX_REQUEST_BYTES: u64 = 1024 * 1024;
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a client may take to read the response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Response {
+    /// The decrypted payload, base64 encoded
+    Payload(String),
+    Error(String),
+}
+
+fn read_request(stream: &mut UnixStream) -> Result<String> {
+    let mut request = String::new();
+    stream
+        .take(MAX_REQUEST_BYTES)
+        .read_to_string(&mut request)
+        .context("Failed to read the request")?;
+    Ok(request.trim().to_string())
+}
+
+/// Answer the request read from `stream` with the payload `decrypt` returns.
+/// The timeouts keep a stalled client from holding its connection forever.
+fn handle(mut stream: UnixStream, decrypt: impl FnOnce(String) -> Result<Vec<u8>>) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(RESPONSE_TIMEOUT))?;
+    let response = match read_request(&mut stream).and_then(decrypt) {
+        Ok(payload) => Response::Payload(general_purpose::STANDARD.encode(payload)),
+        Err(e) => {
+            eprintln!("Decrypt request failed: {:#}", e);
+            Response::Error(format!("{:#}", e))
+        }
+    };
+    serde_json::to_writer(&mut stream, &response).context("Failed to send the response")
+}
+
+/// Uid of the process at the other end of `stream`
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: getsockopt writes at most len bytes to cred, a valid ucred
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&raw mut cred).cast(),
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+/// Refuse clients running as another user than the agent: decrypted
+/// payloads are only for root, whatever the permissions of the socket
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    let uid = peer_uid(stream).context("Failed to get the credentials of the client")?;
+    // SAFETY: geteuid cannot fail
+    let own = unsafe { libc::geteuid() };
+    if uid != own {
+        return Err(anyhow!("Refused a client running as uid {}", uid));
+    }
+    Ok(())
+}
+
+/// Remove the socket left behind by a previous agent
+fn remove_stale_socket(socket: &Path) -> Result<()> {
+    match fs::remove_file(socket) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove {}", socket.display())),
+    }
+}
+
+/// Listen on `socket`, in a directory only root can write to
+fn listen(socket: &Path) -> Result<UnixListener> {
+    if let Some(dir) = socket.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+        // The directory may have been there before, made by someone else
+        check_private_dir(dir)?;
+    }
+    remove_stale_socket(socket)?;
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    // Nobody else can reach the socket in the private directory before its
+    // permissions are restricted
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to restrict {}", socket.display()))?;
+    Ok(listener)
+}
+
+/// A decrypt request passed from its connection to the decrypting thread
+struct Job {
+    jwe: String,
+    reply: mpsc::Sender<Result<Vec<u8>>>,
+}
+
+/// Have the decrypting thread decrypt `jwe`
+fn submit(jobs: &mpsc::Sender<Job>, jwe: String) -> Result<Vec<u8>> {
+    let (reply, response) = mpsc::channel();
+    jobs.send(Job { jwe, reply })
+        .map_err(|_| anyhow!("The agent is shutting down"))?;
+    response
+        .recv()
+        .map_err(|_| anyhow!("The agent is shutting down"))?
+}
+
+/// Accept the connections of `listener`, each served by a thread of its own
+fn accept(listener: UnixListener, jobs: mpsc::Sender<Job>) {
+    for stream in listener.incoming() {
+        let result = stream.map_err(anyhow::Error::from).and_then(|stream| {
+            check_peer(&stream)?;
+            let jobs = jobs.clone();
+            thread::Builder::new()
+                .name("agent-connection".to_string())
+                .spawn(move || {
+                    if let Err(e) = handle(stream, |jwe| submit(&jobs, jwe)) {
+                        eprintln!("Failed to serve a decrypt request: {:#}", e);
+                    }
+                })?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to serve a decrypt request: {:#}", e);
+        }
+    }
+}
+
+/// Serve decrypt requests on `socket` until killed. Connections are served
+/// concurrently, the decryptions one at a time by the calling thread, which
+/// keeps the key fetchers. `runtime` overrides the fetch settings of every
+/// binding.
+pub fn serve(socket: &Path, runtime: &RuntimeConfig) -> Result<()> {
+    let listener = listen(socket)?;
+    eprintln!("Serving decrypt requests on {}", socket.display());
+
+    let (jobs, queue) = mpsc::channel();
+    thread::Builder::new()
+        .name("agent-accept".to_string())
+        .spawn(move || accept(listener, jobs))?;
+    let executors = ExecutorCache::default();
+    for job in queue {
+        // The client may have given up meanwhile
+        let _ = job
+            .reply
+            .send(decrypt_with(&job.jwe, runtime, &executors, &NoEvents));
+    }
+    Ok(())
+}
+
+fn request(stream: &mut UnixStream, jwe: &str) -> Result<Vec<u8>> {
+    stream
+        .write_all(jwe.as_bytes())
+        .context("Failed to send the request")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .context("Failed to read the response")?;
+    let response: Response =
+        serde_json::from_str(&response).context("Invalid response from the agent")?;
+    match response {
+        Response::Payload(payload) => general_purpose::STANDARD
+            .decode(payload)
+            .context("Invalid payload from the agent"),
+        Response::Error(e) => Err(anyhow!("The agent failed to decrypt: {}", e)),
+    }
+}
+
+/// Have the agent listening on `socket` decrypt a compact JWE
+pub fn decrypt(socket: &Path, jwe: &str) -> Result<Vec<u8>> {
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Failed to connect to the agent on {}", socket.display()))?;
+    request(&mut stream, jwe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn test_request_error() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let agent = thread::spawn(move || {
+            handle(server, |jwe| {
+                decrypt_with(
+                    &jwe,
+                    &RuntimeConfig::default(),
+                    &ExecutorCache::default(),
+                    &NoEvents,
+                )
+            })
+        });
+
+        let result = request(&mut client, "not a jwe");
+
+        agent.join().unwrap().unwrap();
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .starts_with("The agent failed to decrypt: Invalid JWE")
+        );
+    }
+
+    #[test]
+    fn test_response_format() {
+        let payload = Response::Payload(general_purpose::STANDARD.encode(b"key"));
+
+        assert_eq!(
+            serde_json::to_string(&payload).unwrap(),
+            r#"{"payload":"a2V5"}"#
+        );
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("agent.sock");
+        UnixListener::bind(&socket).unwrap();
+
+        remove_stale_socket(&socket).unwrap();
+        remove_stale_socket(&socket).unwrap();
+
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn test_listen() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("run/agent.sock");
+
+        let _listener = listen(&socket).unwrap();
+
+        assert_eq!(fs::metadata(&socket).unwrap().mode() & 0o777, 0o600);
+        assert_eq!(
+            fs::metadata(socket.parent().unwrap()).unwrap().mode() & 0o777,
+            0o700
+        );
+    }
+
+    #[test]
+    fn test_listen_shared_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+
+        let error = listen(&dir.path().join("agent.sock")).unwrap_err();
+
+        assert!(
+            error
+                .to_string()
+                .ends_with("is not a directory only its owner can write to")
+        );
+    }
+
+    #[test]
+    fn test_check_peer() {
+        let (client, _server) = UnixStream::pair().unwrap();
+
+        check_peer(&client).unwrap();
+    }
+}
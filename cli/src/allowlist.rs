@@ -0,0 +1,267 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Local allowlist of the servers a binding may fetch its key from. The
+//! clevis header comes with the JWE and can be doctored, e.g. to send the
+//! attestation evidence to a server of the attacker; servers of the header
+//! missing from the list are refused. For the same reason, the header does
+//! not choose how the allowed servers are reached: their certificate, CRL,
+//! request headers and policies are the ones pinned in the list.
+
+use crate::endpoint;
+use anyhow::{Context, Result};
+use clevis_pin_trustee_lib::{Cert, ClevisHeader, Server, TrusteePinError};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// One server per line, `#` starting comments: its URL, then the settings
+/// pinned for it, `cert=PATH`, `crl=PATH`, `header=NAME:VALUE` and
+/// `policy=ID`, the last two repeatable
+pub(crate) const ALLOWED_SERVERS_PATH: &str = "/etc/clevis-trustee/allowed-servers";
+
+/// A server of the list, with its pinned settings
+#[derive(Default)]
+struct AllowedServer {
+    url: String,
+    cert: Option<String>,
+    crl_path: Option<String>,
+    headers: BTreeMap<String, String>,
+    policy_ids: Vec<String>,
+}
+
+impl AllowedServer {
+    fn parse(line: &str) -> Result<Self, TrusteePinError> {
+        let invalid = || {
+            TrusteePinError::Config(format!(
+                "Invalid line {:?} of {}",
+                line, ALLOWED_SERVERS_PATH
+            ))
+        };
+        let mut fields = line.split_whitespace();
+        let mut allowed = AllowedServer {
+            url: endpoint::comparable(fields.next().ok_or_else(invalid)?),
+            ..Default::default()
+        };
+        for field in fields {
+            match field.split_once('=').ok_or_else(invalid)? {
+                ("cert", path) => allowed.cert = Some(path.to_string()),
+                ("crl", path) => allowed.crl_path = Some(path.to_string()),
+                ("header", header) => {
+                    let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+                    allowed.headers.insert(name.to_string(), value.to_string());
+                }
+                ("policy", id) => allowed.policy_ids.push(id.to_string()),
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(allowed)
+    }
+
+    /// `server` with the settings of the header replaced by the pinned ones
+    fn pin(&self, server: Server) -> Server {
+        Server {
+            cert: self.cert.clone().map(Cert::Path).unwrap_or_default(),
+            crl_path: self.crl_path.clone(),
+            headers: Some(self.headers.clone()).filter(|headers| !headers.is_empty()),
+            policy_ids: Some(self.policy_ids.clone()).filter(|ids| !ids.is_empty()),
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            ..server
+        }
+    }
+}
+
+pub(crate) struct Allowlist {
+    servers: Vec<AllowedServer>,
+}
+
+impl Allowlist {
+    /// The list at `path`, `None` when there is no list and every server
+    /// is allowed
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(Self::parse(&content)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, TrusteePinError> {
+        let servers = content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(AllowedServer::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(Allowlist { servers })
+    }
+
+    fn find(&self, url: &str) -> Option<&AllowedServer> {
+        let url = endpoint::comparable(url);
+        self.servers.iter().find(|allowed| allowed.url == url)
+    }
+
+    /// `header` without the settings that choose how, and with what
+    /// backend, its servers are reached, and without discovery: the list
+    /// decides these
+    pub(crate) fn restrict(&self, header: &ClevisHeader) -> ClevisHeader {
+        let unpinned = AllowedServer::default();
+        ClevisHeader {
+            servers: header
+                .servers
+                .iter()
+                .map(|server| unpinned.pin(server.clone()))
+                .collect(),
+            cert: None,
+            crl_path: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            policy_ids: None,
+            backend: None,
+            discovery: None,
+            ..header.clone()
+        }
+    }
+
+    /// The allowed ones of `servers`, in order, with their pinned settings
+    pub(crate) fn filter(&self, servers: Vec<Server>) -> Result<Vec<Server>, TrusteePinError> {
+        let mut allowed = Vec::new();
+        let mut refused = false;
+        for server in servers {
+            match self.find(&server.url) {
+                Some(entry) => allowed.push(entry.pin(server)),
+                None => {
+                    eprintln!(
+                        "Warning: refusing server {}, it is not in {}",
+                        server.url, ALLOWED_SERVERS_PATH
+                    );
+                    refused = true;
+                }
+            }
+        }
+        if allowed.is_empty() && refused {
+            return Err(TrusteePinError::Config(format!(
+                "None of the servers of the binding is in {}",
+                ALLOWED_SERVERS_PATH
+            )));
+        }
+        Ok(allowed)
+    }
+
+    /// `servers`, of a binding moved to a URL given at runtime, with the
+    /// settings pinned for them
+    pub(crate) fn pin(&self, servers: Vec<Server>) -> Vec<Server> {
+        servers
+            .into_iter()
+            .map(|server| match self.find(&server.url) {
+                Some(entry) => entry.pin(server),
+                None => server,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn server(url: &str) -> Server {
+        serde_json::from_value(json!(url)).unwrap()
+    }
+
+    #[test]
+    fn test_filter() {
+        let allowlist = Allowlist::parse(
+            "# Site servers\nhttps://kbs1:8080/\n\n  http://kbs2:8080 # fallback\nhttps://[fd00::1]:8443\n",
+        )
+        .unwrap();
+
+        let allowed = allowlist
+            .filter(vec![
+                server("https://kbs1:8080"),
+                server("https://evil:8080"),
+                server("http://kbs2:8080/"),
+                server("https://[FD00:0::1]:8443"),
+            ])
+            .unwrap();
+
+        let urls: Vec<&str> = allowed.iter().map(|server| server.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://kbs1:8080",
+                "http://kbs2:8080/",
+                "https://[FD00:0::1]:8443"
+            ]
+        );
+        assert!(allowlist.filter(vec![server("https://evil:8080")]).is_err());
+    }
+
+    #[test]
+    fn test_doctored_header() {
+        let allowlist = Allowlist::parse(
+            "https://kbs1:8080 cert=/etc/pki/kbs.pem header=X-Tenant-Id:site1 policy=strict\n\
+             https://kbs2:8080\n",
+        )
+        .unwrap();
+        // Allowed URLs, reached with the certificate and settings of the
+        // attacker
+        let header = ClevisHeader::from_claim(json!({
+            "pin": "trustee",
+            "servers": [
+                {"url": "https://kbs1:8080", "cert": {"path": "/home/evil/ca.pem"},
+                 "headers": {"X-Tenant-Id": "evil"}, "policy_ids": ["lax"]},
+                {"url": "https://kbs2:8080", "crl_path": "/home/evil/empty.crl"},
+            ],
+            "cert": {"path": "/home/evil/ca.pem"},
+            "policy_ids": ["lax"],
+            "backend": "native",
+            "discovery": ["smbios"],
+            "path": "default/key/root",
+            "initdata": null,
+        }))
+        .unwrap();
+
+        let header = allowlist.restrict(&header);
+        let servers = allowlist
+            .filter(Server::with_default_cert(
+                &header.servers,
+                header.cert.as_ref(),
+            ))
+            .unwrap();
+
+        assert!(header.policy_ids.is_none());
+        assert!(header.backend.is_none());
+        assert!(header.discovery.is_none());
+        assert_eq!(servers[0].cert, Cert::Path("/etc/pki/kbs.pem".to_string()));
+        assert_eq!(
+            servers[0].headers,
+            Some(BTreeMap::from([("X-Tenant-Id".into(), "site1".into())]))
+        );
+        assert_eq!(servers[0].policy_ids, Some(vec!["strict".to_string()]));
+        assert_eq!(servers[1].cert, Cert::None);
+        assert!(servers[1].crl_path.is_none());
+    }
+
+    #[test]
+    fn test_invalid_line() {
+        assert!(Allowlist::parse("https://kbs1:8080 cert\n").is_err());
+        assert!(Allowlist::parse("https://kbs1:8080 header=X-Tenant-Id\n").is_err());
+        assert!(Allowlist::parse("https://kbs1:8080 tls=1.0\n").is_err());
+    }
+
+    #[test]
+    fn test_missing_list() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(
+            Allowlist::load(&dir.path().join("allowed-servers"))
+                .unwrap()
+                .is_none()
+        );
+    }
+}
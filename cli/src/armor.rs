@@ -0,0 +1,60 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! PEM-like armor around a compact JWE, with the token folded in short
+//! lines, so it survives being pasted into tickets, configs and emails.
+
+use std::borrow::Cow;
+
+const BEGIN: &str = "-----BEGIN CLEVIS TRUSTEE TOKEN-----";
+const END: &str = "-----END CLEVIS TRUSTEE TOKEN-----";
+const LINE_LENGTH: usize = 64;
+
+/// The compact JWE `jwe` wrapped in the armor
+pub fn armor(jwe: &str) -> String {
+    let mut armored = format!("{}\n", BEGIN);
+    // A compact JWE is ASCII, any byte boundary is a char boundary
+    for line in jwe.trim().as_bytes().chunks(LINE_LENGTH) {
+        armored.push_str(&String::from_utf8_lossy(line));
+        armored.push('\n');
+    }
+    armored.push_str(END);
+    armored.push('\n');
+    armored
+}
+
+/// The compact JWE in `input`, armored or not
+pub fn dearmor(input: &str) -> Cow<'_, str> {
+    let input = input.trim();
+    match input
+        .strip_prefix(BEGIN)
+        .and_then(|body| body.strip_suffix(END))
+    {
+        Some(body) => Cow::Owned(body.split_whitespace().collect()),
+        None => Cow::Borrowed(input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_armor() {
+        let jwe = format!("{}.{}..iv.tag", "h".repeat(100), "k".repeat(30));
+
+        let armored = armor(&jwe);
+
+        let lines: Vec<&str> = armored.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], BEGIN);
+        assert_eq!(lines[1].len(), LINE_LENGTH);
+        assert_eq!(lines[4], END);
+        assert_eq!(
+            dearmor(&format!("\n{}\r\n", armored.replace('\n', "\r\n"))),
+            jwe
+        );
+        assert_eq!(dearmor(&format!("{}\n", jwe)), jwe);
+    }
+}
@@ -0,0 +1,555 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Running trustee-attester: the sandbox it runs in, as it is spawned as
+//! root in early boot while handling secrets, and the classification of its
+//! failures from its stderr, so retries stop on failures that will not go
+//! away and users get a short reason rather than the raw output.
+
+use crate::check_private_dir;
+use clevis_pin_trustee_lib::{FailureCause, FailureKind, TrusteePinError};
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command as StdCommand;
+
+/// PATH of the attester, instead of the one of the caller
+const SANDBOX_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin";
+/// Variables passed on to the attester, every other one is dropped
+const SANDBOX_ENV: &[&str] = &[
+    "RUST_LOG",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+];
+/// Open files allowed to the attester
+const SANDBOX_NOFILE: libc::rlim_t = 1024;
+/// Where inline certificates are written for root
+const DEFAULT_CERT_DIR: &str = "/run/trustee";
+/// Environment variable overriding the certificate directory
+const CERT_DIR_ENV: &str = "CLEVIS_TRUSTEE_CERT_DIR";
+/// Present when SELinux is enabled
+const SELINUX_ENFORCE_PATH: &str = "/sys/fs/selinux/enforce";
+/// Context of the system certificates, which the policy lets the domains
+/// the attester may run in read
+const DEFAULT_CERT_CONTEXT: &str = "system_u:object_r:cert_t:s0";
+const SELINUX_XATTR: &std::ffi::CStr = c"security.selinux";
+
+#[cfg(target_env = "gnu")]
+type RlimitResource = libc::__rlimit_resource_t;
+#[cfg(not(target_env = "gnu"))]
+type RlimitResource = libc::c_int;
+
+fn set_rlimit(resource: RlimitResource, limit: libc::rlim_t) -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: limit,
+        rlim_max: limit,
+    };
+    // SAFETY: setrlimit only reads the struct passed by reference
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Restrict what `command` can do once spawned: a minimal environment, no
+/// privilege gain through setuid binaries, no core dumps that would hold
+/// the key, private files and a bounded number of open files. Whether it
+/// can be traced is up to the command, since execve resets the dumpable
+/// flag.
+pub(crate) fn sandbox(command: &mut StdCommand) {
+    command.env_clear().env("PATH", SANDBOX_PATH);
+    for name in SANDBOX_ENV {
+        if let Some(value) = env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+    // SAFETY: the closure runs between fork and exec and only makes
+    // async-signal-safe system calls, without allocating
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            set_rlimit(libc::RLIMIT_CORE, 0)?;
+            set_rlimit(libc::RLIMIT_NOFILE, SANDBOX_NOFILE)?;
+            libc::umask(0o077);
+            Ok(())
+        });
+    }
+}
+
+/// Directory for inline certificates: the configured one, else the one of
+/// `CLEVIS_TRUSTEE_CERT_DIR`, else `/run/trustee` for root. Other users,
+/// who cannot write to `/run`, get a directory in `XDG_RUNTIME_DIR`, else
+/// one of their own in `TMPDIR`.
+pub(crate) fn cert_dir(configured: Option<&str>) -> PathBuf {
+    // SAFETY: geteuid cannot fail
+    let uid = unsafe { libc::geteuid() };
+    resolve_cert_dir(
+        configured,
+        env::var_os(CERT_DIR_ENV),
+        env::var_os("XDG_RUNTIME_DIR"),
+        uid,
+    )
+}
+
+fn resolve_cert_dir(
+    configured: Option<&str>,
+    from_env: Option<OsString>,
+    runtime_dir: Option<OsString>,
+    uid: libc::uid_t,
+) -> PathBuf {
+    if let Some(dir) = configured {
+        return PathBuf::from(dir);
+    }
+    if let Some(dir) = from_env.filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+    if uid == 0 {
+        return PathBuf::from(DEFAULT_CERT_DIR);
+    }
+    match runtime_dir.filter(|dir| !dir.is_empty()) {
+        Some(dir) => Path::new(&dir).join("trustee"),
+        None => env::temp_dir().join(format!("trustee-{}", uid)),
+    }
+}
+
+/// SELinux context given to the certificate files, as the one they inherit
+/// from the certificate directory may not be readable by the attester in
+/// enforcing mode
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CertLabel {
+    context: CString,
+    /// A configured context must be set, the default one is best effort
+    configured: bool,
+}
+
+impl CertLabel {
+    fn apply(&self, file: &File) -> io::Result<()> {
+        let context = self.context.as_bytes_with_nul();
+        // SAFETY: fsetxattr only reads the name and the value, both valid
+        // for the given lengths
+        let ret = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                SELINUX_XATTR.as_ptr(),
+                context.as_ptr().cast(),
+                context.len(),
+                0,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if self.configured {
+            return Err(io::Error::new(
+                error.kind(),
+                format!(
+                    "failed to set the SELinux context {}: {}",
+                    self.context.to_string_lossy(),
+                    error
+                ),
+            ));
+        }
+        eprintln!(
+            "Warning: failed to set the SELinux context {} of the certificate: {}",
+            self.context.to_string_lossy(),
+            error
+        );
+        Ok(())
+    }
+}
+
+/// Label of the certificate files: the configured context, none when it is
+/// empty, else the one of the system certificates when SELinux is enabled
+pub(crate) fn cert_label(configured: Option<&str>) -> Result<Option<CertLabel>, TrusteePinError> {
+    resolve_cert_label(configured, Path::new(SELINUX_ENFORCE_PATH).exists())
+}
+
+fn resolve_cert_label(
+    configured: Option<&str>,
+    selinux: bool,
+) -> Result<Option<CertLabel>, TrusteePinError> {
+    let (context, configured) = match configured {
+        Some("") => return Ok(None),
+        Some(context) => (context, true),
+        None if selinux => (DEFAULT_CERT_CONTEXT, false),
+        None => return Ok(None),
+    };
+    let context = CString::new(context)
+        .map_err(|_| TrusteePinError::Config(format!("Invalid SELinux context {:?}", context)))?;
+    Ok(Some(CertLabel {
+        context,
+        configured,
+    }))
+}
+
+/// Certificate handed to the attester as a file. Created with `O_TMPFILE`
+/// where the filesystem supports it, the file has no name and goes away
+/// with its last descriptor, even if we are killed. Otherwise the file is
+/// named and removed on drop.
+pub(crate) struct CertFile {
+    file: File,
+    /// Path of the fallback named file
+    named: Option<PathBuf>,
+}
+
+impl CertFile {
+    /// Write `pem` to a file only root can read, in `dir`, labeled with
+    /// `label`
+    pub(crate) fn create(dir: &Path, pem: &str, label: Option<&CertLabel>) -> io::Result<Self> {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        check_private_dir(dir)?;
+        let cert = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(dir)
+        {
+            Ok(file) => CertFile { file, named: None },
+            // Filesystems without O_TMPFILE support
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EOPNOTSUPP | libc::EISDIR)) => {
+                Self::create_named(dir)?
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(label) = label {
+            label.apply(&cert.file)?;
+        }
+        (&cert.file).write_all(pem.as_bytes())?;
+        cert.file.sync_all()?;
+        Ok(cert)
+    }
+
+    fn create_named(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(format!(
+            "cert-{}.pem",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        let cert = CertFile {
+            file,
+            named: Some(path),
+        };
+        File::open(dir)?.sync_all()?;
+        Ok(cert)
+    }
+
+    /// Let `command` inherit the file and return the path it reads the
+    /// certificate from
+    pub(crate) fn pass_to(&self, command: &mut StdCommand) -> PathBuf {
+        if let Some(path) = &self.named {
+            return path.clone();
+        }
+        let fd = self.file.as_raw_fd();
+        // SAFETY: fcntl is async-signal-safe and only changes the flags of
+        // the descriptor in the child
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        PathBuf::from(format!("/proc/self/fd/{}", fd))
+    }
+}
+
+impl Drop for CertFile {
+    fn drop(&mut self) {
+        if let Some(path) = &self.named
+            && let Err(e) = fs::remove_file(path)
+        {
+            eprintln!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Failures of trustee-attester recognized from its output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AttesterFailure {
+    ConnectionRefused,
+    /// DNS failures, resets and timeouts
+    Unreachable,
+    UntrustedCertificate,
+    EvidenceRejected,
+    ResourceNotFound,
+}
+
+/// Lowercase fragments of the stderr of trustee-attester and the failure
+/// they reveal, most specific first
+const PATTERNS: &[(&str, AttesterFailure)] = &[
+    ("connection refused", AttesterFailure::ConnectionRefused),
+    (
+        "certificate verify failed",
+        AttesterFailure::UntrustedCertificate,
+    ),
+    (
+        "invalid peer certificate",
+        AttesterFailure::UntrustedCertificate,
+    ),
+    ("unknownissuer", AttesterFailure::UntrustedCertificate),
+    ("dns error", AttesterFailure::Unreachable),
+    ("failed to lookup address", AttesterFailure::Unreachable),
+    ("connection reset", AttesterFailure::Unreachable),
+    ("timed out", AttesterFailure::Unreachable),
+    ("error trying to connect", AttesterFailure::Unreachable),
+    ("policydeny", AttesterFailure::EvidenceRejected),
+    ("attestation failed", AttesterFailure::EvidenceRejected),
+    ("evidence rejected", AttesterFailure::EvidenceRejected),
+    ("401 unauthorized", AttesterFailure::EvidenceRejected),
+    ("resource not found", AttesterFailure::ResourceNotFound),
+    ("resource not exist", AttesterFailure::ResourceNotFound),
+    ("404 not found", AttesterFailure::ResourceNotFound),
+];
+
+impl AttesterFailure {
+    fn parse(stderr: &str) -> Option<Self> {
+        let stderr = stderr.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| stderr.contains(pattern))
+            .map(|(_, failure)| *failure)
+    }
+
+    fn kind(self) -> FailureKind {
+        match self {
+            AttesterFailure::ConnectionRefused | AttesterFailure::Unreachable => {
+                FailureKind::Transient
+            }
+            AttesterFailure::UntrustedCertificate
+            | AttesterFailure::EvidenceRejected
+            | AttesterFailure::ResourceNotFound => FailureKind::Permanent,
+        }
+    }
+
+    /// The HTTP status is not printed by trustee-attester, only network
+    /// failures are told apart
+    fn cause(self) -> Option<FailureCause> {
+        match self {
+            AttesterFailure::ConnectionRefused | AttesterFailure::Unreachable => {
+                Some(FailureCause::Network)
+            }
+            AttesterFailure::UntrustedCertificate
+            | AttesterFailure::EvidenceRejected
+            | AttesterFailure::ResourceNotFound => None,
+        }
+    }
+}
+
+impl fmt::Display for AttesterFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AttesterFailure::ConnectionRefused => "connection refused by the KBS",
+            AttesterFailure::Unreachable => "the KBS is unreachable",
+            AttesterFailure::UntrustedCertificate => {
+                "the TLS certificate of the KBS is not trusted"
+            }
+            AttesterFailure::EvidenceRejected => "the KBS rejected the attestation evidence",
+            AttesterFailure::ResourceNotFound => "the resource does not exist on the KBS",
+        })
+    }
+}
+
+/// Error for a failed run of trustee-attester against `url`. Unknown
+/// failures are assumed transient and reported with the last line of
+/// `stderr`, which holds the error.
+pub(crate) fn failure(url: &str, stderr: &str) -> TrusteePinError {
+    let (kind, message, cause) = match AttesterFailure::parse(stderr) {
+        Some(failure) => (failure.kind(), failure.to_string(), failure.cause()),
+        None => {
+            let last_line = stderr
+                .lines()
+                .rev()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .unwrap_or("no error output");
+            (FailureKind::Transient, last_line.to_string(), None)
+        }
+    };
+    TrusteePinError::Fetch {
+        server: url.to_string(),
+        kind,
+        message: format!("trustee-attester failed: {}", message),
+        retry_after: None,
+        cause,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_failure() {
+        let refused = failure(
+            "http://kbs",
+            "Error: request failed\n\nCaused by:\n    tcp connect error: Connection refused (os error 111)\n",
+        );
+        let denied = failure(
+            "http://kbs",
+            "Error: RCAR handshake failed: Attestation failed: PolicyDeny\n",
+        );
+        let unknown = failure("http://kbs", "loading\nError: something odd\n\n");
+
+        assert_eq!(refused.kind(), FailureKind::Transient);
+        assert_eq!(
+            refused.to_string(),
+            "trustee-attester failed: connection refused by the KBS"
+        );
+        assert_eq!(denied.kind(), FailureKind::Permanent);
+        assert_eq!(
+            denied.to_string(),
+            "trustee-attester failed: the KBS rejected the attestation evidence"
+        );
+        assert_eq!(unknown.kind(), FailureKind::Transient);
+        assert_eq!(
+            unknown.to_string(),
+            "trustee-attester failed: Error: something odd"
+        );
+    }
+
+    #[test]
+    fn test_cert_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().join("trustee");
+        let cert = CertFile::create(&dir, "PEM", None).unwrap();
+        let mut command = StdCommand::new("cat");
+        let path = cert.pass_to(&mut command);
+        sandbox(&mut command);
+
+        let output = command.arg(&path).output().unwrap();
+
+        assert_eq!(output.stdout, b"PEM");
+        drop(cert);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_named_cert_file_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = CertFile::create_named(dir.path()).unwrap();
+        let path = cert.named.clone().unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+
+        drop(cert);
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_resolve_cert_label() {
+        let label = |context: &str, configured| CertLabel {
+            context: CString::new(context).unwrap(),
+            configured,
+        };
+
+        assert_eq!(resolve_cert_label(None, false).unwrap(), None);
+        assert_eq!(
+            resolve_cert_label(None, true).unwrap(),
+            Some(label(DEFAULT_CERT_CONTEXT, false))
+        );
+        assert_eq!(
+            resolve_cert_label(Some("system_u:object_r:var_run_t:s0"), false).unwrap(),
+            Some(label("system_u:object_r:var_run_t:s0", true))
+        );
+        assert_eq!(resolve_cert_label(Some(""), true).unwrap(), None);
+        assert!(resolve_cert_label(Some("a\0b"), true).is_err());
+    }
+
+    #[test]
+    fn test_resolve_cert_dir() {
+        let xdg = || Some(OsString::from("/run/user/1000"));
+
+        assert_eq!(
+            resolve_cert_dir(Some("/cfg"), Some("/env".into()), xdg(), 1000),
+            Path::new("/cfg")
+        );
+        assert_eq!(
+            resolve_cert_dir(None, Some("/env".into()), xdg(), 1000),
+            Path::new("/env")
+        );
+        assert_eq!(
+            resolve_cert_dir(None, Some("".into()), xdg(), 1000),
+            Path::new("/run/user/1000/trustee")
+        );
+        assert_eq!(
+            resolve_cert_dir(None, None, xdg(), 0),
+            Path::new(DEFAULT_CERT_DIR)
+        );
+        assert_eq!(
+            resolve_cert_dir(None, None, None, 1000),
+            env::temp_dir().join("trustee-1000")
+        );
+    }
+
+    #[test]
+    fn test_cert_dir_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+
+        let error = CertFile::create(dir.path(), "PEM", None).err().unwrap();
+
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "{} is not a directory only its owner can write to",
+                dir.path().display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let mut command = StdCommand::new("sh");
+        command.arg("-c").arg(
+            "echo \"$PATH\" \"${HOME:-unset}\"; ulimit -c; ulimit -n; umask; \
+             grep NoNewPrivs /proc/self/status",
+        );
+        sandbox(&mut command);
+
+        let output = command.output().unwrap();
+
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], format!("{} unset", SANDBOX_PATH));
+        assert_eq!(&lines[1..4], ["0", "1024", "0077"]);
+        assert!(lines[4].ends_with('1'), "{}", lines[4]);
+    }
+
+    #[test]
+    fn test_resource_not_found() {
+        let error = failure(
+            "http://kbs",
+            "Error: get resource failed: 404 Not Found: Resource not found",
+        );
+
+        assert_eq!(error.kind(), FailureKind::Permanent);
+        assert_eq!(
+            error.to_string(),
+            "trustee-attester failed: the resource does not exist on the KBS"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Repeated key fetches against every configured server, to measure how long
+//! attestation takes in practice and size boot timeouts accordingly.
+
+use crate::{
+    ConfigOptions, ExecutorSettings, KeyFetcher, RealMachineIdentity, expand_path_template,
+    make_executor, read_config, select_servers,
+};
+use anyhow::Result;
+use clevis_pin_trustee_lib::{
+    Backend, ConnectionSettings, RuntimeConfig, Server, TlsPolicy, TrusteePinError,
+    duration_setting,
+};
+use std::time::{Duration, Instant};
+
+/// Latencies of the fetches from one server
+pub struct BenchResult {
+    pub url: String,
+    /// Duration of every successful fetch, in the order they were made
+    pub latencies: Vec<Duration>,
+    pub failures: u32,
+}
+
+impl BenchResult {
+    pub fn min(&self) -> Option<Duration> {
+        self.latencies.iter().min().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        let count = u32::try_from(self.latencies.len())
+            .ok()
+            .filter(|n| *n > 0)?;
+        Some(self.latencies.iter().sum::<Duration>() / count)
+    }
+
+    /// 95th percentile, nearest-rank
+    pub fn p95(&self) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort();
+        let rank = (sorted.len() * 95).div_ceil(100);
+        sorted.get(rank.checked_sub(1)?).copied()
+    }
+}
+
+fn bench_servers<E: KeyFetcher + ?Sized>(
+    servers: &[Server],
+    path: &str,
+    initdata: Option<String>,
+    policy_ids: &[String],
+    connection: &ConnectionSettings,
+    cycles: u32,
+    executor: &E,
+) -> Vec<BenchResult> {
+    let mut results: Vec<BenchResult> = servers
+        .iter()
+        .map(|server| BenchResult {
+            url: server.url.clone(),
+            latencies: Vec::new(),
+            failures: 0,
+        })
+        .collect();
+
+    for cycle in 1..=cycles {
+        eprintln!("Benchmark cycle {}/{}", cycle, cycles);
+        for (server, result) in servers.iter().zip(results.iter_mut()) {
+            let policy_ids = server.policy_ids.as_deref().unwrap_or(policy_ids);
+            let connection = server.connection(connection);
+            let start = Instant::now();
+            match executor.try_fetch_luks_key(
+                &server.url,
+                path,
+                &server.cert,
+                &connection,
+                initdata.clone(),
+                policy_ids,
+            ) {
+                Ok(_) => result.latencies.push(start.elapsed()),
+                Err(e) => {
+                    eprintln!("Error with URL {}: {:#}", server.url, e);
+                    result.failures += 1;
+                }
+            }
+        }
+    }
+    results
+}
+
+/// Fetch the key described by `config` from every server selected by
+/// `runtime` `cycles` times, without retrying. The servers, backend and
+/// attester settings of `runtime` override the ones of `config`, as they do
+/// the ones of a binding when decrypting.
+pub fn bench(
+    config: &str,
+    options: ConfigOptions,
+    cycles: u32,
+    runtime: &RuntimeConfig,
+) -> Result<Vec<BenchResult>> {
+    let (config, initdata) = read_config(config, options)?;
+    let servers = match &runtime.servers {
+        Some(servers) => servers.clone(),
+        None => Server::with_default_cert(&config.servers, config.cert.as_ref()),
+    };
+    if servers.is_empty() {
+        return Err(TrusteePinError::Config("No URLs provided".to_string()).into());
+    }
+    let servers = select_servers(&runtime.selection, servers)?;
+    // A protocol version pinned for the native backend means nothing to the
+    // backend chosen at runtime
+    let kbs_protocol_version = config.kbs_protocol_version.filter(|_| {
+        runtime
+            .backend
+            .is_none_or(|backend| backend == Backend::Native)
+    });
+    let attempt_timeout = match runtime.attempt_timeout()? {
+        Some(timeout) => Some(timeout),
+        None => duration_setting("attempt_timeout", config.attempt_timeout.as_deref())?,
+    };
+    let executor = make_executor(&ExecutorSettings {
+        backend: runtime.backend.or(config.backend).unwrap_or_default(),
+        kbs_protocol_version,
+        attester_path: runtime.attester_path.clone(),
+        cert_dir: runtime.cert_dir.clone(),
+        cert_context: runtime.cert_context.clone(),
+        attempt_timeout,
+        mock_resources: runtime.mock_resources.clone(),
+        ..ExecutorSettings::default()
+    })?;
+    let path = expand_path_template(&config.path, &RealMachineIdentity)?;
+    let connection = ConnectionSettings {
+        tls: TlsPolicy {
+            min_version: config.tls_min_version,
+            cipher_suites: config.tls_cipher_suites.clone(),
+            crl_path: config.crl_path.clone(),
+        },
+        headers: config
+            .user_agent
+            .iter()
+            .map(|agent| ("User-Agent".to_string(), agent.clone()))
+            .collect(),
+    };
+    Ok(bench_servers(
+        &servers,
+        &path,
+        initdata,
+        config.policy_ids.as_deref().unwrap_or_default(),
+        &connection,
+        cycles,
+        executor.as_ref(),
+    ))
+}
+
+/// The results for `--output-format json`, latencies in milliseconds
+pub fn report_json(results: &[BenchResult]) -> serde_json::Value {
+    let millis = |duration: Option<Duration>| duration.map(|d| d.as_secs_f64() * 1000.0);
+    results
+        .iter()
+        .map(|result| {
+            serde_json::json!({
+                "url": result.url,
+                "successes": result.latencies.len(),
+                "failures": result.failures,
+                "min_ms": millis(result.min()),
+                "avg_ms": millis(result.avg()),
+                "p95_ms": millis(result.p95()),
+            })
+        })
+        .collect()
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    duration.map_or_else(
+        || "-".to_string(),
+        |duration| format!("{:.1}ms", duration.as_secs_f64() * 1000.0),
+    )
+}
+
+/// One line per server with the success count and min/avg/p95 latency
+pub fn report(results: &[BenchResult]) -> String {
+    let width = results
+        .iter()
+        .map(|result| result.url.len())
+        .max()
+        .unwrap_or(0)
+        .max("URL".len());
+    let mut report = format!(
+        "{:<width$}  {:>7}  {:>10}  {:>10}  {:>10}\n",
+        "URL", "OK", "MIN", "AVG", "P95"
+    );
+    for result in results {
+        let total = result.latencies.len() + result.failures as usize;
+        report.push_str(&format!(
+            "{:<width$}  {:>7}  {:>10}  {:>10}  {:>10}\n",
+            result.url,
+            format!("{}/{}", result.latencies.len(), total),
+            millis(result.min()),
+            millis(result.avg()),
+            millis(result.p95()),
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use clevis_pin_trustee_lib::Cert;
+
+    /// Fails for the URLs in `failing`, succeeds for the others
+    struct FlakyExecutor {
+        failing: Vec<String>,
+    }
+
+    impl KeyFetcher for FlakyExecutor {
+        fn try_fetch_luks_key(
+            &self,
+            url: &str,
+            _path: &str,
+            _cert: &Cert,
+            _connection: &ConnectionSettings,
+            _initdata: Option<String>,
+            _policy_ids: &[String],
+        ) -> Result<String> {
+            if self.failing.iter().any(|failing| failing == url) {
+                return Err(anyhow!("Connection refused"));
+            }
+            Ok("key".to_string())
+        }
+    }
+
+    fn millis_result(url: &str, latencies: &[u64]) -> BenchResult {
+        BenchResult {
+            url: url.to_string(),
+            latencies: latencies
+                .iter()
+                .copied()
+                .map(Duration::from_millis)
+                .collect(),
+            failures: 0,
+        }
+    }
+
+    #[test]
+    fn test_bench_servers() {
+        let servers: Vec<Server> =
+            serde_json::from_str(r#"[{"url": "http://kbs1"}, {"url": "http://kbs2"}]"#).unwrap();
+        let executor = FlakyExecutor {
+            failing: vec!["http://kbs2".to_string()],
+        };
+
+        let results = bench_servers(
+            &servers,
+            "default/key/root",
+            None,
+            &[],
+            &ConnectionSettings::default(),
+            3,
+            &executor,
+        );
+
+        assert_eq!(results[0].latencies.len(), 3);
+        assert_eq!(results[0].failures, 0);
+        assert!(results[1].latencies.is_empty());
+        assert_eq!(results[1].failures, 3);
+        assert_eq!(results[1].p95(), None);
+    }
+
+    #[test]
+    fn test_bench_statistics() {
+        let latencies: Vec<u64> = (1..=20).rev().map(|n| n * 10).collect();
+        let result = millis_result("http://kbs1", &latencies);
+
+        assert_eq!(result.min(), Some(Duration::from_millis(10)));
+        assert_eq!(result.avg(), Some(Duration::from_millis(105)));
+        assert_eq!(result.p95(), Some(Duration::from_millis(190)));
+
+        let single = millis_result("http://kbs2", &[42]);
+        assert_eq!(single.p95(), Some(Duration::from_millis(42)));
+    }
+
+    #[test]
+    fn test_report() {
+        let mut failing = millis_result("http://kbs2", &[]);
+        failing.failures = 2;
+
+        let report = report(&[millis_result("http://kbs1", &[10, 30]), failing]);
+
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("http://kbs1"));
+        assert!(lines[1].contains("2/2"));
+        assert!(lines[1].contains("20.0ms"));
+        assert!(lines[2].contains("0/2"));
+        assert!(lines[2].ends_with('-'));
+    }
+
+    #[test]
+    fn test_report_json() {
+        let mut failing = millis_result("http://kbs2", &[]);
+        failing.failures = 2;
+
+        let report = report_json(&[millis_result("http://kbs1", &[10, 30]), failing]);
+
+        assert_eq!(report[0]["successes"], 2);
+        assert_eq!(report[0]["avg_ms"], 20.0);
+        assert_eq!(
+            report[1],
+            serde_json::json!({
+                "url": "http://kbs2",
+                "successes": 0,
+                "failures": 2,
+                "min_ms": null,
+                "avg_ms": null,
+                "p95_ms": null,
+            })
+        );
+    }
+}
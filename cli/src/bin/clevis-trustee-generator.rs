@@ -0,0 +1,28 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! systemd generator, installed in /usr/lib/systemd/system-generators, that
+//! writes the template of the units opening trustee-bound LUKS2 devices at
+//! boot. Run by udev as `clevis-trustee-generator --probe DEVICE`, it prints
+//! the properties pulling in the unit of `DEVICE`.
+
+use anyhow::{Result, anyhow};
+use clevis_pin_trustee::generator;
+use std::env;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str =
+    "Usage: clevis-trustee-generator NORMAL_DIR [EARLY_DIR LATE_DIR] | --probe DEVICE";
+
+fn main() -> Result<()> {
+    let mut args = env::args_os().skip(1);
+    let first = args.next().ok_or_else(|| anyhow!(USAGE))?;
+    if first == "--probe" {
+        let device = args.next().ok_or_else(|| anyhow!(USAGE))?;
+        print!("{}", generator::probe(Path::new(&device))?);
+        return Ok(());
+    }
+    // systemd passes the normal, early and late unit directories
+    generator::generate(&PathBuf::from(first))
+}
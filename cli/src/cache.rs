@@ -0,0 +1,379 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Opt-in cache of the keys fetched to decrypt, so repeated decrypts within
+//! the TTL, e.g. of nightly backup jobs, skip attestation. Entries are JWEs
+//! encrypted with a random key local to the machine, readable by their
+//! owner only: root, or the user decrypting without privileges. The key is
+//! kept in a state directory of its own, sealed with the TPM by the clevis
+//! tpm2 pin.
+//!
+//! Threat model: a copy of the disk, or of a backup of `/var/cache` and
+//! `/var/lib`, does not open the entries, as the key only unseals with the
+//! TPM of the machine. Whoever runs as the owner of the cache on the machine
+//! itself can unseal the key, as the decrypt does, and reads the cached keys
+//! until they expire: the cache trades attestation on each decrypt for this.
+
+use crate::entropy::OsRandom;
+use crate::jose::{self, A256GCM, DirectKey, JweHeader};
+use crate::tpm2::Sealer;
+use crate::{KeyEncoding, check_private_dir, prepare_jwk, random_key};
+use anyhow::{Context, Result, anyhow};
+use clevis_pin_trustee_lib::ClevisHeader;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Cache of root
+pub(crate) const KEY_CACHE_DIR: &str = "/var/cache/clevis-trustee";
+/// State directory of root, holding the key of its cache
+const STATE_DIR: &str = "/var/lib/clevis-trustee";
+/// Key the entries are encrypted with, sealed with the TPM, in the state
+/// directory
+const LOCAL_KEY_FILE: &str = "cache.key.jwe";
+
+#[derive(Serialize, Deserialize)]
+struct CachedKey {
+    key: String,
+    /// Seconds since the epoch after which the entry is ignored
+    expires: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Create `path` with `content`, only if it does not exist yet
+fn create_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+/// Create `dir`, only its owner having access to it, and check that an
+/// existing one is not shared with other users
+fn private_dir(dir: &Path) -> Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    Ok(check_private_dir(dir)?)
+}
+
+fn is_root() -> bool {
+    // SAFETY: geteuid cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Directory of the cache: `/var/cache/clevis-trustee` for root, the cache
+/// directory of other users, who cannot write to `/var/cache`
+pub(crate) fn key_cache_dir() -> PathBuf {
+    resolve_key_cache_dir(
+        is_root(),
+        env::var_os("XDG_CACHE_HOME"),
+        env::var_os("HOME"),
+    )
+}
+
+/// File of the key of the cache: in `/var/lib/clevis-trustee` for root, in
+/// the state directory of other users
+pub(crate) fn local_key_path() -> PathBuf {
+    resolve_local_key_path(
+        is_root(),
+        env::var_os("XDG_STATE_HOME"),
+        env::var_os("HOME"),
+    )
+}
+
+fn resolve_local_key_path(
+    root: bool,
+    state_home: Option<OsString>,
+    home: Option<OsString>,
+) -> PathBuf {
+    let not_empty = |dir: &OsString| !dir.is_empty();
+    let dir = match (root, state_home.filter(not_empty), home.filter(not_empty)) {
+        (false, Some(state_home), _) => Path::new(&state_home).join("clevis-trustee"),
+        (false, None, Some(home)) => Path::new(&home).join(".local/state/clevis-trustee"),
+        _ => PathBuf::from(STATE_DIR),
+    };
+    dir.join(LOCAL_KEY_FILE)
+}
+
+fn resolve_key_cache_dir(
+    root: bool,
+    cache_home: Option<OsString>,
+    home: Option<OsString>,
+) -> PathBuf {
+    if root {
+        return PathBuf::from(KEY_CACHE_DIR);
+    }
+    let not_empty = |dir: &OsString| !dir.is_empty();
+    match (cache_home.filter(not_empty), home.filter(not_empty)) {
+        (Some(cache_home), _) => Path::new(&cache_home).join("clevis-trustee"),
+        (None, Some(home)) => Path::new(&home).join(".cache/clevis-trustee"),
+        (None, None) => PathBuf::from(KEY_CACHE_DIR),
+    }
+}
+
+pub(crate) struct KeyCache<'a> {
+    dir: PathBuf,
+    /// File of the sealed key of the entries, outside of `dir`
+    key_path: PathBuf,
+    ttl: Duration,
+    sealer: &'a dyn Sealer,
+}
+
+impl<'a> KeyCache<'a> {
+    pub(crate) fn new(dir: &Path, key_path: &Path, ttl: Duration, sealer: &'a dyn Sealer) -> Self {
+        KeyCache {
+            dir: dir.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            ttl,
+            sealer,
+        }
+    }
+
+    /// The key of the machine, created on first use
+    fn local_key(&self) -> Result<String> {
+        let path = &self.key_path;
+        if let Some(dir) = path.parent() {
+            private_dir(dir)?;
+        }
+        if !path.exists() {
+            // No PCR policy: the key unseals on this TPM whatever boots, as
+            // a cache entry is worth no more than attesting again
+            let sealed = self
+                .sealer
+                .seal(&json!({}), random_key(&OsRandom).as_bytes())
+                .context("Failed to seal the key cache key")?;
+            match create_private(path, sealed.as_bytes()) {
+                // Created meanwhile by another decrypt
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                result => result.with_context(|| format!("Failed to create {}", path.display()))?,
+            }
+        }
+        let sealed = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let key = self
+            .sealer
+            .unseal(sealed.trim())
+            .with_context(|| format!("Failed to unseal {}", path.display()))?;
+        String::from_utf8(key).context("Invalid key cache key")
+    }
+
+    /// Entry of the binding of `header`
+    fn entry_path(&self, header: &ClevisHeader) -> Result<PathBuf> {
+        let digest = Sha256::digest(serde_json::to_vec(header)?);
+        Ok(self.dir.join(format!("{}.jwe", hex::encode(digest))))
+    }
+
+    /// The cached key of the binding of `header`, unless missing or expired
+    pub(crate) fn get(&self, header: &ClevisHeader) -> Result<Option<String>> {
+        private_dir(&self.dir)?;
+        let path = self.entry_path(header)?;
+        let entry = match fs::read_to_string(&path) {
+            Ok(entry) => entry,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let decrypter = DirectKey::from_jwk(
+            &prepare_jwk(&self.local_key()?, KeyEncoding::DERIVED)?,
+            "decrypt",
+        )
+        .map_err(|e| anyhow!("Error creating the cache decrypter: {}", e))?;
+        let (payload, _) = jose::deserialize_compact(&entry, &decrypter)
+            .map_err(|e| anyhow!("Error decrypting {}: {}", path.display(), e))?;
+        let cached: CachedKey =
+            serde_json::from_slice(&payload).context("Invalid key cache entry")?;
+        if cached.expires <= now() {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            return Ok(None);
+        }
+        Ok(Some(cached.key))
+    }
+
+    /// Cache `key`, fetched for the binding of `header`, for the TTL
+    pub(crate) fn put(&self, header: &ClevisHeader, key: &str) -> Result<()> {
+        private_dir(&self.dir)?;
+        let encrypter = DirectKey::from_jwk(
+            &prepare_jwk(&self.local_key()?, KeyEncoding::DERIVED)?,
+            "encrypt",
+        )
+        .map_err(|e| anyhow!("Error creating the cache encrypter: {}", e))?;
+        let cached = CachedKey {
+            key: key.to_string(),
+            expires: now().saturating_add(self.ttl.as_secs()),
+        };
+        let mut hdr = JweHeader::new();
+        hdr.set_content_encryption(A256GCM);
+        let entry = jose::serialize_compact(&serde_json::to_vec(&cached)?, &hdr, &encrypter)
+            .map_err(|e| anyhow!("Error encrypting the key cache entry: {}", e))?;
+
+        let path = self.entry_path(header)?;
+        // Written aside and renamed, so a concurrent decrypt never reads
+        // half an entry
+        let partial =
+            path.with_extension(format!("{}.tmp", hex::encode(rand::random::<[u8; 4]>())));
+        create_private(&partial, entry.as_bytes())
+            .and_then(|()| fs::rename(&partial, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::cell::RefCell;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Seals by storing the secret, the JWE being its index
+    #[derive(Default)]
+    struct MockSealer {
+        sealed: RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl Sealer for MockSealer {
+        fn seal(&self, _config: &Value, secret: &[u8]) -> Result<String> {
+            let mut sealed = self.sealed.borrow_mut();
+            sealed.push(secret.to_vec());
+            Ok((sealed.len() - 1).to_string())
+        }
+
+        fn unseal(&self, jwe: &str) -> Result<Vec<u8>> {
+            let index: usize = jwe.parse()?;
+            self.sealed
+                .borrow()
+                .get(index)
+                .cloned()
+                .ok_or_else(|| anyhow!("Cannot unseal {}", jwe))
+        }
+    }
+
+    fn header(path: &str) -> ClevisHeader {
+        ClevisHeader::from_claim(json!({
+            "pin": "trustee",
+            "servers": ["http://kbs:8080"],
+            "path": path,
+            "initdata": null,
+        }))
+        .unwrap()
+    }
+
+    fn cache<'a>(dir: &Path, ttl: Duration, sealer: &'a MockSealer) -> KeyCache<'a> {
+        KeyCache::new(
+            &dir.join("cache"),
+            &dir.join("state/cache.key.jwe"),
+            ttl,
+            sealer,
+        )
+    }
+
+    #[test]
+    fn test_key_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealer = MockSealer::default();
+        let cache = cache(dir.path(), Duration::from_secs(3600), &sealer);
+        let key = random_key(&OsRandom);
+
+        assert!(cache.get(&header("a/b/c")).unwrap().is_none());
+        cache.put(&header("a/b/c"), &key).unwrap();
+
+        let entry = cache.entry_path(&header("a/b/c")).unwrap();
+        assert!(!fs::read_to_string(entry).unwrap().contains(&key));
+        assert_eq!(cache.get(&header("a/b/c")).unwrap(), Some(key));
+        assert!(cache.get(&header("a/b/d")).unwrap().is_none());
+        // Only the entry is in the cache directory
+        assert_eq!(fs::read_dir(dir.path().join("cache")).unwrap().count(), 1);
+        // The key is only stored sealed
+        let local_key = String::from_utf8(sealer.sealed.borrow()[0].clone()).unwrap();
+        let stored = fs::read_to_string(dir.path().join("state/cache.key.jwe")).unwrap();
+        assert!(!stored.contains(&local_key));
+        assert_eq!(sealer.sealed.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_shared_cache_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealer = MockSealer::default();
+        let cache = cache(dir.path(), Duration::from_secs(3600), &sealer);
+        fs::create_dir(dir.path().join("cache")).unwrap();
+        fs::set_permissions(dir.path().join("cache"), fs::Permissions::from_mode(0o777)).unwrap();
+
+        let error = cache.put(&header("a/b/c"), &random_key(&OsRandom));
+
+        assert!(
+            error
+                .unwrap_err()
+                .to_string()
+                .ends_with("is not a directory only its owner can write to")
+        );
+    }
+
+    #[test]
+    fn test_expired_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let sealer = MockSealer::default();
+        let cache = cache(dir.path(), Duration::ZERO, &sealer);
+
+        cache.put(&header("a/b/c"), &random_key(&OsRandom)).unwrap();
+
+        assert!(cache.get(&header("a/b/c")).unwrap().is_none());
+        assert!(!cache.entry_path(&header("a/b/c")).unwrap().exists());
+    }
+
+    #[test]
+    fn test_resolve_key_cache_dir() {
+        let cache_home = || Some(OsString::from("/home/user/.xdg-cache"));
+        let home = || Some(OsString::from("/home/user"));
+
+        assert_eq!(
+            resolve_key_cache_dir(true, cache_home(), home()),
+            Path::new(KEY_CACHE_DIR)
+        );
+        assert_eq!(
+            resolve_key_cache_dir(false, cache_home(), home()),
+            Path::new("/home/user/.xdg-cache/clevis-trustee")
+        );
+        assert_eq!(
+            resolve_key_cache_dir(false, Some("".into()), home()),
+            Path::new("/home/user/.cache/clevis-trustee")
+        );
+    }
+
+    #[test]
+    fn test_resolve_local_key_path() {
+        let state_home = || Some(OsString::from("/home/user/.xdg-state"));
+        let home = || Some(OsString::from("/home/user"));
+
+        assert_eq!(
+            resolve_local_key_path(true, state_home(), home()),
+            Path::new("/var/lib/clevis-trustee/cache.key.jwe")
+        );
+        assert_eq!(
+            resolve_local_key_path(false, state_home(), home()),
+            Path::new("/home/user/.xdg-state/clevis-trustee/cache.key.jwe")
+        );
+        assert_eq!(
+            resolve_local_key_path(false, None, home()),
+            Path::new("/home/user/.local/state/clevis-trustee/cache.key.jwe")
+        );
+    }
+}
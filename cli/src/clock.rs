@@ -0,0 +1,110 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Sanity check of the system clock before the key fetch. With a wrong RTC,
+//! e.g. after the CMOS battery died, every certificate looks not yet valid
+//! and the whole retry budget is spent on an error only time sync can fix.
+
+use crate::logging::{Priority, log};
+use clevis_pin_trustee_lib::{TrusteePinError, format_duration};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// No clock showing an earlier time is right: 2025-01-01T00:00:00Z
+const MIN_PLAUSIBLE_TIME: u64 = 1_735_689_600;
+/// Files whose modification time the clock cannot be behind: the time saved
+/// by systemd-timesyncd at the last sync and the epoch of the OS image
+const CLOCK_STAMPS: &[&str] = &["/var/lib/systemd/timesync/clock", "/usr/lib/clock-epoch"];
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The earliest time the clock may show, given `stamps`
+fn plausible_floor(stamps: &[&str]) -> SystemTime {
+    stamps
+        .iter()
+        .filter_map(|stamp| {
+            fs::metadata(Path::new(stamp))
+                .and_then(|m| m.modified())
+                .ok()
+        })
+        .fold(
+            UNIX_EPOCH + Duration::from_secs(MIN_PLAUSIBLE_TIME),
+            SystemTime::max,
+        )
+}
+
+fn check_time(now: SystemTime, floor: SystemTime) -> Result<(), TrusteePinError> {
+    if now >= floor {
+        return Ok(());
+    }
+    Err(TrusteePinError::ClockSkew(format!(
+        "The system clock ({}) is before {}, certificates cannot be validated",
+        httpdate::fmt_http_date(now),
+        httpdate::fmt_http_date(floor)
+    )))
+}
+
+/// Check that the system clock is plausible, waiting up to `wait` for time
+/// sync to fix it. A clock still wrong is tolerated with a warning, since the
+/// servers may not use TLS, and returned to explain the failures it causes.
+pub(crate) fn check_clock(wait: Option<Duration>) -> Option<TrusteePinError> {
+    let floor = plausible_floor(CLOCK_STAMPS);
+    let wait = wait.unwrap_or_default();
+    let deadline = Instant::now() + wait;
+    let mut waiting = false;
+    loop {
+        let Err(skew) = check_time(SystemTime::now(), floor) else {
+            return None;
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            log(
+                Priority::Warning,
+                &format!("{}, trying anyway", skew),
+                &[("RESULT", "clock-skew")],
+            );
+            return Some(skew);
+        }
+        if !waiting {
+            log(
+                Priority::Info,
+                &format!(
+                    "{}, waiting up to {} for time sync",
+                    skew,
+                    format_duration(wait)
+                ),
+                &[],
+            );
+            waiting = true;
+        }
+        thread::sleep(POLL_INTERVAL.min(remaining));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    #[test]
+    fn test_check_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let stamp = dir.path().join("clock");
+        let synced = UNIX_EPOCH + Duration::from_secs(MIN_PLAUSIBLE_TIME + 86400);
+        File::create(&stamp).unwrap().set_modified(synced).unwrap();
+
+        let floor = plausible_floor(&[stamp.to_str().unwrap(), "/nonexistent"]);
+
+        assert_eq!(floor, synced);
+        assert!(check_time(synced + Duration::from_secs(1), floor).is_ok());
+        let error = check_time(UNIX_EPOCH, floor).unwrap_err();
+        assert!(matches!(error, TrusteePinError::ClockSkew(_)));
+        assert!(
+            error
+                .to_string()
+                .starts_with("The system clock (Thu, 01 Jan 1970 00:00:00 GMT) is before")
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Discovery of the servers from the platform the key is fetched on, so a
+//! golden image can be bound once and unlocked in every environment.
+//!
+//! SMBIOS OEM strings name one server per `io.clevis-trustee.url=URL` entry;
+//! an `io.clevis-trustee.cert=BASE64` entry following it holds the base64
+//! encoded PEM certificate of that server. In the cloud-init metadata, the
+//! `clevis-trustee` key holds a server object, as in the config, or a list
+//! of them, possibly as a JSON string.
+
+use crate::endpoint;
+use crate::logging::{Priority, log};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::{Cert, DiscoverySource, Server, TrusteePinError};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Raw SMBIOS structures exposed by the kernel, one directory per structure
+const DMI_ENTRIES_DIR: &str = "/sys/firmware/dmi/entries";
+/// Type of the SMBIOS OEM strings structure
+const OEM_STRINGS_TYPE: u8 = 11;
+const OEM_STRING_PREFIX: &str = "io.clevis-trustee.";
+const CLOUD_INIT_INSTANCE_DATA: &str = "/run/cloud-init/instance-data.json";
+const CLOUD_INIT_METADATA_KEY: &str = "clevis-trustee";
+
+/// Trait for reading what the platform tells about itself
+trait Platform {
+    /// Every SMBIOS OEM string, in order
+    fn oem_strings(&self) -> Result<Vec<String>>;
+    /// cloud-init instance data, `None` without cloud-init
+    fn instance_data(&self) -> Result<Option<Value>>;
+}
+
+struct RealPlatform;
+
+impl Platform for RealPlatform {
+    fn oem_strings(&self) -> Result<Vec<String>> {
+        let mut strings = Vec::new();
+        // Entries are named <type>-<instance>
+        for instance in 0.. {
+            let raw = Path::new(DMI_ENTRIES_DIR)
+                .join(format!("{}-{}", OEM_STRINGS_TYPE, instance))
+                .join("raw");
+            match fs::read(&raw) {
+                Ok(raw) => strings.extend(smbios_strings(&raw)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", raw.display()));
+                }
+            }
+        }
+        Ok(strings)
+    }
+
+    fn instance_data(&self) -> Result<Option<Value>> {
+        let data = match fs::read_to_string(CLOUD_INIT_INSTANCE_DATA) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read {}", CLOUD_INIT_INSTANCE_DATA));
+            }
+        };
+        let data = serde_json::from_str(&data)
+            .with_context(|| format!("Failed to parse {}", CLOUD_INIT_INSTANCE_DATA))?;
+        Ok(Some(data))
+    }
+}
+
+/// Strings of a raw SMBIOS structure: NUL terminated, after the formatted
+/// area whose length is the second byte of the structure
+fn smbios_strings(raw: &[u8]) -> Vec<String> {
+    let Some(strings) = raw
+        .get(1)
+        .and_then(|length| raw.get(usize::from(*length)..))
+    else {
+        return Vec::new();
+    };
+    strings
+        .split(|byte| *byte == 0)
+        .take_while(|string| !string.is_empty())
+        .map(|string| String::from_utf8_lossy(string).into_owned())
+        .collect()
+}
+
+fn servers_from_oem_strings(strings: &[String]) -> Result<Vec<Server>> {
+    let mut servers: Vec<Server> = Vec::new();
+    for setting in strings
+        .iter()
+        .filter_map(|s| s.strip_prefix(OEM_STRING_PREFIX))
+    {
+        match setting.split_once('=') {
+            Some(("url", url)) => servers.push(Server {
+                name: None,
+                url: url.to_string(),
+                cert: Cert::None,
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            }),
+            Some(("cert", cert)) => {
+                let server = servers.last_mut().ok_or_else(|| {
+                    TrusteePinError::Config(format!(
+                        "OEM string {}cert does not follow a url",
+                        OEM_STRING_PREFIX
+                    ))
+                })?;
+                let pem = general_purpose::STANDARD
+                    .decode(cert.trim())
+                    .context("The certificate in the OEM strings is not base64")?;
+                let pem = String::from_utf8(pem)
+                    .context("The certificate in the OEM strings is not PEM")?;
+                server.cert = Cert::Inline(pem);
+            }
+            _ => eprintln!(
+                "Warning: ignoring unknown OEM string {}{}",
+                OEM_STRING_PREFIX, setting
+            ),
+        }
+    }
+    Ok(servers)
+}
+
+fn servers_from_instance_data(data: &Value) -> Result<Vec<Server>> {
+    let Some(servers) = data
+        .get("ds")
+        .and_then(|ds| ds.get("meta_data"))
+        .and_then(|meta_data| meta_data.get(CLOUD_INIT_METADATA_KEY))
+    else {
+        return Ok(Vec::new());
+    };
+    // Many clouds only allow string values in the metadata
+    let servers = match servers {
+        Value::String(servers) => serde_json::from_str(servers)?,
+        servers => servers.clone(),
+    };
+    let servers = match servers {
+        Value::Array(_) => servers,
+        server => Value::Array(vec![server]),
+    };
+    serde_json::from_value(servers).map_err(|e| {
+        anyhow!(
+            "Invalid {} in the cloud-init metadata: {}",
+            CLOUD_INIT_METADATA_KEY,
+            e
+        )
+    })
+}
+
+fn discover_with<P: Platform>(platform: &P, sources: &[DiscoverySource]) -> Vec<Server> {
+    let mut servers = Vec::new();
+    for source in sources {
+        let found = match source {
+            DiscoverySource::Smbios => platform
+                .oem_strings()
+                .and_then(|strings| servers_from_oem_strings(&strings)),
+            DiscoverySource::CloudInit => platform.instance_data().and_then(|data| {
+                data.map_or(Ok(Vec::new()), |data| servers_from_instance_data(&data))
+            }),
+        };
+        let found = found.and_then(|found| Ok(endpoint::normalize_servers(found)?));
+        // Another source, or the servers of the binding, may still work
+        match found {
+            Ok(found) => {
+                for server in &found {
+                    log(
+                        Priority::Info,
+                        &format!("Discovered server {} from {}", server.url, source),
+                        &[
+                            ("SERVER_URL", &server.url),
+                            ("DISCOVERY", &source.to_string()),
+                        ],
+                    );
+                }
+                servers.extend(found);
+            }
+            Err(e) => log(
+                Priority::Warning,
+                &format!("Failed to discover servers from {}: {:#}", source, e),
+                &[
+                    ("DISCOVERY", &source.to_string()),
+                    ("ERROR", &format!("{:#}", e)),
+                ],
+            ),
+        }
+    }
+    servers
+}
+
+/// Servers found in `sources`, in order. Sources that cannot be read are
+/// skipped with a warning.
+pub(crate) fn discover(sources: &[DiscoverySource]) -> Vec<Server> {
+    discover_with(&RealPlatform, sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct MockPlatform {
+        oem_strings: Vec<String>,
+        instance_data: Option<Value>,
+    }
+
+    impl Platform for MockPlatform {
+        fn oem_strings(&self) -> Result<Vec<String>> {
+            Ok(self.oem_strings.clone())
+        }
+
+        fn instance_data(&self) -> Result<Option<Value>> {
+            Ok(self.instance_data.clone())
+        }
+    }
+
+    #[test]
+    fn test_smbios_strings() {
+        let mut raw = vec![OEM_STRINGS_TYPE, 5, 0x2a, 0x00, 2];
+        raw.extend_from_slice(b"io.clevis-trustee.url=http://kbs1\0other\0\0");
+
+        assert_eq!(
+            smbios_strings(&raw),
+            vec!["io.clevis-trustee.url=http://kbs1", "other"]
+        );
+        assert!(smbios_strings(&[OEM_STRINGS_TYPE]).is_empty());
+    }
+
+    #[test]
+    fn test_discover() {
+        let pem = "-----BEGIN CERTIFICATE-----\nAAAA\n-----END CERTIFICATE-----\n";
+        let platform = MockPlatform {
+            oem_strings: vec![
+                "io.systemd.credential:hostname=vm".to_string(),
+                "io.clevis-trustee.url=https://kbs1".to_string(),
+                format!(
+                    "io.clevis-trustee.cert={}",
+                    general_purpose::STANDARD.encode(pem)
+                ),
+                "io.clevis-trustee.url=http://kbs2".to_string(),
+            ],
+            instance_data: Some(json!({
+                "ds": {"meta_data": {
+                    "clevis-trustee": r#"{"url": "http://kbs3", "policy_ids": ["default"]}"#,
+                }},
+            })),
+        };
+
+        let servers = discover_with(
+            &platform,
+            &[DiscoverySource::CloudInit, DiscoverySource::Smbios],
+        );
+
+        let urls: Vec<&str> = servers.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://kbs3", "https://kbs1", "http://kbs2"]);
+        assert_eq!(servers[0].policy_ids, Some(vec!["default".to_string()]));
+        assert_eq!(servers[1].cert, Cert::Inline(pem.to_string()));
+        assert_eq!(servers[2].cert, Cert::None);
+    }
+
+    #[test]
+    fn test_discover_skips_invalid_sources() {
+        let platform = MockPlatform {
+            oem_strings: vec!["io.clevis-trustee.cert=AAAA".to_string()],
+            instance_data: None,
+        };
+
+        let servers = discover_with(
+            &platform,
+            &[DiscoverySource::Smbios, DiscoverySource::CloudInit],
+        );
+
+        assert!(servers.is_empty());
+    }
+}
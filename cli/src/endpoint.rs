@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Parsing of the server URLs. Hosts may be IPv6 literals, e.g.
+//! `https://[fd00::1]:8080`, so URLs are never split on `:` by hand.
+
+use clevis_pin_trustee_lib::{Cert, Server, TrusteePinError};
+use reqwest::Url;
+use std::io;
+use std::net::SocketAddr;
+
+/// `url` in canonical form: lowercase scheme and host, compressed IPv6
+/// literal, no default port and no trailing slash
+pub(crate) fn normalize_url(url: &str) -> Result<String, TrusteePinError> {
+    let invalid =
+        |reason: &str| TrusteePinError::Config(format!("Invalid URL {}: {}", url, reason));
+    let parsed = Url::parse(url.trim()).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid("the scheme must be http or https"));
+    }
+    if parsed.host().is_none() {
+        return Err(invalid("no host"));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(invalid("queries and fragments are not supported"));
+    }
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// `servers` with their URLs normalized, see `normalize_url`
+pub(crate) fn normalize_servers(servers: Vec<Server>) -> Result<Vec<Server>, TrusteePinError> {
+    servers
+        .into_iter()
+        .map(|server| {
+            Ok(Server {
+                url: normalize_url(&server.url)?,
+                ..server
+            })
+        })
+        .collect()
+}
+
+/// The servers of a binding that moved to `url`: its first server, with its
+/// certificate and settings, at the new URL
+pub(crate) fn override_servers(
+    servers: &[Server],
+    url: &str,
+    cert: Option<&Cert>,
+) -> Result<Vec<Server>, TrusteePinError> {
+    let url = normalize_url(url)?;
+    let server = match servers.first() {
+        Some(server) => Server {
+            url,
+            ..server.clone()
+        },
+        None => Server {
+            url,
+            name: None,
+            cert: cert.cloned().unwrap_or_default(),
+            policy_ids: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            headers: None,
+        },
+    };
+    Ok(vec![server])
+}
+
+/// `url` as compared with other URLs: normalized when valid, else only
+/// trimmed, so bindings made before URLs were checked still match
+pub(crate) fn comparable(url: &str) -> String {
+    normalize_url(url).unwrap_or_else(|_| url.trim().trim_end_matches('/').to_string())
+}
+
+/// Addresses the host of `url` resolves to, with the port of the URL or
+/// of its scheme
+pub(crate) fn socket_addrs(url: &str) -> io::Result<Vec<SocketAddr>> {
+    Url::parse(url)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+        .socket_addrs(|| None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_url() {
+        assert_eq!(
+            normalize_url("https://[FD00:0:0::1]:8080/").unwrap(),
+            "https://[fd00::1]:8080"
+        );
+        assert_eq!(
+            normalize_url(" HTTP://KBS.example:80/prefix/ ").unwrap(),
+            "http://kbs.example/prefix"
+        );
+        assert_eq!(normalize_url("https://[::1]").unwrap(), "https://[::1]");
+        assert!(normalize_url("fd00::1:8080").is_err());
+        assert!(normalize_url("ftp://kbs").is_err());
+        assert!(normalize_url("https://kbs/?x=1").is_err());
+        assert_eq!(comparable("kbs:8080/"), "kbs:8080");
+    }
+
+    #[test]
+    fn test_override_servers() {
+        let servers: Vec<Server> = serde_json::from_value(serde_json::json!([
+            {"url": "https://old-kbs-1", "cert": "/etc/kbs.pem", "name": "kbs-1"},
+            {"url": "https://old-kbs-2"},
+        ]))
+        .unwrap();
+
+        let moved = override_servers(&servers, "https://NEW-KBS:443/", None).unwrap();
+        assert_eq!(moved.len(), 1);
+        assert_eq!(moved[0].url, "https://new-kbs");
+        assert_eq!(moved[0].cert, servers[0].cert);
+        assert_eq!(moved[0].name.as_deref(), Some("kbs-1"));
+
+        let pem = Cert::Inline("PEM".to_string());
+        let discovered = override_servers(&[], "https://new-kbs", Some(&pem)).unwrap();
+        assert_eq!(discovered[0].cert, pem);
+        assert!(override_servers(&servers, "new-kbs", None).is_err());
+    }
+
+    #[test]
+    fn test_socket_addrs() {
+        assert_eq!(
+            socket_addrs("https://[::1]").unwrap(),
+            ["[::1]:443".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            socket_addrs("http://127.0.0.1:8080/").unwrap(),
+            ["127.0.0.1:8080".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Source of the random bytes of the keys, secrets and passphrases the pin
+//! generates and of the retry jitter. They all come from a `RandomSource`,
+//! the OS RNG outside of tests, so the entropy sources can be reviewed here
+//! and tests can use a seeded generator to be reproducible.
+
+use rand::rngs::OsRng;
+use rand::{RngCore, TryRngCore};
+use std::cell::RefCell;
+use std::time::Duration;
+
+pub(crate) trait RandomSource {
+    /// Fill `dest` with random bytes
+    fn fill(&self, dest: &mut [u8]);
+}
+
+/// The random number generator of the operating system, getrandom(2) on
+/// Linux
+pub(crate) struct OsRandom;
+
+impl RandomSource for OsRandom {
+    fn fill(&self, dest: &mut [u8]) {
+        // As rand::random, there is nothing sensible to do without entropy
+        OsRng
+            .try_fill_bytes(dest)
+            .expect("the OS random number generator failed");
+    }
+}
+
+/// Any generator of the rand crate, e.g. a seeded `StdRng` in tests
+impl<R: RngCore> RandomSource for RefCell<R> {
+    fn fill(&self, dest: &mut [u8]) {
+        self.borrow_mut().fill_bytes(dest);
+    }
+}
+
+/// `N` random bytes of `rng`
+pub(crate) fn random_bytes<const N: usize>(rng: &dyn RandomSource) -> [u8; N] {
+    let mut bytes = [0; N];
+    rng.fill(&mut bytes);
+    bytes
+}
+
+/// Random delay between half of `delay` and `delay`, so clients failing
+/// together do not retry in lockstep
+pub(crate) fn jitter(rng: &dyn RandomSource, delay: Duration) -> Duration {
+    let fraction = u64::from_le_bytes(random_bytes(rng)) as f64 / u64::MAX as f64;
+    delay.mul_f64(0.5 + fraction / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_seeded_source() {
+        let seeded = |seed| RefCell::new(StdRng::seed_from_u64(seed));
+
+        assert_eq!(
+            random_bytes::<16>(&seeded(7)),
+            random_bytes::<16>(&seeded(7))
+        );
+        assert_ne!(
+            random_bytes::<16>(&seeded(7)),
+            random_bytes::<16>(&seeded(8))
+        );
+        assert_ne!(random_bytes::<16>(&OsRandom), [0; 16]);
+
+        let rng = seeded(7);
+        for _ in 0..100 {
+            let delay = jitter(&rng, Duration::from_secs(10));
+            assert!((Duration::from_secs(5)..=Duration::from_secs(10)).contains(&delay));
+        }
+    }
+}
@@ -0,0 +1,185 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Happy Eyeballs (RFC 8305) choice of the address of a dual-stack server.
+//! Connections to the addresses are started a short delay apart, alternating
+//! between IPv6 and IPv4, and the first to connect wins, so a broken IPv6
+//! route costs that delay instead of a whole attempt timeout.
+
+use crate::endpoint;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Delay before the next address is tried, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// `addrs` in the order they are tried: alternating between the families,
+/// starting with the one the resolver preferred
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let (mut first, mut second) = if prefer_v6 {
+        (v6.into_iter(), v4.into_iter())
+    } else {
+        (v4.into_iter(), v6.into_iter())
+    };
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Race `connect` over `addrs`, starting the next attempt after `delay` or
+/// as soon as one fails, and return the first address that connected.
+/// Attempts still running when one wins or `timeout` elapses are abandoned.
+fn race<C>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    timeout: Duration,
+    connect: C,
+) -> io::Result<SocketAddr>
+where
+    C: Fn(SocketAddr, Duration) -> io::Result<()> + Send + Sync + 'static,
+{
+    let connect = Arc::new(connect);
+    let (sender, receiver) = mpsc::channel();
+    let deadline = Instant::now() + timeout;
+    let mut next = addrs.iter();
+    let mut pending = 0;
+    let mut last_error = None;
+    loop {
+        if let Some(&addr) = next.next() {
+            let (connect, sender) = (Arc::clone(&connect), sender.clone());
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            thread::spawn(move || {
+                // The receiver is gone once another attempt won
+                let _ = sender.send((addr, connect(addr, remaining)));
+            });
+            pending += 1;
+        } else if pending == 0 {
+            break;
+        }
+        let wait_until = if next.len() > 0 {
+            deadline.min(Instant::now() + delay)
+        } else {
+            deadline
+        };
+        match receiver.recv_timeout(wait_until.saturating_duration_since(Instant::now())) {
+            Ok((addr, Ok(()))) => return Ok(addr),
+            Ok((_, Err(e))) => {
+                pending -= 1;
+                last_error = Some(e);
+            }
+            Err(_) if Instant::now() >= deadline => break,
+            Err(_) => {}
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "connection timed out")))
+}
+
+/// The address of the host of `url` that accepts a connection first, when
+/// the host name resolves to several addresses of both families. `None`
+/// leaves the choice to the HTTP client.
+pub(crate) fn pick_address(
+    url: &str,
+    timeout: Duration,
+) -> io::Result<Option<(String, SocketAddr)>> {
+    let Some(domain) = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.domain().map(str::to_string))
+    else {
+        return Ok(None);
+    };
+    let addrs = endpoint::socket_addrs(url)?;
+    if !(addrs.iter().any(SocketAddr::is_ipv6) && addrs.iter().any(SocketAddr::is_ipv4)) {
+        return Ok(None);
+    }
+    let addr = race(
+        &interleave(addrs),
+        CONNECTION_ATTEMPT_DELAY,
+        timeout,
+        |addr, timeout| TcpStream::connect_timeout(&addr, timeout).map(drop),
+    )?;
+    Ok(Some((domain, addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        assert_eq!(
+            interleave(addrs(&["[::1]:80", "[::2]:80", "[::3]:80", "10.0.0.1:80"])),
+            addrs(&["[::1]:80", "10.0.0.1:80", "[::2]:80", "[::3]:80"])
+        );
+        assert_eq!(
+            interleave(addrs(&["10.0.0.1:80", "10.0.0.2:80", "[::1]:80"])),
+            addrs(&["10.0.0.1:80", "[::1]:80", "10.0.0.2:80"])
+        );
+    }
+
+    #[test]
+    fn test_race() {
+        // IPv6 is black-holed: its attempt hangs until the timeout
+        let blackholed = |addr: SocketAddr, timeout: Duration| {
+            if addr.is_ipv6() {
+                thread::sleep(timeout);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            } else {
+                Ok(())
+            }
+        };
+        let start = Instant::now();
+
+        let addr = race(
+            &addrs(&["[::1]:80", "10.0.0.1:80"]),
+            Duration::from_millis(20),
+            Duration::from_secs(30),
+            blackholed,
+        )
+        .unwrap();
+
+        assert_eq!(addr, "10.0.0.1:80".parse().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // A refused attempt starts the next one right away
+        let refused = |addr: SocketAddr, _: Duration| {
+            if addr.port() == 1 {
+                Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused"))
+            } else {
+                Ok(())
+            }
+        };
+        let addr = race(
+            &addrs(&["[::1]:1", "10.0.0.1:2"]),
+            Duration::from_secs(30),
+            Duration::from_secs(60),
+            refused,
+        )
+        .unwrap();
+        assert_eq!(addr.port(), 2);
+
+        let error = race(
+            &addrs(&["[::1]:1", "10.0.0.1:1"]),
+            Duration::from_millis(20),
+            Duration::from_secs(30),
+            refused,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
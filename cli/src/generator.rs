@@ -0,0 +1,134 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! systemd generator writing the template unit that opens a trustee-bound
+//! LUKS2 device once the network is up, instead of crypttab keyscripts.
+//!
+//! Generators run before the devices are probed, so the instances come from
+//! udev: the rule shipped as `90-clevis-trustee.rules` runs the generator
+//! with `--probe` on every LUKS device, which reads the header for trustee
+//! tokens and has udev pull in the unit of the device, keyed on its UUID.
+//! The volumes open as `luks-<UUID>`, before `remote-cryptsetup.target`, so
+//! they should be left out of crypttab and mounted with `_netdev`.
+
+use crate::luks;
+use crate::systemd::EXIT_PERMANENT;
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+/// Where the clevis-pin-trustee binary is installed
+const CLI_PATH: &str = "/usr/bin/clevis-pin-trustee";
+const CRYPTSETUP_PATH: &str = "/usr/sbin/cryptsetup";
+/// Template of the unlock units, instantiated with the escaped UUID
+const UNIT_TEMPLATE: &str = "clevis-trustee-unlock@.service";
+/// Target of the volumes needing the network, as `_netdev` ones of crypttab
+const TARGET: &str = "remote-cryptsetup.target";
+/// Delay before an unlock that gave up on transient failures is run again;
+/// a permanent failure is not retried
+const RESTART_DELAY: &str = "30s";
+
+/// Escape `path` as `systemd-escape --path` does, for unit names
+fn escape_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    let mut escaped = String::with_capacity(path.len());
+    for (index, byte) in path.bytes().enumerate() {
+        match byte {
+            b'/' => escaped.push('-'),
+            b'.' if index == 0 => escaped.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => {
+                escaped.push(byte as char)
+            }
+            _ => {
+                let _ = write!(escaped, "\\x{:02x}", byte);
+            }
+        }
+    }
+    escaped
+}
+
+/// Unlock unit of the LUKS2 device with the UUID `uuid`
+fn unit_name(uuid: &str) -> String {
+    UNIT_TEMPLATE.replace('@', &format!("@{}", escape_path(uuid)))
+}
+
+/// Unit opening `/dev/disk/by-uuid/<instance>` as `luks-<instance>`. Only
+/// the instance varies, so nothing is interpolated in the unit.
+fn unlock_template() -> String {
+    format!(
+        "# Generated by clevis-trustee-generator\n\
+         [Unit]\n\
+         Description=Unlock LUKS2 device %I with the trustee pin\n\
+         DefaultDependencies=no\n\
+         Wants=network-online.target\n\
+         After=network-online.target dev-disk-by\\x2duuid-%i.device\n\
+         BindsTo=dev-disk-by\\x2duuid-%i.device\n\
+         Before={TARGET} umount.target\n\
+         Conflicts=umount.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         RemainAfterExit=yes\n\
+         NotifyAccess=main\n\
+         Restart=on-failure\n\
+         RestartSec={RESTART_DELAY}\n\
+         RestartPreventExitStatus={EXIT_PERMANENT}\n\
+         ExecStart={CLI_PATH} --log-target journald unlock --device /dev/disk/by-uuid/%I --name luks-%I\n\
+         ExecStop={CRYPTSETUP_PATH} close luks-%I\n"
+    )
+}
+
+/// Write the template of the unlock units to `dir`, the normal directory
+/// systemd gives generators
+pub fn generate(dir: &Path) -> Result<()> {
+    let path = dir.join(UNIT_TEMPLATE);
+    fs::write(&path, unlock_template())
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// udev properties pulling in the unlock unit of `device`, none when it
+/// has no trustee binding
+pub fn probe(device: &Path) -> Result<String> {
+    Ok(match luks::bound_uuid(device)? {
+        Some(uuid) => format!("CLEVIS_TRUSTEE_UNIT={}\n", unit_name(&uuid)),
+        None => String::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_path() {
+        assert_eq!(
+            escape_path("/dev/disk/by-uuid/12ab-34"),
+            "dev-disk-by\\x2duuid-12ab\\x2d34"
+        );
+        assert_eq!(escape_path(".hidden/a b"), "\\x2ehidden-a\\x20b");
+    }
+
+    #[test]
+    fn test_unit_name() {
+        assert_eq!(
+            unit_name("0a1b2c3d-0000-4000"),
+            "clevis-trustee-unlock@0a1b2c3d\\x2d0000\\x2d4000.service"
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        let dir = tempfile::tempdir().unwrap();
+
+        generate(dir.path()).unwrap();
+
+        let unit = fs::read_to_string(dir.path().join(UNIT_TEMPLATE)).unwrap();
+        assert!(unit.contains("After=network-online.target dev-disk-by\\x2duuid-%i.device\n"));
+        assert!(unit.contains("unlock --device /dev/disk/by-uuid/%I --name luks-%I\n"));
+        assert!(unit.contains("ExecStop=/usr/sbin/cryptsetup close luks-%I\n"));
+        assert!(unit.contains("Before=remote-cryptsetup.target umount.target\n"));
+        assert!(unit.contains("RestartPreventExitStatus=77\n"));
+    }
+}
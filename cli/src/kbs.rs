@@ -0,0 +1,1075 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Native client for the Trustee KBS attestation protocol.
+//!
+//! Runs the request/challenge/attestation/response (RCAR) handshake directly
+//! against the KBS instead of spawning `trustee-attester`, with the TEE
+//! evidence supplied by an [`EvidenceProvider`].
+
+use crate::jose::{self, TeeKey};
+use crate::logging::{self, Priority, log};
+use crate::{eyeballs, header_map, http_client_builder, tls};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::{Cert, ConnectionSettings, FailureCause, TrusteePinError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "native-kbs")]
+use {
+    crate::KeyFetcher,
+    aws_lc_rs::rsa::KeySize,
+    serde::Deserialize,
+    serde_json::{Map, Value, json},
+    sha2::{Digest, Sha384},
+};
+
+/// KBS protocol versions spoken by the native client, newest first
+#[cfg(feature = "native-kbs")]
+pub(crate) const KBS_PROTOCOL_VERSIONS: &[&str] = &["0.4.0", "0.1.1"];
+
+const SESSION_COOKIE: &str = "kbs-session-id";
+/// Headers carrying credentials, whose values are not traced
+const SECRET_HEADERS: &[reqwest::header::HeaderName] = &[
+    reqwest::header::AUTHORIZATION,
+    reqwest::header::PROXY_AUTHORIZATION,
+    reqwest::header::COOKIE,
+    reqwest::header::SET_COOKIE,
+];
+/// Parts of the names of other headers whose values are not traced, such
+/// as the gateway credentials of the configured `headers`
+const SECRET_HEADER_WORDS: &[&str] = &["token", "key", "secret", "auth", "password"];
+/// Limit on picking the address of a dual-stack server without an attempt
+/// timeout
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+#[cfg(feature = "native-kbs")]
+const TEE_KEY_SIZE: KeySize = KeySize::Rsa2048;
+#[cfg(feature = "native-kbs")]
+const TEE_KEY_ALGORITHM: &str = "RSA-OAEP";
+
+/// Trait for collecting TEE evidence bound to a KBS challenge
+#[cfg(feature = "native-kbs")]
+pub(crate) trait EvidenceProvider {
+    fn tee(&self) -> Result<String>;
+    fn evidence(&self, report_data: &[u8]) -> Result<String>;
+}
+
+/// Minimal view of an HTTP response from the KBS
+pub(crate) struct KbsResponse {
+    pub status: u16,
+    pub body: String,
+    #[cfg_attr(not(feature = "native-kbs"), allow(dead_code))]
+    pub session: Option<String>,
+    /// Delay asked for with `Retry-After` by an overloaded or maintained KBS
+    pub retry_after: Option<Duration>,
+}
+
+impl KbsResponse {
+    fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+
+    /// Error for this failed response from the KBS at `url`
+    fn error(&self, url: &str, message: String) -> TrusteePinError {
+        TrusteePinError::from_status(url, self.status, message).with_retry_after(self.retry_after)
+    }
+}
+
+/// Delay in a `Retry-After` header: seconds or an HTTP date
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Proof of a successful attestation presented when requesting a resource
+pub(crate) enum Credential<'a> {
+    /// Session cookie from an RCAR handshake run by this process
+    #[cfg(feature = "native-kbs")]
+    Session(&'a str),
+    /// Attestation token obtained by someone else, e.g. the attestation-agent
+    #[cfg(feature = "aa-backend")]
+    Bearer(&'a str),
+}
+
+/// Trait for the HTTP requests issued during the KBS handshake
+pub(crate) trait KbsTransport {
+    #[cfg(feature = "native-kbs")]
+    fn post_json(&self, url: &str, body: &Value, session: Option<&str>) -> Result<KbsResponse>;
+    fn get(&self, url: &str, credential: &Credential) -> Result<KbsResponse>;
+}
+
+/// Real transport backed by a blocking reqwest client
+pub(crate) struct ReqwestTransport {
+    client: reqwest::blocking::Client,
+}
+
+impl ReqwestTransport {
+    /// Transport to the server at `url` trusting `cert` and made with
+    /// `connection`, aborting requests that take longer than `timeout`. Of
+    /// the addresses of a dual-stack server, the first to accept a
+    /// connection is used.
+    pub(crate) fn new(
+        url: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        let tls = &connection.tls;
+        let pem = cert.pem().map_err(|e| {
+            TrusteePinError::Config(format!("Failed to read server certificate: {}", e))
+        })?;
+        let invalid_cert = |e: anyhow::Error| {
+            TrusteePinError::Config(format!("Invalid server certificate: {:#}", e))
+        };
+        let mut builder = if tls.is_default() {
+            http_client_builder(pem.as_deref().unwrap_or_default(), timeout)
+                .map_err(invalid_cert)?
+        } else {
+            let config = tls::client_config(pem.as_deref().filter(|pem| !pem.is_empty()), tls)
+                .map_err(invalid_cert)?;
+            http_client_builder("", timeout)?.tls_backend_preconfigured(config)
+        };
+        builder = builder.default_headers(header_map(&connection.headers)?);
+        if let Some((domain, addr)) =
+            eyeballs::pick_address(url, timeout.unwrap_or(CONNECT_TIMEOUT))
+                .with_context(|| format!("Failed to connect to {}", url))?
+        {
+            builder = builder.resolve(&domain, addr);
+        }
+        let client = builder.build().context("Failed to build HTTPS client")?;
+        Ok(Self { client })
+    }
+
+    /// Send `request`, tracing it and its response with `--trace-http`
+    fn send(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> reqwest::Result<reqwest::blocking::Response> {
+        if !logging::trace_http() {
+            return request.send();
+        }
+        let request = request.build()?;
+        log(
+            Priority::Info,
+            &trace_message(
+                '>',
+                &format!("{} {}", request.method(), request.url()),
+                request.headers(),
+            ),
+            &[],
+        );
+        let response = self.client.execute(request)?;
+        log(
+            Priority::Info,
+            &trace_message(
+                '<',
+                &format!("{:?} {}", response.version(), response.status()),
+                response.headers(),
+            ),
+            &[],
+        );
+        Ok(response)
+    }
+
+    fn response(response: reqwest::blocking::Response) -> Result<KbsResponse> {
+        let status = response.status().as_u16();
+        let session = response
+            .headers()
+            .get_all(reqwest::header::SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|cookie| cookie.split(';').next())
+            .find(|cookie| cookie.starts_with(&format!("{}=", SESSION_COOKIE)))
+            .map(str::to_string);
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .filter(|_| matches!(status, 429 | 503))
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        let body = response.text().context("Failed to read KBS response")?;
+        Ok(KbsResponse {
+            status,
+            body,
+            session,
+            retry_after,
+        })
+    }
+}
+
+/// Value of the header `name` as traced: the credentials are replaced, only
+/// the names of the cookies are kept
+fn redact_header(name: &reqwest::header::HeaderName, value: &str) -> String {
+    if !SECRET_HEADERS.contains(name) {
+        // Header names are lowercase
+        return if SECRET_HEADER_WORDS
+            .iter()
+            .any(|word| name.as_str().contains(word))
+        {
+            "<redacted>".to_string()
+        } else {
+            value.to_string()
+        };
+    }
+    if name == reqwest::header::AUTHORIZATION || name == reqwest::header::PROXY_AUTHORIZATION {
+        return match value.split_once(' ') {
+            Some((scheme, _)) => format!("{} <redacted>", scheme),
+            None => "<redacted>".to_string(),
+        };
+    }
+    value
+        .split(';')
+        .enumerate()
+        .map(|(index, pair)| match pair.split_once('=') {
+            // The pairs after the first of a Set-Cookie are its attributes
+            Some((key, _)) if index == 0 || name == reqwest::header::COOKIE => {
+                format!("{}=<redacted>", key)
+            }
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Trace of the request or status `line` and the `headers`, prefixed with
+/// `>` for requests and `<` for responses as curl does
+fn trace_message(direction: char, line: &str, headers: &reqwest::header::HeaderMap) -> String {
+    let mut message = format!("{} {}", direction, line);
+    for (name, value) in headers {
+        let value = redact_header(name, value.to_str().unwrap_or("<binary>"));
+        message.push_str(&format!("\n{} {}: {}", direction, name, value));
+    }
+    message
+}
+
+/// Server URL, certificate PEM and connection settings a transport was made
+/// for
+type TransportKey = (String, Option<String>, ConnectionSettings);
+
+/// Transports of the servers already contacted, kept by the executors so the
+/// further resources and attempts of a fetch reuse the pooled connections,
+/// over HTTP/2 when the server negotiates it with ALPN, instead of paying a
+/// TCP and TLS handshake each time
+pub(crate) struct TransportPool {
+    /// Limit on each request
+    timeout: Option<Duration>,
+    transports: RefCell<HashMap<TransportKey, Rc<ReqwestTransport>>>,
+}
+
+impl TransportPool {
+    pub(crate) fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            transports: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Run `request` with the transport to the server at `url` trusting
+    /// `cert` and made with `connection`. A failed request drops the
+    /// transport, so the next attempt starts over with new connections, e.g.
+    /// to another address.
+    pub(crate) fn with_transport<T>(
+        &self,
+        url: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        request: impl FnOnce(&ReqwestTransport) -> Result<T>,
+    ) -> Result<T> {
+        let pem = cert.pem().map_err(|e| {
+            TrusteePinError::Config(format!("Failed to read server certificate: {}", e))
+        })?;
+        let key = (url.to_string(), pem, connection.clone());
+        let cached = self.transports.borrow().get(&key).cloned();
+        let transport = match cached {
+            Some(transport) => transport,
+            None => {
+                let transport =
+                    Rc::new(ReqwestTransport::new(url, cert, connection, self.timeout)?);
+                self.transports
+                    .borrow_mut()
+                    .insert(key.clone(), Rc::clone(&transport));
+                transport
+            }
+        };
+        let result = request(&transport);
+        if result.is_err() {
+            self.transports.borrow_mut().remove(&key);
+        }
+        result
+    }
+}
+
+/// Error for a `method` request to `url` that got no response
+fn send_error(method: &str, url: &str, error: reqwest::Error) -> TrusteePinError {
+    let cause = if error.is_timeout() {
+        FailureCause::Timeout
+    } else {
+        FailureCause::Network
+    };
+    TrusteePinError::transport(
+        url,
+        cause,
+        format!("Failed to send {} request to {}: {}", method, url, error),
+    )
+}
+
+impl KbsTransport for ReqwestTransport {
+    #[cfg(feature = "native-kbs")]
+    fn post_json(&self, url: &str, body: &Value, session: Option<&str>) -> Result<KbsResponse> {
+        let mut request = self.client.post(url).json(body);
+        if let Some(cookie) = session {
+            request = request.header(reqwest::header::COOKIE, cookie);
+        }
+        let response = self.send(request).map_err(|e| send_error("POST", url, e))?;
+        Self::response(response)
+    }
+
+    fn get(&self, url: &str, credential: &Credential) -> Result<KbsResponse> {
+        let request = match credential {
+            #[cfg(feature = "native-kbs")]
+            Credential::Session(cookie) => self
+                .client
+                .get(url)
+                .header(reqwest::header::COOKIE, *cookie),
+            #[cfg(feature = "aa-backend")]
+            Credential::Bearer(token) => self.client.get(url).bearer_auth(token),
+        };
+        let response = self.send(request).map_err(|e| send_error("GET", url, e))?;
+        Self::response(response)
+    }
+}
+
+#[cfg(feature = "native-kbs")]
+#[derive(Deserialize)]
+struct Challenge {
+    nonce: String,
+}
+
+#[cfg(feature = "native-kbs")]
+#[derive(Deserialize)]
+struct KbsError {
+    #[serde(rename = "type")]
+    error_type: String,
+}
+
+#[cfg(feature = "native-kbs")]
+/// Whether the KBS refused the handshake because of the protocol version
+fn is_version_mismatch(response: &KbsResponse) -> bool {
+    if !(400..500).contains(&response.status) {
+        return false;
+    }
+    match serde_json::from_str::<KbsError>(&response.body) {
+        Ok(error) => error.error_type.ends_with("ProtocolVersion"),
+        Err(_) => response.body.to_lowercase().contains("protocol version"),
+    }
+}
+
+/// Request a resource from the KBS, returning the JWE-protected response body
+pub(crate) fn get_resource<T: KbsTransport>(
+    transport: &T,
+    url: &str,
+    path: &str,
+    credential: &Credential,
+) -> Result<String> {
+    let resource_url = format!(
+        "{}/kbs/v0/resource/{}",
+        url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let response = transport.get(&resource_url, credential)?;
+    if !response.is_success() {
+        return Err(response
+            .error(
+                url,
+                format!(
+                    "Resource request failed with status {}: {}",
+                    response.status, response.body
+                ),
+            )
+            .into());
+    }
+    Ok(response.body)
+}
+
+/// Decrypt a resource response with the TEE private key and encode it like
+/// `trustee-attester` does, so every backend hands back base64
+pub(crate) fn decrypt_resource(body: &str, tee_key: &TeeKey) -> Result<String> {
+    let resource = jose::deserialize_json(body, tee_key)
+        .map_err(|e| TrusteePinError::Crypto(format!("Failed to decrypt KBS resource: {}", e)))?;
+    if resource.is_empty() {
+        return Err(anyhow!("Received empty LUKS key"));
+    }
+    Ok(general_purpose::STANDARD.encode(resource))
+}
+
+#[cfg(feature = "native-kbs")]
+/// Check that a pinned protocol version is one the native client speaks
+pub(crate) fn validate_protocol_version(version: &str) -> Result<&'static str> {
+    KBS_PROTOCOL_VERSIONS
+        .iter()
+        .find(|supported| **supported == version)
+        .copied()
+        .ok_or_else(|| {
+            anyhow!(
+                "Unsupported KBS protocol version {} (supported: {})",
+                version,
+                KBS_PROTOCOL_VERSIONS.join(", ")
+            )
+        })
+}
+
+/// A fresh TEE key pair and its public JWK, sent with the evidence
+#[cfg(feature = "native-kbs")]
+fn tee_key_pair() -> Result<(TeeKey, Value)> {
+    let key_pair = TeeKey::generate(TEE_KEY_SIZE).context("Failed to generate TEE key pair")?;
+    let mut public_key = key_pair.public_jwk();
+    public_key.set_algorithm(TEE_KEY_ALGORITHM);
+    let public_key: Map<String, Value> = public_key.into();
+    Ok((key_pair, public_key.into()))
+}
+
+#[cfg(feature = "native-kbs")]
+/// Attestation session established with a KBS
+struct Session {
+    cookie: String,
+    /// Private half of the TEE key the resources are encrypted to
+    tee_key: TeeKey,
+}
+
+/// What an attestation was made for: the server URL, the initdata and the
+/// policy ids. Sessions are only reused for the same ones.
+#[cfg(feature = "native-kbs")]
+type SessionKey = (String, Option<String>, Vec<String>);
+
+#[cfg(feature = "native-kbs")]
+/// Key fetcher running the KBS handshake in-process. The session of a
+/// successful attestation is kept, so fetching further resources from the
+/// same server does not attest again.
+pub(crate) struct NativeKbsExecutor<P: EvidenceProvider> {
+    evidence: P,
+    protocol_version: Option<&'static str>,
+    transports: TransportPool,
+    negotiated: RefCell<HashMap<String, &'static str>>,
+    sessions: RefCell<HashMap<SessionKey, Session>>,
+}
+
+#[cfg(feature = "native-kbs")]
+impl<P: EvidenceProvider> NativeKbsExecutor<P> {
+    pub(crate) fn new(
+        evidence: P,
+        protocol_version: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Self> {
+        Ok(Self {
+            evidence,
+            protocol_version: protocol_version
+                .map(validate_protocol_version)
+                .transpose()?,
+            transports: TransportPool::new(timeout),
+            negotiated: RefCell::new(HashMap::new()),
+            sessions: RefCell::new(HashMap::new()),
+        })
+    }
+
+    /// Versions to offer to a server: the pinned one, the one negotiated
+    /// earlier in this process, or every supported version newest first.
+    fn candidate_versions(&self, url: &str) -> Vec<&'static str> {
+        if let Some(version) = self.protocol_version {
+            return vec![version];
+        }
+        match self.negotiated.borrow().get(url) {
+            Some(version) => vec![*version],
+            None => KBS_PROTOCOL_VERSIONS.to_vec(),
+        }
+    }
+
+    fn authenticate<T: KbsTransport>(
+        &self,
+        transport: &T,
+        url: &str,
+        tee: &str,
+        policy_ids: &[String],
+    ) -> Result<(&'static str, Challenge, Option<String>)> {
+        let versions = self.candidate_versions(url);
+        let mut extra_params = Map::new();
+        if !policy_ids.is_empty() {
+            extra_params.insert("policy_ids".to_string(), json!(policy_ids));
+        }
+
+        for version in &versions {
+            let request = json!({
+                "version": version,
+                "tee": tee,
+                "extra-params": extra_params,
+            });
+            let response = transport.post_json(&format!("{}/kbs/v0/auth", url), &request, None)?;
+            if response.is_success() {
+                let challenge: Challenge = serde_json::from_str(&response.body)
+                    .context("Failed to parse KBS challenge")?;
+                self.negotiated
+                    .borrow_mut()
+                    .insert(url.to_string(), version);
+                return Ok((version, challenge, response.session));
+            }
+            if is_version_mismatch(&response) {
+                eprintln!("KBS at {} rejected protocol version {}", url, version);
+                continue;
+            }
+            return Err(response
+                .error(
+                    url,
+                    format!(
+                        "KBS authentication failed with status {}: {}",
+                        response.status, response.body
+                    ),
+                )
+                .into());
+        }
+
+        Err(TrusteePinError::permanent(
+            url,
+            format!(
+                "KBS at {} accepted none of the protocol versions {}",
+                url,
+                versions.join(", ")
+            ),
+        )
+        .into())
+    }
+
+    fn attestation_request(
+        &self,
+        version: &str,
+        nonce: &str,
+        tee_pubkey: Value,
+        initdata: Option<String>,
+    ) -> Result<Value> {
+        if version == "0.1.1" {
+            // The digest measured at launch would no longer match what the
+            // policy of the binding checks, so do not attest without it
+            if initdata.is_some() {
+                return Err(TrusteePinError::Config(
+                    "The KBS speaks protocol 0.1.1, which cannot carry the initdata of the binding"
+                        .to_string(),
+                )
+                .into());
+            }
+            let mut hasher = Sha384::new();
+            hasher.update(nonce.as_bytes());
+            hasher.update(serde_json::to_vec(&tee_pubkey)?);
+            let evidence = self.evidence.evidence(&hasher.finalize())?;
+            return Ok(json!({
+                "tee-pubkey": tee_pubkey,
+                "tee-evidence": evidence,
+            }));
+        }
+
+        let runtime_data = json!({
+            "nonce": nonce,
+            "tee-pubkey": tee_pubkey,
+        });
+        let evidence = self
+            .evidence
+            .evidence(&Sha384::digest(serde_json::to_vec(&runtime_data)?))?;
+        let primary_evidence: Value =
+            serde_json::from_str(&evidence).context("TEE evidence is not valid JSON")?;
+        let mut request = json!({
+            "runtime-data": runtime_data,
+            "tee-evidence": {
+                "primary_evidence": primary_evidence,
+                "additional_evidence": "",
+            },
+        });
+        if let Some(body) = initdata {
+            request["init-data"] = json!({ "format": "toml", "body": body });
+        }
+        Ok(request)
+    }
+
+    fn attest<T: KbsTransport>(
+        &self,
+        transport: &T,
+        url: &str,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<Session> {
+        let tee = self.evidence.tee()?;
+        let (version, challenge, session) = self.authenticate(transport, url, &tee, policy_ids)?;
+        let session = session.ok_or_else(|| anyhow!("KBS did not return a session cookie"))?;
+
+        let (key_pair, public_key) = tee_key_pair()?;
+        let request = self.attestation_request(version, &challenge.nonce, public_key, initdata)?;
+        let response =
+            transport.post_json(&format!("{}/kbs/v0/attest", url), &request, Some(&session))?;
+        if !response.is_success() {
+            return Err(response
+                .error(
+                    url,
+                    format!(
+                        "Attestation rejected with status {}: {}",
+                        response.status, response.body
+                    ),
+                )
+                .into());
+        }
+
+        Ok(Session {
+            cookie: session,
+            tee_key: key_pair,
+        })
+    }
+
+    /// The TEE type and the attestation request this client would send for
+    /// `nonce`, with a throwaway TEE key, collected without contacting a KBS
+    pub(crate) fn dump_evidence(&self, nonce: &str, initdata: Option<String>) -> Result<Value> {
+        let version = self.protocol_version.unwrap_or(KBS_PROTOCOL_VERSIONS[0]);
+        let (_, public_key) = tee_key_pair()?;
+        Ok(json!({
+            "tee": self.evidence.tee()?,
+            "version": version,
+            "request": self.attestation_request(version, nonce, public_key, initdata)?,
+        }))
+    }
+
+    fn session_resource<T: KbsTransport>(
+        &self,
+        transport: &T,
+        url: &str,
+        path: &str,
+        session: &Session,
+    ) -> Result<String> {
+        let body = get_resource(transport, url, path, &Credential::Session(&session.cookie))?;
+        decrypt_resource(&body, &session.tee_key)
+    }
+
+    fn fetch_resource<T: KbsTransport>(
+        &self,
+        transport: &T,
+        url: &str,
+        path: &str,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<String> {
+        let url = url.trim_end_matches('/');
+        let key = (url.to_string(), initdata.clone(), policy_ids.to_vec());
+
+        let cached = self.sessions.borrow_mut().remove(&key);
+        if let Some(session) = cached {
+            match self.session_resource(transport, url, path, &session) {
+                Ok(resource) => {
+                    self.sessions.borrow_mut().insert(key, session);
+                    return Ok(resource);
+                }
+                // The session may have expired since
+                Err(e) => eprintln!(
+                    "Request in the attestation session with {} failed, attesting again: {:#}",
+                    url, e
+                ),
+            }
+        }
+
+        let session = self.attest(transport, url, initdata, policy_ids)?;
+        let resource = self.session_resource(transport, url, path, &session);
+        self.sessions.borrow_mut().insert(key, session);
+        resource
+    }
+}
+
+#[cfg(feature = "native-kbs")]
+impl<P: EvidenceProvider> KeyFetcher for NativeKbsExecutor<P> {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<String> {
+        self.transports
+            .with_transport(url, cert, connection, |transport| {
+                self.fetch_resource(transport, url, path, initdata, policy_ids)
+            })
+    }
+}
+
+#[cfg(all(test, feature = "native-kbs"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2026 07:28:00 GMT").unwrap();
+
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:29:30 GMT", now),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2026 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    struct MockEvidence;
+
+    impl EvidenceProvider for MockEvidence {
+        fn tee(&self) -> Result<String> {
+            Ok("sample".to_string())
+        }
+
+        fn evidence(&self, report_data: &[u8]) -> Result<String> {
+            Ok(json!({ "report_data": hex::encode(report_data) }).to_string())
+        }
+    }
+
+    struct MockTransport {
+        responses: RefCell<Vec<KbsResponse>>,
+        requests: RefCell<Vec<(String, Option<Value>)>>,
+        #[cfg_attr(not(feature = "aa-backend"), allow(dead_code))]
+        bearer: RefCell<Option<String>>,
+    }
+
+    impl MockTransport {
+        fn new(responses: Vec<(u16, &str)>) -> Self {
+            Self {
+                responses: RefCell::new(
+                    responses
+                        .into_iter()
+                        .rev()
+                        .map(|(status, body)| KbsResponse {
+                            status,
+                            body: body.to_string(),
+                            session: Some(format!("{}=1234", SESSION_COOKIE)),
+                            retry_after: None,
+                        })
+                        .collect(),
+                ),
+                requests: RefCell::new(Vec::new()),
+                bearer: RefCell::new(None),
+            }
+        }
+
+        fn next(&self) -> Result<KbsResponse> {
+            self.responses
+                .borrow_mut()
+                .pop()
+                .ok_or_else(|| anyhow!("No more mock responses available"))
+        }
+    }
+
+    impl KbsTransport for MockTransport {
+        fn post_json(
+            &self,
+            url: &str,
+            body: &Value,
+            _session: Option<&str>,
+        ) -> Result<KbsResponse> {
+            self.requests
+                .borrow_mut()
+                .push((url.to_string(), Some(body.clone())));
+            self.next()
+        }
+
+        #[cfg_attr(not(feature = "aa-backend"), allow(unused_variables))]
+        fn get(&self, url: &str, credential: &Credential) -> Result<KbsResponse> {
+            #[cfg(feature = "aa-backend")]
+            if let Credential::Bearer(token) = credential {
+                *self.bearer.borrow_mut() = Some(token.to_string());
+            }
+            self.requests.borrow_mut().push((url.to_string(), None));
+            self.next()
+        }
+    }
+
+    const VERSION_MISMATCH: &str = r#"{"type":"https://github.com/confidential-containers/kbs/errors/ProtocolVersion","detail":"expected ^0.1"}"#;
+    const CHALLENGE: &str = r#"{"nonce":"abcd","extra-params":""}"#;
+
+    #[test]
+    fn test_negotiation_falls_back_to_older_version() {
+        let executor = NativeKbsExecutor::new(MockEvidence, None, None).unwrap();
+        let transport = MockTransport::new(vec![
+            (401, VERSION_MISMATCH),
+            (200, CHALLENGE),
+            (200, r#"{"token":"t"}"#),
+            (404, "resource not found"),
+        ]);
+
+        let result = executor.fetch_resource(&transport, "http://kbs:8080/", "a/b/c", None, &[]);
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Resource request failed with status 404: resource not found"
+        );
+        let requests = transport.requests.borrow();
+        assert_eq!(requests[0].1.as_ref().unwrap()["version"], "0.4.0");
+        assert_eq!(requests[1].1.as_ref().unwrap()["version"], "0.1.1");
+        let attestation = requests[2].1.as_ref().unwrap();
+        assert!(attestation.get("tee-pubkey").is_some());
+        assert_eq!(requests[3].0, "http://kbs:8080/kbs/v0/resource/a/b/c");
+        assert_eq!(
+            executor.candidate_versions("http://kbs:8080"),
+            vec!["0.1.1"]
+        );
+    }
+
+    #[test]
+    fn test_older_version_refuses_initdata() {
+        let executor = NativeKbsExecutor::new(MockEvidence, None, None).unwrap();
+        let transport = MockTransport::new(vec![(401, VERSION_MISMATCH), (200, CHALLENGE)]);
+
+        let result = executor.fetch_resource(
+            &transport,
+            "http://kbs:8080/",
+            "a/b/c",
+            Some("version = \"0.1.0\"".to_string()),
+            &[],
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "The KBS speaks protocol 0.1.1, which cannot carry the initdata of the binding"
+        );
+        assert_eq!(transport.requests.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_session_reused_for_further_resources() {
+        let executor = NativeKbsExecutor::new(MockEvidence, None, None).unwrap();
+        let transport = MockTransport::new(vec![
+            (200, CHALLENGE),
+            (200, r#"{"token":"t"}"#),
+            (404, "resource not found"),
+            (404, "resource not found"),
+            (200, CHALLENGE),
+            (200, r#"{"token":"t"}"#),
+            (404, "resource not found"),
+        ]);
+
+        for path in ["a/b/c", "a/b/d"] {
+            let result = executor.fetch_resource(&transport, "http://kbs:8080", path, None, &[]);
+            assert!(result.is_err());
+        }
+        {
+            let requests = transport.requests.borrow();
+            let urls: Vec<&str> = requests.iter().map(|(url, _)| url.as_str()).collect();
+            assert_eq!(
+                urls,
+                vec![
+                    "http://kbs:8080/kbs/v0/auth",
+                    "http://kbs:8080/kbs/v0/attest",
+                    "http://kbs:8080/kbs/v0/resource/a/b/c",
+                    "http://kbs:8080/kbs/v0/resource/a/b/d",
+                    "http://kbs:8080/kbs/v0/auth",
+                    "http://kbs:8080/kbs/v0/attest",
+                    "http://kbs:8080/kbs/v0/resource/a/b/d",
+                ]
+            );
+        }
+
+        // Other policies need another attestation
+        let result = executor.fetch_resource(
+            &transport,
+            "http://kbs:8080",
+            "a/b/c",
+            None,
+            &["strict".to_string()],
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "No more mock responses available"
+        );
+        assert_eq!(
+            transport.requests.borrow()[7].0,
+            "http://kbs:8080/kbs/v0/auth"
+        );
+    }
+
+    #[test]
+    fn test_pinned_version_does_not_fall_back() {
+        let executor = NativeKbsExecutor::new(MockEvidence, Some("0.4.0"), None).unwrap();
+        let transport = MockTransport::new(vec![(401, VERSION_MISMATCH)]);
+
+        let result = executor.fetch_resource(&transport, "http://kbs:8080", "a/b/c", None, &[]);
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "KBS at http://kbs:8080 accepted none of the protocol versions 0.4.0"
+        );
+        assert_eq!(transport.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_current_version_attestation_request() {
+        let executor = NativeKbsExecutor::new(MockEvidence, None, None).unwrap();
+        let transport = MockTransport::new(vec![(200, CHALLENGE), (401, "evidence rejected")]);
+
+        let result = executor.fetch_resource(
+            &transport,
+            "http://kbs:8080",
+            "a/b/c",
+            Some("version = \"0.1.0\"".to_string()),
+            &["strict".to_string()],
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Attestation rejected with status 401: evidence rejected"
+        );
+        let requests = transport.requests.borrow();
+        let auth = requests[0].1.as_ref().unwrap();
+        assert_eq!(auth["extra-params"]["policy_ids"], json!(["strict"]));
+        let attestation = requests[1].1.as_ref().unwrap();
+        assert_eq!(attestation["runtime-data"]["nonce"], "abcd");
+        assert_eq!(attestation["init-data"]["format"], "toml");
+        assert!(attestation["tee-evidence"]["primary_evidence"]["report_data"].is_string());
+    }
+
+    #[test]
+    fn test_dump_evidence() {
+        let executor = NativeKbsExecutor::new(MockEvidence, None, None).unwrap();
+
+        let dump = executor
+            .dump_evidence("abcd", Some("version = \"0.1.0\"".to_string()))
+            .unwrap();
+
+        assert_eq!(dump["tee"], "sample");
+        assert_eq!(dump["version"], "0.4.0");
+        let request = &dump["request"];
+        assert_eq!(request["runtime-data"]["nonce"], "abcd");
+        assert_eq!(
+            request["runtime-data"]["tee-pubkey"]["alg"],
+            TEE_KEY_ALGORITHM
+        );
+        assert_eq!(request["init-data"]["body"], "version = \"0.1.0\"");
+        let report_data = Sha384::digest(serde_json::to_vec(&request["runtime-data"]).unwrap());
+        assert_eq!(
+            request["tee-evidence"]["primary_evidence"]["report_data"],
+            hex::encode(report_data)
+        );
+    }
+
+    #[test]
+    fn test_unsupported_protocol_version() {
+        let result = NativeKbsExecutor::new(MockEvidence, Some("0.9.0"), None);
+
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Unsupported KBS protocol version 0.9.0 (supported: 0.4.0, 0.1.1)"
+        );
+    }
+
+    #[cfg(feature = "aa-backend")]
+    #[test]
+    fn test_get_resource_with_bearer_token() {
+        let transport = MockTransport::new(vec![(200, "{}"), (403, "policy denied")]);
+
+        let body = get_resource(
+            &transport,
+            "https://kbs:8080/",
+            "/default/key/root",
+            &Credential::Bearer("token"),
+        );
+        let denied = get_resource(
+            &transport,
+            "https://kbs:8080",
+            "default/key/root",
+            &Credential::Bearer("token"),
+        );
+
+        assert_eq!(body.unwrap(), "{}");
+        assert_eq!(
+            denied.unwrap_err().to_string(),
+            "Resource request failed with status 403: policy denied"
+        );
+        assert_eq!(
+            transport.requests.borrow()[0].0,
+            "https://kbs:8080/kbs/v0/resource/default/key/root"
+        );
+        assert_eq!(transport.bearer.borrow().as_deref(), Some("token"));
+    }
+
+    #[test]
+    fn test_trace_message() {
+        use reqwest::header::{self, HeaderMap, HeaderValue};
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer eyJh.eyJi.c2ln"),
+        );
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("kbs-session-id=1234; lb=a"),
+        );
+        headers.insert("x-api-key", HeaderValue::from_static("0123456789abcdef"));
+        headers.insert("X-Auth-Token", HeaderValue::from_static("s3cr3t"));
+        headers.insert("x-request-id", HeaderValue::from_static("42"));
+        let mut response = HeaderMap::new();
+        response.insert(
+            header::SET_COOKIE,
+            HeaderValue::from_static("kbs-session-id=5678; Path=/; Max-Age=300"),
+        );
+
+        assert_eq!(
+            trace_message('>', "POST http://kbs:8080/kbs/v0/attest", &headers),
+            "> POST http://kbs:8080/kbs/v0/attest\n\
+             > content-type: application/json\n\
+             > authorization: Bearer <redacted>\n\
+             > cookie: kbs-session-id=<redacted>; lb=<redacted>\n\
+             > x-api-key: <redacted>\n\
+             > x-auth-token: <redacted>\n\
+             > x-request-id: 42"
+        );
+        assert_eq!(
+            trace_message('<', "HTTP/1.1 200 OK", &response),
+            "< HTTP/1.1 200 OK\n< set-cookie: kbs-session-id=<redacted>; Path=/; Max-Age=300"
+        );
+    }
+
+    #[test]
+    fn test_transport_pool() {
+        let pool = TransportPool::new(None);
+        let url = "http://127.0.0.1:8080";
+
+        let first = pool
+            .with_transport(
+                url,
+                &Cert::None,
+                &ConnectionSettings::default(),
+                |transport| Ok(std::ptr::from_ref(transport)),
+            )
+            .unwrap();
+        let second = pool
+            .with_transport(
+                url,
+                &Cert::None,
+                &ConnectionSettings::default(),
+                |transport| Ok(std::ptr::from_ref(transport)),
+            )
+            .unwrap();
+        assert_eq!(first, second);
+
+        let failed: Result<()> =
+            pool.with_transport(url, &Cert::None, &ConnectionSettings::default(), |_| {
+                Err(anyhow!("reset"))
+            });
+        assert!(failed.is_err());
+        assert!(pool.transports.borrow().is_empty());
+    }
+}
@@ -0,0 +1,4597 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Clevis PIN for Trustee: binds data to a key released by Trustee KBS
+//! servers after a successful attestation.
+
+#[cfg(any(feature = "native-kbs", feature = "aa-backend"))]
+mod aa;
+pub mod admin;
+pub mod agent;
+mod allowlist;
+pub mod armor;
+#[cfg(feature = "subprocess-backend")]
+mod attester;
+pub mod bench;
+mod cache;
+mod clock;
+mod discovery;
+mod endpoint;
+mod entropy;
+#[cfg(any(feature = "native-kbs", feature = "aa-backend"))]
+mod eyeballs;
+pub mod generator;
+mod jose;
+#[cfg(any(feature = "native-kbs", feature = "aa-backend"))]
+mod kbs;
+pub mod logging;
+pub mod luks;
+pub mod memory;
+#[cfg(any(test, feature = "mock-server"))]
+mod mock;
+#[cfg(feature = "mock-server")]
+pub mod mock_server;
+mod network;
+pub mod passphrase;
+mod pipeline;
+pub mod progress;
+mod replay;
+mod rewrite;
+mod signature;
+pub mod systemd;
+pub mod telemetry;
+#[cfg(any(
+    feature = "native-kbs",
+    feature = "aa-backend",
+    feature = "vendored-roots"
+))]
+mod tls;
+mod tpm2;
+#[cfg(any(feature = "native-kbs", feature = "aa-backend"))]
+mod ttrpc;
+pub mod verify;
+pub mod wizard;
+
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::*;
+use entropy::{OsRandom, RandomSource, random_bytes};
+
+/// Progress reporting of the public API
+pub use clevis_pin_trustee_lib::{EventHandler, FailureKind, NoEvents};
+use jose::{A256GCM, DirectKey, JweHeader, Jwk};
+use logging::{Priority, log};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command as StdCommand;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+const DEFAULT_TRIES: u32 = 10;
+const DELAY: Duration = Duration::from_secs(5);
+/// Longest wait a server can ask for with `Retry-After`
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+/// Binary of the trustee-attester backend, looked up in `PATH`
+#[cfg(feature = "subprocess-backend")]
+const TRUSTEE_ATTESTER: &str = "trustee-attester";
+/// How often a child with a timeout is checked for completion
+#[cfg(feature = "subprocess-backend")]
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// TPM constants
+const TPM_DIR: &str = "/var/tpm";
+const AK_PATH: &str = "/var/tpm/ak.pub";
+const AK_CTX_PATH: &str = "/var/tpm/ak.ctx";
+const AK_REGISTERD: &str = "/var/tpm/ak.registerd";
+const AK_HANDLE: &str = "0x81010002";
+const EK_HANDLE: &str = "0x81010001";
+
+// Machine identity sources for resource path templates
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+const HOSTNAME_PATH: &str = "/proc/sys/kernel/hostname";
+const PRODUCT_UUID_PATH: &str = "/sys/class/dmi/id/product_uuid";
+
+/// Scheme of resource URIs, see `normalize_resource_path`
+const KBS_URI_SCHEME: &str = "kbs://";
+
+/// Domain separation of the key ids, see `key_id`
+const KEY_ID_CONTEXT: &[u8] = b"clevis-pin-trustee key id";
+/// Bytes of the SHA-256 digest kept in a key id
+const KEY_ID_BYTES: usize = 8;
+
+/// Fragments merged into the encryption config, see `load_config`
+const CONFIG_DROPIN_DIR: &str = "/etc/clevis-trustee/config.d";
+
+/// Check that no other user can swap the files of `dir`, which may be in a
+/// directory shared with them such as `/tmp`
+pub(crate) fn check_private_dir(dir: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    // SAFETY: geteuid cannot fail
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory only its owner can write to",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn failure_kind(error: &anyhow::Error) -> FailureKind {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<TrusteePinError>())
+        .map_or(FailureKind::Transient, TrusteePinError::kind)
+}
+
+/// `failure_kind` of `error`, unless `retry_on` lists the failures to retry:
+/// then a failure of known cause is retried only when one of them matches
+fn retry_kind(error: &anyhow::Error, retry_on: Option<&[RetryClass]>) -> FailureKind {
+    let cause = error
+        .chain()
+        .find_map(|e| e.downcast_ref::<TrusteePinError>())
+        .and_then(TrusteePinError::cause);
+    match (retry_on, cause) {
+        (Some(classes), Some(cause)) => {
+            if classes.iter().any(|class| class.matches(cause)) {
+                FailureKind::Transient
+            } else {
+                FailureKind::Permanent
+            }
+        }
+        _ => failure_kind(error),
+    }
+}
+
+/// Delay the server asked for before it is tried again, capped so a server
+/// cannot stall the boot
+fn retry_after(error: &anyhow::Error) -> Option<Duration> {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<TrusteePinError>())
+        .and_then(TrusteePinError::retry_after)
+        .map(|delay| delay.min(MAX_RETRY_AFTER))
+}
+
+/// Fetches the key of a binding from one server. The shipped backends, and
+/// the mock one for tests, are chosen with the `backend` setting of the
+/// config or runtime config; embedders can bring their own with
+/// `decrypt_with_fetcher`. The key is returned base64 encoded and failures
+/// are classified with `TrusteePinError` to drive the retries.
+pub trait KeyFetcher {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<String>;
+}
+
+/// Trait for generating attestation keys
+trait AttestationKeyGeneratorTrait {
+    fn generate_attestation_key(&self) -> Result<String>;
+}
+
+/// Response wrapper to make HTTP responses mockable
+struct HttpResponse {
+    status_code: u16,
+}
+
+impl HttpResponse {
+    fn is_success(&self) -> bool {
+        self.status_code >= 200 && self.status_code < 300
+    }
+
+    fn status_code(&self) -> u16 {
+        self.status_code
+    }
+}
+
+/// Trait for HTTP client operations
+trait HttpClient {
+    fn put_json(&self, url: &str, payload: &RegistrationPayload) -> Result<HttpResponse>;
+}
+
+/// Trait for filesystem operations
+trait FileSystem {
+    fn write_marker(&self, path: &str) -> Result<()>;
+}
+
+/// Trait for looking up the machine identity used in resource path templates
+trait MachineIdentity {
+    fn machine_id(&self) -> Result<String>;
+    fn hostname(&self) -> Result<String>;
+    fn system_uuid(&self) -> Result<String>;
+}
+
+/// Real implementation that calls the trustee-attester binary
+#[cfg(feature = "subprocess-backend")]
+struct RealCommandExecutor {
+    program: String,
+    /// trustee-attester is killed once an attempt takes longer
+    timeout: Option<Duration>,
+    /// Where inline certificates are written for trustee-attester
+    cert_dir: PathBuf,
+    /// SELinux label of the certificates written for trustee-attester
+    cert_label: Option<attester::CertLabel>,
+}
+
+#[cfg(feature = "subprocess-backend")]
+impl KeyFetcher for RealCommandExecutor {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<String> {
+        // trustee-attester has no TLS settings, failing is safer than
+        // connecting below the baseline
+        if !connection.tls.is_default() {
+            return Err(TrusteePinError::Config(
+                "TLS settings are not supported by the trustee-attester backend".to_string(),
+            )
+            .into());
+        }
+        // Nor can it send headers, which a gateway may need to route the
+        // requests to the right KBS
+        if !connection.headers.is_empty() {
+            return Err(TrusteePinError::Config(
+                "Request headers are not supported by the trustee-attester backend".to_string(),
+            )
+            .into());
+        }
+        let mut command = StdCommand::new(&self.program);
+        attester::sandbox(&mut command);
+        // Kept until the attester exited
+        let mut cert_file = None;
+        match cert {
+            Cert::None => {}
+            Cert::Path(cert_path) => {
+                command.arg("--cert-file").arg(cert_path);
+            }
+            Cert::Inline(pem) => {
+                let file =
+                    attester::CertFile::create(&self.cert_dir, pem, self.cert_label.as_ref())
+                        .with_context(|| {
+                            format!(
+                                "Failed to write the certificate to {}",
+                                self.cert_dir.display()
+                            )
+                        })?;
+                let cert_path = file.pass_to(&mut command);
+                command.arg("--cert-file").arg(cert_path);
+                cert_file = Some(file);
+            }
+        }
+        command
+            .arg("--url")
+            .arg(url)
+            .arg("get-resource")
+            .arg("--path")
+            .arg(path);
+        if let Some(initdata_str) = initdata {
+            command.arg("--initdata").arg(initdata_str);
+        }
+        for policy_id in policy_ids {
+            command.arg("--policy-id").arg(policy_id);
+        }
+        let output = output_with_timeout(&mut command, self.timeout)
+            .map_err(|e| {
+                let message = format!("Failed to execute {}: {}", self.program, e);
+                if e.kind() == io::ErrorKind::NotFound {
+                    TrusteePinError::permanent(url, message).into()
+                } else {
+                    anyhow!(message)
+                }
+            })?
+            .ok_or_else(|| {
+                TrusteePinError::transport(
+                    url,
+                    FailureCause::Timeout,
+                    format!(
+                        "{} timed out after {}",
+                        self.program,
+                        format_duration(self.timeout.unwrap_or_default())
+                    ),
+                )
+            })?;
+
+        drop(cert_file);
+        io::stderr().write_all(&output.stderr)?;
+        io::stderr().write_all(&output.stdout)?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(attester::failure(url, &stderr).into());
+        }
+
+        let key = String::from_utf8(output.stdout)
+            .map_err(|e| anyhow!("Invalid UTF-8 for the LUKS key: {}", e))?
+            .trim()
+            .to_string();
+
+        if key.is_empty() {
+            return Err(anyhow!("Received empty LUKS key"));
+        }
+
+        Ok(key)
+    }
+}
+
+#[cfg(feature = "subprocess-backend")]
+fn drain<R: io::Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+/// Run `command` to completion, collecting its output, or kill it once
+/// `timeout` passed and return `None`
+#[cfg(feature = "subprocess-backend")]
+fn output_with_timeout(
+    command: &mut StdCommand,
+    timeout: Option<Duration>,
+) -> io::Result<Option<std::process::Output>> {
+    let Some(timeout) = timeout else {
+        return command.output().map(Some);
+    };
+    let mut child = command
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    // Drain the pipes while waiting, a full pipe would block the child
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = std::time::Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        let now = std::time::Instant::now();
+        if now >= deadline {
+            child.kill()?;
+            child.wait()?;
+            break None;
+        }
+        thread::sleep(CHILD_POLL_INTERVAL.min(deadline - now));
+    };
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+    Ok(status.map(|status| std::process::Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+/// Real implementation that generates attestation keys using TPM
+struct AttestationKeyGenerator;
+
+impl AttestationKeyGeneratorTrait for AttestationKeyGenerator {
+    fn generate_attestation_key(&self) -> Result<String> {
+        generate_attestation_key()
+    }
+}
+
+/// Real HTTP client wrapper
+struct RealHttpClient {
+    client: reqwest::blocking::Client,
+}
+
+impl HttpClient for RealHttpClient {
+    fn put_json(&self, url: &str, payload: &RegistrationPayload) -> Result<HttpResponse> {
+        let response = self
+            .client
+            .put(url)
+            .json(payload)
+            .send()
+            .map_err(|e| anyhow!("Failed to send PUT request: {}", e))?;
+
+        Ok(HttpResponse {
+            status_code: response.status().as_u16(),
+        })
+    }
+}
+
+/// Real filesystem implementation
+struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn write_marker(&self, path: &str) -> Result<()> {
+        fs::write(path, "").context("Failed to write marker file")
+    }
+}
+
+/// Real machine identity read from the running system
+struct RealMachineIdentity;
+
+impl RealMachineIdentity {
+    fn read_trimmed(path: &str) -> Result<String> {
+        let value = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path))?
+            .trim()
+            .to_string();
+        if value.is_empty() {
+            return Err(anyhow!("{} is empty", path));
+        }
+        Ok(value)
+    }
+}
+
+impl MachineIdentity for RealMachineIdentity {
+    fn machine_id(&self) -> Result<String> {
+        Self::read_trimmed(MACHINE_ID_PATH)
+    }
+
+    fn hostname(&self) -> Result<String> {
+        Self::read_trimmed(HOSTNAME_PATH)
+    }
+
+    fn system_uuid(&self) -> Result<String> {
+        Ok(Self::read_trimmed(PRODUCT_UUID_PATH)?.to_lowercase())
+    }
+}
+
+#[cfg(test)]
+pub struct MockCommandExecutor {
+    pub response: Result<String>,
+}
+
+#[cfg(test)]
+impl KeyFetcher for MockCommandExecutor {
+    fn try_fetch_luks_key(
+        &self,
+        _url: &str,
+        _path: &str,
+        _cert: &Cert,
+        _connection: &ConnectionSettings,
+        _initdata: Option<String>,
+        _policy_ids: &[String],
+    ) -> Result<String> {
+        match &self.response {
+            Ok(key) => Ok(key.clone()),
+            Err(e) => match e.downcast_ref::<TrusteePinError>() {
+                Some(error) => Err(error.clone().into()),
+                None => Err(anyhow!("{}", e)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub struct RecordingCommandExecutor {
+    pub calls: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl KeyFetcher for RecordingCommandExecutor {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        _path: &str,
+        _cert: &Cert,
+        _connection: &ConnectionSettings,
+        _initdata: Option<String>,
+        _policy_ids: &[String],
+    ) -> Result<String> {
+        self.calls.borrow_mut().push(url.to_string());
+        Err(anyhow!("Connection refused"))
+    }
+}
+
+#[cfg(test)]
+pub struct MockAttestationKeyGenerator {
+    pub response: Result<String>,
+}
+
+#[cfg(test)]
+impl AttestationKeyGeneratorTrait for MockAttestationKeyGenerator {
+    fn generate_attestation_key(&self) -> Result<String> {
+        match &self.response {
+            Ok(key) => Ok(key.clone()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub struct MockHttpClient {
+    pub responses: Vec<Result<u16>>,
+    pub call_count: std::cell::RefCell<usize>,
+}
+
+#[cfg(test)]
+impl HttpClient for MockHttpClient {
+    fn put_json(&self, _url: &str, _payload: &RegistrationPayload) -> Result<HttpResponse> {
+        let mut count = self.call_count.borrow_mut();
+        let index = *count;
+        *count += 1;
+
+        if index >= self.responses.len() {
+            return Err(anyhow!("No more mock responses available"));
+        }
+
+        match &self.responses[index] {
+            Ok(status_code) => Ok(HttpResponse {
+                status_code: *status_code,
+            }),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub struct MockFileSystem {
+    pub write_result: Result<()>,
+    pub written: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FileSystem for MockFileSystem {
+    fn write_marker(&self, path: &str) -> Result<()> {
+        self.written.borrow_mut().push(path.to_string());
+        match &self.write_result {
+            Ok(()) => Ok(()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+pub struct MockMachineIdentity {
+    pub machine_id: Result<String>,
+    pub hostname: Result<String>,
+    pub system_uuid: Result<String>,
+}
+
+#[cfg(test)]
+impl MachineIdentity for MockMachineIdentity {
+    fn machine_id(&self) -> Result<String> {
+        match &self.machine_id {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+
+    fn hostname(&self) -> Result<String> {
+        match &self.hostname {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+
+    fn system_uuid(&self) -> Result<String> {
+        match &self.system_uuid {
+            Ok(value) => Ok(value.clone()),
+            Err(e) => Err(anyhow!("{}", e)),
+        }
+    }
+}
+
+/// Fetch the key of a binding from its servers, base64 encoded. The
+/// settings of `runtime` take precedence over the ones of the header.
+fn fetch_header_key(
+    header: &ClevisHeader,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    fetch_header_key_with(header, runtime, &ExecutorCache::default(), events)
+}
+
+fn fetch_header_key_with(
+    header: &ClevisHeader,
+    runtime: &RuntimeConfig,
+    executors: &ExecutorCache,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    let path = versioned_path(&header.path, header.resource_version.as_deref())?;
+    let mut keys = fetch_header_keys(header, &[&path], runtime, executors, events)?;
+    Ok(keys.remove(0))
+}
+
+/// Fetch the resources at `paths`, path templates like `ClevisHeader::path`,
+/// with the servers and settings of a binding. One executor serves them all,
+/// so backends keeping their attestation session attest once per server.
+fn fetch_header_keys(
+    header: &ClevisHeader,
+    paths: &[&str],
+    runtime: &RuntimeConfig,
+    executors: &ExecutorCache,
+    events: &dyn EventHandler,
+) -> Result<Vec<String>> {
+    let allowlist = allowlist::Allowlist::load(Path::new(allowlist::ALLOWED_SERVERS_PATH))?;
+    // The header may be doctored, the list decides how its servers are reached
+    let header = &match &allowlist {
+        Some(allowlist) => allowlist.restrict(header),
+        None => header.clone(),
+    };
+    let backend = runtime.backend.or(header.backend).unwrap_or_default();
+    // A protocol version pinned for the native backend means nothing to the
+    // backend chosen at runtime
+    let kbs_protocol_version = header.kbs_protocol_version.as_deref().filter(|_| {
+        runtime
+            .backend
+            .is_none_or(|backend| backend == Backend::Native)
+    });
+    let attempt_timeout = match runtime.attempt_timeout()? {
+        Some(timeout) => Some(timeout),
+        None => duration_setting("attempt_timeout", header.attempt_timeout.as_deref())?,
+    };
+    let executor = executors.get(ExecutorSettings {
+        backend,
+        kbs_protocol_version: kbs_protocol_version.map(str::to_string),
+        attester_path: runtime.attester_path.clone(),
+        cert_dir: runtime.cert_dir.clone(),
+        cert_context: runtime.cert_context.clone(),
+        attempt_timeout,
+        record: runtime.record.as_ref().map(PathBuf::from),
+        replay: runtime.replay.as_ref().map(PathBuf::from),
+        mock_resources: runtime.mock_resources.clone(),
+    })?;
+    let num_retries = runtime
+        .num_retries
+        .as_ref()
+        .or(header.num_retries.as_ref())
+        .unwrap_or(&NumRetries::Finite(DEFAULT_TRIES));
+    let retry = RetryPolicy {
+        num_retries,
+        delay: runtime.retry_delay()?.unwrap_or(DELAY),
+        circuit_breaker: header.circuit_breaker.as_ref(),
+        backoff: header.backoff.as_ref(),
+        retry_on: header.retry_on.as_deref(),
+        cancellation: Some(&runtime.cancellation),
+        rng: &OsRandom,
+        failures: Cell::new(0),
+    };
+    let header_servers = Server::with_default_cert(&header.servers, header.cert.as_ref());
+    // Servers given at runtime replace the discovered ones as well
+    let servers = match (&runtime.servers, &runtime.override_url, &header.discovery) {
+        (Some(servers), _, _) => servers.clone(),
+        // The binding moved, its servers and the discovered ones are stale
+        (None, Some(url), _) => {
+            let servers = endpoint::override_servers(&header_servers, url, header.cert.as_ref())?;
+            match &allowlist {
+                Some(allowlist) => allowlist.pin(servers),
+                None => servers,
+            }
+        }
+        (None, None, Some(sources)) => {
+            let mut servers = discovery::discover(sources);
+            servers.extend(header_servers);
+            allow_servers(allowlist.as_ref(), rewrite_servers(servers)?)?
+        }
+        (None, None, None) => allow_servers(allowlist.as_ref(), rewrite_servers(header_servers)?)?,
+    };
+    let servers = select_servers(&runtime.selection, servers)?;
+    // A replay contacts no server
+    if let Some(timeout) = runtime.network_wait()?.filter(|_| runtime.replay.is_none()) {
+        network::wait_for_network(&servers, timeout);
+    }
+    let clock_skew = clock::check_clock(runtime.time_sync_wait()?);
+    paths
+        .iter()
+        .map(|path| {
+            let path = expand_path_template(path, &RealMachineIdentity)?;
+            let span = telemetry::span("fetch_key");
+            span.set_attribute("backend", backend);
+            span.set_attribute("path", &path);
+            let request = FetchRequest {
+                path: &path,
+                initdata: header.initdata.clone(),
+                policy_ids: header.policy_ids.as_deref().unwrap_or_default(),
+                connection: header.connection(),
+            };
+            let result = fetch_luks_key(&servers, &request, &retry, executor.as_ref(), events)
+                .map_err(|e| match &clock_skew {
+                    // Most likely the cause of the failures
+                    Some(skew) => e.context(skew.clone()),
+                    None => e,
+                });
+            if let Err(e) = &result {
+                span.set_error(e);
+            }
+            result
+        })
+        .collect()
+}
+
+/// The servers of `selection`, warning about the labels naming none of them
+fn select_servers(selection: &ServerSelection, servers: Vec<Server>) -> Result<Vec<Server>> {
+    let unmatched = selection.unmatched(&servers);
+    if !servers.is_empty() && !unmatched.is_empty() {
+        log(
+            Priority::Warning,
+            &format!("Warning: no server is labeled {:?}", unmatched),
+            &[],
+        );
+    }
+    Ok(selection.select(servers)?)
+}
+
+/// The servers of a binding, with the URLs of the local rewrite map, if
+/// any, replaced
+fn rewrite_servers(servers: Vec<Server>) -> Result<Vec<Server>> {
+    match rewrite::RewriteMap::load(Path::new(rewrite::SERVER_REWRITES_PATH))? {
+        Some(rewrites) => Ok(rewrites.apply(servers)),
+        None => Ok(servers),
+    }
+}
+
+/// The servers of a binding that the local allowlist, if any, allows
+fn allow_servers(
+    allowlist: Option<&allowlist::Allowlist>,
+    servers: Vec<Server>,
+) -> Result<Vec<Server>> {
+    match allowlist {
+        Some(allowlist) => Ok(allowlist.filter(servers)?),
+        None => Ok(servers),
+    }
+}
+
+/// Settings a key fetcher is created with
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+struct ExecutorSettings {
+    backend: Backend,
+    /// KBS protocol version pinned for the native backend
+    kbs_protocol_version: Option<String>,
+    /// trustee-attester binary of the trustee-attester backend
+    attester_path: Option<String>,
+    /// Directory for the certificates of the trustee-attester backend
+    cert_dir: Option<String>,
+    /// SELinux context of these certificates
+    cert_context: Option<String>,
+    /// Limit on a single fetch attempt
+    attempt_timeout: Option<Duration>,
+    /// Recording the attempts are written to
+    record: Option<PathBuf>,
+    /// Recording answering the attempts instead of the backend
+    replay: Option<PathBuf>,
+    /// Directory of the resources of the mock backend
+    mock_resources: Option<String>,
+}
+
+/// Key fetchers kept across fetches, so the attestation sessions held by a
+/// backend, e.g. the native one, are reused by the following fetches
+#[derive(Default)]
+struct ExecutorCache {
+    executors: RefCell<HashMap<ExecutorSettings, Rc<dyn KeyFetcher>>>,
+    /// Key fetcher of the embedder, used whatever the settings
+    fetcher: Option<Rc<dyn KeyFetcher>>,
+}
+
+impl ExecutorCache {
+    fn with_fetcher(fetcher: Rc<dyn KeyFetcher>) -> Self {
+        ExecutorCache {
+            fetcher: Some(fetcher),
+            ..ExecutorCache::default()
+        }
+    }
+
+    /// The key fetcher for these settings, created on first use
+    fn get(&self, settings: ExecutorSettings) -> Result<Rc<dyn KeyFetcher>> {
+        if let Some(fetcher) = &self.fetcher {
+            return Ok(fetcher.clone());
+        }
+        if let Some(executor) = self.executors.borrow().get(&settings) {
+            return Ok(executor.clone());
+        }
+        let executor: Rc<dyn KeyFetcher> = make_executor(&settings)?.into();
+        self.executors
+            .borrow_mut()
+            .insert(settings, executor.clone());
+        Ok(executor)
+    }
+}
+
+/// Create the key fetcher for the configured backend, or for the recording
+/// to replay, recording its attempts if asked to
+fn make_executor(settings: &ExecutorSettings) -> Result<Box<dyn KeyFetcher>> {
+    if let Some(path) = &settings.replay {
+        return Ok(Box::new(replay::Replayer::load(path)?));
+    }
+    let executor = make_backend_executor(settings)?;
+    match &settings.record {
+        Some(path) => Ok(Box::new(replay::Recorder::new(executor, path)?)),
+        None => Ok(executor),
+    }
+}
+
+fn make_backend_executor(settings: &ExecutorSettings) -> Result<Box<dyn KeyFetcher>> {
+    let backend = settings.backend;
+    if settings.kbs_protocol_version.is_some() && backend != Backend::Native {
+        return Err(TrusteePinError::Config(
+            "kbs_protocol_version is only supported by the native backend".to_string(),
+        )
+        .into());
+    }
+    match backend {
+        #[cfg(feature = "subprocess-backend")]
+        Backend::TrusteeAttester => Ok(Box::new(RealCommandExecutor {
+            program: settings
+                .attester_path
+                .as_deref()
+                .unwrap_or(TRUSTEE_ATTESTER)
+                .to_string(),
+            timeout: settings.attempt_timeout,
+            cert_dir: attester::cert_dir(settings.cert_dir.as_deref()),
+            cert_label: attester::cert_label(settings.cert_context.as_deref())?,
+        })),
+        #[cfg(feature = "native-kbs")]
+        Backend::Native => Ok(Box::new(kbs::NativeKbsExecutor::new(
+            aa::AttestationAgent::new(aa::AA_SOCKET),
+            settings.kbs_protocol_version.as_deref(),
+            settings.attempt_timeout,
+        )?)),
+        #[cfg(feature = "aa-backend")]
+        Backend::AttestationAgent => Ok(Box::new(aa::AttestationAgentExecutor::new(
+            aa::AA_SOCKET,
+            settings.attempt_timeout,
+        ))),
+        #[cfg(any(test, feature = "mock-server"))]
+        Backend::Mock => Ok(Box::new(mock::MockKeyFetcher::new(
+            settings.mock_resources.as_deref(),
+        )?)),
+        #[allow(unreachable_patterns)]
+        _ => Err(TrusteePinError::Config(format!(
+            "The {} backend is not included in this build",
+            backend
+        ))
+        .into()),
+    }
+}
+
+/// Bytes of the A256GCM keys the payloads are encrypted with
+const KEY_BYTES: usize = 32;
+
+/// Guess the format of the `resource` holding a key: a JSON object is a JWK
+/// when it has a `kty`, else a `Key`; text decoding to a key of the right
+/// size is base64 or hexadecimal; anything else is the key itself.
+fn detect_key_format(resource: &[u8]) -> KeyFormat {
+    if let Ok(serde_json::Value::Object(object)) = serde_json::from_slice(resource) {
+        return if object.contains_key("kty") {
+            KeyFormat::Jwk
+        } else {
+            KeyFormat::Key
+        };
+    }
+    if let Ok(text) = std::str::from_utf8(resource).map(str::trim) {
+        if general_purpose::STANDARD
+            .decode(text)
+            .is_ok_and(|key| key.len() == KEY_BYTES)
+        {
+            return KeyFormat::Base64;
+        }
+        if hex::decode(text).is_ok_and(|key| key.len() == KEY_BYTES) {
+            return KeyFormat::Hex;
+        }
+    }
+    KeyFormat::Raw
+}
+
+/// How the key of a binding is stored in its resource
+#[derive(Debug, Default, Clone, Copy)]
+struct KeyEncoding<'a> {
+    /// Guessed when `None`
+    format: Option<KeyFormat>,
+    /// Applied to the resource before reading the key in it
+    pipeline: &'a [KeyTransform],
+}
+
+impl<'a> KeyEncoding<'a> {
+    /// Keys the pin derives itself, for recipients, split keys or the key
+    /// cache: JWKs, or `Key`s for the bindings made before
+    const DERIVED: KeyEncoding<'static> = KeyEncoding {
+        format: None,
+        pipeline: &[],
+    };
+
+    /// Encoding of the key the payload of `header` is encrypted with
+    fn payload(header: &'a ClevisHeader) -> Self {
+        if header.recipients.is_some() || header.tpm2_jwe.is_some() {
+            return KeyEncoding::DERIVED;
+        }
+        KeyEncoding {
+            format: header.key_format,
+            pipeline: header.key_pipeline.as_deref().unwrap_or_default(),
+        }
+    }
+}
+
+/// Turn a key fetched from the servers into the JWK used for the JWE. The
+/// resource is read as `encoding` says; a JWK is taken as it is.
+fn prepare_jwk(key: &str, encoding: KeyEncoding) -> Result<Jwk> {
+    let resource = general_purpose::STANDARD
+        .decode(key)
+        .context("Error decoding key in base64")?;
+    let resource = pipeline::apply(encoding.pipeline, resource)?;
+    let format = encoding
+        .format
+        .unwrap_or_else(|| detect_key_format(&resource));
+    let key = match format {
+        KeyFormat::Jwk => {
+            let jwk = Jwk::from_bytes(&resource)
+                .map_err(|e| TrusteePinError::Crypto(format!("Invalid JWK: {}", e)))?;
+            log(
+                Priority::Debug,
+                &format!("JWK of type {}", jwk.key_type()),
+                &[],
+            );
+            if jwk.key_type() != "oct" {
+                return Err(TrusteePinError::Crypto(format!(
+                    "The fetched JWK is of type {}, not a symmetric oct key",
+                    jwk.key_type()
+                ))
+                .into());
+            }
+            return Ok(jwk);
+        }
+        KeyFormat::Key => {
+            let key = String::from_utf8(resource).context("Error decoding the key in JSON")?;
+            let key: Key =
+                serde_json::from_str(&key).context("Error in parsing the fetched key")?;
+            log(Priority::Debug, &format!("Key: {:?}", key), &[]);
+
+            let mut jwk = Jwk::new(&key.key_type);
+            jwk.set_key_value(&key.key);
+            jwk.set_key_operations(vec!["encrypt", "decrypt"]);
+            return Ok(jwk);
+        }
+        KeyFormat::Base64 => general_purpose::STANDARD
+            .decode(String::from_utf8_lossy(&resource).trim())
+            .context("Error decoding the fetched key in base64")?,
+        KeyFormat::Hex => hex::decode(String::from_utf8_lossy(&resource).trim())
+            .context("Error decoding the fetched key in hexadecimal")?,
+        KeyFormat::Raw => resource,
+    };
+    log(
+        Priority::Debug,
+        &format!("Key of {} bytes in {:?} format", key.len(), format),
+        &[],
+    );
+    if key.len() != KEY_BYTES {
+        return Err(TrusteePinError::Crypto(format!(
+            "The fetched key is {} bytes long, A256GCM needs {}",
+            key.len(),
+            KEY_BYTES
+        ))
+        .into());
+    }
+    let mut jwk = Jwk::new("oct");
+    jwk.set_key_value(&key);
+    jwk.set_key_operations(vec!["encrypt", "decrypt"]);
+    Ok(jwk)
+}
+
+/// Expand `{machine-id}`, `{hostname}` and `{uuid}` placeholders in a resource path.
+///
+/// The template itself is what gets stored in the clevis header, so every machine
+/// sharing a binding expands it to its own resource at fetch time.
+fn expand_path_template<M: MachineIdentity>(template: &str, identity: &M) -> Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(|| {
+            TrusteePinError::Config(format!("Unterminated placeholder in path: {}", template))
+        })?;
+        let value = match &rest[start + 1..start + end] {
+            "machine-id" => identity.machine_id()?,
+            "hostname" => identity.hostname()?,
+            "uuid" => identity.system_uuid()?,
+            other => {
+                return Err(TrusteePinError::Config(format!(
+                    "Unknown placeholder {{{}}} in path",
+                    other
+                ))
+                .into());
+            }
+        };
+        if value.contains('/') {
+            return Err(anyhow!("Placeholder value must not contain '/': {}", value));
+        }
+        expanded.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+/// The `repository/type/tag` triple of a resource given as a URI such as
+/// `kbs:///default/key/root`, as other Confidential Containers tools name
+/// resources; other paths are returned as they are
+fn normalize_resource_path(path: &str) -> Result<String> {
+    let Some(uri) = path.strip_prefix(KBS_URI_SCHEME) else {
+        return Ok(path.to_string());
+    };
+    let (address, resource) = uri.split_once('/').unwrap_or((uri, ""));
+    if !address.is_empty() {
+        return Err(TrusteePinError::Config(format!(
+            "Resource URI {} names the KBS {}, list it in servers and use kbs:///{} instead",
+            path, address, resource
+        ))
+        .into());
+    }
+    let segments: Vec<&str> = resource.split('/').collect();
+    if segments.len() != 3 || segments.iter().any(|segment| segment.is_empty()) {
+        return Err(TrusteePinError::Config(format!(
+            "Resource URI {} is not kbs:///repository/type/tag",
+            path
+        ))
+        .into());
+    }
+    Ok(resource.to_string())
+}
+
+/// Resource `path` selecting `version` of the resource, if any, with the
+/// query parameter the KBS passes to its resource storage
+fn versioned_path(path: &str, version: Option<&str>) -> Result<String> {
+    let Some(version) = version else {
+        return Ok(path.to_string());
+    };
+    if version.is_empty()
+        || !version
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return Err(TrusteePinError::Config(format!(
+            "Invalid resource_version {:?}, only letters, digits, '.', '_' and '-' are allowed",
+            version
+        ))
+        .into());
+    }
+    Ok(format!("{}?version={}", path, version))
+}
+
+fn generate_attestation_key() -> Result<String> {
+    fs::create_dir_all(TPM_DIR)
+        .with_context(|| format!("couldn't create {} directory", TPM_DIR))?;
+
+    if Path::new(AK_PATH).exists() {
+        eprintln!("Attestation Key already exists, skipping generation");
+        return fs::read_to_string(AK_PATH).context("Failed to read existing attestation key");
+    }
+
+    eprintln!("Generating Attestation Key");
+
+    // Generate attestation key using tpm2_createak
+    let output = StdCommand::new("tpm2_createak")
+        .args([
+            "-C",
+            EK_HANDLE,
+            "-c",
+            AK_CTX_PATH,
+            "-G",
+            "rsa",
+            "-g",
+            "sha256",
+            "-s",
+            "rsassa",
+            "-u",
+            AK_PATH,
+            "-f",
+            "pem",
+        ])
+        .output()
+        .context("Failed to execute tpm2_createak command")?;
+
+    io::stderr().write_all(&output.stderr)?;
+    io::stderr().write_all(&output.stdout)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("failed to create attestation key: {}", stderr));
+    }
+
+    eprintln!("Persisting Attestation Key");
+
+    // Persist attestation key using tpm2_evictcontrol
+    let output = StdCommand::new("tpm2_evictcontrol")
+        .args(["-c", AK_CTX_PATH, AK_HANDLE])
+        .output()
+        .context("Failed to execute tpm2_evictcontrol command")?;
+
+    io::stderr().write_all(&output.stderr)?;
+    io::stderr().write_all(&output.stdout)?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow!("failed to persist attestation key: {}", stderr));
+    }
+
+    // Read and return the generated attestation key
+    fs::read_to_string(AK_PATH).context("Failed to read generated attestation key")
+}
+
+fn attestation_key_handle_with_deps<G, C, F>(
+    attestation_key: &Option<AttestationKey>,
+    generator: &G,
+    http_client_factory: C,
+    filesystem: &F,
+) -> Result<()>
+where
+    G: AttestationKeyGeneratorTrait,
+    C: Fn(&str) -> Result<Box<dyn HttpClient>>,
+    F: FileSystem,
+{
+    if attestation_key.is_none() {
+        return Ok(());
+    }
+
+    let key_config = attestation_key.as_ref().unwrap();
+    let generated_key = generator.generate_attestation_key()?;
+
+    if !key_config.registration.url.is_empty() {
+        let payload = RegistrationPayload {
+            attestation_key: generated_key,
+            uuid: key_config.registration.uuid.clone(),
+        };
+
+        let client = http_client_factory(&key_config.registration.cert)?;
+
+        let mut last_error = None;
+        for attempt in 1..=DEFAULT_TRIES {
+            eprintln!(
+                "Attempting to register attestation key (attempt {}/{})",
+                attempt, DEFAULT_TRIES
+            );
+
+            match client.put_json(&key_config.registration.url, &payload) {
+                Ok(response) => {
+                    if response.is_success() {
+                        eprintln!("Attestation key registered successfully.");
+
+                        // Create the registered marker file
+                        filesystem.write_marker(AK_REGISTERD)?;
+
+                        return Ok(());
+                    } else {
+                        last_error = Some(anyhow!(
+                            "Attestation key registration failed with status: {}",
+                            response.status_code()
+                        ));
+                        eprintln!(
+                            "Registration attempt {} failed with status: {}",
+                            attempt,
+                            response.status_code()
+                        );
+                    }
+                }
+                Err(e) => {
+                    last_error = Some(anyhow!(
+                        "Failed to send PUT request for attestation key registration: {}",
+                        e
+                    ));
+                    eprintln!("Registration attempt {} failed: {}", attempt, e);
+                }
+            }
+
+            if attempt < DEFAULT_TRIES {
+                eprintln!("Retrying in {:?}...", DELAY);
+                thread::sleep(DELAY);
+            }
+        }
+
+        return Err(last_error.unwrap_or_else(|| {
+            anyhow!(
+                "Failed to register attestation key after {} attempts",
+                DEFAULT_TRIES
+            )
+        }));
+    }
+
+    Ok(())
+}
+
+/// Build a blocking HTTP client trusting `cert` (PEM) in addition to the system roots
+fn build_http_client(cert: &str, timeout: Option<Duration>) -> Result<reqwest::blocking::Client> {
+    http_client_builder(cert, timeout)?
+        .build()
+        .context("Failed to build HTTPS client")
+}
+
+fn http_client_builder(
+    cert: &str,
+    timeout: Option<Duration>,
+) -> Result<reqwest::blocking::ClientBuilder> {
+    let mut builder = reqwest::blocking::Client::builder();
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    // The built-in roots are only known to rustls
+    #[cfg(feature = "vendored-roots")]
+    {
+        let config = tls::client_config(
+            Some(cert).filter(|cert| !cert.is_empty()),
+            &TlsPolicy::default(),
+        )?;
+        builder = builder.tls_backend_preconfigured(config);
+    }
+    #[cfg(not(feature = "vendored-roots"))]
+    if !cert.is_empty() {
+        let cert = reqwest::Certificate::from_pem(cert.as_bytes())
+            .context("Failed to parse TLS certificate")?;
+        builder = builder.add_root_certificate(cert);
+    }
+    Ok(builder)
+}
+
+/// `headers` as sent by the HTTP client, rejecting invalid names and values
+fn header_map(
+    headers: &BTreeMap<String, String>,
+) -> Result<reqwest::header::HeaderMap, TrusteePinError> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let invalid = |e: &dyn std::fmt::Display| {
+                TrusteePinError::Config(format!("Invalid request header {}: {}", name, e))
+            };
+            Ok((
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| invalid(&e))?,
+                reqwest::header::HeaderValue::from_str(value).map_err(|e| invalid(&e))?,
+            ))
+        })
+        .collect()
+}
+
+fn attestation_key_handle(attestation_key: &Option<AttestationKey>) -> Result<()> {
+    let generator = AttestationKeyGenerator;
+    let filesystem = RealFileSystem;
+    let http_client_factory = |cert: &str| -> Result<Box<dyn HttpClient>> {
+        Ok(Box::new(RealHttpClient {
+            client: build_http_client(cert, None)?,
+        }))
+    };
+
+    attestation_key_handle_with_deps(
+        attestation_key,
+        &generator,
+        http_client_factory,
+        &filesystem,
+    )
+}
+
+/// Syntax of the encryption config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    /// JSON if it starts with `{`, otherwise TOML, falling back to YAML
+    #[default]
+    Auto,
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Format of a drop-in fragment, from its file extension
+    fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "json" => Some(ConfigFormat::Json),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// How the encryption config is read
+#[derive(Debug, Clone, Default, clap::Args)]
+pub struct ConfigOptions {
+    /// Reject unknown fields in the configuration
+    #[arg(long)]
+    pub strict: bool,
+    /// Syntax of the configuration
+    #[arg(long, value_enum, default_value_t)]
+    pub format: ConfigFormat,
+    /// Detached minisign signature of the configuration, required when the
+    /// image has a config signing key
+    #[arg(long, value_name = "FILE")]
+    pub signature: Option<PathBuf>,
+}
+
+fn config_parse_error(e: serde_json::Error) -> TrusteePinError {
+    TrusteePinError::Config(format!("Failed to parse config JSON: {}", e))
+}
+
+/// Parse a config, or a fragment of it, written in `format` into its JSON
+/// representation
+fn parse_config_text(text: &str, format: ConfigFormat) -> Result<serde_json::Value> {
+    let value = match format {
+        ConfigFormat::Json => serde_json::from_str(text).map_err(config_parse_error)?,
+        ConfigFormat::Yaml => serde_yaml_ng::from_str(text)
+            .map_err(|e| TrusteePinError::Config(format!("Failed to parse config YAML: {}", e)))?,
+        ConfigFormat::Toml => toml::from_str(text)
+            .map_err(|e| TrusteePinError::Config(format!("Failed to parse config TOML: {}", e)))?,
+        ConfigFormat::Auto if text.trim_start().starts_with('{') => {
+            parse_config_text(text, ConfigFormat::Json)?
+        }
+        // A TOML document is rarely valid YAML as well, and vice versa
+        ConfigFormat::Auto => match toml::from_str(text) {
+            Ok(value) => value,
+            Err(_) => parse_config_text(text, ConfigFormat::Yaml)?,
+        },
+    };
+    Ok(value)
+}
+
+/// The data of an initdata fragment: an object, a string holding a JSON
+/// object, or TOML, either a table of data or a whole initdata document
+fn initdata_fragment(
+    fragment: serde_json::Value,
+) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        TrusteePinError::Config(format!("Invalid initdata fragment: {e}"))
+    };
+    let text = match fragment {
+        serde_json::Value::Object(data) => return Ok(data),
+        serde_json::Value::String(text) => text,
+        other => {
+            return Err(invalid(&format!("expected an object or a string, got {other}")).into());
+        }
+    };
+    if text.trim_start().starts_with('{') {
+        return serde_json::from_str(&text).map_err(|e| invalid(&e).into());
+    }
+    let mut table: toml::Table = toml::from_str(&text).map_err(|e| invalid(&e))?;
+    if table.contains_key("version")
+        && table.contains_key("algorithm")
+        && let Some(toml::Value::Table(data)) = table.remove("data")
+    {
+        table = data;
+    }
+    serde_json::to_value(table)
+        .ok()
+        .and_then(|value| match value {
+            serde_json::Value::Object(data) => Some(data),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(&"not representable as JSON").into())
+}
+
+/// Merge `fragment` into `data`: tables are merged key by key, any other
+/// value of a later fragment replaces the earlier one
+fn merge_initdata(
+    data: &mut serde_json::Map<String, serde_json::Value>,
+    fragment: serde_json::Map<String, serde_json::Value>,
+) {
+    for (key, value) in fragment {
+        match (data.get_mut(&key), value) {
+            (Some(serde_json::Value::Object(table)), serde_json::Value::Object(more)) => {
+                merge_initdata(table, more)
+            }
+            (_, value) => {
+                data.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The initdata of the list of `fragments` merged in order. Keys are kept
+/// sorted, so the same fragments always give the same document and digest.
+fn merge_initdata_fragments(fragments: Vec<serde_json::Value>) -> Result<serde_json::Value> {
+    let mut data = serde_json::Map::new();
+    for (index, fragment) in fragments.into_iter().enumerate() {
+        let fragment =
+            initdata_fragment(fragment).with_context(|| format!("initdata[{}]", index))?;
+        merge_initdata(&mut data, fragment);
+    }
+    Ok(serde_json::Value::Object(data))
+}
+
+/// JSON keeps the initdata as a string holding a JSON object, which YAML and
+/// TOML configs can write as a plain mapping instead, or as a list of
+/// fragments merged into one
+fn normalize_initdata(config: &mut serde_json::Value) -> Result<()> {
+    let Some(initdata) = config.get_mut("initdata") else {
+        return Ok(());
+    };
+    if let serde_json::Value::Array(fragments) = initdata {
+        *initdata = merge_initdata_fragments(std::mem::take(fragments))?;
+    }
+    if initdata.is_object() {
+        *initdata = serde_json::Value::String(initdata.to_string());
+    }
+    Ok(())
+}
+
+/// Parse the encryption config. Unknown fields, usually typos, are rejected
+/// in strict mode and only warned about otherwise.
+fn parse_config(config: serde_json::Value, strict: bool) -> Result<Config> {
+    let mut unknown = Vec::new();
+    let config: Config = serde_ignored::deserialize(config, |path| unknown.push(path.to_string()))
+        .map_err(config_parse_error)?;
+
+    if strict && !unknown.is_empty() {
+        return Err(TrusteePinError::Config(format!(
+            "Unknown fields in config: {}",
+            unknown.join(", ")
+        ))
+        .into());
+    }
+    for field in &unknown {
+        eprintln!("Warning: ignoring unknown config field {}", field);
+    }
+    if let Some(backend) = config.backend.filter(|backend| backend.runtime_only()) {
+        return Err(TrusteePinError::Config(format!(
+            "The {} backend can only be chosen at runtime, not bound",
+            backend
+        ))
+        .into());
+    }
+    Ok(config)
+}
+
+/// JSON, YAML and TOML fragments in the drop-in directory, in lexical order
+fn config_fragments(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", dir.display())),
+    };
+    let mut fragments = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if ConfigFormat::from_extension(&path).is_some() && path.is_file() {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/// Merge a drop-in fragment into the config: servers are appended, every
+/// other field replaces the value it had so far
+fn merge_config_fragment(
+    config: &mut serde_json::Value,
+    fragment: serde_json::Value,
+) -> Result<()> {
+    let (Some(config), serde_json::Value::Object(fragment)) = (config.as_object_mut(), fragment)
+    else {
+        return Err(TrusteePinError::Config("Config must be a JSON object".to_string()).into());
+    };
+    for (key, value) in fragment {
+        match (key.as_str(), config.get_mut(&key), value) {
+            (
+                "servers",
+                Some(serde_json::Value::Array(servers)),
+                serde_json::Value::Array(more),
+            ) => servers.extend(more),
+            (_, _, value) => {
+                config.insert(key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse the config given on the command line with the drop-in fragments of
+/// `dropin_dir` merged in
+fn load_config(config: &str, dropin_dir: &Path, options: ConfigOptions) -> Result<Config> {
+    let mut merged = parse_config_text(config, options.format)?;
+    normalize_initdata(&mut merged)?;
+    for path in config_fragments(dropin_dir)? {
+        eprintln!("Merging config fragment {}", path.display());
+        let fragment = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        // A fragment overrides the servers and certificates as much as the
+        // config itself, so it is held to the same signature
+        signature::check_config_file(&path, &fragment)?;
+        let format = ConfigFormat::from_extension(&path).unwrap_or_default();
+        let mut fragment = parse_config_text(&fragment, format)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        normalize_initdata(&mut fragment)
+            .with_context(|| format!("Invalid config fragment {}", path.display()))?;
+        merge_config_fragment(&mut merged, fragment)
+            .with_context(|| format!("Invalid config fragment {}", path.display()))?;
+    }
+    parse_config(merged, options.strict)
+}
+
+/// Convert the JSON initdata of the config into a Trustee initdata TOML
+/// document. Values may be nested objects, which become TOML tables.
+fn initdata_toml(json: &str) -> Result<String> {
+    let data: BTreeMap<String, toml::Value> = serde_json::from_str(json)
+        .map_err(|e| TrusteePinError::Config(format!("Failed to parse config initdata: {e}")))?;
+    toml::to_string(&Initdata {
+        version: "0.1.0".to_string(),
+        algorithm: "sha256".to_string(),
+        data,
+    })
+    .map_err(|e| anyhow!("Failed to serialize initdata: {e}"))
+}
+
+/// Field of the TEE evidence the digest of the initdata is measured into,
+/// zero-padded to its size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum InitdataField {
+    /// TDX MRCONFIGID, 48 bytes
+    Tdx,
+    /// SEV-SNP HOST_DATA, 32 bytes
+    Snp,
+}
+
+impl InitdataField {
+    fn size(self) -> usize {
+        match self {
+            InitdataField::Tdx => 48,
+            InitdataField::Snp => 32,
+        }
+    }
+}
+
+/// Hex digest of `initdata`, as the attestation service computes it from
+/// the document sent by the pin: the hash, with the algorithm the document
+/// names, of the TOML document. `initdata` is the JSON object of the config,
+/// a JSON list of fragments merged as in the config, or the TOML document
+/// itself. With `field`, the digest is zero-padded to
+/// the size of that field of the evidence.
+pub fn initdata_digest(initdata: &str, field: Option<InitdataField>) -> Result<String> {
+    // A TOML document is hashed byte for byte, as the service does
+    let toml = if initdata.trim_start().starts_with('{') {
+        initdata_toml(initdata)?
+    } else if initdata.trim_start().starts_with('[') {
+        let fragments = serde_json::from_str(initdata).map_err(|e| {
+            TrusteePinError::Config(format!("Invalid list of initdata fragments: {e}"))
+        })?;
+        initdata_toml(&merge_initdata_fragments(fragments)?.to_string())?
+    } else {
+        initdata.to_string()
+    };
+    let document: Initdata = toml::from_str(&toml)
+        .map_err(|e| TrusteePinError::Config(format!("Invalid initdata document: {e}")))?;
+    let mut digest = match document.algorithm.as_str() {
+        "sha256" => Sha256::digest(toml.as_bytes()).to_vec(),
+        "sha384" => Sha384::digest(toml.as_bytes()).to_vec(),
+        "sha512" => Sha512::digest(toml.as_bytes()).to_vec(),
+        other => {
+            return Err(TrusteePinError::Config(format!(
+                "Unsupported initdata algorithm {}",
+                other
+            ))
+            .into());
+        }
+    };
+    if let Some(field) = field {
+        if digest.len() > field.size() {
+            return Err(TrusteePinError::Config(format!(
+                "A {} digest does not fit the {} bytes of the {:?} field",
+                document.algorithm,
+                field.size(),
+                field
+            ))
+            .into());
+        }
+        digest.resize(field.size(), 0);
+    }
+    Ok(hex::encode(digest))
+}
+
+/// Parse the config given on the command line, merging the drop-in fragments
+fn read_config(config: &str, options: ConfigOptions) -> Result<(Config, Option<String>)> {
+    signature::check_config(config, options.signature.as_deref())?;
+    let mut config = load_config(config, Path::new(CONFIG_DROPIN_DIR), options)?;
+    config.servers = endpoint::normalize_servers(config.servers)?;
+    for headers in config
+        .servers
+        .iter()
+        .filter_map(|server| server.headers.as_ref())
+    {
+        header_map(headers)?;
+    }
+    if let Some(backoff) = &config.backoff {
+        backoff.delay(DELAY, 0)?;
+    }
+    if let Some(agent) = &config.user_agent {
+        reqwest::header::HeaderValue::from_str(agent)
+            .map_err(|e| TrusteePinError::Config(format!("Invalid user_agent: {}", e)))?;
+    }
+    config.path = normalize_resource_path(&config.path)?;
+    versioned_path(&config.path, config.resource_version.as_deref())?;
+    for recipient in config.recipients.iter_mut().flatten() {
+        recipient.path = recipient
+            .path
+            .as_deref()
+            .map(normalize_resource_path)
+            .transpose()?;
+    }
+    let initdata = config.initdata.as_deref().map(initdata_toml).transpose()?;
+    Ok((config, initdata))
+}
+
+/// Fetch the key of a new binding described by `config`, unless given as
+/// `local_key`, and build the protected header and the encrypter of its JWE
+fn prepare_binding(
+    config: &str,
+    options: ConfigOptions,
+    runtime: &RuntimeConfig,
+    local_key: Option<&[u8]>,
+    events: &dyn EventHandler,
+) -> Result<(JweHeader, DirectKey)> {
+    let (mut config, initdata) = read_config(config, options)?;
+
+    // The attestation key belongs to the machine that attests, not to the
+    // one building with a local key
+    if local_key.is_none() {
+        attestation_key_handle(&config.attestation_key)?;
+    }
+
+    let recipients = config.recipients.take();
+    let tpm2 = config.tpm2.take();
+    let mut private_hdr = ClevisHeader::new(config, initdata);
+    let executors = ExecutorCache::default();
+    let fetch = |header: &ClevisHeader| fetch_header_key_with(header, runtime, &executors, events);
+    let key = match (recipients, local_key) {
+        (Some(_), Some(_)) => {
+            return Err(TrusteePinError::Config(
+                "A local key cannot be used with recipients".to_string(),
+            )
+            .into());
+        }
+        (Some(recipients), None) => {
+            encrypt_to_recipients(&mut private_hdr, &recipients, &OsRandom, fetch)?
+        }
+        (None, Some([])) => {
+            return Err(TrusteePinError::Config("The local key is empty".to_string()).into());
+        }
+        // Encoded as the backends return the resource
+        (None, Some(key)) => general_purpose::STANDARD.encode(key),
+        (None, None) => fetch(&private_hdr)?,
+    };
+    let key = match tpm2 {
+        Some(tpm2) => {
+            tpm2::seal_split_key(&tpm2::ClevisTpm2, &OsRandom, &mut private_hdr, &tpm2, &key)?
+        }
+        None => key,
+    };
+    bind_to_key(private_hdr, &key)
+}
+
+/// `key` as a symmetric JWK, in the form the servers hand out keys
+fn oct_key(key: &[u8]) -> String {
+    let mut jwk = Jwk::new("oct");
+    jwk.set_key_value(key);
+    general_purpose::STANDARD.encode(jwk.to_string())
+}
+
+/// Random key for the payload of a binding with several recipients, 32 bytes
+/// as A256GCM needs
+fn random_key(rng: &dyn RandomSource) -> String {
+    oct_key(&random_bytes::<32>(rng))
+}
+
+/// Generate the key of a JWE, encrypt it to the resource of `private_hdr`
+/// and to every recipient, with the keys returned by `fetch`, and record
+/// the results in `private_hdr`
+fn encrypt_to_recipients(
+    private_hdr: &mut ClevisHeader,
+    recipients: &[Recipient],
+    rng: &dyn RandomSource,
+    mut fetch: impl FnMut(&ClevisHeader) -> Result<String>,
+) -> Result<String> {
+    let key = random_key(rng);
+    let headers: Vec<ClevisHeader> = std::iter::once(private_hdr.clone())
+        .chain(recipients.iter().map(|r| private_hdr.for_recipient(r)))
+        .collect();
+    let mut jwes = Vec::with_capacity(headers.len());
+    for header in headers {
+        let recipient_key = fetch(&header)
+            .with_context(|| format!("Failed to fetch the key of recipient {}", header.path))?;
+        let (hdr, encrypter) = bind_to_key(header, &recipient_key)?;
+        jwes.push(serialize_jwe(key.as_bytes(), &hdr, &encrypter)?);
+    }
+    private_hdr.recipients = Some(jwes);
+    Ok(key)
+}
+
+/// Key of a binding with several recipients, decrypted from the first
+/// recipient JWE `decrypt` succeeds with
+fn decrypt_any_recipient(
+    recipients: &[String],
+    mut decrypt: impl FnMut(&str) -> Result<Vec<u8>>,
+) -> Result<String> {
+    let mut last_error = anyhow!("The binding has no recipients");
+    for (index, jwe) in recipients.iter().enumerate() {
+        match decrypt(jwe) {
+            Ok(key) => return String::from_utf8(key).context("Invalid key in a recipient"),
+            Err(e) => {
+                log(
+                    Priority::Warning,
+                    &format!("Failed to decrypt recipient {}: {:#}", index, e),
+                    &[("ERROR", &format!("{:#}", e))],
+                );
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error.context(format!(
+        "None of the {} recipients could be decrypted",
+        recipients.len()
+    )))
+}
+
+/// Identifier of a fetched key, stored in the header to tell whether the key
+/// changed on the KBS since binding. Truncated, it gives nothing away about
+/// the key.
+fn key_id(key: &str) -> String {
+    let digest = Sha256::new()
+        .chain_update(KEY_ID_CONTEXT)
+        .chain_update(key.as_bytes())
+        .finalize();
+    hex::encode(&digest[..KEY_ID_BYTES])
+}
+
+/// Build the protected header and the encrypter of a JWE bound to `key`,
+/// with `private_hdr` as its clevis header
+fn bind_to_key(mut private_hdr: ClevisHeader, key: &str) -> Result<(JweHeader, DirectKey)> {
+    private_hdr.key_id = Some(key_id(key));
+    let jwk = prepare_jwk(key, KeyEncoding::payload(&private_hdr))?;
+
+    eprintln!("JWK: {:?}", Redacted(&jwk.to_string()));
+    let encrypter = DirectKey::from_jwk(&jwk, "encrypt")
+        .map_err(|e| TrusteePinError::Crypto(format!("Error creating direct encrypter: {}", e)))?;
+
+    let mut hdr = JweHeader::new();
+    hdr.set_algorithm("ECDH-ES");
+    hdr.set_content_encryption(A256GCM);
+    hdr.set_claim("clevis", private_hdr.to_claim()?);
+
+    Ok((hdr, encrypter))
+}
+
+/// Fetch the key described by `config` and return `input` encrypted with it
+/// as a compact JWE carrying the clevis header needed to decrypt it again.
+/// The progress of the key fetch is reported to `events`.
+pub fn encrypt(
+    config: &str,
+    options: ConfigOptions,
+    input: &[u8],
+    events: &dyn EventHandler,
+) -> Result<String> {
+    let _span = telemetry::span("encrypt");
+    let (hdr, encrypter) =
+        prepare_binding(config, options, &RuntimeConfig::default(), None, events)?;
+    serialize_jwe(input, &hdr, &encrypter)
+}
+
+/// `encrypt` with `key`, the resource the operator uploads separately to the
+/// KBS at the path of `config`, instead of the key fetched from the servers,
+/// for air-gapped image builds on a host that cannot attest
+pub fn encrypt_with_local_key(
+    config: &str,
+    options: ConfigOptions,
+    key: &[u8],
+    input: &[u8],
+) -> Result<String> {
+    let _span = telemetry::span("encrypt");
+    let (hdr, encrypter) = prepare_binding(
+        config,
+        options,
+        &RuntimeConfig::default(),
+        Some(key),
+        &NoEvents,
+    )?;
+    serialize_jwe(input, &hdr, &encrypter)
+}
+
+fn serialize_jwe(input: &[u8], hdr: &JweHeader, encrypter: &DirectKey) -> Result<String> {
+    let _jwe_span = telemetry::span("jwe_encrypt");
+    let jwe_token = jose::serialize_compact(input, hdr, encrypter)
+        .map_err(|e| TrusteePinError::Crypto(format!("Error serializing JWE token: {}", e)))?;
+
+    Ok(jwe_token)
+}
+
+/// Go through `encrypt` up to the key fetch and return the protected header
+/// the JWE would get, without encrypting anything
+pub fn encrypt_dry_run(config: &str, options: ConfigOptions) -> Result<serde_json::Value> {
+    let (hdr, _) = prepare_binding(config, options, &RuntimeConfig::default(), None, &NoEvents)?;
+    Ok(serde_json::Value::Object(hdr.claims_set().clone()))
+}
+
+/// The attestation request the native backend would send, with the evidence
+/// of the attestation-agent and the initdata and protocol version of
+/// `config`, if given, collected without contacting a KBS to find out why
+/// attestation is rejected. `nonce` stands for the challenge of the KBS.
+pub fn collect_evidence(
+    config: Option<&str>,
+    options: ConfigOptions,
+    nonce: Option<&str>,
+) -> Result<serde_json::Value> {
+    let (version, initdata) = match config {
+        Some(config) => {
+            let (config, initdata) = read_config(config, options)?;
+            (config.kbs_protocol_version, initdata)
+        }
+        None => (None, None),
+    };
+    let nonce = nonce.map_or_else(
+        || general_purpose::STANDARD.encode(random_bytes::<32>(&OsRandom)),
+        str::to_string,
+    );
+    #[cfg(feature = "native-kbs")]
+    {
+        kbs::NativeKbsExecutor::new(
+            aa::AttestationAgent::new(aa::AA_SOCKET),
+            version.as_deref(),
+            None,
+        )?
+        .dump_evidence(&nonce, initdata)
+    }
+    #[cfg(not(feature = "native-kbs"))]
+    {
+        let _ = (version, initdata, nonce);
+        Err(TrusteePinError::Config(
+            "Collecting evidence needs the native backend, which is not included in this build"
+                .to_string(),
+        )
+        .into())
+    }
+}
+
+/// The URL of the environment variable `name` replacing the ones of the
+/// bindings, if set. An environment variable cannot be signed, so it is
+/// refused when configs must be.
+pub fn override_url_from_env(name: &str) -> Result<Option<String>> {
+    let Some(url) = env::var(name).ok().filter(|url| !url.is_empty()) else {
+        return Ok(None);
+    };
+    signature::check_unsigned(name)?;
+    Ok(Some(url))
+}
+
+/// Read the runtime settings given with `--config-file`
+pub fn read_runtime_config(path: &Path) -> Result<RuntimeConfig> {
+    let runtime =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    signature::check_config_file(path, &runtime)?;
+    let mut runtime: RuntimeConfig = serde_json::from_str(&runtime).map_err(|e| {
+        TrusteePinError::Config(format!("Failed to parse {}: {}", path.display(), e))
+    })?;
+    // Reject a bad duration now rather than after the first failed attempt
+    runtime.retry_delay()?;
+    runtime.attempt_timeout()?;
+    runtime.key_cache_ttl()?;
+    runtime.network_wait()?;
+    runtime.time_sync_wait()?;
+    runtime.servers = runtime
+        .servers
+        .map(endpoint::normalize_servers)
+        .transpose()?;
+    runtime.override_url = runtime
+        .override_url
+        .as_deref()
+        .map(endpoint::normalize_url)
+        .transpose()?;
+    Ok(runtime)
+}
+
+/// Fetch the key of the binding in the clevis header of a compact JWE and
+/// return the decrypted payload. `runtime` overrides the fetch settings of
+/// the header and the progress of the key fetch is reported to `events`.
+pub fn decrypt(input: &str, runtime: &RuntimeConfig, events: &dyn EventHandler) -> Result<Vec<u8>> {
+    decrypt_with(input, runtime, &ExecutorCache::default(), events)
+}
+
+/// `decrypt` with the key fetched by `fetcher` instead of the backend of the
+/// binding, e.g. to attest with a mechanism of the embedder
+pub fn decrypt_with_fetcher(
+    input: &str,
+    runtime: &RuntimeConfig,
+    fetcher: Rc<dyn KeyFetcher>,
+    events: &dyn EventHandler,
+) -> Result<Vec<u8>> {
+    decrypt_with(
+        input,
+        runtime,
+        &ExecutorCache::with_fetcher(fetcher),
+        events,
+    )
+}
+
+fn decrypt_with(
+    input: &str,
+    runtime: &RuntimeConfig,
+    executors: &ExecutorCache,
+    events: &dyn EventHandler,
+) -> Result<Vec<u8>> {
+    let _span = telemetry::span("decrypt");
+    let limits = runtime.input_limits.clone().unwrap_or_default();
+    limits.check_jwe(input)?;
+    let input = &armor::dearmor(input);
+    let hdr_clevis = ClevisHeader::from_compact_jwe_within(input, &limits)?;
+
+    eprintln!("Decrypt with header: {:?}", hdr_clevis);
+
+    let key = match &hdr_clevis.recipients {
+        Some(recipients) => decrypt_any_recipient(recipients, |jwe| {
+            decrypt_with(jwe, runtime, executors, events)
+        })?,
+        None => fetch_cached_header_key(&hdr_clevis, runtime, executors, events)?,
+    };
+    let key = match tpm2::unseal_local_secret(&tpm2::ClevisTpm2, &hdr_clevis)? {
+        Some(local_secret) => tpm2::split_key(&key, &local_secret)?,
+        None => key,
+    };
+    decrypt_with_key(input, &key, KeyEncoding::payload(&hdr_clevis))
+}
+
+/// Fetch the key of the binding of `header`, through the key cache when
+/// `runtime` enables it. A broken cache only costs the attestation.
+fn fetch_cached_header_key(
+    header: &ClevisHeader,
+    runtime: &RuntimeConfig,
+    executors: &ExecutorCache,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    let Some(ttl) = runtime.key_cache_ttl()? else {
+        return fetch_header_key_with(header, runtime, executors, events);
+    };
+    let cache = cache::KeyCache::new(
+        &cache::key_cache_dir(),
+        &cache::local_key_path(),
+        ttl,
+        &tpm2::ClevisTpm2,
+    );
+    match cache.get(header) {
+        Ok(Some(key)) => {
+            log(
+                Priority::Info,
+                &format!("Using the cached key of {}", header.path),
+                &[("RESOURCE_PATH", &header.path), ("RESULT", "cached")],
+            );
+            return Ok(key);
+        }
+        Ok(None) => {}
+        Err(e) => log(
+            Priority::Warning,
+            &format!("Ignoring the key cache: {:#}", e),
+            &[
+                ("RESOURCE_PATH", &header.path),
+                ("ERROR", &format!("{:#}", e)),
+            ],
+        ),
+    }
+    let key = fetch_header_key_with(header, runtime, executors, events)?;
+    if let Err(e) = cache.put(header, &key) {
+        log(
+            Priority::Warning,
+            &format!("Failed to cache the key: {:#}", e),
+            &[
+                ("RESOURCE_PATH", &header.path),
+                ("ERROR", &format!("{:#}", e)),
+            ],
+        );
+    }
+    Ok(key)
+}
+
+fn decrypt_with_key(input: &str, key: &str, encoding: KeyEncoding) -> Result<Vec<u8>> {
+    let decrypter_jwk = prepare_jwk(key, encoding)?;
+
+    let _jwe_span = telemetry::span("jwe_decrypt");
+    let decrypter = DirectKey::from_jwk(&decrypter_jwk, "decrypt")
+        .map_err(|e| TrusteePinError::Crypto(format!("Error creating decrypter: {}", e)))?;
+
+    let (payload, _) = jose::deserialize_compact(input, &decrypter)
+        .map_err(|e| TrusteePinError::Crypto(format!("Error decrypting JWE: {}", e)))?;
+
+    Ok(payload)
+}
+
+/// Check whether the key of the binding in the compact JWE `input` changed
+/// on the KBS since binding and if so, return `input` encrypted with the
+/// current key instead. The payload is then decrypted with the key at
+/// `previous_path`, where the KBS keeps the key rotated out. Returns `None`
+/// if the key did not change.
+pub fn reencrypt(
+    input: &str,
+    previous_path: Option<&str>,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<Option<String>> {
+    let input = &armor::dearmor(input);
+    let header = ClevisHeader::from_compact_jwe(input)?;
+    if header.recipients.is_some() {
+        return Err(anyhow!(
+            "Bindings with several recipients cannot be re-encrypted, bind again"
+        ));
+    }
+    let executors = ExecutorCache::default();
+    let local_secret = tpm2::unseal_local_secret(&tpm2::ClevisTpm2, &header)?;
+    // The key of a split-key binding changes with the one fetched
+    let payload_key = |key: &str| match &local_secret {
+        Some(local_secret) => tpm2::split_key(key, local_secret),
+        None => Ok(key.to_string()),
+    };
+    let key = payload_key(&fetch_header_key_with(
+        &header, runtime, &executors, events,
+    )?)?;
+    if header.key_id.as_deref() == Some(key_id(&key).as_str()) {
+        return Ok(None);
+    }
+
+    let encoding = KeyEncoding::payload(&header);
+    let payload = match decrypt_with_key(input, &key, encoding) {
+        // Bound before key ids were recorded: re-encrypting records it
+        Ok(payload) => payload,
+        Err(e) => {
+            eprintln!("The current key does not decrypt the JWE: {:#}", e);
+            let previous_path = previous_path.ok_or_else(|| {
+                anyhow!("The key changed on the KBS, the path of the previous key is needed")
+            })?;
+            let previous =
+                fetch_header_keys(&header, &[previous_path], runtime, &executors, events)?;
+            decrypt_with_key(input, &payload_key(&previous[0])?, encoding)
+                .context("The previous key does not decrypt the JWE either")?
+        }
+    };
+    let (hdr, encrypter) = bind_to_key(header, &key)?;
+    serialize_jwe(&payload, &hdr, &encrypter).map(Some)
+}
+
+/// New server metadata of a binding, see `update_binding`
+#[derive(Debug, Clone, Default)]
+pub struct BindingUpdate {
+    /// Servers replacing the ones of the binding
+    pub servers: Option<Vec<Server>>,
+    /// Certificate of every server
+    pub cert: Option<Cert>,
+    /// Resource path of the key
+    pub path: Option<String>,
+}
+
+impl BindingUpdate {
+    fn is_empty(&self) -> bool {
+        self.servers.is_none() && self.cert.is_none() && self.path.is_none()
+    }
+
+    /// `header` with the metadata of the update. Servers replaced keep the
+    /// certificate of the first old one unless a certificate is given.
+    fn apply(&self, mut header: ClevisHeader) -> Result<ClevisHeader> {
+        if let Some(servers) = &self.servers {
+            if header.cert.is_none() {
+                header.cert = header
+                    .servers
+                    .first()
+                    .map(|server| server.cert.clone())
+                    .filter(|cert| *cert != Cert::None);
+            }
+            header.servers = endpoint::normalize_servers(servers.clone())?;
+        }
+        if let Some(cert) = &self.cert {
+            for server in &mut header.servers {
+                server.cert = Cert::None;
+            }
+            header.cert = Some(cert.clone());
+        }
+        if let Some(path) = &self.path {
+            header.path = normalize_resource_path(path)?;
+        }
+        Ok(header)
+    }
+}
+
+/// Re-emit the compact JWE `input` with the servers, certificate or path of
+/// `update` in its clevis header, keeping its key and payload, e.g. when
+/// only the endpoint of the KBS changed. The header is authenticated with
+/// the payload, so the payload is encrypted again with the same key: it is
+/// fetched with the new metadata and must decrypt `input`.
+pub fn update_binding(
+    input: &str,
+    update: &BindingUpdate,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    if update.is_empty() {
+        return Err(TrusteePinError::Config("Nothing to update".to_string()).into());
+    }
+    let input = &armor::dearmor(input);
+    let header = ClevisHeader::from_compact_jwe(input)?;
+    if header.recipients.is_some() {
+        return Err(anyhow!(
+            "Bindings with several recipients cannot be updated, bind again"
+        ));
+    }
+    let header = update.apply(header)?;
+    let key = fetch_header_key(&header, runtime, events)?;
+    let key = match tpm2::unseal_local_secret(&tpm2::ClevisTpm2, &header)? {
+        Some(local_secret) => tpm2::split_key(&key, &local_secret)?,
+        None => key,
+    };
+    let payload = decrypt_with_key(input, &key, KeyEncoding::payload(&header))
+        .context("The key served with the new metadata does not decrypt the JWE")?;
+    let (hdr, encrypter) = bind_to_key(header, &key)?;
+    serialize_jwe(&payload, &hdr, &encrypter)
+}
+
+/// Fetch the key described by `config` without binding anything to it.
+/// The key is returned base64 encoded, as handed out by the servers.
+pub fn fetch_key(
+    config: &str,
+    options: ConfigOptions,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    let (config, initdata) = read_config(config, options)?;
+    fetch_header_key(
+        &ClevisHeader::new(config, initdata),
+        &RuntimeConfig::default(),
+        events,
+    )
+}
+
+/// Fetch the resources at `paths` with the servers and settings of the
+/// binding described by `config`, e.g. the shares of a split key. The native
+/// backend attests once per server for all of them. The resources are
+/// returned base64 encoded, in the order of `paths`.
+pub fn fetch_keys(
+    config: &str,
+    options: ConfigOptions,
+    paths: &[&str],
+    events: &dyn EventHandler,
+) -> Result<Vec<String>> {
+    let (config, initdata) = read_config(config, options)?;
+    fetch_header_keys(
+        &ClevisHeader::new(config, initdata),
+        paths,
+        &RuntimeConfig::default(),
+        &ExecutorCache::default(),
+        events,
+    )
+}
+
+/// Encrypt a random payload with the binding described by `config`, then
+/// decrypt it again through the servers and check that the payload survived.
+/// Both key fetches only go to the servers selected by `runtime`, with its
+/// settings.
+pub fn self_test(config: &str, options: ConfigOptions, runtime: &RuntimeConfig) -> Result<()> {
+    let payload: [u8; 32] = random_bytes(&OsRandom);
+    let jwe = prepare_binding(config, options, runtime, None, &NoEvents)
+        .and_then(|(hdr, encrypter)| serialize_jwe(&payload, &hdr, &encrypter))
+        .context("Self-test encryption failed")?;
+    let decrypted = decrypt(&jwe, runtime, &NoEvents).context("Self-test decryption failed")?;
+    if decrypted != payload {
+        return Err(anyhow!("Self-test failed: the decrypted payload differs"));
+    }
+    Ok(())
+}
+
+/// Per-server bookkeeping across retry attempts
+#[derive(Default)]
+struct ServerState {
+    /// Set once the server failed in a way retrying cannot fix
+    permanent: bool,
+    last_error: Option<String>,
+    consecutive_failures: u32,
+    /// Attempts left to skip the server while its circuit is open
+    cooldown_remaining: u32,
+    /// Not to be tried before then, as the server asked
+    retry_at: Option<Instant>,
+}
+
+/// `delay`, unless every server left asked to be retried later: then the
+/// time until the first of them is ready
+fn next_delay(states: &[ServerState], delay: Duration, now: Instant) -> Duration {
+    states
+        .iter()
+        .filter(|state| !state.permanent)
+        .map(|state| state.retry_at.map(|at| at.saturating_duration_since(now)))
+        .collect::<Option<Vec<Duration>>>()
+        .and_then(|delays| delays.into_iter().min())
+        .unwrap_or(delay)
+}
+
+fn try_fetch_from_servers<E: KeyFetcher + ?Sized>(
+    servers: &[Server],
+    request: &FetchRequest,
+    retry: &RetryPolicy,
+    executor: &E,
+    events: &dyn EventHandler,
+    states: &mut [ServerState],
+) -> Option<String> {
+    // Never skip every server: with all circuits open, probe them all
+    let all_open = states
+        .iter()
+        .all(|state| state.permanent || state.cooldown_remaining > 0);
+
+    for (index, (server, state)) in servers.iter().zip(states.iter_mut()).enumerate() {
+        if state.permanent {
+            continue;
+        }
+        if state.cooldown_remaining > 0 && !all_open {
+            state.cooldown_remaining -= 1;
+            log(
+                Priority::Info,
+                &format!(
+                    "Skipping URL {} after {} consecutive failures",
+                    server.url, state.consecutive_failures
+                ),
+                &[("SERVER_URL", &server.url), ("RESULT", "skipped")],
+            );
+            continue;
+        }
+        if let Some(retry_at) = state.retry_at
+            && retry_at > Instant::now()
+        {
+            log(
+                Priority::Info,
+                &format!(
+                    "Skipping URL {}, it asked to be retried in {:?}",
+                    server.url,
+                    retry_at.saturating_duration_since(Instant::now())
+                ),
+                &[("SERVER_URL", &server.url), ("RESULT", "skipped")],
+            );
+            continue;
+        }
+        log(
+            Priority::Info,
+            &format!("Trying URL {}/{}: {}", index + 1, servers.len(), server.url),
+            &[("SERVER_URL", &server.url)],
+        );
+        let span = telemetry::span("fetch_from_server");
+        span.set_attribute("server.url", &server.url);
+        let policy_ids = server.policy_ids.as_deref().unwrap_or(request.policy_ids);
+        let connection = server.connection(&request.connection);
+        match executor.try_fetch_luks_key(
+            &server.url,
+            request.path,
+            &server.cert,
+            &connection,
+            request.initdata.clone(),
+            policy_ids,
+        ) {
+            Ok(key) => {
+                log(
+                    Priority::Info,
+                    &format!("Successfully fetched LUKS key from URL: {}", server.url),
+                    &[("SERVER_URL", &server.url), ("RESULT", "success")],
+                );
+                events.on_success(&server.url);
+                return Some(key);
+            }
+            Err(e) => {
+                span.set_error(&e);
+                let kind = retry_kind(&e, retry.retry_on);
+                events.on_server_failure(&server.url, &format!("{:#}", e), kind);
+                let permanent = kind == FailureKind::Permanent;
+                log(
+                    Priority::Warning,
+                    &format!("Error with URL {}: {}", server.url, e),
+                    &[
+                        ("SERVER_URL", &server.url),
+                        (
+                            "RESULT",
+                            if permanent {
+                                "permanent-failure"
+                            } else {
+                                "failure"
+                            },
+                        ),
+                        ("ERROR", &format!("{:#}", e)),
+                    ],
+                );
+                if permanent {
+                    log(
+                        Priority::Info,
+                        &format!("Not retrying URL {}: the error is permanent", server.url),
+                        &[("SERVER_URL", &server.url), ("RESULT", "not-retrying")],
+                    );
+                    state.permanent = true;
+                }
+                state.last_error = Some(format!("{:#}", e));
+                state.retry_at = retry_after(&e).map(|delay| Instant::now() + delay);
+                state.consecutive_failures += 1;
+                if let Some(breaker) = retry.circuit_breaker
+                    && state.consecutive_failures >= breaker.failure_threshold.max(1)
+                {
+                    state.cooldown_remaining = breaker.cooldown_attempts;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Last error seen for every server, one per line, giving up with `kind`
+fn failure_report(servers: &[Server], states: &[ServerState], kind: FailureKind) -> anyhow::Error {
+    let lines: Vec<String> = servers
+        .iter()
+        .zip(states)
+        .map(|(server, state)| {
+            format!(
+                "{}: {}",
+                server.url,
+                state.last_error.as_deref().unwrap_or("not attempted")
+            )
+        })
+        .collect();
+    TrusteePinError::GaveUp {
+        kind,
+        message: lines.join("\n"),
+    }
+    .into()
+}
+
+/// Resource to fetch from every server
+struct FetchRequest<'a> {
+    path: &'a str,
+    initdata: Option<String>,
+    /// Used for servers without their own policy IDs
+    policy_ids: &'a [String],
+    /// Used for what servers do not set themselves
+    connection: ConnectionSettings,
+}
+
+#[cfg(test)]
+impl<'a> FetchRequest<'a> {
+    fn new(path: &'a str) -> Self {
+        FetchRequest {
+            path,
+            initdata: None,
+            policy_ids: &[],
+            connection: ConnectionSettings::default(),
+        }
+    }
+}
+
+/// How many attempts to make and how long to wait between them
+struct RetryPolicy<'a> {
+    num_retries: &'a NumRetries,
+    /// Delay between attempts when `num_retries` has no schedule
+    delay: Duration,
+    circuit_breaker: Option<&'a CircuitBreaker>,
+    backoff: Option<&'a Backoff>,
+    /// Failures retried instead of those `failure_kind` deems transient
+    retry_on: Option<&'a [RetryClass]>,
+    cancellation: Option<&'a CancellationToken>,
+    /// Source of the jitter of the backoff
+    rng: &'a dyn RandomSource,
+    /// Failed attempts the backoff has grown the delay for
+    failures: Cell<u32>,
+}
+
+impl RetryPolicy<'_> {
+    /// Delay before the attempt following the failed `attempt`, or `None`
+    /// when it was the last one. The backoff grows the delay of counted and
+    /// infinite retries; the delays of a schedule are used as they are.
+    fn retry_delay(&self, attempt: u32) -> Result<Option<Duration>, TrusteePinError> {
+        let Some(delay) = self.num_retries.retry_delay(attempt, self.delay) else {
+            return Ok(None);
+        };
+        match self.backoff {
+            Some(backoff) if !matches!(self.num_retries, NumRetries::Schedule(_)) => {
+                let failures = self.failures.replace(self.failures.get().saturating_add(1));
+                let delay = backoff.delay(delay, failures)?;
+                Ok(Some(if backoff.jitter {
+                    entropy::jitter(self.rng, delay)
+                } else {
+                    delay
+                }))
+            }
+            _ => Ok(Some(delay)),
+        }
+    }
+
+    /// Fail when the fetch was cancelled
+    fn check_cancelled(&self) -> Result<(), TrusteePinError> {
+        match self.cancellation {
+            Some(token) if token.is_cancelled() => Err(TrusteePinError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait `delay` before the next attempt, failing as soon as the fetch
+    /// is cancelled
+    fn sleep(&self, delay: Duration) -> Result<(), TrusteePinError> {
+        match self.cancellation {
+            Some(token) if token.wait(delay) => Err(TrusteePinError::Cancelled),
+            Some(_) => Ok(()),
+            None => {
+                thread::sleep(delay);
+                Ok(())
+            }
+        }
+    }
+
+    /// Note that a key was fetched
+    fn succeeded(&self) {
+        if self
+            .backoff
+            .is_some_and(|backoff| backoff.reset_after_success)
+        {
+            self.failures.set(0);
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a> RetryPolicy<'a> {
+    fn new(num_retries: &'a NumRetries) -> Self {
+        RetryPolicy {
+            num_retries,
+            delay: DELAY,
+            circuit_breaker: None,
+            backoff: None,
+            retry_on: None,
+            cancellation: None,
+            rng: &OsRandom,
+            failures: Cell::new(0),
+        }
+    }
+}
+
+fn fetch_luks_key<E: KeyFetcher + ?Sized>(
+    servers: &[Server],
+    request: &FetchRequest,
+    retry: &RetryPolicy,
+    executor: &E,
+    events: &dyn EventHandler,
+) -> Result<String> {
+    if servers.is_empty() {
+        return Err(TrusteePinError::Config("No URLs provided".to_string()).into());
+    }
+
+    let mut states: Vec<ServerState> = servers.iter().map(|_| ServerState::default()).collect();
+
+    let max_attempts = retry.num_retries.max_attempts();
+    let mut attempt = 0;
+    loop {
+        retry.check_cancelled()?;
+        attempt += 1;
+        let message = match max_attempts {
+            Some(max_attempts) => format!(
+                "Attempting to fetch LUKS key (attempt {}/{})",
+                attempt, max_attempts
+            ),
+            None => format!("Attempting to fetch LUKS key (attempt {})", attempt),
+        };
+        log(
+            Priority::Info,
+            &message,
+            &[("ATTEMPT", &attempt.to_string())],
+        );
+        events.on_attempt_start(attempt, max_attempts);
+
+        // The delay before the next attempt is not part of the attempt span
+        let found = {
+            let span = telemetry::span("fetch_attempt");
+            span.set_attribute("attempt", attempt);
+            try_fetch_from_servers(servers, request, retry, executor, events, &mut states)
+        };
+        if let Some(key) = found {
+            retry.succeeded();
+            return Ok(key);
+        }
+        if states.iter().all(|state| state.permanent) {
+            return Err(failure_report(servers, &states, FailureKind::Permanent)
+                .context("Failed to fetch the LUKS key, not retrying after permanent errors"));
+        }
+
+        let Some(delay) = retry.retry_delay(attempt)? else {
+            return Err(
+                failure_report(servers, &states, FailureKind::Transient).context(format!(
+                    "Failed to fetch the LUKS key from all URLs after {} attempts",
+                    attempt
+                )),
+            );
+        };
+        let delay = next_delay(&states, delay, Instant::now());
+        log(
+            Priority::Warning,
+            &format!(
+                "All URLs failed for attempt {}. Retrying in {:?}...",
+                attempt, delay
+            ),
+            &[("ATTEMPT", &attempt.to_string()), ("RESULT", "retrying")],
+        );
+        retry.sleep(delay)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_fetch_luks_key_success() {
+        let mock = MockCommandExecutor {
+            response: Ok("test_luks_key_12345".to_string()),
+        };
+
+        let servers = vec![Server {
+            name: None,
+            url: "http://server1.example.com".to_string(),
+            cert: Cert::None,
+            policy_ids: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            headers: None,
+        }];
+
+        let num_retries = NumRetries::Finite(3);
+        let result = fetch_luks_key(
+            &servers,
+            &FetchRequest::new("/test/path"),
+            &RetryPolicy::new(&num_retries),
+            &mock,
+            &NoEvents,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "test_luks_key_12345");
+    }
+
+    /// Fails, asking to wait an hour on servers named `busy`
+    struct BusyExecutor {
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl KeyFetcher for BusyExecutor {
+        fn try_fetch_luks_key(
+            &self,
+            url: &str,
+            _path: &str,
+            _cert: &Cert,
+            _connection: &ConnectionSettings,
+            _initdata: Option<String>,
+            _policy_ids: &[String],
+        ) -> Result<String> {
+            self.calls.borrow_mut().push(url.to_string());
+            let retry_after = url.contains("busy").then_some(Duration::from_secs(3600));
+            Err(TrusteePinError::from_status(url, 503, "unavailable")
+                .with_retry_after(retry_after)
+                .into())
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let executor = BusyExecutor {
+            calls: RefCell::new(Vec::new()),
+        };
+        let servers: Vec<Server> =
+            serde_json::from_value(serde_json::json!(["http://busy", "http://other"])).unwrap();
+        let num_retries = NumRetries::Finite(2);
+        let retry = RetryPolicy {
+            delay: Duration::from_millis(1),
+            ..RetryPolicy::new(&num_retries)
+        };
+
+        let result = fetch_luks_key(
+            &servers,
+            &FetchRequest::new("/test/path"),
+            &retry,
+            &executor,
+            &NoEvents,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            *executor.calls.borrow(),
+            ["http://busy", "http://other", "http://other"]
+        );
+    }
+
+    #[test]
+    fn test_next_delay() {
+        let now = Instant::now();
+        let waiting = |secs| ServerState {
+            retry_at: Some(now + Duration::from_secs(secs)),
+            ..ServerState::default()
+        };
+        let delay = Duration::from_secs(5);
+
+        assert_eq!(
+            next_delay(&[waiting(30), waiting(20)], delay, now),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            next_delay(&[waiting(30), ServerState::default()], delay, now),
+            delay
+        );
+        let permanent = ServerState {
+            permanent: true,
+            ..ServerState::default()
+        };
+        assert_eq!(
+            next_delay(&[waiting(1), permanent], delay, now),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_fetch_luks_key_error() {
+        let mock = MockCommandExecutor {
+            response: Err(anyhow!("Failed to connect to server")),
+        };
+
+        let servers = vec![Server {
+            name: None,
+            url: "http://server1.example.com".to_string(),
+            cert: Cert::None,
+            policy_ids: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            headers: None,
+        }];
+
+        let num_retries = NumRetries::Finite(3);
+        let result = fetch_luks_key(
+            &servers,
+            &FetchRequest::new("/test/path"),
+            &RetryPolicy::new(&num_retries),
+            &mock,
+            &NoEvents,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to fetch the LUKS key from all URLs after 3 attempts"
+        );
+    }
+
+    #[test]
+    fn test_fetch_luks_key_permanent_error_stops_retrying() {
+        let mock = MockCommandExecutor {
+            response: Err(TrusteePinError::from_status(
+                "http://server1.example.com",
+                404,
+                "Resource not found",
+            )
+            .into()),
+        };
+
+        let servers = vec![Server {
+            name: None,
+            url: "http://server1.example.com".to_string(),
+            cert: Cert::None,
+            policy_ids: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            headers: None,
+        }];
+
+        let num_retries = NumRetries::Infinity;
+        let start = std::time::Instant::now();
+        let result = fetch_luks_key(
+            &servers,
+            &FetchRequest::new("/test/path"),
+            &RetryPolicy::new(&num_retries),
+            &mock,
+            &NoEvents,
+        );
+
+        assert!(start.elapsed() < DELAY);
+        assert_eq!(
+            format!("{:#}", result.unwrap_err()),
+            "Failed to fetch the LUKS key, not retrying after permanent errors: \
+             http://server1.example.com: Resource not found"
+        );
+    }
+
+    #[test]
+    fn test_fetch_luks_key_cancelled() {
+        let mock = MockCommandExecutor {
+            response: Err(anyhow!("Connection refused")),
+        };
+        let servers = &two_servers()[..1];
+        let cancellation = CancellationToken::default();
+        let retry = RetryPolicy {
+            delay: Duration::from_secs(3600),
+            cancellation: Some(&cancellation),
+            ..RetryPolicy::new(&NumRetries::Infinity)
+        };
+        let canceller = cancellation.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        let start = Instant::now();
+
+        let result = fetch_luks_key(
+            servers,
+            &FetchRequest::new("/test/path"),
+            &retry,
+            &mock,
+            &NoEvents,
+        );
+
+        handle.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TrusteePinError>(),
+            Some(&TrusteePinError::Cancelled)
+        );
+
+        // Cancelled before the first attempt: no server is tried
+        let recorder = RecordingCommandExecutor {
+            calls: RefCell::new(Vec::new()),
+        };
+        assert!(
+            fetch_luks_key(
+                servers,
+                &FetchRequest::new("/test/path"),
+                &retry,
+                &recorder,
+                &NoEvents
+            )
+            .is_err()
+        );
+        assert!(recorder.calls.borrow().is_empty());
+    }
+
+    fn two_servers() -> Vec<Server> {
+        vec![
+            Server {
+                name: None,
+                url: "http://server1.example.com".to_string(),
+                cert: Cert::None,
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            },
+            Server {
+                name: None,
+                url: "http://server2.example.com".to_string(),
+                cert: Cert::None,
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff: Backoff = serde_json::from_str(r#"{"max_delay": "40s"}"#).unwrap();
+        let infinity = NumRetries::Infinity;
+        let retry = RetryPolicy {
+            delay: Duration::from_secs(10),
+            backoff: Some(&backoff),
+            ..RetryPolicy::new(&infinity)
+        };
+
+        let delays: Vec<u64> = (1..=5)
+            .map(|attempt| retry.retry_delay(attempt).unwrap().unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, [10, 20, 40, 40, 40]);
+
+        // The next key starts again from the initial delay
+        retry.succeeded();
+        assert_eq!(retry.retry_delay(1).unwrap(), Some(Duration::from_secs(10)));
+
+        let sticky = Backoff {
+            reset_after_success: false,
+            ..backoff.clone()
+        };
+        let retry = RetryPolicy {
+            backoff: Some(&sticky),
+            ..retry
+        };
+        retry.succeeded();
+        assert_eq!(retry.retry_delay(1).unwrap(), Some(Duration::from_secs(20)));
+
+        // A schedule keeps its own delays
+        let schedule: NumRetries = serde_json::from_str(r#"["5s", "5s"]"#).unwrap();
+        let retry = RetryPolicy {
+            backoff: Some(&backoff),
+            ..RetryPolicy::new(&schedule)
+        };
+        assert_eq!(retry.retry_delay(2).unwrap(), Some(Duration::from_secs(5)));
+
+        // The jitter is reproducible with a seeded generator
+        let jittered = Backoff {
+            jitter: true,
+            ..backoff.clone()
+        };
+        let delays = |seed| {
+            let rng = RefCell::new(StdRng::seed_from_u64(seed));
+            let retry = RetryPolicy {
+                delay: Duration::from_secs(10),
+                backoff: Some(&jittered),
+                rng: &rng,
+                ..RetryPolicy::new(&infinity)
+            };
+            (1..=4)
+                .map(|attempt| retry.retry_delay(attempt).unwrap().unwrap())
+                .collect::<Vec<Duration>>()
+        };
+        assert_eq!(delays(7), delays(7));
+        for (delay, max) in delays(7).into_iter().zip([10, 20, 40, 40]) {
+            let max = Duration::from_secs(max);
+            assert!(max / 2 <= delay && delay <= max);
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_skips_and_probes_server() {
+        let executor = RecordingCommandExecutor {
+            calls: std::cell::RefCell::new(Vec::new()),
+        };
+        let servers = two_servers();
+        let breaker = CircuitBreaker {
+            failure_threshold: 3,
+            cooldown_attempts: 1,
+        };
+        let mut states = vec![ServerState::default(), ServerState::default()];
+        states[0].consecutive_failures = 2;
+
+        let retry = RetryPolicy {
+            circuit_breaker: Some(&breaker),
+            ..RetryPolicy::new(&NumRetries::Once)
+        };
+        let round = |states: &mut [ServerState]| {
+            try_fetch_from_servers(
+                &servers,
+                &FetchRequest::new("/test/path"),
+                &retry,
+                &executor,
+                &NoEvents,
+                states,
+            )
+        };
+
+        // Third consecutive failure of server1 opens its circuit
+        assert!(round(&mut states).is_none());
+        assert_eq!(states[0].cooldown_remaining, 1);
+        // Open: skipped for one attempt
+        assert!(round(&mut states).is_none());
+        assert_eq!(states[0].cooldown_remaining, 0);
+        // Half-open: probed once, fails and opens again
+        assert!(round(&mut states).is_none());
+        assert_eq!(states[0].cooldown_remaining, 1);
+
+        assert_eq!(
+            *executor.calls.borrow(),
+            vec![
+                "http://server1.example.com",
+                "http://server2.example.com",
+                "http://server2.example.com",
+                "http://server1.example.com",
+                "http://server2.example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_circuit_breaker_probes_when_all_open() {
+        let executor = RecordingCommandExecutor {
+            calls: std::cell::RefCell::new(Vec::new()),
+        };
+        let servers = two_servers();
+        let breaker = CircuitBreaker {
+            failure_threshold: 1,
+            cooldown_attempts: 5,
+        };
+        let mut states = vec![ServerState::default(), ServerState::default()];
+        states[0].cooldown_remaining = 3;
+        states[1].cooldown_remaining = 3;
+
+        let retry = RetryPolicy {
+            circuit_breaker: Some(&breaker),
+            ..RetryPolicy::new(&NumRetries::Once)
+        };
+        let result = try_fetch_from_servers(
+            &servers,
+            &FetchRequest::new("/test/path"),
+            &retry,
+            &executor,
+            &NoEvents,
+            &mut states,
+        );
+
+        assert!(result.is_none());
+        assert_eq!(executor.calls.borrow().len(), 2);
+        assert_eq!(states[0].cooldown_remaining, 5);
+    }
+
+    #[test]
+    fn test_failure_kind_classification() {
+        let url = "http://server1.example.com";
+        assert_eq!(
+            failure_kind(&TrusteePinError::from_status(url, 403, "denied").into()),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            failure_kind(&TrusteePinError::from_status(url, 503, "unavailable").into()),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            failure_kind(&TrusteePinError::from_status(url, 429, "slow down").into()),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            failure_kind(
+                &anyhow::Error::from(TrusteePinError::permanent(url, "gone")).context("wrapped")
+            ),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            failure_kind(&TrusteePinError::Config("bad config".to_string()).into()),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            failure_kind(&anyhow!("Connection refused")),
+            FailureKind::Transient
+        );
+    }
+
+    #[test]
+    fn test_retry_on_classification() {
+        let url = "http://server1.example.com";
+        let retry_on: Vec<RetryClass> =
+            serde_json::from_str(r#"["5xx", "408", "timeout"]"#).unwrap();
+        let kind = |error: TrusteePinError| retry_kind(&error.into(), Some(&retry_on));
+
+        assert_eq!(
+            kind(TrusteePinError::from_status(url, 503, "unavailable")),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            kind(TrusteePinError::from_status(url, 408, "timeout")),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            kind(TrusteePinError::from_status(url, 429, "slow down")),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            kind(TrusteePinError::transport(
+                url,
+                FailureCause::Timeout,
+                "timed out"
+            )),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            kind(TrusteePinError::transport(
+                url,
+                FailureCause::Network,
+                "refused"
+            )),
+            FailureKind::Permanent
+        );
+        // Failures of unknown cause keep their classification
+        assert_eq!(
+            kind(TrusteePinError::permanent(url, "gone")),
+            FailureKind::Permanent
+        );
+        assert_eq!(
+            retry_kind(&anyhow!("Connection refused"), Some(&retry_on)),
+            FailureKind::Transient
+        );
+        assert_eq!(
+            retry_kind(
+                &TrusteePinError::from_status(url, 429, "slow down").into(),
+                None
+            ),
+            FailureKind::Transient
+        );
+
+        assert_eq!(
+            serde_json::to_string(&retry_on).unwrap(),
+            r#"["5xx","408","timeout"]"#
+        );
+        for invalid in [r#""6xx""#, r#""99""#, r#""dns""#] {
+            assert!(serde_json::from_str::<RetryClass>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn test_num_retries_none() {
+        let num_retries: NumRetries = serde_json::from_str("\"none\"").unwrap();
+        assert_eq!(num_retries, NumRetries::Once);
+        assert_eq!(serde_json::to_string(&num_retries).unwrap(), "\"none\"");
+
+        let result = serde_json::from_str::<NumRetries>("\"never\"");
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("expected 'infinity' or 'none', got: 'never'")
+        );
+    }
+
+    #[test]
+    fn test_fetch_luks_key_once_does_not_retry() {
+        let executor = RecordingCommandExecutor {
+            calls: std::cell::RefCell::new(Vec::new()),
+        };
+
+        let start = std::time::Instant::now();
+        let result = fetch_luks_key(
+            &two_servers(),
+            &FetchRequest::new("/test/path"),
+            &RetryPolicy::new(&NumRetries::Once),
+            &executor,
+            &NoEvents,
+        );
+
+        assert!(start.elapsed() < DELAY);
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to fetch the LUKS key from all URLs after 1 attempts"
+        );
+        assert_eq!(
+            error.root_cause().to_string(),
+            "http://server1.example.com: Connection refused\n\
+             http://server2.example.com: Connection refused"
+        );
+        assert_eq!(executor.calls.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_num_retries_schedule() {
+        let schedule: NumRetries =
+            serde_json::from_str(r#"["5s", "2@500ms", "infinity@5m"]"#).unwrap();
+
+        assert_eq!(schedule.max_attempts(), None);
+        let delays: Vec<_> = (1..=5)
+            .map(|attempt| schedule.retry_delay(attempt, DELAY))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(5)),
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_millis(500)),
+                Some(Duration::from_secs(300)),
+                Some(Duration::from_secs(300)),
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&schedule).unwrap(),
+            r#"["5s","2@500ms","infinity@5m"]"#
+        );
+
+        let finite: NumRetries = serde_json::from_str(r#"["1s", "3@2s"]"#).unwrap();
+        assert_eq!(finite.max_attempts(), Some(5));
+        assert_eq!(finite.retry_delay(4, DELAY), Some(Duration::from_secs(2)));
+        assert_eq!(finite.retry_delay(5, DELAY), None);
+    }
+
+    #[test]
+    fn test_num_retries_schedule_invalid() {
+        for (schedule, error) in [
+            (r#"[]"#, "retry schedule must not be empty"),
+            (r#"["5"]"#, "missing unit in duration '5'"),
+            (r#"["5d"]"#, "unknown unit 'd' in duration '5d'"),
+            (r#"["0@5s"]"#, "invalid retry count in '0@5s'"),
+            (
+                r#"["infinity@5s", "10s"]"#,
+                "'infinity@' must be the last entry of a retry schedule",
+            ),
+        ] {
+            let result = serde_json::from_str::<NumRetries>(schedule);
+            assert!(
+                result.as_ref().unwrap_err().to_string().contains(error),
+                "{}: {:?}",
+                schedule,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_fetch_luks_key_infinity_retries() {
+        use std::sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        };
+        use std::time::Instant;
+
+        let mock = MockCommandExecutor {
+            response: Err(anyhow!("Failed to connect to server")),
+        };
+
+        let servers = vec![Server {
+            name: None,
+            url: "http://server1.example.com".to_string(),
+            cert: Cert::None,
+            policy_ids: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            headers: None,
+        }];
+
+        let num_retries = NumRetries::Infinity;
+
+        let returned = Arc::new(AtomicBool::new(false));
+        let returned_clone = Arc::clone(&returned);
+        let handle = std::thread::spawn(move || {
+            let _ = fetch_luks_key(
+                &servers,
+                &FetchRequest::new("/test/path"),
+                &RetryPolicy::new(&num_retries),
+                &mock,
+                &NoEvents,
+            );
+            returned_clone.store(true, Ordering::SeqCst);
+        });
+        let start = Instant::now();
+        let timeout = Duration::from_secs(60);
+
+        while start.elapsed() < timeout {
+            thread::sleep(Duration::from_secs(1));
+            if returned.load(Ordering::SeqCst) {
+                panic!("fetch_luks_key returned before 1 minute with infinite retries");
+            }
+        }
+
+        assert!(
+            !returned.load(Ordering::SeqCst),
+            "fetch_luks_key should not have returned after 1 minute with infinite retries"
+        );
+
+        drop(handle);
+    }
+
+    #[test]
+    fn test_fetch_luks_key_events() {
+        struct EventRecorder {
+            events: std::cell::RefCell<Vec<String>>,
+        }
+
+        impl EventHandler for EventRecorder {
+            fn on_attempt_start(&self, attempt: u32, max_attempts: Option<u32>) {
+                self.events
+                    .borrow_mut()
+                    .push(format!("attempt {}/{:?}", attempt, max_attempts));
+            }
+
+            fn on_server_failure(&self, url: &str, error: &str, kind: FailureKind) {
+                self.events
+                    .borrow_mut()
+                    .push(format!("{} failed ({:?}): {}", url, kind, error));
+            }
+        }
+
+        let recorder = EventRecorder {
+            events: std::cell::RefCell::new(Vec::new()),
+        };
+        let executor = RecordingCommandExecutor {
+            calls: std::cell::RefCell::new(Vec::new()),
+        };
+
+        let result = fetch_luks_key(
+            &two_servers(),
+            &FetchRequest::new("/test/path"),
+            &RetryPolicy::new(&NumRetries::Once),
+            &executor,
+            &recorder,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            *recorder.events.borrow(),
+            vec![
+                "attempt 1/Some(1)",
+                "http://server1.example.com failed (Transient): Connection refused",
+                "http://server2.example.com failed (Transient): Connection refused",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch_luks_key_server_policy_ids_override() {
+        struct PolicyRecorder {
+            seen: std::cell::RefCell<Vec<Vec<String>>>,
+        }
+
+        impl KeyFetcher for PolicyRecorder {
+            fn try_fetch_luks_key(
+                &self,
+                _url: &str,
+                _path: &str,
+                _cert: &Cert,
+                _connection: &ConnectionSettings,
+                _initdata: Option<String>,
+                policy_ids: &[String],
+            ) -> Result<String> {
+                self.seen.borrow_mut().push(policy_ids.to_vec());
+                Err(anyhow!("Failed to connect to server"))
+            }
+        }
+
+        let recorder = PolicyRecorder {
+            seen: std::cell::RefCell::new(Vec::new()),
+        };
+        let servers = vec![
+            Server {
+                name: None,
+                url: "http://server1.example.com".to_string(),
+                cert: Cert::None,
+                policy_ids: Some(vec!["strict".to_string()]),
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            },
+            Server {
+                name: None,
+                url: "http://server2.example.com".to_string(),
+                cert: Cert::None,
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            },
+        ];
+
+        let policy_ids = ["default".to_string()];
+        let request = FetchRequest {
+            policy_ids: &policy_ids,
+            ..FetchRequest::new("/test/path")
+        };
+        let result = try_fetch_from_servers(
+            &servers,
+            &request,
+            &RetryPolicy::new(&NumRetries::Once),
+            &recorder,
+            &NoEvents,
+            &mut [ServerState::default(), ServerState::default()],
+        );
+
+        assert!(result.is_none());
+        assert_eq!(
+            *recorder.seen.borrow(),
+            vec![vec!["strict".to_string()], vec!["default".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_attestation_key_handle_none() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            panic!("HTTP client should not be called when attestation_key is None");
+        };
+
+        let result =
+            attestation_key_handle_with_deps(&None, &generator, http_client_factory, &filesystem);
+
+        assert!(result.is_ok());
+        assert!(filesystem.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_attestation_key_handle_empty_registration_url() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            panic!("HTTP client should not be called when registration URL is empty");
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: String::new(),
+                cert: String::new(),
+                uuid: "test-uuid".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_ok());
+        assert!(filesystem.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_attestation_key_handle_successful_registration() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(MockHttpClient {
+                responses: vec![Ok(200)],
+                call_count: std::cell::RefCell::new(0),
+            }))
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(filesystem.written.borrow().len(), 1);
+        assert_eq!(filesystem.written.borrow()[0], AK_REGISTERD);
+    }
+
+    #[test]
+    fn test_attestation_key_handle_registration_fails_with_retries() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(MockHttpClient {
+                responses: vec![
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                    Ok(500),
+                ],
+                call_count: std::cell::RefCell::new(0),
+            }))
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .contains("failed with status: 500")
+        );
+        assert!(filesystem.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_attestation_key_handle_registration_succeeds_after_retries() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(MockHttpClient {
+                responses: vec![Ok(500), Ok(500), Ok(200)],
+                call_count: std::cell::RefCell::new(0),
+            }))
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(filesystem.written.borrow().len(), 1);
+        assert_eq!(filesystem.written.borrow()[0], AK_REGISTERD);
+    }
+
+    #[test]
+    fn test_attestation_key_handle_generation_fails() {
+        let generator = MockAttestationKeyGenerator {
+            response: Err(anyhow!("Failed to generate attestation key")),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            panic!("HTTP client should not be called when key generation fails");
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to generate attestation key"
+        );
+        assert!(filesystem.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_attestation_key_handle_http_client_error() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Ok(()),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(MockHttpClient {
+                responses: vec![
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                    Err(anyhow!("Network error")),
+                ],
+                call_count: std::cell::RefCell::new(0),
+            }))
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("Network error"));
+        assert!(filesystem.written.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_attestation_key_handle_filesystem_write_fails() {
+        let generator = MockAttestationKeyGenerator {
+            response: Ok("mock_attestation_key".to_string()),
+        };
+        let filesystem = MockFileSystem {
+            write_result: Err(anyhow!("Permission denied")),
+            written: std::cell::RefCell::new(Vec::new()),
+        };
+        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
+            Ok(Box::new(MockHttpClient {
+                responses: vec![Ok(200)],
+                call_count: std::cell::RefCell::new(0),
+            }))
+        };
+
+        let attestation_key = Some(AttestationKey {
+            registration: Registration {
+                url: "http://test.example.com/register".to_string(),
+                cert: String::new(),
+                uuid: "test-uuid-123".to_string(),
+            },
+        });
+
+        let result = attestation_key_handle_with_deps(
+            &attestation_key,
+            &generator,
+            http_client_factory,
+            &filesystem,
+        );
+
+        assert!(result.is_err());
+        assert_eq!(result.unwrap_err().to_string(), "Permission denied");
+    }
+
+    #[test]
+    fn test_clevis_header_debug_is_redacted() {
+        let cert = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----",
+            "A".repeat(64)
+        );
+        let header = ClevisHeader {
+            pin: "trustee".to_string(),
+            servers: vec![Server {
+                name: None,
+                url: "https://kbs.example.com".to_string(),
+                cert: Cert::Inline(cert.clone()),
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            }],
+            cert: None,
+            path: "default/key/root".to_string(),
+            initdata: Some("secret = \"value\"".to_string()),
+            num_retries: None,
+            policy_ids: None,
+            backend: None,
+            kbs_protocol_version: None,
+            circuit_breaker: None,
+            backoff: None,
+            retry_on: None,
+            attempt_timeout: None,
+            discovery: None,
+            tls_min_version: None,
+            tls_cipher_suites: None,
+            crl_path: None,
+            user_agent: None,
+            key_format: None,
+            key_pipeline: None,
+            resource_version: None,
+            key_id: None,
+            recipients: None,
+            tpm2_jwe: None,
+        };
+
+        let debug = format!("{:?}", header);
+
+        assert!(!debug.contains(&cert));
+        assert!(debug.contains(&format!("... ({} bytes)", cert.len())));
+        assert!(!debug.contains("secret"));
+        assert!(debug.contains("initdata: Some(<redacted>)"));
+        assert!(debug.contains("https://kbs.example.com"));
+    }
+
+    #[test]
+    fn test_parse_config_strict() {
+        let config = r#"{
+            "servers": [{"url": "http://kbs:8080", "cert": "", "certt": ""}],
+            "path": "default/key/root",
+            "num_retrys": 3
+        }"#;
+
+        let config: serde_json::Value = serde_json::from_str(config).unwrap();
+        let lenient = parse_config(config.clone(), false).unwrap();
+        let strict = parse_config(config, true);
+
+        assert_eq!(lenient.servers.len(), 1);
+        assert!(lenient.num_retries.is_none());
+        assert_eq!(
+            strict.unwrap_err().to_string(),
+            "Unknown fields in config: servers.0.certt, num_retrys"
+        );
+    }
+
+    #[test]
+    fn test_load_config_merges_dropins() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("20-retries.json"),
+            r#"{"num_retries": 5, "path": "override/key/root"}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("10-servers.json"),
+            r#"{"servers": [{"url": "http://kbs2:8080", "cert": ""}], "num_retries": 2}"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("README"), "not a fragment").unwrap();
+
+        let config = load_config(
+            r#"{"servers": [{"url": "http://kbs1:8080", "cert": ""}], "path": "default/key/root"}"#,
+            dir.path(),
+            ConfigOptions {
+                strict: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let urls: Vec<&str> = config.servers.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, vec!["http://kbs1:8080", "http://kbs2:8080"]);
+        assert_eq!(config.path, "override/key/root");
+        assert_eq!(config.num_retries, Some(NumRetries::Finite(5)));
+    }
+
+    #[test]
+    fn test_load_config_without_dropin_dir() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = load_config(
+            r#"{"servers": [], "path": "default/key/root"}"#,
+            &dir.path().join("missing"),
+            ConfigOptions::default(),
+        )
+        .unwrap();
+
+        assert!(config.servers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_config_text_formats() {
+        let json = r#"{"servers": [{"url": "http://kbs:8080"}], "path": "default/key/root"}"#;
+        let yaml = "servers:\n  - url: http://kbs:8080\npath: default/key/root\n";
+        let toml = "path = \"default/key/root\"\n\n[[servers]]\nurl = \"http://kbs:8080\"\n";
+        let expected: serde_json::Value = serde_json::from_str(json).unwrap();
+
+        for (text, format) in [
+            (json, ConfigFormat::Json),
+            (yaml, ConfigFormat::Yaml),
+            (toml, ConfigFormat::Toml),
+        ] {
+            assert_eq!(parse_config_text(text, format).unwrap(), expected);
+            assert_eq!(
+                parse_config_text(text, ConfigFormat::Auto).unwrap(),
+                expected
+            );
+        }
+
+        let error = parse_config_text(yaml, ConfigFormat::Json).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to parse config JSON"));
+        let error = parse_config_text("{\"servers\": [", ConfigFormat::Auto).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to parse config JSON"));
+    }
+
+    #[test]
+    fn test_load_config_yaml_with_initdata() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("10-retries.toml"), "num_retries = 4\n").unwrap();
+        let config = "servers: []\n\
+            path: default/key/root\n\
+            initdata:\n  \
+              policy: |\n    \
+                package policy\n    \
+                default allow := true\n";
+
+        let config = load_config(
+            config,
+            dir.path(),
+            ConfigOptions {
+                strict: true,
+                format: ConfigFormat::Yaml,
+                signature: None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(config.num_retries, Some(NumRetries::Finite(4)));
+        let initdata: serde_json::Value =
+            serde_json::from_str(config.initdata.as_deref().unwrap()).unwrap();
+        assert_eq!(
+            initdata["policy"],
+            "package policy\ndefault allow := true\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_initdata_merges_fragments() {
+        let base = r#"{"aa": {"url": "http://kbs:8080", "retries": 3}, "policy": "base"}"#;
+        let document = "version = \"0.1.0\"\nalgorithm = \"sha384\"\n\n\
+            [data.aa]\nretries = 5\n";
+        let mut config = serde_json::json!({
+            "initdata": [base, document, {"cdh": {"socket": "/run/cdh.sock"}}, "policy = \"host\""],
+        });
+
+        normalize_initdata(&mut config).unwrap();
+
+        let initdata: serde_json::Value =
+            serde_json::from_str(config["initdata"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            initdata,
+            serde_json::json!({
+                "aa": {"url": "http://kbs:8080", "retries": 5},
+                "cdh": {"socket": "/run/cdh.sock"},
+                "policy": "host",
+            })
+        );
+
+        // The merged document does not depend on the order of the keys
+        let reordered = r#"[{"policy": "host", "cdh": {"socket": "/run/cdh.sock"}},
+            {"aa": {"retries": 5, "url": "http://kbs:8080"}}]"#;
+        assert_eq!(
+            initdata_digest(reordered, None).unwrap(),
+            initdata_digest(&initdata.to_string(), None).unwrap()
+        );
+
+        let mut invalid = serde_json::json!({"initdata": [{"a": "b"}, 3]});
+        assert!(
+            format!("{:#}", normalize_initdata(&mut invalid).unwrap_err())
+                .starts_with("initdata[1]: Invalid initdata fragment")
+        );
+    }
+
+    #[test]
+    fn test_bind_to_key_records_key_id() {
+        let key = general_purpose::STANDARD.encode(r#"{"key_type": "oct", "key": "c2VjcmV0"}"#);
+        let rotated = general_purpose::STANDARD.encode(r#"{"key_type": "oct", "key": "bmV3"}"#);
+        let header = ClevisHeader::from_claim(serde_json::json!({
+            "pin": "trustee",
+            "servers": [],
+            "path": "default/key/root",
+            "initdata": null,
+        }))
+        .unwrap();
+
+        let (hdr, _) = bind_to_key(header, &key).unwrap();
+
+        let claims = hdr.claims_set();
+        assert_eq!(claims["clevis"]["key_id"], key_id(&key));
+        assert_eq!(key_id(&key).len(), 2 * KEY_ID_BYTES);
+        assert_ne!(key_id(&key), key_id(&rotated));
+    }
+
+    fn jwe_bound_to(key: &str) -> String {
+        let header = ClevisHeader::from_claim(serde_json::json!({
+            "pin": "trustee",
+            "servers": ["http://kbs:8080"],
+            "path": "default/key/root",
+            "initdata": null,
+            "num_retries": "none",
+        }))
+        .unwrap();
+        let (hdr, encrypter) = bind_to_key(header, key).unwrap();
+        serialize_jwe(b"payload", &hdr, &encrypter).unwrap()
+    }
+
+    #[test]
+    fn test_decrypt_mock_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let resource = "0123456789abcdef0123456789abcdef";
+        fs::create_dir_all(dir.path().join("default/key")).unwrap();
+        fs::write(dir.path().join("default/key/root"), resource).unwrap();
+        let jwe = jwe_bound_to(&general_purpose::STANDARD.encode(resource));
+
+        let runtime = RuntimeConfig {
+            backend: Some(Backend::Mock),
+            mock_resources: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        assert_eq!(decrypt(&jwe, &runtime, &NoEvents).unwrap(), b"payload");
+
+        let fetcher = Rc::new(MockCommandExecutor {
+            response: Ok(general_purpose::STANDARD.encode(resource)),
+        });
+        let plaintext =
+            decrypt_with_fetcher(&jwe, &RuntimeConfig::default(), fetcher, &NoEvents).unwrap();
+        assert_eq!(plaintext, b"payload");
+
+        // A binding cannot skip attestation with the mock backend
+        let config = serde_json::json!({
+            "servers": ["http://kbs:8080"],
+            "path": "default/key/root",
+            "backend": "mock",
+        });
+        assert!(parse_config(config.clone(), true).is_err());
+        let mut claim = config;
+        claim["pin"] = "trustee".into();
+        claim["initdata"] = serde_json::Value::Null;
+        assert!(ClevisHeader::from_claim(claim).is_err());
+    }
+
+    #[test]
+    fn test_random_key() {
+        let key = random_key(&OsRandom);
+
+        let jwk = prepare_jwk(&key, KeyEncoding::DERIVED).unwrap();
+        assert_eq!(jwk.key_type(), "oct");
+        assert_eq!(jwk.key_value().unwrap().len(), 32);
+        assert_ne!(random_key(&OsRandom), key);
+
+        // Derived keys of the bindings made before they were JWKs
+        let secret = "0123456789abcdef0123456789abcdef";
+        let legacy = general_purpose::STANDARD
+            .encode(format!(r#"{{"key_type": "oct", "key": "{}"}}"#, secret));
+        let jwk = prepare_jwk(&legacy, KeyEncoding::DERIVED).unwrap();
+        assert_eq!(jwk.key_value().unwrap(), secret.as_bytes());
+    }
+
+    #[test]
+    fn test_update_binding() {
+        let dir = tempfile::tempdir().unwrap();
+        let resource = "0123456789abcdef0123456789abcdef";
+        fs::create_dir_all(dir.path().join("site/key")).unwrap();
+        fs::write(dir.path().join("site/key/root"), resource).unwrap();
+        fs::write(dir.path().join("site/key/other"), resource.to_uppercase()).unwrap();
+        let jwe = jwe_bound_to(&general_purpose::STANDARD.encode(resource));
+        let runtime = RuntimeConfig {
+            backend: Some(Backend::Mock),
+            mock_resources: Some(dir.path().to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let update = BindingUpdate {
+            servers: Some(vec![
+                serde_json::from_value(serde_json::json!("https://kbs.new.example")).unwrap(),
+            ]),
+            cert: None,
+            path: Some("site/key/root".to_string()),
+        };
+
+        let updated = update_binding(&jwe, &update, &runtime, &NoEvents).unwrap();
+
+        let header = ClevisHeader::from_compact_jwe(&updated).unwrap();
+        assert_eq!(header.servers[0].url, "https://kbs.new.example");
+        assert_eq!(header.path, "site/key/root");
+        assert_eq!(decrypt(&updated, &runtime, &NoEvents).unwrap(), b"payload");
+
+        let other = BindingUpdate {
+            path: Some("site/key/other".to_string()),
+            ..BindingUpdate::default()
+        };
+        assert!(update_binding(&jwe, &other, &runtime, &NoEvents).is_err());
+        assert!(update_binding(&jwe, &BindingUpdate::default(), &runtime, &NoEvents).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_override_url() {
+        let jwe = jwe_bound_to(&general_purpose::STANDARD.encode([0u8; KEY_BYTES]));
+        let fetcher = Rc::new(RecordingCommandExecutor {
+            calls: RefCell::new(Vec::new()),
+        });
+        let runtime = RuntimeConfig {
+            override_url: Some("https://moved-kbs:8443".to_string()),
+            ..Default::default()
+        };
+
+        assert!(decrypt_with_fetcher(&jwe, &runtime, fetcher.clone(), &NoEvents).is_err());
+        assert_eq!(*fetcher.calls.borrow(), ["https://moved-kbs:8443"]);
+    }
+
+    #[test]
+    fn test_encrypt_with_local_key() {
+        let config = r#"{"servers": ["http://kbs:8080"], "path": "default/key/root"}"#;
+        let key = b"0123456789abcdef0123456789abcdef";
+
+        let jwe =
+            encrypt_with_local_key(config, ConfigOptions::default(), key, b"payload").unwrap();
+
+        let fetcher = Rc::new(MockCommandExecutor {
+            response: Ok(general_purpose::STANDARD.encode(key)),
+        });
+        let plaintext =
+            decrypt_with_fetcher(&jwe, &RuntimeConfig::default(), fetcher, &NoEvents).unwrap();
+        assert_eq!(plaintext, b"payload");
+        assert!(encrypt_with_local_key(config, ConfigOptions::default(), b"", b"payload").is_err());
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let schema = config_schema();
+
+        assert_eq!(schema["required"], serde_json::json!(["servers", "path"]));
+        assert_eq!(
+            schema["properties"]["servers"]["items"]["$ref"],
+            "#/$defs/Server"
+        );
+        assert_eq!(schema["$defs"]["Server"]["anyOf"][0]["type"], "string");
+        assert!(header_schema()["properties"]["key_id"].is_object());
+    }
+
+    #[test]
+    fn test_bare_string_servers() {
+        let config = serde_json::json!({
+            "servers": ["https://kbs1", {"url": "https://kbs2", "name": "backup"}],
+            "path": "default/key/root",
+            "policy_ids": ["default"],
+        });
+
+        let config = parse_config(config, true).unwrap();
+
+        let urls: Vec<&str> = config.servers.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, vec!["https://kbs1", "https://kbs2"]);
+        assert_eq!(config.servers[0].cert, Cert::None);
+        assert_eq!(config.servers[1].name.as_deref(), Some("backup"));
+        assert!(
+            parse_config(
+                serde_json::json!({"servers": [42], "path": "default/key/root"}),
+                false
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_default_cert() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "servers": [
+                {"url": "https://kbs1"},
+                {"url": "https://kbs2", "cert": {"path": "/etc/pki/kbs2.pem"}},
+            ],
+            "cert": {"path": "/etc/pki/ca.pem"},
+            "path": "default/key/root",
+        }))
+        .unwrap();
+        let header = ClevisHeader::new(config, None);
+
+        let servers = Server::with_default_cert(&header.servers, header.cert.as_ref());
+
+        let certs: Vec<&Cert> = servers.iter().map(|s| &s.cert).collect();
+        assert_eq!(
+            certs,
+            vec![
+                &Cert::Path("/etc/pki/ca.pem".to_string()),
+                &Cert::Path("/etc/pki/kbs2.pem".to_string()),
+            ]
+        );
+        // Stored once, not in every server
+        assert_eq!(header.servers[0].cert, Cert::None);
+    }
+
+    #[test]
+    fn test_server_selection() {
+        let servers: Vec<Server> = serde_json::from_value(serde_json::json!([
+            {"url": "http://kbs1", "name": "prod-kbs-1"},
+            {"url": "http://kbs2", "name": "prod-kbs-2"},
+            {"url": "http://backup"},
+        ]))
+        .unwrap();
+        let urls = |selection: ServerSelection| {
+            selection
+                .select(servers.clone())
+                .map(|servers| servers.into_iter().map(|s| s.url).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            urls(ServerSelection {
+                only: vec!["prod-kbs-2".to_string(), "http://backup".to_string()],
+                skip: vec![],
+            })
+            .unwrap(),
+            vec!["http://kbs2", "http://backup"]
+        );
+        assert_eq!(
+            urls(ServerSelection {
+                only: vec![],
+                skip: vec!["prod-kbs-1".to_string()],
+            })
+            .unwrap(),
+            vec!["http://kbs2", "http://backup"]
+        );
+        assert!(
+            urls(ServerSelection {
+                only: vec!["prod-kbs-1".to_string()],
+                skip: vec!["http://kbs1".to_string()],
+            })
+            .is_err()
+        );
+        let typo = ServerSelection {
+            only: vec!["prod-kbs-1".to_string()],
+            skip: vec!["prod-kbs-3".to_string()],
+        };
+        assert_eq!(typo.unmatched(&servers), vec!["prod-kbs-3"]);
+        assert!(ServerSelection::default().unmatched(&servers).is_empty());
+    }
+
+    #[test]
+    fn test_multiple_recipients() {
+        let mut header = ClevisHeader::from_claim(serde_json::json!({
+            "pin": "trustee",
+            "servers": [{"url": "http://kbs1"}],
+            "path": "default/key/a",
+            "initdata": null,
+        }))
+        .unwrap();
+        let recipients: Vec<Recipient> = serde_json::from_str(
+            r#"[{"path": "default/key/b"}, {"servers": [{"url": "http://kbs2"}]}]"#,
+        )
+        .unwrap();
+        let resource_key = |header: &ClevisHeader| {
+            let key = format!(
+                "{:<32}",
+                format!("{}{}", header.servers[0].url, header.path)
+            );
+            general_purpose::STANDARD.encode(format!(r#"{{"key_type": "oct", "key": "{}"}}"#, key))
+        };
+
+        let key = encrypt_to_recipients(&mut header, &recipients, &OsRandom, |header| {
+            Ok(resource_key(header))
+        })
+        .unwrap();
+        let (hdr, encrypter) = bind_to_key(header, &key).unwrap();
+        let jwe = serialize_jwe(b"payload", &hdr, &encrypter).unwrap();
+
+        let header = ClevisHeader::from_compact_jwe(&jwe).unwrap();
+        let jwes = header.recipients.unwrap();
+        let bound: Vec<(String, String)> = jwes
+            .iter()
+            .map(|jwe| {
+                let header = ClevisHeader::from_compact_jwe(jwe).unwrap();
+                (header.servers[0].url.clone(), header.path)
+            })
+            .collect();
+        assert_eq!(
+            bound,
+            vec![
+                ("http://kbs1".to_string(), "default/key/a".to_string()),
+                ("http://kbs1".to_string(), "default/key/b".to_string()),
+                ("http://kbs2".to_string(), "default/key/a".to_string()),
+            ]
+        );
+
+        // Only the last recipient is reachable
+        let key = decrypt_any_recipient(&jwes, |jwe| {
+            let header = ClevisHeader::from_compact_jwe(jwe)?;
+            if header.servers[0].url != "http://kbs2" {
+                return Err(anyhow!("Connection refused"));
+            }
+            decrypt_with_key(jwe, &resource_key(&header), KeyEncoding::default())
+        })
+        .unwrap();
+        assert_eq!(
+            decrypt_with_key(&jwe, &key, KeyEncoding::DERIVED).unwrap(),
+            b"payload"
+        );
+
+        let error = decrypt_any_recipient(&jwes, |_| Err(anyhow!("Connection refused")));
+        assert_eq!(
+            format!("{:#}", error.unwrap_err()),
+            "None of the 3 recipients could be decrypted: Connection refused"
+        );
+    }
+
+    #[test]
+    fn test_self_test_without_servers() {
+        let error = self_test(
+            r#"{"servers": [], "path": "default/key/root"}"#,
+            ConfigOptions::default(),
+            &RuntimeConfig::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(error.to_string(), "Self-test encryption failed");
+        assert_eq!(error.root_cause().to_string(), "No URLs provided");
+    }
+
+    #[cfg(feature = "subprocess-backend")]
+    #[test]
+    fn test_output_with_timeout() {
+        let start = std::time::Instant::now();
+        let output = output_with_timeout(
+            StdCommand::new("sleep").arg("10"),
+            Some(Duration::from_millis(100)),
+        )
+        .unwrap();
+        assert!(output.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let output = output_with_timeout(
+            StdCommand::new("echo").arg("key"),
+            Some(Duration::from_secs(10)),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"key\n");
+    }
+
+    #[test]
+    fn test_read_runtime_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("runtime.json");
+        fs::write(
+            &path,
+            r#"{
+                "servers": [{"url": "http://kbs-local:8080"}],
+                "num_retries": "infinity",
+                "retry_delay": "30s",
+                "attester_path": "/usr/libexec/trustee-attester"
+            }"#,
+        )
+        .unwrap();
+
+        let runtime = read_runtime_config(&path).unwrap();
+
+        assert_eq!(
+            runtime.servers.as_ref().unwrap()[0].url,
+            "http://kbs-local:8080"
+        );
+        assert_eq!(runtime.num_retries, Some(NumRetries::Infinity));
+        assert_eq!(
+            runtime.retry_delay().unwrap(),
+            Some(Duration::from_secs(30))
+        );
+        assert!(runtime.backend.is_none());
+
+        fs::write(&path, r#"{"retry_delay": "30 seconds"}"#).unwrap();
+        assert!(
+            read_runtime_config(&path)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Invalid retry_delay")
+        );
+
+        fs::write(&path, r#"{"path": "other/key/root"}"#).unwrap();
+        assert!(
+            read_runtime_config(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("unknown field `path`")
+        );
+    }
+
+    #[test]
+    fn test_server_cert_forms() {
+        let servers: Vec<Server> = serde_json::from_str(
+            r#"[
+                {"url": "a", "cert": ""},
+                {"url": "b", "cert": "-----BEGIN CERTIFICATE-----"},
+                {"url": "c", "cert": {"inline": "-----BEGIN CERTIFICATE-----"}},
+                {"url": "d", "cert": {"path": "/etc/pki/kbs.pem"}},
+                {"url": "e", "cert": null},
+                {"url": "f"}
+            ]"#,
+        )
+        .unwrap();
+
+        let certs: Vec<&Cert> = servers.iter().map(|s| &s.cert).collect();
+        let pem = Cert::Inline("-----BEGIN CERTIFICATE-----".to_string());
+        let path = Cert::Path("/etc/pki/kbs.pem".to_string());
+        assert_eq!(
+            certs,
+            vec![&Cert::None, &pem, &pem, &path, &Cert::None, &Cert::None]
+        );
+        assert_eq!(
+            serde_json::to_value(&servers[1..4]).unwrap(),
+            serde_json::json!([
+                {"url": "b", "cert": "-----BEGIN CERTIFICATE-----"},
+                {"url": "c", "cert": "-----BEGIN CERTIFICATE-----"},
+                {"url": "d", "cert": {"path": "/etc/pki/kbs.pem"}}
+            ])
+        );
+        assert_eq!(serde_json::to_value(&servers[0]).unwrap()["cert"], "");
+    }
+
+    #[test]
+    fn test_initdata_digest() {
+        let json = r#"{"aa.toml": "[token_configs]"}"#;
+        let toml = initdata_toml(json).unwrap();
+        let expected = hex::encode(Sha256::digest(toml.as_bytes()));
+
+        assert_eq!(initdata_digest(json, None).unwrap(), expected);
+        assert_eq!(initdata_digest(&toml, None).unwrap(), expected);
+        assert_eq!(
+            initdata_digest(json, Some(InitdataField::Tdx)).unwrap(),
+            format!("{}{}", expected, "0".repeat(32))
+        );
+        assert_eq!(
+            initdata_digest(json, Some(InitdataField::Snp)).unwrap(),
+            expected
+        );
+
+        let sha512 = toml.replace("sha256", "sha512");
+        assert_eq!(initdata_digest(&sha512, None).unwrap().len(), 128);
+        assert!(initdata_digest(&sha512, Some(InitdataField::Tdx)).is_err());
+    }
+
+    #[test]
+    fn test_initdata_toml_nested() {
+        let toml = initdata_toml(
+            r#"{"policy.rego": "package policy", "aa": {"url": "http://kbs:8080", "retries": 3}}"#,
+        )
+        .unwrap();
+
+        let initdata: Initdata = toml::from_str(&toml).unwrap();
+        assert_eq!(
+            initdata.data["policy.rego"].as_str(),
+            Some("package policy")
+        );
+        let aa = initdata.data["aa"].as_table().unwrap();
+        assert_eq!(aa["url"].as_str(), Some("http://kbs:8080"));
+        assert_eq!(aa["retries"].as_integer(), Some(3));
+    }
+
+    #[test]
+    fn test_server_connection_headers() {
+        let server: Server = serde_json::from_value(serde_json::json!({
+            "url": "https://gateway.example",
+            "headers": {"X-Tenant-Id": "tenant-a", "User-Agent": "fleet-a"},
+        }))
+        .unwrap();
+        let header = ClevisHeader {
+            user_agent: Some("clevis-trustee/1".to_string()),
+            ..ClevisHeader::new(
+                serde_json::from_str(r#"{"servers": [], "path": "a/b/c"}"#).unwrap(),
+                None,
+            )
+        };
+
+        let connection = server.connection(&header.connection());
+
+        assert_eq!(connection.headers["X-Tenant-Id"], "tenant-a");
+        assert_eq!(connection.headers["User-Agent"], "fleet-a");
+        assert_eq!(header_map(&connection.headers).unwrap().len(), 2);
+        assert!(!format!("{:?}", server).contains("tenant-a"));
+
+        let invalid = BTreeMap::from([("X Tenant".to_string(), "a".to_string())]);
+        assert!(
+            header_map(&invalid)
+                .unwrap_err()
+                .to_string()
+                .starts_with("Invalid request header X Tenant")
+        );
+    }
+
+    #[test]
+    fn test_clevis_header_from_compact_jwe() {
+        let config: Config = serde_json::from_str(
+            r#"{"servers": [{"url": "http://kbs:8080", "cert": ""}], "path": "default/key/root"}"#,
+        )
+        .unwrap();
+        let claim = ClevisHeader::new(config, None).to_claim().unwrap();
+        let protected = serde_json::json!({"alg": "dir", "enc": "A256GCM", "clevis": claim});
+        let jwe = format!(
+            "{}..iv.ciphertext.tag",
+            general_purpose::URL_SAFE_NO_PAD.encode(protected.to_string())
+        );
+
+        let header = ClevisHeader::from_compact_jwe(&jwe).unwrap();
+
+        assert_eq!(header.pin, "trustee");
+        assert_eq!(header.servers[0].url, "http://kbs:8080");
+        assert_eq!(header.path, "default/key/root");
+
+        let other_pin = general_purpose::URL_SAFE_NO_PAD.encode(claim_with_pin("tang"));
+        assert_eq!(
+            ClevisHeader::from_compact_jwe(&other_pin)
+                .unwrap_err()
+                .to_string(),
+            "JWE was bound with the tang pin, not trustee"
+        );
+        assert!(ClevisHeader::from_compact_jwe("not-base64!.a.b.c.d").is_err());
+    }
+
+    #[test]
+    fn test_prepare_jwk() {
+        let encode = |key: &[u8]| general_purpose::STANDARD.encode(key);
+        let secret = "0123456789abcdef0123456789abcdef";
+
+        let envelope = prepare_jwk(
+            &encode(format!(r#"{{"key_type": "oct", "key": "{}"}}"#, secret).as_bytes()),
+            KeyEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(envelope.key_value().unwrap(), secret.as_bytes());
+
+        let k = general_purpose::URL_SAFE_NO_PAD.encode(secret);
+        let jwk = prepare_jwk(
+            &encode(format!(r#"{{"kty": "oct", "k": "{}", "alg": "A256GCM"}}"#, k).as_bytes()),
+            KeyEncoding::default(),
+        )
+        .unwrap();
+        assert_eq!(jwk.key_value().unwrap(), secret.as_bytes());
+        assert_eq!(jwk.algorithm(), Some("A256GCM"));
+        // Used as it is: no key operations are added
+        assert!(jwk.parameter("key_ops").is_none());
+
+        let error = prepare_jwk(
+            &encode(br#"{"kty": "EC", "crv": "P-256"}"#),
+            KeyEncoding::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The fetched JWK is of type EC, not a symmetric oct key"
+        );
+        assert!(prepare_jwk(&encode(br#"{"key": "no type"}"#), KeyEncoding::default()).is_err());
+    }
+
+    #[test]
+    fn test_key_formats() {
+        let key = [7u8; KEY_BYTES];
+        let base64 = format!("{}\n", general_purpose::STANDARD.encode(key));
+        let hex = hex::encode(key);
+
+        for (resource, format) in [
+            (base64.as_bytes(), KeyFormat::Base64),
+            (hex.as_bytes(), KeyFormat::Hex),
+            (&key[..], KeyFormat::Raw),
+        ] {
+            assert_eq!(detect_key_format(resource), format);
+            let resource = general_purpose::STANDARD.encode(resource);
+            assert_eq!(
+                prepare_jwk(&resource, KeyEncoding::default())
+                    .unwrap()
+                    .key_value()
+                    .unwrap(),
+                key
+            );
+            assert_eq!(
+                prepare_jwk(
+                    &resource,
+                    KeyEncoding {
+                        format: Some(format),
+                        ..Default::default()
+                    }
+                )
+                .unwrap()
+                .key_value()
+                .unwrap(),
+                key
+            );
+        }
+
+        // An explicit format disables the guessing
+        let hex = general_purpose::STANDARD.encode(&hex);
+        assert_eq!(
+            prepare_jwk(
+                &hex,
+                KeyEncoding {
+                    format: Some(KeyFormat::Raw),
+                    ..Default::default()
+                }
+            )
+            .unwrap_err()
+            .to_string(),
+            "The fetched key is 64 bytes long, A256GCM needs 32"
+        );
+        assert!(
+            prepare_jwk(
+                &hex,
+                KeyEncoding {
+                    format: Some(KeyFormat::Key),
+                    ..Default::default()
+                }
+            )
+            .is_err()
+        );
+        assert!(
+            prepare_jwk(
+                &general_purpose::STANDARD.encode("short"),
+                KeyEncoding::default()
+            )
+            .is_err()
+        );
+
+        // The pipeline runs before the format is guessed
+        let pipeline: Vec<KeyTransform> =
+            serde_json::from_str(r#"["base64-decode", "hkdf:info=luks"]"#).unwrap();
+        let encoding = KeyEncoding {
+            pipeline: &pipeline,
+            ..Default::default()
+        };
+        let jwk = prepare_jwk(
+            &general_purpose::STANDARD.encode(general_purpose::STANDARD.encode("site secret")),
+            encoding,
+        )
+        .unwrap();
+        assert_eq!(jwk.key_value().unwrap().len(), KEY_BYTES);
+    }
+
+    #[test]
+    fn test_decrypt_input_limits() {
+        let jwe = |clevis: serde_json::Value| {
+            let protected = serde_json::json!({"alg": "dir", "clevis": clevis});
+            format!(
+                "{}..iv.ciphertext.tag",
+                general_purpose::URL_SAFE_NO_PAD.encode(protected.to_string())
+            )
+        };
+        let error = |input: &str, limits: InputLimits| {
+            let runtime = RuntimeConfig {
+                input_limits: Some(limits),
+                ..Default::default()
+            };
+            decrypt(input, &runtime, &NoEvents).unwrap_err().to_string()
+        };
+        let limits = InputLimits {
+            max_jwe_size: 1024,
+            max_header_size: 512,
+            max_header_depth: 4,
+            max_claim_size: 64,
+        };
+
+        assert_eq!(
+            error(&"a".repeat(1025), limits.clone()),
+            "Invalid JWE: 1025 bytes exceed the limit of 1024"
+        );
+        let large = jwe(serde_json::json!({"pin": "trustee", "path": "a".repeat(600)}));
+        assert_eq!(
+            error(&large, limits.clone()),
+            "Invalid JWE: the protected header exceeds 512 bytes"
+        );
+        let long_claim = jwe(serde_json::json!({"pin": "trustee", "path": "a".repeat(65)}));
+        assert_eq!(
+            error(&long_claim, limits.clone()),
+            "Invalid JWE: a value of the clevis claim exceeds 64 bytes"
+        );
+        let deep = jwe(serde_json::json!({"pin": "trustee", "servers": [[[["x"]]]]}));
+        assert_eq!(
+            error(&deep, limits),
+            "Invalid JWE: the clevis claim is nested deeper than 4 levels"
+        );
+    }
+
+    fn claim_with_pin(pin: &str) -> String {
+        serde_json::json!({
+            "alg": "dir",
+            "clevis": {"pin": pin, "servers": [], "path": "a", "initdata": null}
+        })
+        .to_string()
+    }
+
+    fn mock_identity() -> MockMachineIdentity {
+        MockMachineIdentity {
+            machine_id: Ok("4c4c4544004d3510".to_string()),
+            hostname: Ok("node-1".to_string()),
+            system_uuid: Err(anyhow!("Failed to read product_uuid")),
+        }
+    }
+
+    #[test]
+    fn test_expand_path_template_without_placeholders() {
+        let result = expand_path_template("conf-cluster/12345/root", &mock_identity());
+
+        assert_eq!(result.unwrap(), "conf-cluster/12345/root");
+    }
+
+    #[test]
+    fn test_normalize_resource_path() {
+        assert_eq!(
+            normalize_resource_path("kbs:///default/luks-keys/vm1").unwrap(),
+            "default/luks-keys/vm1"
+        );
+        assert_eq!(
+            normalize_resource_path("kbs:///fleet/{hostname}/root").unwrap(),
+            "fleet/{hostname}/root"
+        );
+        assert_eq!(
+            normalize_resource_path("default/key/root").unwrap(),
+            "default/key/root"
+        );
+        assert!(normalize_resource_path("kbs:///default/key").is_err());
+        assert!(normalize_resource_path("kbs:///default//root").is_err());
+        let error = normalize_resource_path("kbs://kbs:8080/default/key/root").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Resource URI kbs://kbs:8080/default/key/root names the KBS kbs:8080, \
+             list it in servers and use kbs:///default/key/root instead"
+        );
+    }
+
+    #[test]
+    fn test_versioned_path() {
+        assert_eq!(
+            versioned_path("default/key/root", None).unwrap(),
+            "default/key/root"
+        );
+        assert_eq!(
+            versioned_path("default/key/root", Some("v2.1")).unwrap(),
+            "default/key/root?version=v2.1"
+        );
+        assert!(versioned_path("default/key/root", Some("")).is_err());
+        let error = versioned_path("default/key/root", Some("1&tag=x")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid resource_version \"1&tag=x\", only letters, digits, '.', '_' and '-' are allowed"
+        );
+
+        // Recorded in the header of the binding
+        let (config, initdata) = read_config(
+            r#"{"servers": [], "path": "default/key/root", "resource_version": "3"}"#,
+            ConfigOptions::default(),
+        )
+        .unwrap();
+        let claim = serde_json::to_value(ClevisHeader::new(config, initdata)).unwrap();
+        assert_eq!(claim["resource_version"], "3");
+        assert!(
+            read_config(
+                r#"{"servers": [], "path": "default/key/root", "resource_version": "../x"}"#,
+                ConfigOptions::default(),
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_expand_path_template_placeholders() {
+        let result = expand_path_template("fleet/{hostname}/key-{machine-id}", &mock_identity());
+
+        assert_eq!(result.unwrap(), "fleet/node-1/key-4c4c4544004d3510");
+    }
+
+    #[test]
+    fn test_expand_path_template_lookup_fails() {
+        let result = expand_path_template("fleet/keys/{uuid}", &mock_identity());
+
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Failed to read product_uuid"
+        );
+    }
+
+    #[test]
+    fn test_expand_path_template_invalid() {
+        let identity = mock_identity();
+
+        let result = expand_path_template("fleet/{serial}/root", &identity);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Unknown placeholder {serial} in path"
+        );
+
+        let result = expand_path_template("fleet/{hostname/root", &identity);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Unterminated placeholder in path: fleet/{hostname/root"
+        );
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Progress messages of the key fetch, written to stderr or, with structured
+//! fields, to the systemd journal.
+
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Socket of the native journal protocol
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_IDENTIFIER: &str = "clevis-pin-trustee";
+
+static JOURNALD: AtomicBool = AtomicBool::new(false);
+static TRACE_HTTP: AtomicBool = AtomicBool::new(false);
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Where progress messages are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogTarget {
+    #[default]
+    Stderr,
+    Journald,
+}
+
+pub fn set_log_target(target: LogTarget) {
+    JOURNALD.store(target == LogTarget::Journald, Ordering::Relaxed);
+}
+
+/// Log the HTTP requests to the KBS and their responses, with the
+/// credentials redacted, e.g. to see what a reverse proxy changes
+pub fn set_trace_http(trace: bool) {
+    TRACE_HTTP.store(trace, Ordering::Relaxed);
+}
+
+/// Log the messages of debug priority, dropped otherwise
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::Relaxed);
+}
+
+#[cfg(any(feature = "native-kbs", feature = "aa-backend"))]
+pub(crate) fn trace_http() -> bool {
+    TRACE_HTTP.load(Ordering::Relaxed)
+}
+
+/// syslog priority of a message
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Priority {
+    Warning = 4,
+    Info = 6,
+    Debug = 7,
+}
+
+/// Serialize an entry with the native journal protocol. Values containing a
+/// newline use the length-prefixed binary form.
+fn journal_entry(message: &str, priority: Priority, fields: &[(&str, &str)]) -> Vec<u8> {
+    let priority = (priority as u8).to_string();
+    let common = [
+        ("MESSAGE", message),
+        ("PRIORITY", priority.as_str()),
+        ("SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER),
+    ];
+    let mut entry = Vec::new();
+    for (key, value) in common.iter().chain(fields) {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+    entry
+}
+
+fn send_to_journal(entry: &[u8]) -> std::io::Result<()> {
+    UnixDatagram::unbound()?.send_to(entry, JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+/// Log `message` with the structured `fields`, e.g. `SERVER_URL`. The fields
+/// only reach the journal; on stderr the message alone is printed.
+pub(crate) fn log(priority: Priority, message: &str, fields: &[(&str, &str)]) {
+    if matches!(priority, Priority::Debug) && !DEBUG.load(Ordering::Relaxed) {
+        return;
+    }
+    if JOURNALD.load(Ordering::Relaxed) {
+        match send_to_journal(&journal_entry(message, priority, fields)) {
+            Ok(()) => return,
+            // The journal may not be running yet, early in the initramfs
+            Err(e) => eprintln!("Failed to log to the journal: {}", e),
+        }
+    }
+    eprintln!("{}", message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_journal_entry() {
+        let entry = journal_entry(
+            "Error with URL http://kbs1",
+            Priority::Warning,
+            &[("SERVER_URL", "http://kbs1"), ("ERROR", "line 1\nline 2")],
+        );
+
+        let mut expected = b"MESSAGE=Error with URL http://kbs1\n\
+            PRIORITY=4\n\
+            SYSLOG_IDENTIFIER=clevis-pin-trustee\n\
+            SERVER_URL=http://kbs1\n\
+            ERROR\n"
+            .to_vec();
+        expected.extend_from_slice(&13u64.to_le_bytes());
+        expected.extend_from_slice(b"line 1\nline 2\n");
+        assert_eq!(entry, expected);
+    }
+}
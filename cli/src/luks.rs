@@ -0,0 +1,1780 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Binding LUKS2 devices to the trustee pin, compatible with `clevis luks`:
+//! the JWE is stored in a `clevis` token of the LUKS2 header, next to the
+//! keyslot of the passphrase it protects.
+
+use crate::passphrase::{self, PassphraseOptions};
+use crate::{
+    BindingUpdate, ConfigOptions, ExecutorCache, decrypt, decrypt_with, encrypt, endpoint, tpm2,
+};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::{
+    ClevisHeader, EventHandler, Initdata, NoEvents, RuntimeConfig, TrusteePinError,
+};
+use serde_json::{Value, json};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command as StdCommand, Stdio};
+use std::time::Duration;
+
+/// How long `status` waits for a server to accept a connection
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(3);
+/// Directory for the new passphrase while cryptsetup adds it
+const KEY_DIR: &str = "/run/trustee";
+/// Where cryptsetup creates the mappings of opened devices
+const MAPPER_DIR: &str = "/dev/mapper";
+
+/// Credential unlocking an existing keyslot, needed to add a new one
+pub enum ExistingKey {
+    File(PathBuf),
+    Passphrase(String),
+}
+
+/// Trait for the LUKS2 operations, implemented with cryptsetup and clevis
+trait Luks {
+    /// Every LUKS device of the machine
+    fn devices(&self) -> Result<Vec<String>>;
+    /// LUKS2 metadata of the device, as JSON
+    fn metadata(&self, device: &str) -> Result<Value>;
+    fn add_key(
+        &self,
+        device: &str,
+        existing: &ExistingKey,
+        slot: Option<u32>,
+        passphrase: &str,
+    ) -> Result<()>;
+    fn import_token(&self, device: &str, token: &Value) -> Result<()>;
+    fn remove_token(&self, device: &str, token_id: u32) -> Result<()>;
+    /// Check that `passphrase` opens the keyslot `slot`
+    fn test_passphrase(&self, device: &str, slot: u32, passphrase: &str) -> Result<()>;
+    /// Wipe a keyslot, authorized by the `passphrase` of another, remaining
+    /// keyslot, or in batch mode without one
+    fn kill_slot(&self, device: &str, slot: u32, passphrase: Option<&str>) -> Result<()>;
+    /// Passphrase of a keyslot bound with any clevis pin, recovered with
+    /// that pin
+    fn clevis_pass(&self, device: &str, slot: u32) -> Result<String>;
+    /// Open `device` as the mapping `name` with `passphrase`, passing the
+    /// extra cryptsetup `flags`
+    fn open(&self, device: &str, name: &str, passphrase: &[u8], flags: &[&str]) -> Result<()>;
+}
+
+/// Real implementation that calls the cryptsetup and clevis binaries
+struct Cryptsetup;
+
+/// Removes the file when dropped, so secrets do not outlive the command
+struct KeyFile(PathBuf);
+
+impl KeyFile {
+    fn create(passphrase: &str) -> Result<Self> {
+        fs::create_dir_all(KEY_DIR).with_context(|| format!("Failed to create {}", KEY_DIR))?;
+        let path = Path::new(KEY_DIR).join(format!("luks_{}.key", std::process::id()));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let key_file = KeyFile(path);
+        file.write_all(passphrase.as_bytes())?;
+        Ok(key_file)
+    }
+}
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+impl Cryptsetup {
+    fn run(&self, command: &mut StdCommand, stdin: Option<&str>) -> Result<String> {
+        let program = command.get_program().to_string_lossy().into_owned();
+        let mut child = command
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", program))?;
+        if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+            pipe.write_all(input.as_bytes())?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("{} failed: {}", program, stderr.trim()));
+        }
+        String::from_utf8(output.stdout)
+            .with_context(|| format!("{} output is not valid UTF-8", program))
+    }
+}
+
+impl Luks for Cryptsetup {
+    fn devices(&self) -> Result<Vec<String>> {
+        let output = StdCommand::new("blkid")
+            .args(["-t", "TYPE=crypto_LUKS", "-o", "device"])
+            .output()
+            .context("Failed to execute blkid")?;
+        // blkid exits with 2 when no device matches
+        if !output.status.success() && output.status.code() != Some(2) {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("blkid failed: {}", stderr.trim()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn metadata(&self, device: &str) -> Result<Value> {
+        let dump = self
+            .run(
+                StdCommand::new("cryptsetup").args(["luksDump", "--dump-json-metadata", device]),
+                None,
+            )
+            .with_context(|| format!("{} is not a LUKS2 device", device))?;
+        serde_json::from_str(&dump).context("Failed to parse the LUKS2 metadata")
+    }
+
+    fn add_key(
+        &self,
+        device: &str,
+        existing: &ExistingKey,
+        slot: Option<u32>,
+        passphrase: &str,
+    ) -> Result<()> {
+        let new_key = KeyFile::create(passphrase)?;
+        let mut command = StdCommand::new("cryptsetup");
+        command.arg("luksAddKey");
+        if let Some(slot) = slot {
+            command.arg("--key-slot").arg(slot.to_string());
+        }
+        let stdin = match existing {
+            ExistingKey::File(path) => {
+                command.arg("--key-file").arg(path);
+                None
+            }
+            ExistingKey::Passphrase(passphrase) => {
+                command.args(["--key-file", "-"]);
+                Some(passphrase.as_str())
+            }
+        };
+        command.arg(device).arg(&new_key.0);
+        self.run(&mut command, stdin)
+            .context("Failed to add the passphrase to a keyslot")?;
+        Ok(())
+    }
+
+    fn import_token(&self, device: &str, token: &Value) -> Result<()> {
+        self.run(
+            StdCommand::new("cryptsetup").args(["token", "import", "--json-file", "-", device]),
+            Some(&token.to_string()),
+        )
+        .context("Failed to import the LUKS2 token")?;
+        Ok(())
+    }
+
+    fn remove_token(&self, device: &str, token_id: u32) -> Result<()> {
+        self.run(
+            StdCommand::new("cryptsetup")
+                .args(["token", "remove", "--token-id"])
+                .arg(token_id.to_string())
+                .arg(device),
+            None,
+        )
+        .with_context(|| format!("Failed to remove LUKS2 token {}", token_id))?;
+        Ok(())
+    }
+
+    fn test_passphrase(&self, device: &str, slot: u32, passphrase: &str) -> Result<()> {
+        self.run(
+            StdCommand::new("cryptsetup")
+                .args(["open", "--test-passphrase", "--key-file", "-", "--key-slot"])
+                .arg(slot.to_string())
+                .arg(device),
+            Some(passphrase),
+        )
+        .with_context(|| format!("The passphrase does not open keyslot {}", slot))?;
+        Ok(())
+    }
+
+    fn kill_slot(&self, device: &str, slot: u32, passphrase: Option<&str>) -> Result<()> {
+        let mut command = StdCommand::new("cryptsetup");
+        command.arg("luksKillSlot");
+        match passphrase {
+            Some(_) => command.args(["--key-file", "-"]),
+            None => command.arg("--batch-mode"),
+        };
+        command.arg(device).arg(slot.to_string());
+        self.run(&mut command, passphrase)
+            .with_context(|| format!("Failed to wipe keyslot {}", slot))?;
+        Ok(())
+    }
+
+    fn clevis_pass(&self, device: &str, slot: u32) -> Result<String> {
+        self.run(
+            StdCommand::new("clevis")
+                .args(["luks", "pass", "-d", device, "-s"])
+                .arg(slot.to_string()),
+            None,
+        )
+        .with_context(|| format!("Failed to recover the passphrase of keyslot {}", slot))
+    }
+
+    fn open(&self, device: &str, name: &str, passphrase: &[u8], flags: &[&str]) -> Result<()> {
+        let passphrase =
+            std::str::from_utf8(passphrase).context("The bound passphrase is not valid UTF-8")?;
+        self.run(
+            StdCommand::new("cryptsetup")
+                .args(["open", "--key-file", "-"])
+                .args(flags)
+                .arg(device)
+                .arg(name),
+            Some(passphrase),
+        )
+        .with_context(|| format!("Failed to open {} as {}", device, name))?;
+        Ok(())
+    }
+}
+
+/// Numbers of the keyslots in use
+fn keyslots(metadata: &Value) -> Result<Vec<u32>> {
+    let keyslots = metadata
+        .get("keyslots")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("No keyslots in the LUKS2 metadata"))?;
+    keyslots
+        .keys()
+        .map(|slot| {
+            slot.parse()
+                .with_context(|| format!("Invalid keyslot number {}", slot))
+        })
+        .collect()
+}
+
+/// `clevis` LUKS2 token holding a compact JWE, converted to the flattened
+/// JSON serialization clevis uses in tokens
+fn clevis_token(slot: u32, jwe: &str) -> Result<Value> {
+    let [protected, encrypted_key, iv, ciphertext, tag] = jwe
+        .split('.')
+        .collect::<Vec<_>>()
+        .try_into()
+        .map_err(|_| TrusteePinError::Crypto("JWE is not in compact form".to_string()))?;
+    Ok(json!({
+        "type": "clevis",
+        "keyslots": [slot.to_string()],
+        "jwe": {
+            "protected": protected,
+            "encrypted_key": encrypted_key,
+            "iv": iv,
+            "ciphertext": ciphertext,
+            "tag": tag,
+        },
+    }))
+}
+
+/// A `clevis` token of the LUKS2 header made with the trustee pin
+struct TrusteeToken {
+    id: u32,
+    keyslot: u32,
+    /// The JWE in compact form
+    jwe: String,
+    header: ClevisHeader,
+}
+
+/// Compact form of the JWE of a `clevis` token
+fn token_jwe(token: &Value) -> Option<String> {
+    let jwe = token.get("jwe")?;
+    let parts: Option<Vec<&str>> = ["protected", "encrypted_key", "iv", "ciphertext", "tag"]
+        .iter()
+        .map(|part| jwe.get(part).and_then(Value::as_str))
+        .collect();
+    Some(parts?.join("."))
+}
+
+/// A `clevis` token of the LUKS2 header, of any pin
+struct ClevisToken {
+    id: u32,
+    keyslot: u32,
+    pin: String,
+    /// The JWE in compact form
+    jwe: String,
+}
+
+/// Name of the pin in the protected header of a compact JWE
+fn jwe_pin(jwe: &str) -> Option<String> {
+    let protected = general_purpose::URL_SAFE_NO_PAD
+        .decode(jwe.split('.').next()?)
+        .ok()?;
+    let protected: Value = serde_json::from_slice(&protected).ok()?;
+    Some(protected.get("clevis")?.get("pin")?.as_str()?.to_string())
+}
+
+/// `clevis` tokens of the LUKS2 header, malformed ones are skipped
+fn clevis_tokens(metadata: &Value) -> Vec<ClevisToken> {
+    let Some(tokens) = metadata.get("tokens").and_then(Value::as_object) else {
+        return Vec::new();
+    };
+    let mut found: Vec<ClevisToken> = tokens
+        .iter()
+        .filter(|(_, token)| token.get("type").and_then(Value::as_str) == Some("clevis"))
+        .filter_map(|(id, token)| {
+            let jwe = token_jwe(token)?;
+            Some(ClevisToken {
+                id: id.parse().ok()?,
+                keyslot: token.get("keyslots")?.get(0)?.as_str()?.parse().ok()?,
+                pin: jwe_pin(&jwe)?,
+                jwe,
+            })
+        })
+        .collect();
+    found.sort_by_key(|token| token.id);
+    found
+}
+
+/// Tokens of the LUKS2 header bound with the trustee pin
+fn trustee_tokens(metadata: &Value) -> Vec<TrusteeToken> {
+    clevis_tokens(metadata)
+        .into_iter()
+        .filter(|token| token.pin == ClevisHeader::PIN)
+        .filter_map(|token| {
+            Some(TrusteeToken {
+                header: ClevisHeader::from_compact_jwe(&token.jwe).ok()?,
+                id: token.id,
+                keyslot: token.keyslot,
+                jwe: token.jwe,
+            })
+        })
+        .collect()
+}
+
+fn bind_with<L: Luks>(
+    luks: &L,
+    device: &str,
+    existing: &ExistingKey,
+    slot: Option<u32>,
+    options: &PassphraseOptions,
+    encrypt: impl FnOnce(&[u8]) -> Result<String>,
+) -> Result<(u32, String)> {
+    let before = keyslots(&luks.metadata(device)?)?;
+    if let Some(slot) = slot
+        && before.contains(&slot)
+    {
+        return Err(anyhow!("Keyslot {} of {} is already in use", slot, device));
+    }
+
+    // Encrypt first: an unreachable server must not leave a keyslot behind
+    let passphrase = passphrase::generate(options)?;
+    let jwe = encrypt(passphrase.as_bytes())?;
+
+    luks.add_key(device, existing, slot, &passphrase)?;
+    let slot = match slot {
+        Some(slot) => slot,
+        None => keyslots(&luks.metadata(device)?)?
+            .into_iter()
+            .find(|slot| !before.contains(slot))
+            .ok_or_else(|| anyhow!("Cannot find the keyslot added to {}", device))?,
+    };
+
+    // The keyslot was just added: wipe it in batch mode, as its own
+    // passphrase does not authorize luksKillSlot
+    if let Err(e) = luks.import_token(device, &clevis_token(slot, &jwe)?) {
+        if let Err(wipe) = luks.kill_slot(device, slot, None) {
+            eprintln!("Warning: keyslot {} left without a token: {:#}", slot, wipe);
+        }
+        return Err(e);
+    }
+    Ok((slot, passphrase))
+}
+
+/// Bind `device` to the trustee pin: add a random passphrase of
+/// `passphrase` to a keyslot and store it, encrypted with the key described
+/// by `config`, in a LUKS2 token. Returns the keyslot used.
+pub fn bind(
+    device: &str,
+    config: &str,
+    options: ConfigOptions,
+    existing: &ExistingKey,
+    slot: Option<u32>,
+    passphrase: &PassphraseOptions,
+) -> Result<u32> {
+    let (slot, _) = bind_with(
+        &Cryptsetup,
+        device,
+        existing,
+        slot,
+        passphrase,
+        |passphrase| encrypt(config, options, passphrase, &NoEvents),
+    )?;
+    Ok(slot)
+}
+
+/// The trustee binding of `device` in `slot`, or its only one
+fn trustee_token<L: Luks>(luks: &L, device: &str, slot: Option<u32>) -> Result<TrusteeToken> {
+    let mut tokens: Vec<TrusteeToken> = trustee_tokens(&luks.metadata(device)?)
+        .into_iter()
+        .filter(|token| slot.is_none_or(|slot| token.keyslot == slot))
+        .collect();
+    match tokens.len() {
+        1 => Ok(tokens.remove(0)),
+        0 => match slot {
+            Some(slot) => Err(anyhow!(
+                "Keyslot {} of {} is not bound to the trustee pin",
+                slot,
+                device
+            )),
+            None => Err(anyhow!("{} is not bound to the trustee pin", device)),
+        },
+        _ => {
+            let slots: Vec<String> = tokens.iter().map(|t| t.keyslot.to_string()).collect();
+            Err(anyhow!(
+                "{} has several trustee bindings, choose a keyslot among {}",
+                device,
+                slots.join(", ")
+            ))
+        }
+    }
+}
+
+fn unbind_with<L: Luks>(
+    luks: &L,
+    device: &str,
+    slot: Option<u32>,
+    force: bool,
+    allow_last_keyslot: bool,
+    decrypt: impl FnOnce(&str) -> Result<Vec<u8>>,
+) -> Result<u32> {
+    let token = trustee_token(luks, device, slot)?;
+    let slots = keyslots(&luks.metadata(device)?)?;
+
+    // A keyslot already wiped by an unbind that failed to remove the token
+    // only leaves the token to remove
+    if slots.contains(&token.keyslot) {
+        // Batch mode wipes the last keyslot without asking, which leaves the
+        // volume unrecoverable
+        if !allow_last_keyslot && slots.iter().all(|slot| *slot == token.keyslot) {
+            return Err(anyhow!(
+                "Keyslot {} is the last keyslot of {}, wiping it would make the volume \
+                 unrecoverable, use --allow-last-keyslot to wipe it anyway",
+                token.keyslot,
+                device
+            ));
+        }
+        // cryptsetup authorizes luksKillSlot with the passphrase of another,
+        // remaining keyslot: check the bound one against its own keyslot,
+        // then wipe it in batch mode. --force skips the check when the
+        // servers are unreachable.
+        if !force {
+            let passphrase = decrypt(&token.jwe)
+                .context("Failed to decrypt the binding, use --force to remove it anyway")?;
+            let passphrase =
+                String::from_utf8(passphrase).context("The bound passphrase is not valid UTF-8")?;
+            luks.test_passphrase(device, token.keyslot, &passphrase)?;
+        }
+        luks.kill_slot(device, token.keyslot, None)?;
+    }
+    luks.remove_token(device, token.id).with_context(|| {
+        format!(
+            "Keyslot {} is wiped but its token is left, run unbind again to remove it",
+            token.keyslot
+        )
+    })?;
+    Ok(token.keyslot)
+}
+
+/// Remove the trustee binding of `device`: wipe its keyslot and remove its
+/// LUKS2 token. `slot` selects the binding when there are several of them.
+/// The last keyslot of the device is only wiped if `allow_last_keyslot`.
+/// Returns the keyslot wiped.
+pub fn unbind(
+    device: &str,
+    slot: Option<u32>,
+    force: bool,
+    allow_last_keyslot: bool,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<u32> {
+    unbind_with(
+        &Cryptsetup,
+        device,
+        slot,
+        force,
+        allow_last_keyslot,
+        |jwe| decrypt(jwe, runtime, events),
+    )
+}
+
+fn update_binding_with<L: Luks>(
+    luks: &L,
+    device: &str,
+    slot: Option<u32>,
+    update: impl FnOnce(&str) -> Result<String>,
+) -> Result<u32> {
+    let token = trustee_token(luks, device, slot)?;
+    let jwe = update(&token.jwe)?;
+    // The new token goes in first: a failure leaves the old one in place
+    luks.import_token(device, &clevis_token(token.keyslot, &jwe)?)?;
+    luks.remove_token(device, token.id)?;
+    Ok(token.keyslot)
+}
+
+/// Replace the servers, certificate or path of the trustee binding of
+/// `device` in its LUKS2 token, keeping its keyslot and passphrase, see
+/// `crate::update_binding`. `slot` selects the binding when there are
+/// several of them. Returns the keyslot of the binding.
+pub fn update_binding(
+    device: &str,
+    slot: Option<u32>,
+    update: &BindingUpdate,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<u32> {
+    update_binding_with(&Cryptsetup, device, slot, |jwe| {
+        crate::update_binding(jwe, update, runtime, events)
+    })
+}
+
+/// The JWE of the trustee binding of `device` in `slot`, or of its only one,
+/// in compact form, read from its LUKS2 token
+pub fn read_token(device: &str, slot: Option<u32>) -> Result<String> {
+    Ok(trustee_token(&Cryptsetup, device, slot)?.jwe)
+}
+
+fn write_token_with<L: Luks>(luks: &L, device: &str, slot: u32, jwe: &str) -> Result<()> {
+    if jwe_pin(jwe).as_deref() != Some(ClevisHeader::PIN) {
+        return Err(anyhow!("The JWE is not bound to the trustee pin"));
+    }
+    ClevisHeader::from_compact_jwe(jwe)?;
+    let metadata = luks.metadata(device)?;
+    if !keyslots(&metadata)?.contains(&slot) {
+        return Err(anyhow!("Keyslot {} of {} is not in use", slot, device));
+    }
+    let old: Vec<u32> = trustee_tokens(&metadata)
+        .into_iter()
+        .filter(|token| token.keyslot == slot)
+        .map(|token| token.id)
+        .collect();
+    // Any other clevis token of the keyslot, even malformed, is left alone
+    let others = metadata
+        .get("tokens")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten()
+        .filter(|(id, token)| {
+            token.get("type").and_then(Value::as_str) == Some("clevis")
+                && token["keyslots"].get(0).and_then(Value::as_str) == Some(&slot.to_string())
+                && !old.iter().any(|old| old.to_string() == **id)
+        })
+        .count();
+    if others > 0 {
+        return Err(anyhow!(
+            "Keyslot {} of {} is bound to another clevis pin, use migrate",
+            slot,
+            device
+        ));
+    }
+    // The new token goes in first: a failure leaves the old one in place
+    luks.import_token(device, &clevis_token(slot, jwe)?)?;
+    for id in old {
+        luks.remove_token(device, id)?;
+    }
+    Ok(())
+}
+
+/// Store the compact JWE `jwe` of the trustee pin in a LUKS2 token of
+/// `device` for `slot`, replacing the trustee token of the keyslot if any,
+/// e.g. for a JWE made with encrypt of the passphrase of the keyslot. The
+/// passphrase is not checked against the keyslot.
+pub fn write_token(device: &str, slot: u32, jwe: &str) -> Result<()> {
+    write_token_with(&Cryptsetup, device, slot, jwe)
+}
+
+/// A trustee binding found by `status`
+pub struct BindingStatus {
+    pub device: String,
+    pub keyslot: u32,
+    pub path: String,
+    /// URL of every server of the binding and whether it accepts connections
+    pub servers: Vec<(String, bool)>,
+}
+
+impl fmt::Display for BindingStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} keyslot {}: {}", self.device, self.keyslot, self.path)?;
+        for (url, reachable) in &self.servers {
+            let state = if *reachable {
+                "reachable"
+            } else {
+                "unreachable"
+            };
+            writeln!(f, "  {} {}", url, state)?;
+        }
+        Ok(())
+    }
+}
+
+impl BindingStatus {
+    /// The binding for `--output-format json`
+    pub fn to_json(&self) -> Value {
+        json!({
+            "device": self.device,
+            "keyslot": self.keyslot,
+            "path": self.path,
+            "servers": self
+                .servers
+                .iter()
+                .map(|(url, reachable)| json!({"url": url, "reachable": reachable}))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Whether the host of `url` accepts TCP connections. This does not attest,
+/// it only tells whether the server could be reached at all.
+fn is_reachable(url: &str) -> bool {
+    let Ok(addrs) = endpoint::socket_addrs(url) else {
+        return false;
+    };
+    addrs
+        .iter()
+        .any(|addr| TcpStream::connect_timeout(addr, REACHABILITY_TIMEOUT).is_ok())
+}
+
+fn status_with<L: Luks>(
+    luks: &L,
+    devices: &[String],
+    reachable: impl Fn(&str) -> bool,
+) -> Result<Vec<BindingStatus>> {
+    let devices = match devices {
+        [] => luks.devices()?,
+        devices => devices.to_vec(),
+    };
+    let mut bindings = Vec::new();
+    for device in devices {
+        let metadata = match luks.metadata(&device) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("Skipping {}: {:#}", device, e);
+                continue;
+            }
+        };
+        for token in trustee_tokens(&metadata) {
+            bindings.push(BindingStatus {
+                device: device.clone(),
+                keyslot: token.keyslot,
+                path: token.header.path,
+                servers: token
+                    .header
+                    .servers
+                    .into_iter()
+                    .map(|server| {
+                        let up = reachable(&server.url);
+                        (server.url, up)
+                    })
+                    .collect(),
+            });
+        }
+    }
+    Ok(bindings)
+}
+
+/// Trustee bindings of `devices`, or of every LUKS device if empty
+pub fn status(devices: &[String]) -> Result<Vec<BindingStatus>> {
+    status_with(&Cryptsetup, devices, is_reachable)
+}
+
+/// Pin configuration of a binding as `clevis luks list` prints it: the
+/// config that binds a device the same way again
+fn pin_config(header: &ClevisHeader) -> Result<Value> {
+    let mut config = serde_json::to_value(header)?;
+    let config_fields = config
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("The clevis header is not a JSON object"))?;
+    // Not part of the config, set when binding
+    config_fields.remove("pin");
+    config_fields.remove("key_id");
+    config_fields.retain(|_, value| !value.is_null());
+    if let Some(initdata) = &header.initdata {
+        // The header stores the initdata TOML document, the config its data
+        let initdata: Initdata =
+            toml::from_str(initdata).context("Failed to parse the initdata of the binding")?;
+        config_fields.insert("initdata".to_string(), serde_json::to_value(initdata.data)?);
+    }
+    if let Some(jwes) = &header.recipients {
+        // The first recipient is the resource of the binding itself
+        let recipients = jwes
+            .iter()
+            .skip(1)
+            .map(|jwe| {
+                let recipient = ClevisHeader::from_compact_jwe(jwe)?;
+                Ok(json!({"servers": recipient.servers, "path": recipient.path}))
+            })
+            .collect::<Result<Vec<Value>>>()?;
+        config_fields.insert("recipients".to_string(), Value::Array(recipients));
+    }
+    if let Some(jwe) = &header.tpm2_jwe {
+        // Only the sealing policy is config, the sealed secret is per binding
+        config_fields.remove("tpm2_jwe");
+        config_fields.insert("tpm2".to_string(), tpm2::sealed_config(jwe)?);
+    }
+    Ok(config)
+}
+
+/// A trustee binding in the shape of a `clevis luks list` line
+pub struct BindingReport {
+    pub keyslot: u32,
+    pub config: Value,
+}
+
+impl fmt::Display for BindingReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} '{}'",
+            self.keyslot,
+            ClevisHeader::PIN,
+            self.config
+        )
+    }
+}
+
+impl BindingReport {
+    /// The binding for `--output-format json`
+    pub fn to_json(&self) -> Value {
+        json!({"keyslot": self.keyslot, "pin": ClevisHeader::PIN, "config": self.config})
+    }
+}
+
+fn report_with<L: Luks>(luks: &L, device: &str, slot: Option<u32>) -> Result<Vec<BindingReport>> {
+    let reports = trustee_tokens(&luks.metadata(device)?)
+        .into_iter()
+        .filter(|token| slot.is_none_or(|slot| token.keyslot == slot))
+        .map(|token| {
+            Ok(BindingReport {
+                keyslot: token.keyslot,
+                config: pin_config(&token.header)
+                    .with_context(|| format!("Invalid binding in keyslot {}", token.keyslot))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let (Some(slot), []) = (slot, reports.as_slice()) {
+        return Err(anyhow!(
+            "Keyslot {} of {} is not bound to the trustee pin",
+            slot,
+            device
+        ));
+    }
+    Ok(reports)
+}
+
+/// Trustee bindings of `device`, or only the one in `slot`, with the pin
+/// configuration `clevis luks list` shows for them
+pub fn report(device: &str, slot: Option<u32>) -> Result<Vec<BindingReport>> {
+    report_with(&Cryptsetup, device, slot)
+}
+
+/// Result of `migrate`
+#[derive(Debug)]
+pub struct Migration {
+    pub pin: String,
+    pub old_slot: u32,
+    pub new_slot: u32,
+}
+
+impl Migration {
+    /// The migration for `--output-format json`
+    pub fn to_json(&self) -> Value {
+        json!({"pin": self.pin, "old_keyslot": self.old_slot, "new_keyslot": self.new_slot})
+    }
+}
+
+fn migrate_with<L: Luks>(
+    luks: &L,
+    device: &str,
+    slot: Option<u32>,
+    remove_old: bool,
+    options: &PassphraseOptions,
+    encrypt: impl FnOnce(&[u8]) -> Result<String>,
+) -> Result<Migration> {
+    let tokens: Vec<ClevisToken> = clevis_tokens(&luks.metadata(device)?)
+        .into_iter()
+        .filter(|token| token.pin != ClevisHeader::PIN)
+        .filter(|token| slot.is_none_or(|slot| token.keyslot == slot))
+        .collect();
+    let old = match tokens.as_slice() {
+        [token] => token,
+        [] => return Err(anyhow!("{} has no clevis binding to migrate", device)),
+        _ => {
+            let slots: Vec<String> = tokens
+                .iter()
+                .map(|t| format!("{} ({})", t.keyslot, t.pin))
+                .collect();
+            return Err(anyhow!(
+                "{} has several clevis bindings, choose a keyslot among {}",
+                device,
+                slots.join(", ")
+            ));
+        }
+    };
+
+    let passphrase = luks.clevis_pass(device, old.keyslot)?;
+    let existing = ExistingKey::Passphrase(passphrase);
+    let (new_slot, new_passphrase) = bind_with(luks, device, &existing, None, options, encrypt)?;
+
+    // cryptsetup authorizes luksKillSlot with the passphrase of a remaining
+    // keyslot: the trustee one, which proves it opens before the old one goes
+    if remove_old {
+        luks.kill_slot(device, old.keyslot, Some(&new_passphrase))?;
+        luks.remove_token(device, old.id)?;
+    }
+    Ok(Migration {
+        pin: old.pin.clone(),
+        old_slot: old.keyslot,
+        new_slot,
+    })
+}
+
+/// Bind `device`, already bound with another clevis pin such as tang or
+/// tpm2, to the trustee pin. The passphrase of the old binding unlocks the
+/// device for the new one, and the old binding is removed if `remove_old`.
+/// `slot` selects the old binding when there are several of them.
+pub fn migrate(
+    device: &str,
+    config: &str,
+    options: ConfigOptions,
+    slot: Option<u32>,
+    remove_old: bool,
+    passphrase: &PassphraseOptions,
+) -> Result<Migration> {
+    migrate_with(
+        &Cryptsetup,
+        device,
+        slot,
+        remove_old,
+        passphrase,
+        |passphrase| encrypt(config, options, passphrase, &NoEvents),
+    )
+}
+
+/// A volume of the unlock manifest, in the format of crypttab(5)
+#[derive(Debug, PartialEq)]
+struct ManifestEntry {
+    name: String,
+    device: String,
+    /// cryptsetup flags for the crypttab options understood
+    flags: Vec<&'static str>,
+}
+
+/// Device path of a crypttab device field, which may be a `UUID=`,
+/// `PARTUUID=` or `LABEL=` tag
+fn device_path(device: &str) -> String {
+    let tags = [
+        ("UUID=", "/dev/disk/by-uuid/"),
+        ("PARTUUID=", "/dev/disk/by-partuuid/"),
+        ("LABEL=", "/dev/disk/by-label/"),
+    ];
+    tags.iter()
+        .find_map(|(tag, dir)| Some(format!("{}{}", dir, device.strip_prefix(tag)?)))
+        .unwrap_or_else(|| device.to_string())
+}
+
+/// crypttab options of volumes that cannot be LUKS2, skipped
+const NOT_LUKS_OPTIONS: [&str; 5] = ["plain", "swap", "tmp", "tcrypt", "bitlk"];
+
+/// Volumes of a crypttab-like manifest: name, device, key file and options
+/// per line. Key files are ignored, the key comes from the trustee binding.
+fn parse_manifest(manifest: &str) -> Result<Vec<ManifestEntry>> {
+    let entries = manifest
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.split('#').next().unwrap_or_default()))
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [name, device, rest @ ..] = fields.as_slice() else {
+                return Err(anyhow!("Line {} of the manifest has no device", index + 1));
+            };
+            let options = rest.get(1).copied().unwrap_or_default();
+            if options
+                .split(',')
+                .any(|option| NOT_LUKS_OPTIONS.contains(&option))
+            {
+                return Ok(None);
+            }
+            let flags = options
+                .split(',')
+                .filter_map(|option| match option {
+                    "discard" => Some("--allow-discards"),
+                    "readonly" | "read-only" => Some("--readonly"),
+                    _ => None,
+                })
+                .collect();
+            Ok(Some(ManifestEntry {
+                name: name.to_string(),
+                device: device_path(device),
+                flags,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(entries.into_iter().flatten().collect())
+}
+
+/// Outcome of unlocking a volume of the manifest
+pub struct Unlock {
+    pub name: String,
+    pub device: String,
+    pub result: Result<UnlockState>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum UnlockState {
+    Opened,
+    AlreadyOpen,
+    /// The device has no trustee binding, left to other unlock methods
+    NotBound,
+}
+
+impl fmt::Display for Unlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.result {
+            Ok(UnlockState::Opened) => write!(f, "{}: opened {}", self.name, self.device),
+            Ok(UnlockState::AlreadyOpen) => write!(f, "{}: already open", self.name),
+            Ok(UnlockState::NotBound) => {
+                write!(f, "{}: {} has no trustee binding", self.name, self.device)
+            }
+            Err(e) => write!(f, "{}: failed: {:#}", self.name, e),
+        }
+    }
+}
+
+impl UnlockState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnlockState::Opened => "opened",
+            UnlockState::AlreadyOpen => "already-open",
+            UnlockState::NotBound => "not-bound",
+        }
+    }
+}
+
+impl Unlock {
+    /// The outcome for `--output-format json`
+    pub fn to_json(&self) -> Value {
+        let mut unlock = json!({"name": self.name, "device": self.device});
+        match &self.result {
+            Ok(state) => unlock["state"] = json!(state.as_str()),
+            Err(e) => {
+                unlock["state"] = json!("failed");
+                unlock["error"] = json!(format!("{:#}", e));
+            }
+        }
+        unlock
+    }
+}
+
+fn unlock_entry<L: Luks>(
+    luks: &L,
+    entry: &ManifestEntry,
+    is_open: &impl Fn(&str) -> bool,
+    decrypt: &mut impl FnMut(&str) -> Result<Vec<u8>>,
+) -> Result<UnlockState> {
+    if is_open(&entry.name) {
+        return Ok(UnlockState::AlreadyOpen);
+    }
+    let tokens = trustee_tokens(&luks.metadata(&entry.device)?);
+    if tokens.is_empty() {
+        return Ok(UnlockState::NotBound);
+    }
+    let mut last_error = None;
+    for token in tokens {
+        match decrypt(&token.jwe) {
+            Ok(passphrase) => {
+                luks.open(&entry.device, &entry.name, &passphrase, &entry.flags)?;
+                return Ok(UnlockState::Opened);
+            }
+            Err(e) => {
+                last_error = Some(e.context(format!("keyslot {}", token.keyslot)));
+            }
+        }
+    }
+    Err(last_error.expect("at least one token was tried"))
+}
+
+fn unlock_all_with<L: Luks>(
+    luks: &L,
+    manifest: &str,
+    is_open: impl Fn(&str) -> bool,
+    mut decrypt: impl FnMut(&str) -> Result<Vec<u8>>,
+) -> Result<Vec<Unlock>> {
+    Ok(parse_manifest(manifest)?
+        .into_iter()
+        .map(|entry| Unlock {
+            result: unlock_entry(luks, &entry, &is_open, &mut decrypt),
+            name: entry.name,
+            device: entry.device,
+        })
+        .collect())
+}
+
+/// Open `device` as the mapping `name` with its trustee binding, unless
+/// already open, reporting the progress of the key fetch to `events`
+pub fn unlock(
+    device: &str,
+    name: &str,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<UnlockState> {
+    let executors = ExecutorCache::default();
+    let entry = ManifestEntry {
+        name: name.to_string(),
+        device: device.to_string(),
+        flags: Vec::new(),
+    };
+    unlock_entry(
+        &Cryptsetup,
+        &entry,
+        &|name| Path::new(MAPPER_DIR).join(name).exists(),
+        &mut |jwe| decrypt_with(jwe, runtime, &executors, events),
+    )
+}
+
+/// Magic of a LUKS binary header
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+/// Size of the LUKS2 binary header, the JSON area follows it
+const LUKS2_BINARY_HEADER_SIZE: usize = 4096;
+/// Largest LUKS2 header, binary header and JSON area, cryptsetup writes
+const LUKS2_MAX_HEADER_SIZE: usize = 4 << 20;
+/// Offset and size of the UUID in the binary header
+const LUKS2_UUID_FIELD: (usize, usize) = (168, 40);
+
+/// UUID of the LUKS2 header at the start of `reader`, when it has a trustee
+/// binding. Only the primary header is read and its checksum is left to
+/// cryptsetup, which checks it when opening the device.
+fn bound_uuid_with(reader: &mut impl Read) -> Result<Option<String>> {
+    let mut binary = vec![0; LUKS2_BINARY_HEADER_SIZE];
+    reader
+        .read_exact(&mut binary)
+        .context("Failed to read the LUKS header")?;
+    let version = u16::from_be_bytes([binary[6], binary[7]]);
+    if !binary.starts_with(LUKS_MAGIC) || version != 2 {
+        return Ok(None);
+    }
+    let size = u64::from_be_bytes(binary[8..16].try_into()?);
+    let size = usize::try_from(size)
+        .ok()
+        .filter(|size| (LUKS2_BINARY_HEADER_SIZE..=LUKS2_MAX_HEADER_SIZE).contains(size))
+        .ok_or_else(|| anyhow!("Invalid LUKS2 header size {}", size))?;
+    let mut json_area = vec![0; size - LUKS2_BINARY_HEADER_SIZE];
+    reader
+        .read_exact(&mut json_area)
+        .context("Failed to read the LUKS2 JSON area")?;
+    let json_end = json_area
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(json_area.len());
+    let metadata: Value =
+        serde_json::from_slice(&json_area[..json_end]).context("Invalid LUKS2 metadata")?;
+    if trustee_tokens(&metadata).is_empty() {
+        return Ok(None);
+    }
+
+    let (offset, len) = LUKS2_UUID_FIELD;
+    let uuid = &binary[offset..offset + len];
+    let uuid = &uuid[..uuid.iter().position(|&byte| byte == 0).unwrap_or(len)];
+    // Ends up in unit names and device paths
+    if uuid.is_empty()
+        || !uuid
+            .iter()
+            .all(|&byte| byte.is_ascii_hexdigit() || byte == b'-')
+    {
+        return Err(anyhow!(
+            "Invalid LUKS2 UUID {:?}",
+            String::from_utf8_lossy(uuid)
+        ));
+    }
+    Ok(Some(String::from_utf8(uuid.to_vec())?))
+}
+
+/// UUID of the LUKS2 `device` when it has a trustee binding, read from its
+/// header directly, as udev runs this before cryptsetup could be
+pub(crate) fn bound_uuid(device: &Path) -> Result<Option<String>> {
+    let mut file =
+        File::open(device).with_context(|| format!("Failed to open {}", device.display()))?;
+    bound_uuid_with(&mut file).with_context(|| format!("Failed to probe {}", device.display()))
+}
+
+/// Open every trustee-bound volume of the crypttab-like `manifest`. The keys
+/// are fetched with the same key fetchers, so backends keeping their
+/// attestation session attest once per server rather than once per volume.
+pub fn unlock_all(
+    manifest: &Path,
+    runtime: &RuntimeConfig,
+    events: &dyn EventHandler,
+) -> Result<Vec<Unlock>> {
+    let manifest = fs::read_to_string(manifest)
+        .with_context(|| format!("Failed to read {}", manifest.display()))?;
+    let executors = ExecutorCache::default();
+    unlock_all_with(
+        &Cryptsetup,
+        &manifest,
+        |name| Path::new(MAPPER_DIR).join(name).exists(),
+        |jwe| decrypt_with(jwe, runtime, &executors, events),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    /// In-memory LUKS2 header
+    struct MockLuks {
+        metadata: RefCell<Value>,
+        /// Passphrase of each keyslot, `passphrase-<slot>` initially
+        passphrases: RefCell<BTreeMap<u32, String>>,
+        import_fails: bool,
+        calls: RefCell<Vec<String>>,
+    }
+
+    impl MockLuks {
+        fn new(slots: &[u32]) -> Self {
+            let keyslots: serde_json::Map<String, Value> = slots
+                .iter()
+                .map(|slot| (slot.to_string(), json!({"type": "luks2"})))
+                .collect();
+            MockLuks {
+                metadata: RefCell::new(json!({"keyslots": keyslots, "tokens": {}})),
+                passphrases: RefCell::new(
+                    slots
+                        .iter()
+                        .map(|slot| (*slot, format!("passphrase-{}", slot)))
+                        .collect(),
+                ),
+                import_fails: false,
+                calls: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl Luks for MockLuks {
+        fn devices(&self) -> Result<Vec<String>> {
+            Ok(vec!["/dev/vda3".to_string()])
+        }
+
+        fn metadata(&self, _device: &str) -> Result<Value> {
+            Ok(self.metadata.borrow().clone())
+        }
+
+        fn add_key(
+            &self,
+            _device: &str,
+            _existing: &ExistingKey,
+            slot: Option<u32>,
+            passphrase: &str,
+        ) -> Result<()> {
+            let mut metadata = self.metadata.borrow_mut();
+            let keyslots = metadata["keyslots"].as_object_mut().unwrap();
+            let slot = slot.unwrap_or_else(|| {
+                (0..)
+                    .find(|n| !keyslots.contains_key(&n.to_string()))
+                    .unwrap()
+            });
+            keyslots.insert(slot.to_string(), json!({"type": "luks2"}));
+            self.passphrases
+                .borrow_mut()
+                .insert(slot, passphrase.to_string());
+            self.calls.borrow_mut().push(format!("add {}", slot));
+            Ok(())
+        }
+
+        fn import_token(&self, _device: &str, token: &Value) -> Result<()> {
+            if self.import_fails {
+                return Err(anyhow!("Failed to import the LUKS2 token"));
+            }
+            let mut metadata = self.metadata.borrow_mut();
+            let tokens = metadata["tokens"].as_object_mut().unwrap();
+            tokens.insert(tokens.len().to_string(), token.clone());
+            self.calls.borrow_mut().push("import".to_string());
+            Ok(())
+        }
+
+        fn remove_token(&self, _device: &str, token_id: u32) -> Result<()> {
+            let mut metadata = self.metadata.borrow_mut();
+            metadata["tokens"]
+                .as_object_mut()
+                .unwrap()
+                .remove(&token_id.to_string());
+            self.calls.borrow_mut().push(format!("remove {}", token_id));
+            Ok(())
+        }
+
+        fn clevis_pass(&self, _device: &str, slot: u32) -> Result<String> {
+            self.calls.borrow_mut().push(format!("pass {}", slot));
+            Ok(self.passphrases.borrow()[&slot].clone())
+        }
+
+        fn test_passphrase(&self, _device: &str, slot: u32, passphrase: &str) -> Result<()> {
+            if self.passphrases.borrow().get(&slot).map(String::as_str) != Some(passphrase) {
+                return Err(anyhow!("No key available with this passphrase"));
+            }
+            self.calls.borrow_mut().push(format!("test {}", slot));
+            Ok(())
+        }
+
+        /// Like cryptsetup, only a passphrase of another keyslot authorizes
+        /// the wipe
+        fn kill_slot(&self, _device: &str, slot: u32, passphrase: Option<&str>) -> Result<()> {
+            if let Some(passphrase) = passphrase
+                && !self
+                    .passphrases
+                    .borrow()
+                    .iter()
+                    .any(|(other, p)| *other != slot && p == passphrase)
+            {
+                return Err(anyhow!("No key available with this passphrase"));
+            }
+            let mut metadata = self.metadata.borrow_mut();
+            metadata["keyslots"]
+                .as_object_mut()
+                .unwrap()
+                .remove(&slot.to_string());
+            self.passphrases.borrow_mut().remove(&slot);
+            self.calls.borrow_mut().push(format!("kill {}", slot));
+            Ok(())
+        }
+
+        fn open(&self, device: &str, name: &str, passphrase: &[u8], flags: &[&str]) -> Result<()> {
+            self.calls.borrow_mut().push(format!(
+                "open {} {} {} {}",
+                device,
+                name,
+                String::from_utf8_lossy(passphrase),
+                flags.join(" ")
+            ));
+            Ok(())
+        }
+    }
+
+    const JWE: &str = "eyJhbGciOiJkaXIifQ..aXY.Y2lwaGVy.dGFn";
+
+    /// Compact JWE whose header carries a trustee binding
+    fn trustee_jwe() -> String {
+        jwe_with_clevis(json!({
+            "pin": "trustee",
+            "servers": [{"url": "http://kbs1"}],
+            "path": "default/key/root",
+        }))
+    }
+
+    fn jwe_with_clevis(clevis: Value) -> String {
+        let protected = json!({"alg": "ECDH-ES", "clevis": clevis});
+        format!(
+            "{}..aXY.Y2lwaGVy.dGFn",
+            general_purpose::URL_SAFE_NO_PAD.encode(protected.to_string())
+        )
+    }
+
+    /// Header with slot 0 bound to another pin and slots 1 and 2 to trustee
+    fn bound_luks() -> MockLuks {
+        let luks = MockLuks::new(&[0, 1, 2]);
+        {
+            let mut metadata = luks.metadata.borrow_mut();
+            metadata["tokens"] = json!({
+                "0": clevis_token(0, JWE).unwrap(),
+                "1": clevis_token(1, &trustee_jwe()).unwrap(),
+                "3": clevis_token(2, &trustee_jwe()).unwrap(),
+                "4": {"type": "systemd-tpm2", "keyslots": ["2"]},
+            });
+        }
+        luks
+    }
+
+    #[test]
+    fn test_bind() {
+        let luks = MockLuks::new(&[0, 2]);
+        let existing = ExistingKey::Passphrase("existing".to_string());
+
+        let (slot, passphrase) = bind_with(
+            &luks,
+            "/dev/vda3",
+            &existing,
+            None,
+            &PassphraseOptions::default(),
+            |passphrase| {
+                assert_eq!(passphrase.len(), 43);
+                Ok(JWE.to_string())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(slot, 1);
+        assert_eq!(luks.passphrases.borrow()[&1], passphrase);
+        let token = &luks.metadata.borrow()["tokens"]["0"];
+        assert_eq!(token["type"], "clevis");
+        assert_eq!(token["keyslots"], json!(["1"]));
+        assert_eq!(token["jwe"]["protected"], "eyJhbGciOiJkaXIifQ");
+        assert_eq!(token["jwe"]["encrypted_key"], "");
+        assert_eq!(token["jwe"]["tag"], "dGFn");
+    }
+
+    #[test]
+    fn test_bind_encrypt_failure_leaves_header_untouched() {
+        let luks = MockLuks::new(&[0]);
+        let existing = ExistingKey::File(PathBuf::from("/root/luks.key"));
+
+        let result = bind_with(
+            &luks,
+            "/dev/vda3",
+            &existing,
+            None,
+            &PassphraseOptions::default(),
+            |_| Err(anyhow!("Failed to fetch the LUKS key")),
+        );
+
+        assert!(result.is_err());
+        assert!(luks.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_bind_import_failure_wipes_keyslot() {
+        let mut luks = MockLuks::new(&[0]);
+        luks.import_fails = true;
+        let existing = ExistingKey::Passphrase("existing".to_string());
+
+        let result = bind_with(
+            &luks,
+            "/dev/vda3",
+            &existing,
+            Some(3),
+            &PassphraseOptions::default(),
+            |_| Ok(JWE.to_string()),
+        );
+
+        // The mock rejects the passphrase of keyslot 3 itself for the wipe
+        assert!(result.is_err());
+        assert_eq!(*luks.calls.borrow(), vec!["add 3", "kill 3"]);
+        assert_eq!(keyslots(&luks.metadata.borrow()).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_bind_slot_in_use() {
+        let luks = MockLuks::new(&[0, 1]);
+        let existing = ExistingKey::Passphrase("existing".to_string());
+
+        let result = bind_with(
+            &luks,
+            "/dev/vda3",
+            &existing,
+            Some(1),
+            &PassphraseOptions::default(),
+            |_| Ok(JWE.to_string()),
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Keyslot 1 of /dev/vda3 is already in use"
+        );
+    }
+
+    #[test]
+    fn test_trustee_tokens() {
+        let tokens = trustee_tokens(&bound_luks().metadata.borrow());
+
+        let found: Vec<(u32, u32)> = tokens.iter().map(|t| (t.id, t.keyslot)).collect();
+        assert_eq!(found, vec![(1, 1), (3, 2)]);
+        assert_eq!(tokens[0].jwe, trustee_jwe());
+    }
+
+    #[test]
+    fn test_unbind() {
+        let luks = bound_luks();
+
+        let slot = unbind_with(&luks, "/dev/vda3", Some(2), false, false, |jwe| {
+            assert_eq!(jwe, trustee_jwe());
+            Ok(b"passphrase-2".to_vec())
+        })
+        .unwrap();
+
+        assert_eq!(slot, 2);
+        assert_eq!(*luks.calls.borrow(), vec!["test 2", "kill 2", "remove 3"]);
+    }
+
+    #[test]
+    fn test_unbind_wrong_passphrase() {
+        let luks = bound_luks();
+
+        let result = unbind_with(&luks, "/dev/vda3", Some(2), false, false, |_| {
+            Ok(b"passphrase-1".to_vec())
+        });
+
+        assert!(result.is_err());
+        assert!(luks.calls.borrow().is_empty());
+        assert_eq!(keyslots(&luks.metadata.borrow()).unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_unbind_requires_slot_with_several_bindings() {
+        let luks = bound_luks();
+
+        let result = unbind_with(&luks, "/dev/vda3", None, false, false, |_| Ok(Vec::new()));
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "/dev/vda3 has several trustee bindings, choose a keyslot among 1, 2"
+        );
+        let result = unbind_with(
+            &luks,
+            "/dev/vda3",
+            Some(0),
+            false,
+            false,
+            |_| Ok(Vec::new()),
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Keyslot 0 of /dev/vda3 is not bound to the trustee pin"
+        );
+        assert!(luks.calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_unbind_unreachable_servers() {
+        let luks = bound_luks();
+        let unreachable = || Err(anyhow!("Failed to fetch the LUKS key"));
+
+        let result = unbind_with(&luks, "/dev/vda3", Some(1), false, false, |_| unreachable());
+        assert!(result.is_err());
+        assert!(luks.calls.borrow().is_empty());
+
+        let slot =
+            unbind_with(&luks, "/dev/vda3", Some(1), true, false, |_| unreachable()).unwrap();
+        assert_eq!(slot, 1);
+        assert_eq!(*luks.calls.borrow(), vec!["kill 1", "remove 1"]);
+    }
+
+    #[test]
+    fn test_unbind_last_keyslot() {
+        let luks = MockLuks::new(&[1]);
+        luks.metadata.borrow_mut()["tokens"] =
+            json!({"0": clevis_token(1, &trustee_jwe()).unwrap()});
+        let decrypt = |_: &str| Ok(b"passphrase-1".to_vec());
+
+        for force in [false, true] {
+            let result = unbind_with(&luks, "/dev/vda3", None, force, false, decrypt);
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "Keyslot 1 is the last keyslot of /dev/vda3, wiping it would make the volume \
+                 unrecoverable, use --allow-last-keyslot to wipe it anyway"
+            );
+        }
+        assert!(luks.calls.borrow().is_empty());
+
+        let slot = unbind_with(&luks, "/dev/vda3", None, false, true, decrypt).unwrap();
+        assert_eq!(slot, 1);
+        assert_eq!(*luks.calls.borrow(), vec!["test 1", "kill 1", "remove 0"]);
+    }
+
+    #[test]
+    fn test_unbind_wiped_keyslot() {
+        // An earlier unbind wiped keyslot 2 but failed to remove its token
+        let luks = bound_luks();
+        luks.kill_slot("/dev/vda3", 2, None).unwrap();
+        luks.calls.borrow_mut().clear();
+
+        let slot = unbind_with(&luks, "/dev/vda3", Some(2), false, false, |_| {
+            Err(anyhow!("Failed to fetch the LUKS key"))
+        })
+        .unwrap();
+
+        assert_eq!(slot, 2);
+        assert_eq!(*luks.calls.borrow(), vec!["remove 3"]);
+    }
+
+    #[test]
+    fn test_update_binding() {
+        let luks = bound_luks();
+        let updated = jwe_with_clevis(json!({
+            "pin": "trustee",
+            "servers": [{"url": "https://kbs.new.example"}],
+            "path": "default/key/root",
+        }));
+
+        let result = update_binding_with(&luks, "/dev/vda3", Some(1), |_| {
+            Err(anyhow!("Failed to fetch the LUKS key"))
+        });
+        assert!(result.is_err());
+        assert!(luks.calls.borrow().is_empty());
+
+        let slot = update_binding_with(&luks, "/dev/vda3", Some(1), |jwe| {
+            assert_eq!(jwe, trustee_jwe());
+            Ok(updated.clone())
+        })
+        .unwrap();
+
+        assert_eq!(slot, 1);
+        assert_eq!(*luks.calls.borrow(), vec!["import", "remove 1"]);
+        let tokens = trustee_tokens(&luks.metadata("/dev/vda3").unwrap());
+        let token = tokens.iter().find(|token| token.keyslot == 1).unwrap();
+        assert_eq!(token.jwe, updated);
+    }
+
+    #[test]
+    fn test_write_token() {
+        let luks = bound_luks();
+        let updated = jwe_with_clevis(json!({
+            "pin": "trustee",
+            "servers": [{"url": "https://kbs.new.example"}],
+            "path": "default/key/root",
+        }));
+
+        assert!(write_token_with(&luks, "/dev/vda3", 0, &updated).is_err());
+        assert!(write_token_with(&luks, "/dev/vda3", 5, &updated).is_err());
+        assert!(write_token_with(&luks, "/dev/vda3", 1, JWE).is_err());
+        assert!(luks.calls.borrow().is_empty());
+
+        write_token_with(&luks, "/dev/vda3", 1, &updated).unwrap();
+
+        assert_eq!(*luks.calls.borrow(), vec!["import", "remove 1"]);
+        let token = trustee_token(&luks, "/dev/vda3", Some(1)).unwrap();
+        assert_eq!(token.jwe, updated);
+    }
+
+    #[test]
+    fn test_status() {
+        let luks = bound_luks();
+
+        let bindings = status_with(&luks, &[], |url| url == "http://kbs1").unwrap();
+
+        assert_eq!(bindings.len(), 2);
+        assert_eq!(
+            bindings[1].to_string(),
+            "/dev/vda3 keyslot 2: default/key/root\n  http://kbs1 reachable\n"
+        );
+        assert_eq!(
+            bindings[1].to_json(),
+            json!({
+                "device": "/dev/vda3",
+                "keyslot": 2,
+                "path": "default/key/root",
+                "servers": [{"url": "http://kbs1", "reachable": true}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let luks = MockLuks::new(&[0, 1]);
+        let initdata = "version = \"0.1.0\"\nalgorithm = \"sha256\"\n\n[data]\nkey = \"value\"\n";
+        luks.metadata.borrow_mut()["tokens"] = json!({
+            "0": clevis_token(0, JWE).unwrap(),
+            "1": clevis_token(1, &jwe_with_clevis(json!({
+                "pin": "trustee",
+                "servers": [{"url": "http://kbs1", "cert": ""}],
+                "path": "default/key/root",
+                "initdata": initdata,
+                "num_retries": null,
+            }))).unwrap(),
+        });
+
+        let reports = report_with(&luks, "/dev/vda3", None).unwrap();
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(
+            reports[0].to_string(),
+            "1: trustee '{\"servers\":[{\"url\":\"http://kbs1\",\"cert\":\"\"}],\
+             \"path\":\"default/key/root\",\"initdata\":{\"key\":\"value\"}}'\n"
+        );
+        assert_eq!(
+            report_with(&luks, "/dev/vda3", Some(0))
+                .err()
+                .unwrap()
+                .to_string(),
+            "Keyslot 0 of /dev/vda3 is not bound to the trustee pin"
+        );
+    }
+
+    #[test]
+    fn test_is_reachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(is_reachable(&format!("http://127.0.0.1:{}", port)));
+        drop(listener);
+        assert!(!is_reachable(&format!("http://127.0.0.1:{}", port)));
+        assert!(!is_reachable("not a url"));
+    }
+
+    #[test]
+    fn test_migrate() {
+        let luks = MockLuks::new(&[0, 1]);
+        luks.metadata.borrow_mut()["tokens"] = json!({
+            "0": clevis_token(1, &jwe_with_clevis(json!({"pin": "tang", "tang": {}}))).unwrap(),
+        });
+
+        let migration = migrate_with(
+            &luks,
+            "/dev/vda3",
+            None,
+            true,
+            &PassphraseOptions::default(),
+            |_| Ok(trustee_jwe()),
+        )
+        .unwrap();
+
+        assert_eq!(migration.pin, "tang");
+        assert_eq!(migration.old_slot, 1);
+        assert_eq!(migration.new_slot, 2);
+        assert_eq!(
+            *luks.calls.borrow(),
+            vec!["pass 1", "add 2", "import", "kill 1", "remove 0"]
+        );
+        let tokens = trustee_tokens(&luks.metadata.borrow());
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].keyslot, 2);
+    }
+
+    #[test]
+    fn test_migrate_without_clevis_binding() {
+        let luks = bound_luks();
+
+        let result = migrate_with(
+            &luks,
+            "/dev/vda3",
+            None,
+            false,
+            &PassphraseOptions::default(),
+            |_| Ok(trustee_jwe()),
+        );
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "/dev/vda3 has no clevis binding to migrate"
+        );
+    }
+
+    #[test]
+    fn test_unlock_all() {
+        let luks = bound_luks();
+        let manifest = "# volumes\n\
+                        root UUID=1234 none discard,_netdev\n\
+                        \n\
+                        home /dev/vdb - readonly # already open\n\
+                        swap /dev/vdc /dev/urandom swap,cipher=aes-xts-plain64\n";
+        let mut attempts = 0;
+
+        let unlocks = unlock_all_with(
+            &luks,
+            manifest,
+            |name| name == "home",
+            |_| {
+                attempts += 1;
+                match attempts {
+                    1 => Err(anyhow!("unreachable")),
+                    _ => Ok(b"passphrase".to_vec()),
+                }
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            unlocks[0].to_json(),
+            json!({"name": "root", "device": "/dev/disk/by-uuid/1234", "state": "opened"})
+        );
+        let failed = Unlock {
+            name: "data".to_string(),
+            device: "/dev/vdc".to_string(),
+            result: Err(anyhow!("unreachable")),
+        };
+        assert_eq!(
+            failed.to_json(),
+            json!({"name": "data", "device": "/dev/vdc", "state": "failed", "error": "unreachable"})
+        );
+        let states: Vec<(String, String, UnlockState)> = unlocks
+            .into_iter()
+            .map(|unlock| (unlock.name, unlock.device, unlock.result.unwrap()))
+            .collect();
+        assert_eq!(
+            states,
+            [
+                (
+                    "root".to_string(),
+                    "/dev/disk/by-uuid/1234".to_string(),
+                    UnlockState::Opened
+                ),
+                (
+                    "home".to_string(),
+                    "/dev/vdb".to_string(),
+                    UnlockState::AlreadyOpen
+                ),
+            ]
+        );
+        assert_eq!(
+            *luks.calls.borrow(),
+            ["open /dev/disk/by-uuid/1234 root passphrase --allow-discards"]
+        );
+    }
+
+    #[test]
+    fn test_unlock_unbound_device() {
+        let luks = MockLuks::new(&[0]);
+
+        let unlocks = unlock_all_with(
+            &luks,
+            "data /dev/vdc",
+            |_| false,
+            |_| Err(anyhow!("no binding to decrypt")),
+        )
+        .unwrap();
+
+        assert_eq!(unlocks[0].result.as_ref().unwrap(), &UnlockState::NotBound);
+        assert!(parse_manifest("lonely").is_err());
+    }
+
+    /// LUKS2 header with the JSON area `metadata` and the UUID `uuid`
+    fn luks2_header(metadata: &Value, uuid: &str) -> Vec<u8> {
+        let size = 4 * LUKS2_BINARY_HEADER_SIZE;
+        let mut header = vec![0; size];
+        header[..6].copy_from_slice(LUKS_MAGIC);
+        header[6..8].copy_from_slice(&2u16.to_be_bytes());
+        header[8..16].copy_from_slice(&(size as u64).to_be_bytes());
+        header[168..168 + uuid.len()].copy_from_slice(uuid.as_bytes());
+        let json = serde_json::to_vec(metadata).unwrap();
+        header[LUKS2_BINARY_HEADER_SIZE..LUKS2_BINARY_HEADER_SIZE + json.len()]
+            .copy_from_slice(&json);
+        header
+    }
+
+    #[test]
+    fn test_bound_uuid() {
+        let uuid = "0a1b2c3d-0000-4000-8000-123456789abc";
+        let metadata = bound_luks().metadata.borrow().clone();
+
+        let bound = luks2_header(&metadata, uuid);
+        assert_eq!(
+            bound_uuid_with(&mut bound.as_slice()).unwrap().as_deref(),
+            Some(uuid)
+        );
+        let unbound = luks2_header(&MockLuks::new(&[0]).metadata.borrow(), uuid);
+        assert!(bound_uuid_with(&mut unbound.as_slice()).unwrap().is_none());
+        let mut luks1 = bound.clone();
+        luks1[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert!(bound_uuid_with(&mut luks1.as_slice()).unwrap().is_none());
+        let forged = luks2_header(&metadata, "../../etc");
+        assert!(bound_uuid_with(&mut forged.as_slice()).is_err());
+        let mut oversized = bound.clone();
+        oversized[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(bound_uuid_with(&mut oversized.as_slice()).is_err());
+    }
+}
@@ -2,1041 +2,1299 @@
 //
 // SPDX-License-Identifier: MIT
 
-use anyhow::{Context, Result, anyhow};
-use base64::{Engine as _, engine::general_purpose};
-use clap::{Parser, Subcommand};
-use clevis_pin_trustee_lib::*;
-use josekit::jwe::alg::direct::DirectJweAlgorithm::Dir;
-use josekit::jwk::Jwk;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+mod man;
+
+use anyhow::{Context, Result, bail};
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use clevis_pin_trustee::logging::{self, LogTarget};
+use clevis_pin_trustee::luks::{self, ExistingKey, UnlockState};
+#[cfg(feature = "mock-server")]
+use clevis_pin_trustee::mock_server;
+use clevis_pin_trustee::passphrase::{self, PassphraseOptions};
+use clevis_pin_trustee::progress::NdjsonProgress;
+use clevis_pin_trustee::systemd::{self, Failure};
+use clevis_pin_trustee::wizard::generate_config;
+use clevis_pin_trustee::{
+    BindingUpdate, ConfigOptions, InitdataField, bench, collect_evidence, decrypt, encrypt,
+    encrypt_dry_run, encrypt_with_local_key, initdata_digest, override_url_from_env,
+    read_runtime_config, reencrypt, self_test, telemetry, update_binding,
+};
+use clevis_pin_trustee::{admin, agent, armor, memory, verify};
+use clevis_pin_trustee_lib::{
+    Cert, EventHandler, NoEvents, NumRetries, RuntimeConfig, Server, ServerSelection,
+    config_schema, header_schema, parse_duration, runtime_config_schema, set_verbose_debug,
+};
+use serde_json::{Value, json};
+use std::cell::Cell;
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::path::Path;
-use std::process::Command as StdCommand;
-use std::time::Duration;
-use std::{fs, thread};
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-const DEFAULT_TRIES: u32 = 10;
-const DELAY: Duration = Duration::from_secs(5);
+/// URL replacing the ones of the bindings, as `--override-url`, for the
+/// clevis decrypt and unlock scripts that cannot pass flags
+const OVERRIDE_URL_ENV: &str = "CLEVIS_TRUSTEE_OVERRIDE_URL";
 
-// TPM constants
-const TPM_DIR: &str = "/var/tpm";
-const AK_PATH: &str = "/var/tpm/ak.pub";
-const AK_CTX_PATH: &str = "/var/tpm/ak.ctx";
-const AK_REGISTERD: &str = "/var/tpm/ak.registerd";
-const AK_HANDLE: &str = "0x81010002";
-const EK_HANDLE: &str = "0x81010001";
+/// Clevis PIN for Trustee
+#[derive(Parser)]
+#[command(name = "clevis-pin-trustee")]
+#[command(version = "0.1.0")]
+#[command(about = "Clevis PIN for Trustee")]
+#[command(
+    after_long_help = "Exit status: 0 on success, 75 when the key fetch gave up on failures that \
+                       may go away, such as unreachable servers, 77 when it failed for good, \
+                       such as a denied attestation or a bad configuration, 1 otherwise."
+)]
+struct Cli {
+    /// Log debug messages, and certificates, initdata and keys in full
+    #[arg(long, global = true)]
+    verbose: bool,
+    /// Where to log the progress of the key fetch
+    #[arg(long, global = true, value_enum, default_value_t)]
+    log_target: LogTarget,
+    /// Log the HTTP requests to the KBS of the native and attestation-agent
+    /// backends and their responses, with credentials redacted
+    #[arg(long, global = true)]
+    trace_http: bool,
+    /// Format of the results of status, report, bench, bind and the other
+    /// commands; messages still go to stderr
+    #[arg(long, global = true, value_enum, default_value_t)]
+    output_format: OutputFormat,
+    /// Report the progress of the key fetch of encrypt, decrypt, reencrypt,
+    /// unlock and unlock-all on stderr
+    #[arg(long, global = true, value_enum, default_value_t)]
+    progress: Progress,
+    #[command(subcommand)]
+    command: Commands,
+}
 
-/// Trait for executing commands to fetch LUKS keys
-trait CommandExecutor {
-    fn try_fetch_luks_key(
-        &self,
-        url: &str,
-        path: &str,
-        cert: &str,
-        initdata: Option<String>,
-    ) -> Result<String>;
+/// How the results of the commands are printed
+#[derive(Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+enum OutputFormat {
+    #[default]
+    Text,
+    /// One JSON document on stdout, `{"error": ...}` on failure
+    Json,
 }
 
-/// Trait for generating attestation keys
-trait AttestationKeyGeneratorTrait {
-    fn generate_attestation_key(&self) -> Result<String>;
+/// How the progress of the key fetch is reported
+#[derive(Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+enum Progress {
+    /// Only in the log
+    #[default]
+    None,
+    /// One JSON event per attempt and per server result on stderr
+    Ndjson,
 }
 
-/// Response wrapper to make HTTP responses mockable
-struct HttpResponse {
-    status_code: u16,
+/// Results printed on stdout, as text or as the JSON document of the command
+struct Output {
+    format: OutputFormat,
+    /// Whether the JSON document was printed, failures included
+    printed: Cell<bool>,
 }
 
-impl HttpResponse {
-    fn is_success(&self) -> bool {
-        self.status_code >= 200 && self.status_code < 300
+impl Output {
+    fn new(format: OutputFormat) -> Self {
+        Output {
+            format,
+            printed: Cell::new(false),
+        }
     }
 
-    fn status_code(&self) -> u16 {
-        self.status_code
+    fn is_json(&self) -> bool {
+        self.format == OutputFormat::Json
     }
-}
 
-/// Trait for HTTP client operations
-trait HttpClient {
-    fn put_json(&self, url: &str, payload: &RegistrationPayload) -> Result<HttpResponse>;
-}
-
-/// Trait for filesystem operations
-trait FileSystem {
-    fn write_marker(&self, path: &str) -> Result<()>;
-}
-
-/// Real implementation that calls the trustee-attester binary
-struct RealCommandExecutor;
+    /// Print the JSON document of the command
+    fn json(&self, value: Value) -> Result<()> {
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        self.printed.set(true);
+        Ok(())
+    }
 
-impl CommandExecutor for RealCommandExecutor {
-    fn try_fetch_luks_key(
-        &self,
-        url: &str,
-        path: &str,
-        cert: &str,
-        initdata: Option<String>,
-    ) -> Result<String> {
-        let mut command = StdCommand::new("trustee-attester");
-        if !cert.is_empty() {
-            // Create a unique filename based on the URL
-            let url_sanitized = url.replace("://", "_").replace("/", "_").replace(":", "_");
-            let cert_path = format!("/run/trustee/cert_{}.pem", url_sanitized);
-            let cert_path_obj = Path::new(&cert_path);
-            if let Some(parent) = cert_path_obj.parent() {
-                fs::create_dir_all(parent)?;
+    /// Print the text of `text` or the JSON document `value`
+    fn print(&self, text: impl FnOnce() -> String, value: impl FnOnce() -> Value) -> Result<()> {
+        match self.format {
+            OutputFormat::Text => {
+                print!("{}", text());
+                Ok(())
             }
-            fs::write(&cert_path, cert)?;
-            command.arg("--cert-file").arg(&cert_path);
+            OutputFormat::Json => self.json(value()),
         }
-        command
-            .arg("--url")
-            .arg(url)
-            .arg("get-resource")
-            .arg("--path")
-            .arg(path);
-        if let Some(initdata_str) = initdata {
-            command.arg("--initdata").arg(initdata_str);
-        }
-        let output = command
-            .output()
-            .map_err(|e| anyhow!("Failed to execute trustee-attester: {}", e))?;
-
-        io::stderr().write_all(&output.stderr)?;
-        io::stderr().write_all(&output.stdout)?;
-
-        if !output.status.success() {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            return Err(anyhow!("trustee-attester failed: {}", stderr));
-        }
-
-        let key = String::from_utf8(output.stdout)
-            .map_err(|e| anyhow!("Invalid UTF-8 for the LUKS key: {}", e))?
-            .trim()
-            .to_string();
-
-        if key.is_empty() {
-            return Err(anyhow!("Received empty LUKS key"));
-        }
-
-        Ok(key)
-    }
-}
-
-/// Real implementation that generates attestation keys using TPM
-struct AttestationKeyGenerator;
-
-impl AttestationKeyGeneratorTrait for AttestationKeyGenerator {
-    fn generate_attestation_key(&self) -> Result<String> {
-        generate_attestation_key()
     }
-}
-
-/// Real HTTP client wrapper
-struct RealHttpClient {
-    client: reqwest::blocking::Client,
-}
 
-impl HttpClient for RealHttpClient {
-    fn put_json(&self, url: &str, payload: &RegistrationPayload) -> Result<HttpResponse> {
-        let response = self
-            .client
-            .put(url)
-            .json(payload)
-            .send()
-            .map_err(|e| anyhow!("Failed to send PUT request: {}", e))?;
-
-        Ok(HttpResponse {
-            status_code: response.status().as_u16(),
-        })
+    /// Report the failure of the command in the JSON document, unless the
+    /// command already printed it
+    fn finish(&self, result: Result<()>) -> Result<()> {
+        if let Err(e) = &result
+            && self.is_json()
+            && !self.printed.get()
+        {
+            println!("{}", json!({"error": format!("{:#}", e)}));
+        }
+        result
     }
 }
 
-/// Real filesystem implementation
-struct RealFileSystem;
-
-impl FileSystem for RealFileSystem {
-    fn write_marker(&self, path: &str) -> Result<()> {
-        fs::write(path, "").context("Failed to write marker file")
-    }
+/// `items` as a JSON array
+fn json_list<T>(items: &[T], to_json: impl Fn(&T) -> Value) -> Value {
+    Value::Array(items.iter().map(to_json).collect())
 }
 
-#[cfg(test)]
-pub struct MockCommandExecutor {
-    pub response: Result<String>,
+/// Servers to use, by `name` or URL, e.g. to find out which replica misbehaves
+#[derive(clap::Args)]
+struct ServerArgs {
+    /// Only use this server, can be repeated
+    #[arg(long, value_name = "NAME")]
+    only_server: Vec<String>,
+    /// Do not use this server, can be repeated
+    #[arg(long, value_name = "NAME")]
+    skip_server: Vec<String>,
 }
 
-#[cfg(test)]
-impl CommandExecutor for MockCommandExecutor {
-    fn try_fetch_luks_key(
-        &self,
-        _url: &str,
-        _path: &str,
-        _cert: &str,
-        _initdata: Option<String>,
-    ) -> Result<String> {
-        match &self.response {
-            Ok(key) => Ok(key.clone()),
-            Err(e) => Err(anyhow!("{}", e)),
+impl From<ServerArgs> for ServerSelection {
+    fn from(args: ServerArgs) -> Self {
+        ServerSelection {
+            only: args.only_server,
+            skip: args.skip_server,
         }
     }
 }
 
-#[cfg(test)]
-pub struct MockAttestationKeyGenerator {
-    pub response: Result<String>,
-}
-
-#[cfg(test)]
-impl AttestationKeyGeneratorTrait for MockAttestationKeyGenerator {
-    fn generate_attestation_key(&self) -> Result<String> {
-        match &self.response {
-            Ok(key) => Ok(key.clone()),
-            Err(e) => Err(anyhow!("{}", e)),
-        }
-    }
+/// `--retries` value: a number of attempts, `infinity` or `none`
+fn parse_num_retries(value: &str) -> Result<NumRetries, String> {
+    let value = match value.parse::<i64>() {
+        Ok(number) => serde_json::Value::from(number),
+        Err(_) => serde_json::Value::from(value),
+    };
+    serde_json::from_value(value).map_err(|e| e.to_string())
 }
 
-#[cfg(test)]
-pub struct MockHttpClient {
-    pub responses: Vec<Result<u16>>,
-    pub call_count: std::cell::RefCell<usize>,
+/// `--delay` value, kept as given like the `retry_delay` of the config file
+fn parse_delay(value: &str) -> Result<String, String> {
+    parse_duration(value)?;
+    Ok(value.to_string())
 }
 
-#[cfg(test)]
-impl HttpClient for MockHttpClient {
-    fn put_json(&self, _url: &str, _payload: &RegistrationPayload) -> Result<HttpResponse> {
-        let mut count = self.call_count.borrow_mut();
-        let index = *count;
-        *count += 1;
-
-        if index >= self.responses.len() {
-            return Err(anyhow!("No more mock responses available"));
-        }
-
-        match &self.responses[index] {
-            Ok(status_code) => Ok(HttpResponse {
-                status_code: *status_code,
-            }),
-            Err(e) => Err(anyhow!("{}", e)),
-        }
-    }
+/// Document described by the `schema` subcommand
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum SchemaKind {
+    /// Configuration given to encrypt, bind and the other binding commands
+    #[default]
+    Config,
+    /// Runtime settings given with `decrypt --config-file`
+    Runtime,
+    /// Clevis header stored in the JWE
+    Header,
 }
 
-#[cfg(test)]
-pub struct MockFileSystem {
-    pub write_result: Result<()>,
-    pub written: std::cell::RefCell<Vec<String>>,
+#[derive(Subcommand)]
+enum Commands {
+    /// Encrypt data using the configuration
+    Encrypt {
+        /// Input data or arguments
+        config: String,
+        #[command(flatten)]
+        options: ConfigOptions,
+        /// Attest and fetch the key, then print the JWE header instead of
+        /// encrypting the input
+        #[arg(long)]
+        dry_run: bool,
+        /// Read the plaintext from this inherited file descriptor instead
+        /// of stdin
+        #[arg(long, value_name = "N", conflicts_with = "dry_run")]
+        input_fd: Option<RawFd>,
+        /// Wrap the JWE in BEGIN/END CLEVIS TRUSTEE TOKEN lines, folded to
+        /// be pasted safely; decrypt reads both forms
+        #[arg(long, conflicts_with = "dry_run")]
+        armor: bool,
+        /// Encrypt with the key in this file instead of fetching it, the
+        /// same key being uploaded to the KBS at the path of the config, e.g.
+        /// to build images on a host that cannot attest
+        #[arg(long, value_name = "PATH", conflicts_with = "dry_run")]
+        local_key: Option<PathBuf>,
+    },
+    /// Decrypt the input data
+    Decrypt {
+        /// JSON file with servers, retries, backend and attester path
+        /// overriding the ones stored in the binding
+        #[arg(long, conflicts_with = "agent")]
+        config_file: Option<PathBuf>,
+        /// Number of attempts, `infinity` or `none`, replacing the retries of
+        /// the binding and of the config file, e.g. for a manual recovery
+        #[arg(long, value_parser = parse_num_retries, conflicts_with = "agent")]
+        retries: Option<NumRetries>,
+        /// Delay between attempts, e.g. `1s`, replacing the one of the
+        /// config file; a retry schedule keeps its own delays
+        #[arg(long, value_parser = parse_delay, conflicts_with = "agent")]
+        delay: Option<String>,
+        /// Record the fetch attempts, without the key, as JSON lines to this
+        /// file to debug a failure offline
+        #[arg(long, value_name = "PATH", conflicts_with = "agent")]
+        record: Option<PathBuf>,
+        /// Replay the fetch attempts of a recording instead of contacting
+        /// the servers
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["agent", "record"])]
+        replay: Option<PathBuf>,
+        /// Fetch from this URL, with the certificate and settings of the
+        /// first server of the binding, instead of the stale URLs of the
+        /// header, e.g. after the KBS moved; CLEVIS_TRUSTEE_OVERRIDE_URL
+        /// otherwise
+        #[arg(long, value_name = "URL", conflicts_with = "agent")]
+        override_url: Option<String>,
+        /// Have the agent listening on this socket decrypt instead
+        #[arg(
+            long,
+            num_args = 0..=1,
+            default_missing_value = agent::AGENT_SOCKET,
+            conflicts_with_all = ["only_server", "skip_server"]
+        )]
+        agent: Option<PathBuf>,
+        #[command(flatten)]
+        servers: ServerArgs,
+    },
+    /// Re-encrypt the input JWE with the current key if the key was rotated
+    /// on the KBS since binding, printing the input unchanged otherwise
+    Reencrypt {
+        /// Resource path where the KBS keeps the previous key, to decrypt
+        /// the payload after a rotation
+        #[arg(long)]
+        previous_path: Option<String>,
+        /// JSON file with settings overriding the ones of the binding, as
+        /// for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+    },
+    /// Stay resident and serve decrypt requests on a unix socket, keeping
+    /// attestation sessions across requests
+    Agent {
+        /// Socket to listen on
+        #[arg(long, default_value = agent::AGENT_SOCKET)]
+        socket: PathBuf,
+        /// JSON file with settings overriding the ones of every binding, as
+        /// for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+    },
+    /// Fetch the key from every server several times and report the latency
+    Bench {
+        /// Configuration, as for encrypt
+        config: String,
+        #[command(flatten)]
+        options: ConfigOptions,
+        /// Number of fetches from each server
+        #[arg(short = 'n', long, default_value_t = 10)]
+        cycles: u32,
+        /// JSON file with servers, backend and attester path overriding the
+        /// ones of the configuration, as for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+        #[command(flatten)]
+        servers: ServerArgs,
+    },
+    /// Bind a LUKS2 device: add a random passphrase to a keyslot and store
+    /// it, encrypted with the trustee pin, in a LUKS2 token
+    Bind {
+        /// LUKS2 device to bind
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Configuration, as for encrypt
+        config: String,
+        /// File with an existing passphrase of the device, prompted otherwise
+        #[arg(short = 'k', long)]
+        key_file: Option<PathBuf>,
+        /// Keyslot for the new passphrase, the first free one by default
+        #[arg(short = 's', long)]
+        slot: Option<u32>,
+        #[command(flatten)]
+        options: ConfigOptions,
+        #[command(flatten)]
+        passphrase: PassphraseOptions,
+    },
+    /// Remove the trustee binding of a LUKS2 device and wipe its keyslot
+    Unbind {
+        /// LUKS2 device to unbind
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Keyslot of the binding, needed when there are several of them
+        #[arg(short = 's', long)]
+        slot: Option<u32>,
+        /// Wipe the keyslot without decrypting the binding first, e.g. when
+        /// the servers are unreachable
+        #[arg(short = 'f', long)]
+        force: bool,
+        /// Wipe the keyslot even when it is the last one of the device,
+        /// leaving it impossible to unlock
+        #[arg(long)]
+        allow_last_keyslot: bool,
+        /// JSON file with settings overriding the ones of the binding, as
+        /// for decrypt
+        #[arg(long, conflicts_with = "force")]
+        config_file: Option<PathBuf>,
+    },
+    /// Replace the servers, certificate or resource path of a trustee
+    /// binding, keeping its key, e.g. after the KBS moved; without a device,
+    /// of the JWE read from stdin
+    UpdateBinding {
+        /// LUKS2 device whose binding to update
+        #[arg(short = 'd', long)]
+        device: Option<String>,
+        /// Keyslot of the binding, needed when there are several of them
+        #[arg(short = 's', long, requires = "device")]
+        slot: Option<u32>,
+        /// URL of a server replacing the ones of the binding, repeated for
+        /// each server
+        #[arg(long = "server", value_name = "URL")]
+        servers: Vec<String>,
+        /// PEM certificate of the servers, stored in the binding
+        #[arg(long, value_name = "PATH")]
+        cert: Option<PathBuf>,
+        /// Resource path of the key on the servers
+        #[arg(long)]
+        path: Option<String>,
+        /// JSON file with settings for fetching the key, as for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+    },
+    /// Print the JWE of the trustee binding of a LUKS2 device, read from its
+    /// LUKS2 token
+    ReadToken {
+        /// LUKS2 device to read
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Keyslot of the binding, needed when there are several of them
+        #[arg(short = 's', long)]
+        slot: Option<u32>,
+    },
+    /// Store the JWE read from stdin, such as the output of encrypt for the
+    /// passphrase of a keyslot, in a LUKS2 token for that keyslot
+    WriteToken {
+        /// LUKS2 device to write
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Keyslot whose passphrase the JWE protects
+        #[arg(short = 's', long)]
+        slot: u32,
+    },
+    /// Bind a LUKS2 device bound with another clevis pin, such as tang or
+    /// tpm2, to the trustee pin, unlocking it with the old binding
+    Migrate {
+        /// LUKS2 device to migrate
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Configuration, as for encrypt
+        config: String,
+        /// Keyslot of the old binding, needed when there are several of them
+        #[arg(short = 's', long)]
+        slot: Option<u32>,
+        /// Remove the old binding once the trustee one is in place
+        #[arg(long)]
+        remove_old: bool,
+        #[command(flatten)]
+        options: ConfigOptions,
+        #[command(flatten)]
+        passphrase: PassphraseOptions,
+    },
+    /// List the trustee bindings of LUKS2 devices and whether their servers
+    /// are reachable
+    Status {
+        /// Devices to inspect, every LUKS device by default
+        devices: Vec<String>,
+    },
+    /// Open a LUKS2 device with its trustee binding, as the units of
+    /// clevis-trustee-generator do
+    Unlock {
+        /// LUKS2 device to open
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Name of the mapping in /dev/mapper
+        #[arg(short = 'n', long)]
+        name: String,
+        /// JSON file with settings overriding the ones of the binding, as
+        /// for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+        /// Number of attempts, as for decrypt
+        #[arg(long, value_parser = parse_num_retries)]
+        retries: Option<NumRetries>,
+        /// Delay between attempts, as for decrypt
+        #[arg(long, value_parser = parse_delay)]
+        delay: Option<String>,
+        /// Record the fetch attempts, as for decrypt
+        #[arg(long, value_name = "PATH")]
+        record: Option<PathBuf>,
+        /// Replay the fetch attempts of a recording, as for decrypt
+        #[arg(long, value_name = "PATH", conflicts_with = "record")]
+        replay: Option<PathBuf>,
+        /// Fetch from this URL instead of the ones of the binding, as for
+        /// decrypt
+        #[arg(long, value_name = "URL")]
+        override_url: Option<String>,
+        #[command(flatten)]
+        servers: ServerArgs,
+    },
+    /// Open every trustee-bound volume of a crypttab-like manifest,
+    /// attesting once for all of them
+    UnlockAll {
+        /// Volumes to open, one "name device [keyfile] [options]" per line
+        #[arg(short = 'm', long, default_value = "/etc/crypttab")]
+        manifest: PathBuf,
+        /// JSON file with settings overriding the ones of every binding, as
+        /// for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+    },
+    /// Print the trustee bindings of a LUKS2 device with their pin
+    /// configuration, in the format of `clevis luks list`
+    Report {
+        /// LUKS2 device to inspect
+        #[arg(short = 'd', long)]
+        device: String,
+        /// Keyslot of the binding to show, all of them by default
+        #[arg(short = 's', long)]
+        slot: Option<u32>,
+    },
+    /// Encrypt and decrypt a random payload to check the whole round trip
+    SelfTest {
+        /// Configuration, as for encrypt
+        config: String,
+        #[command(flatten)]
+        options: ConfigOptions,
+        /// JSON file with settings overriding the ones of the binding, as
+        /// for decrypt
+        #[arg(long)]
+        config_file: Option<PathBuf>,
+        #[command(flatten)]
+        servers: ServerArgs,
+    },
+    /// Compare the binding of the JWE read from stdin with the one the
+    /// configuration would make now and report the differences, such as
+    /// stale servers or changed certificates
+    VerifyBinding {
+        /// Configuration, as for encrypt
+        config: String,
+        #[command(flatten)]
+        options: ConfigOptions,
+    },
+    /// Print the attestation request, with the TEE evidence, that the native
+    /// backend would send, without contacting a KBS, to debug rejections
+    Evidence {
+        /// Configuration, as for encrypt, for its initdata and KBS protocol
+        /// version
+        config: Option<String>,
+        #[command(flatten)]
+        options: ConfigOptions,
+        /// Nonce of the KBS challenge the evidence is bound to, random by
+        /// default
+        #[arg(long)]
+        nonce: Option<String>,
+        /// Write the request to this file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Upload a rego resource policy to every server of the configuration,
+    /// authenticating with the admin key of the KBS
+    SetPolicy {
+        /// Configuration, as for encrypt
+        config: String,
+        #[command(flatten)]
+        options: ConfigOptions,
+        /// Rego policy file
+        #[arg(long, value_name = "PATH")]
+        policy_file: PathBuf,
+        /// Ed25519 private key (PKCS#8 PEM) whose public key is in the admin
+        /// section of the KBS config
+        #[arg(long, value_name = "PATH")]
+        admin_key: PathBuf,
+    },
+    /// Serve the files of a directory as KBS resources over HTTPS with a
+    /// generated certificate, accepting any attestation, to test round
+    /// trips without a Trustee deployment
+    #[cfg(feature = "mock-server")]
+    MockServer {
+        /// Directory of the resources, `default/key/root` being served from
+        /// DIR/default/key/root
+        #[arg(long, value_name = "DIR")]
+        resources: PathBuf,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Write the certificate to this file instead of stdout
+        #[arg(long, value_name = "PATH")]
+        cert_out: Option<PathBuf>,
+    },
+    /// Print a random passphrase from the OS RNG, as bind adds to the
+    /// keyslot
+    GeneratePassphrase {
+        #[command(flatten)]
+        passphrase: PassphraseOptions,
+    },
+    /// Print the digest of the initdata as the attestation service computes
+    /// it, to register the expected value in the attestation policy
+    InitdataDigest {
+        /// Initdata, as the JSON object of the config, a JSON list of
+        /// fragments or a TOML initdata document
+        initdata: String,
+        /// Pad the digest to the size of this field of the TEE evidence
+        #[arg(long, value_enum)]
+        field: Option<InitdataField>,
+    },
+    /// Ask for the servers, trusting their certificates on confirmation,
+    /// the resource path and the initdata, and print the resulting config
+    GenerateConfig,
+    /// Print the JSON Schema of the configuration, to validate it before
+    /// deployment
+    Schema {
+        #[arg(value_enum, default_value_t)]
+        kind: SchemaKind,
+    },
+    /// Print the shell completion script for the given shell
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Write man pages for the CLI and the config format to a directory
+    #[command(hide = true)]
+    GenerateMan { out_dir: PathBuf },
 }
 
-#[cfg(test)]
-impl FileSystem for MockFileSystem {
-    fn write_marker(&self, path: &str) -> Result<()> {
-        self.written.borrow_mut().push(path.to_string());
-        match &self.write_result {
-            Ok(()) => Ok(()),
-            Err(e) => Err(anyhow!("{}", e)),
-        }
+impl Commands {
+    /// Whether the command fetches keys or decrypts secrets
+    fn handles_secrets(&self) -> bool {
+        !matches!(
+            self,
+            Commands::VerifyBinding { .. }
+                | Commands::Evidence { .. }
+                | Commands::SetPolicy { .. }
+                | Commands::InitdataDigest { .. }
+                | Commands::Status { .. }
+                | Commands::Report { .. }
+                | Commands::ReadToken { .. }
+                | Commands::WriteToken { .. }
+                | Commands::GenerateConfig
+                | Commands::Schema { .. }
+                | Commands::Completions { .. }
+                | Commands::GenerateMan { .. }
+        )
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ClevisHeader {
-    pin: String,
-    servers: Vec<Server>,
-    path: String,
-    initdata: Option<String>,
-    #[serde(default)]
-    num_retries: Option<NumRetries>,
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    Ok(input)
 }
 
-fn fetch_and_prepare_jwk<E: CommandExecutor>(
-    servers: &[Server],
-    path: &str,
-    initdata: Option<String>,
-    num_retries: &NumRetries,
-    executor: &E,
-) -> Result<Jwk> {
-    let key = fetch_luks_key(servers, path, initdata, num_retries, executor)?;
-    let key = String::from_utf8(
-        general_purpose::STANDARD
-            .decode(&key)
-            .context("Error decoding key in base64")?,
-    )
-    .context("Error decoding the key in JSON")?;
-    eprintln!("Key: {:?}", key);
-    let key: Key = serde_json::from_str(&key).context("Error in parsing the fetched key")?;
-
-    let mut jwk = Jwk::new(&key.key_type);
-    jwk.set_key_value(&key.key);
-    jwk.set_key_operations(vec!["encrypt", "decrypt"]);
-
-    Ok(jwk)
+/// Read stdin up to one byte past `limit`, enough for decrypt to refuse
+/// an oversized JWE without reading all of it
+fn read_stdin_within(limit: usize) -> Result<Vec<u8>> {
+    let mut input = Vec::new();
+    io::stdin()
+        .take(limit.saturating_add(1) as u64)
+        .read_to_end(&mut input)?;
+    Ok(input)
 }
 
-fn generate_attestation_key() -> Result<String> {
-    fs::create_dir_all(TPM_DIR)
-        .with_context(|| format!("couldn't create {} directory", TPM_DIR))?;
-
-    if Path::new(AK_PATH).exists() {
-        eprintln!("Attestation Key already exists, skipping generation");
-        return fs::read_to_string(AK_PATH).context("Failed to read existing attestation key");
-    }
-
-    eprintln!("Generating Attestation Key");
-
-    // Generate attestation key using tpm2_createak
-    let output = StdCommand::new("tpm2_createak")
-        .args([
-            "-C",
-            EK_HANDLE,
-            "-c",
-            AK_CTX_PATH,
-            "-G",
-            "rsa",
-            "-g",
-            "sha256",
-            "-s",
-            "rsassa",
-            "-u",
-            AK_PATH,
-            "-f",
-            "pem",
-        ])
-        .output()
-        .context("Failed to execute tpm2_createak command")?;
-
-    io::stderr().write_all(&output.stderr)?;
-    io::stderr().write_all(&output.stdout)?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("failed to create attestation key: {}", stderr));
+/// Read all of the inherited file descriptor `fd`, closing it
+fn read_fd(fd: RawFd) -> Result<Vec<u8>> {
+    // Reading stdin, stdout or stderr this way would close it
+    if (0..=2).contains(&fd) {
+        bail!(
+            "File descriptor {} is a standard stream, read stdin without --input-fd instead",
+            fd
+        );
     }
-
-    eprintln!("Persisting Attestation Key");
-
-    // Persist attestation key using tpm2_evictcontrol
-    let output = StdCommand::new("tpm2_evictcontrol")
-        .args(["-c", AK_CTX_PATH, AK_HANDLE])
-        .output()
-        .context("Failed to execute tpm2_evictcontrol command")?;
-
-    io::stderr().write_all(&output.stderr)?;
-    io::stderr().write_all(&output.stdout)?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        return Err(anyhow!("failed to persist attestation key: {}", stderr));
+    // SAFETY: fcntl only queries the flags of the descriptor
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("File descriptor {} is not open", fd));
     }
-
-    // Read and return the generated attestation key
-    fs::read_to_string(AK_PATH).context("Failed to read generated attestation key")
+    // SAFETY: the descriptor is open, is not a standard stream and was handed
+    // to us to read, nothing else in the process uses it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    let mut input = Vec::new();
+    file.read_to_end(&mut input)
+        .with_context(|| format!("Failed to read file descriptor {}", fd))?;
+    Ok(input)
 }
 
-fn attestation_key_handle_with_deps<G, C, F>(
-    attestation_key: &Option<AttestationKey>,
-    generator: &G,
-    http_client_factory: C,
-    filesystem: &F,
-) -> Result<()>
-where
-    G: AttestationKeyGeneratorTrait,
-    C: Fn(&str) -> Result<Box<dyn HttpClient>>,
-    F: FileSystem,
-{
-    if attestation_key.is_none() {
-        return Ok(());
-    }
-
-    let key_config = attestation_key.as_ref().unwrap();
-    let generated_key = generator.generate_attestation_key()?;
-
-    if !key_config.registration.url.is_empty() {
-        let payload = RegistrationPayload {
-            attestation_key: generated_key,
-            uuid: key_config.registration.uuid.clone(),
-        };
-
-        let client = http_client_factory(&key_config.registration.cert)?;
-
-        let mut last_error = None;
-        for attempt in 1..=DEFAULT_TRIES {
-            eprintln!(
-                "Attempting to register attestation key (attempt {}/{})",
-                attempt, DEFAULT_TRIES
-            );
-
-            match client.put_json(&key_config.registration.url, &payload) {
-                Ok(response) => {
-                    if response.is_success() {
-                        eprintln!("Attestation key registered successfully.");
-
-                        // Create the registered marker file
-                        filesystem.write_marker(AK_REGISTERD)?;
-
-                        return Ok(());
-                    } else {
-                        last_error = Some(anyhow!(
-                            "Attestation key registration failed with status: {}",
-                            response.status_code()
-                        ));
-                        eprintln!(
-                            "Registration attempt {} failed with status: {}",
-                            attempt,
-                            response.status_code()
-                        );
-                    }
-                }
-                Err(e) => {
-                    last_error = Some(anyhow!(
-                        "Failed to send PUT request for attestation key registration: {}",
-                        e
-                    ));
-                    eprintln!("Registration attempt {} failed: {}", attempt, e);
-                }
-            }
-
-            if attempt < DEFAULT_TRIES {
-                eprintln!("Retrying in {:?}...", DELAY);
-                thread::sleep(DELAY);
-            }
-        }
-
-        return Err(last_error.unwrap_or_else(|| {
-            anyhow!(
-                "Failed to register attestation key after {} attempts",
-                DEFAULT_TRIES
-            )
-        }));
+/// Record or replay the fetch attempts as asked on the command line, in place
+/// of what the config file asks for
+fn set_recording(runtime: &mut RuntimeConfig, record: Option<PathBuf>, replay: Option<PathBuf>) {
+    let path = |path: PathBuf| path.to_string_lossy().into_owned();
+    if record.is_some() || replay.is_some() {
+        runtime.record = record.map(path);
+        runtime.replay = replay.map(path);
     }
-
-    Ok(())
 }
 
-fn attestation_key_handle(attestation_key: &Option<AttestationKey>) -> Result<()> {
-    let generator = AttestationKeyGenerator;
-    let filesystem = RealFileSystem;
-    let http_client_factory = |cert: &str| -> Result<Box<dyn HttpClient>> {
-        let client = if cert.is_empty() {
-            reqwest::blocking::Client::new()
-        } else {
-            let cert = reqwest::Certificate::from_pem(cert.as_bytes())
-                .context("Failed to parse TLS certificate")?;
-            reqwest::blocking::Client::builder()
-                .add_root_certificate(cert)
-                .build()
-                .context("Failed to build HTTPS client")?
-        };
-        Ok(Box::new(RealHttpClient { client }))
+/// Fetch from the URL of `--override-url`, else of
+/// `CLEVIS_TRUSTEE_OVERRIDE_URL`, else of the config file
+fn set_override_url(runtime: &mut RuntimeConfig, override_url: Option<String>) -> Result<()> {
+    let override_url = match override_url {
+        Some(url) => Some(url),
+        None => override_url_from_env(OVERRIDE_URL_ENV)?,
     };
-
-    attestation_key_handle_with_deps(
-        attestation_key,
-        &generator,
-        http_client_factory,
-        &filesystem,
-    )
+    if let Some(url) = override_url {
+        runtime.override_url = Some(url);
+    }
+    Ok(())
 }
 
-fn encrypt(config: &str) -> Result<()> {
-    let config: Config =
-        serde_json::from_str(config).map_err(|e| anyhow!("Failed to parse config JSON: {}", e))?;
-
-    attestation_key_handle(&config.attestation_key)?;
-
-    let initdata_str = config.initdata.as_ref();
-    let initdata_data: Option<HashMap<String, String>> = initdata_str
-        .map(|s| {
-            serde_json::from_str(s).map_err(|e| anyhow!("Failed to parse config initdata: {e}"))
+/// The metadata given to update-binding, the certificate read from its file
+/// to be stored inline
+fn binding_update(
+    servers: Vec<String>,
+    cert: Option<PathBuf>,
+    path: Option<String>,
+) -> Result<BindingUpdate> {
+    let servers = (!servers.is_empty())
+        .then(|| {
+            servers
+                .into_iter()
+                .map(|url| serde_json::from_value(json!(url)))
+                .collect::<Result<Vec<Server>, _>>()
         })
-        .transpose()?;
-    let initdata = initdata_data
-        .map(|data| {
-            toml::to_string(&Initdata {
-                version: "0.1.0".to_string(),
-                algorithm: "sha256".to_string(),
-                data,
-            })
-            .map_err(|e| anyhow!("Failed to serialize initdata: {e}"))
+        .transpose()
+        .context("Invalid server")?;
+    let cert = cert
+        .map(|path| {
+            fs::read_to_string(&path)
+                .map(Cert::Inline)
+                .with_context(|| format!("Failed to read {}", path.display()))
         })
         .transpose()?;
+    Ok(BindingUpdate {
+        servers,
+        cert,
+        path,
+    })
+}
 
-    let mut input = Vec::new();
-    io::stdin().read_to_end(&mut input)?;
-
-    let executor = RealCommandExecutor;
-    let num_retries = config
-        .num_retries
-        .as_ref()
-        .unwrap_or(&NumRetries::Finite(DEFAULT_TRIES));
-    let jwk = fetch_and_prepare_jwk(
-        &config.servers,
-        &config.path,
-        initdata.clone(),
-        num_retries,
-        &executor,
-    )?;
-
-    eprintln!("{}", jwk);
-    let encrypter = Dir
-        .encrypter_from_jwk(&jwk)
-        .context("Error creating direct encrypter")?;
+fn read_runtime_config_file(path: Option<PathBuf>) -> Result<RuntimeConfig> {
+    match path {
+        Some(path) => read_runtime_config(&path),
+        None => Ok(RuntimeConfig::default()),
+    }
+}
 
-    let private_hdr = ClevisHeader {
-        pin: "trustee".to_string(),
-        servers: config.servers.clone(),
-        path: config.path,
-        initdata,
-        num_retries: config.num_retries,
+/// Exit with the code of `result`, telling transient failures from
+/// permanent ones for `Restart=on-failure` units, and report the failure to
+/// the service manager
+fn exit(result: Result<()>) -> ExitCode {
+    let Err(e) = result else {
+        return ExitCode::SUCCESS;
     };
-
-    let mut hdr = josekit::jwe::JweHeader::new();
-    hdr.set_algorithm("ECDH-ES");
-    hdr.set_content_encryption("A256GCM");
-    hdr.set_claim(
-        "clevis",
-        Some(serde_json::value::to_value(private_hdr).context("Error serializing private header")?),
-    )
-    .context("Error adding clevis claim")?;
-
-    let jwe_token = josekit::jwe::serialize_compact(&input, &hdr, &encrypter)
-        .context("Error serializing JWE token")?;
-
-    io::stdout()
-        .write_all(jwe_token.as_bytes())
-        .context("Error writing the token on stdout")?;
-    eprintln!("Encryption successful.");
-
-    Ok(())
+    eprintln!("Error: {:?}", e);
+    let failure = Failure::of(&e);
+    systemd::notify(&failure.status(&e));
+    ExitCode::from(failure.exit_code())
 }
 
-fn decrypt() -> Result<()> {
-    let mut input = Vec::new();
-    io::stdin().read_to_end(&mut input)?;
-    let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
-
-    let hdr = josekit::jwt::decode_header(input).context("Error decoding header")?;
-    let hdr_clevis = hdr.claim("clevis").context("Error getting clevis claim")?;
-    let hdr_clevis: ClevisHeader =
-        serde_json::from_value(hdr_clevis.clone()).context("Error deserializing clevis header")?;
-
-    eprintln!("Decrypt with header: {:?}", hdr_clevis);
-
-    let executor = RealCommandExecutor;
-    let num_retries = hdr_clevis
-        .num_retries
-        .as_ref()
-        .unwrap_or(&NumRetries::Finite(DEFAULT_TRIES));
-    let decrypter_jwk = fetch_and_prepare_jwk(
-        &hdr_clevis.servers,
-        &hdr_clevis.path,
-        hdr_clevis.initdata,
-        num_retries,
-        &executor,
-    )?;
-
-    let decrypter = Dir
-        .decrypter_from_jwk(&decrypter_jwk)
-        .context("Error creating decrypter")?;
-
-    let (payload, _) =
-        josekit::jwe::deserialize_compact(input, &decrypter).context("Error decrypting JWE")?;
-
-    io::stdout().write_all(&payload)?;
-
-    eprintln!("Decryption successful.");
-    Ok(())
+fn main() -> ExitCode {
+    exit(try_main())
 }
 
-fn try_fetch_from_servers<E: CommandExecutor>(
-    servers: &[Server],
-    path: &str,
-    initdata: &Option<String>,
-    executor: &E,
-) -> Option<String> {
-    for (index, server) in servers.iter().enumerate() {
-        eprintln!("Trying URL {}/{}: {}", index + 1, servers.len(), server.url);
-        match executor.try_fetch_luks_key(&server.url, path, &server.cert, initdata.clone()) {
-            Ok(key) => {
-                eprintln!("Successfully fetched LUKS key from URL: {}", server.url);
-                return Some(key);
-            }
-            Err(e) => {
-                eprintln!("Error with URL {}: {}", server.url, e);
-            }
-        }
+fn try_main() -> Result<()> {
+    let cli = Cli::parse();
+    set_verbose_debug(cli.verbose);
+    logging::set_debug(cli.verbose);
+    logging::set_log_target(cli.log_target);
+    logging::set_trace_http(cli.trace_http);
+    let _telemetry = telemetry::init()?;
+    if cli.command.handles_secrets() {
+        memory::protect_secrets();
     }
-    None
+    let output = Output::new(cli.output_format);
+    let events: Box<dyn EventHandler> = match cli.progress {
+        Progress::None => Box::new(NoEvents),
+        Progress::Ndjson => Box::new(NdjsonProgress::new()),
+    };
+    output.finish(run(cli.command, &output, events.as_ref()))
 }
 
-fn fetch_luks_key<E: CommandExecutor>(
-    servers: &[Server],
-    path: &str,
-    initdata: Option<String>,
-    num_retries: &NumRetries,
-    executor: &E,
-) -> Result<String> {
-    if servers.is_empty() {
-        return Err(anyhow!("No URLs provided"));
-    }
-
-    match num_retries {
-        NumRetries::Finite(max_attempts) => (1..=*max_attempts)
-            .find_map(|attempt| {
-                eprintln!(
-                    "Attempting to fetch LUKS key (attempt {}/{})",
-                    attempt, max_attempts
-                );
-
-                if let Some(key) = try_fetch_from_servers(servers, path, &initdata, executor) {
-                    return Some(Ok(key));
+fn run(command: Commands, output: &Output, events: &dyn EventHandler) -> Result<()> {
+    match command {
+        Commands::Encrypt {
+            config,
+            options,
+            dry_run: true,
+            ..
+        } => {
+            let header = encrypt_dry_run(&config, options)?;
+            let header = serde_json::to_string_pretty(&header)?;
+            println!("{}", header);
+            eprintln!("Dry run successful, nothing was encrypted.");
+        }
+        Commands::Encrypt {
+            config,
+            options,
+            input_fd,
+            armor,
+            local_key,
+            ..
+        } => {
+            let input = match input_fd {
+                Some(fd) => read_fd(fd)?,
+                None => read_stdin()?,
+            };
+            let mut jwe_token = match local_key {
+                Some(path) => {
+                    let key = fs::read(&path)
+                        .with_context(|| format!("Failed to read {}", path.display()))?;
+                    encrypt_with_local_key(&config, options, &key, &input)?
                 }
-
-                if attempt < *max_attempts {
+                None => encrypt(&config, options, &input, events)?,
+            };
+            if armor {
+                jwe_token = armor::armor(&jwe_token);
+            }
+            io::stdout()
+                .write_all(jwe_token.as_bytes())
+                .context("Error writing the token on stdout")?;
+            eprintln!("Encryption successful.");
+        }
+        Commands::Decrypt {
+            config_file,
+            retries,
+            delay,
+            record,
+            replay,
+            override_url,
+            agent: None,
+            servers,
+        } => {
+            let mut runtime = read_runtime_config_file(config_file)?;
+            runtime.selection = servers.into();
+            runtime.num_retries = retries.or(runtime.num_retries);
+            runtime.retry_delay = delay.or(runtime.retry_delay);
+            set_recording(&mut runtime, record, replay);
+            set_override_url(&mut runtime, override_url)?;
+            let limits = runtime.input_limits.clone().unwrap_or_default();
+            let input = read_stdin_within(limits.max_jwe_size)?;
+            let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+            io::stdout().write_all(&decrypt(input, &runtime, events)?)?;
+            eprintln!("Decryption successful.");
+        }
+        Commands::Decrypt {
+            agent: Some(socket),
+            ..
+        } => {
+            let input = read_stdin()?;
+            let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+            io::stdout().write_all(&agent::decrypt(&socket, &armor::dearmor(input))?)?;
+            eprintln!("Decryption successful.");
+        }
+        Commands::Reencrypt {
+            previous_path,
+            config_file,
+        } => {
+            let runtime = read_runtime_config_file(config_file)?;
+            let input = read_stdin()?;
+            let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+            let input = input.trim();
+            match reencrypt(input, previous_path.as_deref(), &runtime, events)? {
+                Some(jwe_token) => {
+                    io::stdout().write_all(jwe_token.as_bytes())?;
+                    eprintln!("Re-encrypted with the current key.");
+                }
+                None => {
+                    io::stdout().write_all(input.as_bytes())?;
+                    eprintln!("The key has not changed, nothing to do.");
+                }
+            }
+        }
+        Commands::Agent {
+            socket,
+            config_file,
+        } => agent::serve(&socket, &read_runtime_config_file(config_file)?)?,
+        Commands::Bench {
+            config,
+            options,
+            cycles,
+            config_file,
+            servers,
+        } => {
+            let mut runtime = read_runtime_config_file(config_file)?;
+            runtime.selection = servers.into();
+            let results = bench::bench(&config, options, cycles, &runtime)?;
+            output.print(
+                || bench::report(&results),
+                || json!({"servers": bench::report_json(&results)}),
+            )?;
+        }
+        Commands::Bind {
+            device,
+            config,
+            key_file,
+            slot,
+            options,
+            passphrase,
+        } => {
+            let existing = match key_file {
+                Some(path) => ExistingKey::File(path),
+                None => ExistingKey::Passphrase(
+                    rpassword::prompt_password("Enter existing LUKS passphrase: ")
+                        .context("Failed to read the passphrase")?,
+                ),
+            };
+            let slot = luks::bind(&device, &config, options, &existing, slot, &passphrase)?;
+            eprintln!("Bound {} to the trustee pin in keyslot {}.", device, slot);
+            if output.is_json() {
+                output.json(json!({"device": device, "keyslot": slot}))?;
+            }
+        }
+        Commands::Unbind {
+            device,
+            slot,
+            force,
+            allow_last_keyslot,
+            config_file,
+        } => {
+            let runtime = read_runtime_config_file(config_file)?;
+            let slot = luks::unbind(&device, slot, force, allow_last_keyslot, &runtime, events)?;
+            eprintln!(
+                "Removed the trustee binding of {} in keyslot {}.",
+                device, slot
+            );
+            if output.is_json() {
+                output.json(json!({"device": device, "keyslot": slot}))?;
+            }
+        }
+        Commands::UpdateBinding {
+            device,
+            slot,
+            servers,
+            cert,
+            path,
+            config_file,
+        } => {
+            let runtime = read_runtime_config_file(config_file)?;
+            let update = binding_update(servers, cert, path)?;
+            match device {
+                Some(device) => {
+                    let slot = luks::update_binding(&device, slot, &update, &runtime, events)?;
                     eprintln!(
-                        "All URLs failed for attempt {}. Retrying in {:?} seconds...",
-                        attempt, DELAY
+                        "Updated the trustee binding of {} in keyslot {}.",
+                        device, slot
                     );
-                    thread::sleep(DELAY);
+                    if output.is_json() {
+                        output.json(json!({"device": device, "keyslot": slot}))?;
+                    }
                 }
-                None
-            })
-            .unwrap_or_else(|| {
-                Err(anyhow!(
-                    "Failed to fetch the LUKS key from all URLs after {} attempts",
-                    max_attempts
-                ))
-            }),
-        NumRetries::Infinity => {
-            let mut attempt = 0;
-            loop {
-                attempt += 1;
-                eprintln!("Attempting to fetch LUKS key (attempt {})", attempt);
-
-                if let Some(key) = try_fetch_from_servers(servers, path, &initdata, executor) {
-                    return Ok(key);
+                None => {
+                    let input = read_stdin()?;
+                    let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+                    let jwe_token = update_binding(input.trim(), &update, &runtime, events)?;
+                    io::stdout().write_all(jwe_token.as_bytes())?;
+                    eprintln!("Binding updated.");
                 }
-
-                eprintln!(
-                    "All URLs failed for attempt {}. Retrying in {:?} seconds...",
-                    attempt, DELAY
-                );
-                thread::sleep(DELAY);
             }
         }
+        Commands::ReadToken { device, slot } => {
+            let jwe = luks::read_token(&device, slot)?;
+            output.print(|| format!("{}\n", jwe), || json!({"jwe": jwe}))?;
+        }
+        Commands::WriteToken { device, slot } => {
+            let input = read_stdin()?;
+            let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+            luks::write_token(&device, slot, &armor::dearmor(input))?;
+            eprintln!(
+                "Stored the JWE in a token of {} for keyslot {}.",
+                device, slot
+            );
+            if output.is_json() {
+                output.json(json!({"device": device, "keyslot": slot}))?;
+            }
+        }
+        Commands::Migrate {
+            device,
+            config,
+            slot,
+            remove_old,
+            options,
+            passphrase,
+        } => {
+            let migration =
+                luks::migrate(&device, &config, options, slot, remove_old, &passphrase)?;
+            eprintln!(
+                "Bound {} to the trustee pin in keyslot {}, from the {} binding in keyslot {}{}.",
+                device,
+                migration.new_slot,
+                migration.pin,
+                migration.old_slot,
+                if remove_old { " (removed)" } else { "" }
+            );
+            if output.is_json() {
+                let mut migrated = migration.to_json();
+                migrated["device"] = json!(device);
+                migrated["removed_old"] = json!(remove_old);
+                output.json(migrated)?;
+            }
+        }
+        Commands::Status { devices } => {
+            let bindings = luks::status(&devices)?;
+            if bindings.is_empty() {
+                eprintln!("No trustee bindings found.");
+            }
+            output.print(
+                || bindings.iter().map(ToString::to_string).collect(),
+                || json!({"bindings": json_list(&bindings, luks::BindingStatus::to_json)}),
+            )?;
+        }
+        Commands::Unlock {
+            device,
+            name,
+            config_file,
+            retries,
+            delay,
+            record,
+            replay,
+            override_url,
+            servers,
+        } => {
+            let mut runtime = read_runtime_config_file(config_file)?;
+            runtime.selection = servers.into();
+            runtime.num_retries = retries.or(runtime.num_retries);
+            runtime.retry_delay = delay.or(runtime.retry_delay);
+            set_recording(&mut runtime, record, replay);
+            set_override_url(&mut runtime, override_url)?;
+            let state = luks::unlock(&device, &name, &runtime, events)?;
+            match state {
+                UnlockState::NotBound => bail!("{} has no trustee binding", device),
+                UnlockState::AlreadyOpen => eprintln!("{} is already open.", name),
+                UnlockState::Opened => eprintln!("Opened {} as {}.", device, name),
+            }
+            if output.is_json() {
+                output.json(json!({"device": device, "name": name, "state": state.as_str()}))?;
+            }
+        }
+        Commands::UnlockAll {
+            manifest,
+            config_file,
+        } => {
+            let runtime = read_runtime_config_file(config_file)?;
+            let unlocks = luks::unlock_all(&manifest, &runtime, events)?;
+            let failed = unlocks
+                .iter()
+                .filter(|unlock| unlock.result.is_err())
+                .count();
+            for unlock in &unlocks {
+                eprintln!("{}", unlock);
+            }
+            let error = (failed > 0)
+                .then(|| format!("Failed to open {} of {} volumes", failed, unlocks.len()));
+            if output.is_json() {
+                let mut result = json!({"volumes": json_list(&unlocks, luks::Unlock::to_json)});
+                if let Some(error) = &error {
+                    result["error"] = json!(error);
+                }
+                output.json(result)?;
+            }
+            if let Some(error) = error {
+                bail!(error);
+            }
+        }
+        Commands::Report { device, slot } => {
+            let reports = luks::report(&device, slot)?;
+            output.print(
+                || reports.iter().map(ToString::to_string).collect(),
+                || json!({"bindings": json_list(&reports, luks::BindingReport::to_json)}),
+            )?;
+        }
+        Commands::SelfTest {
+            config,
+            options,
+            config_file,
+            servers,
+        } => {
+            let mut runtime = read_runtime_config_file(config_file)?;
+            runtime.selection = servers.into();
+            self_test(&config, options, &runtime)?;
+            eprintln!("Self-test successful.");
+            if output.is_json() {
+                output.json(json!({"success": true}))?;
+            }
+        }
+        Commands::VerifyBinding { config, options } => {
+            let input = read_stdin()?;
+            let input = std::str::from_utf8(&input).context("Input is not valid UTF-8")?;
+            let drifts = verify::verify_binding(input.trim(), &config, options)?;
+            let error = (!drifts.is_empty()).then(|| {
+                format!(
+                    "The binding differs from the config in {} ways",
+                    drifts.len()
+                )
+            });
+            output.print(
+                || drifts.iter().map(|drift| format!("{}\n", drift)).collect(),
+                || {
+                    let mut result = json!({"drifts": json_list(&drifts, verify::Drift::to_json)});
+                    if let Some(error) = &error {
+                        result["error"] = json!(error);
+                    }
+                    result
+                },
+            )?;
+            if let Some(error) = error {
+                bail!(error);
+            }
+            eprintln!("The binding matches the config.");
+        }
+        Commands::Evidence {
+            config,
+            options,
+            nonce,
+            output,
+        } => {
+            let evidence = collect_evidence(config.as_deref(), options, nonce.as_deref())?;
+            let evidence = serde_json::to_string_pretty(&evidence)?;
+            match output {
+                Some(path) => fs::write(&path, evidence + "\n")
+                    .with_context(|| format!("Failed to write {}", path.display()))?,
+                None => println!("{}", evidence),
+            }
+        }
+        Commands::SetPolicy {
+            config,
+            options,
+            policy_file,
+            admin_key,
+        } => {
+            let policy = fs::read_to_string(&policy_file)
+                .with_context(|| format!("Failed to read {}", policy_file.display()))?;
+            let key = fs::read_to_string(&admin_key)
+                .with_context(|| format!("Failed to read {}", admin_key.display()))?;
+            admin::set_policy(&config, options, &policy, &key)?;
+            eprintln!("Resource policy set.");
+            if output.is_json() {
+                output.json(json!({"success": true}))?;
+            }
+        }
+        #[cfg(feature = "mock-server")]
+        Commands::MockServer {
+            resources,
+            listen,
+            cert_out,
+        } => mock_server::serve(&listen, &resources, cert_out.as_deref())?,
+        Commands::GeneratePassphrase { passphrase } => {
+            let passphrase = passphrase::generate(&passphrase)?;
+            output.print(
+                || format!("{}\n", passphrase),
+                || json!({"passphrase": passphrase}),
+            )?;
+        }
+        Commands::InitdataDigest { initdata, field } => {
+            let digest = initdata_digest(&initdata, field)?;
+            output.print(|| format!("{}\n", digest), || json!({"digest": digest}))?;
+        }
+        Commands::GenerateConfig => {
+            let config = generate_config()?;
+            println!("{}", serde_json::to_string_pretty(&config)?);
+        }
+        Commands::Schema { kind } => {
+            let schema = match kind {
+                SchemaKind::Config => config_schema(),
+                SchemaKind::Runtime => runtime_config_schema(),
+                SchemaKind::Header => header_schema(),
+            };
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        }
+        Commands::GenerateMan { out_dir } => man::generate(&out_dir)?,
     }
-}
-
-/// Clevis PIN for Trustee
-#[derive(Parser)]
-#[command(name = "clevis-pin-trustee")]
-#[command(version = "0.1.0")]
-#[command(about = "Clevis PIN for Trustee")]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
-}
-
-#[derive(Subcommand)]
-enum Commands {
-    /// Encrypt data using the configuration
-    Encrypt {
-        /// Input data or arguments
-        config: String,
-    },
-    /// Decrypt the input data
-    Decrypt,
-}
-
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-
-    match cli.command {
-        Commands::Encrypt { config } => encrypt(&config),
-        Commands::Decrypt => decrypt(),
-    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Seek;
+    use std::os::fd::IntoRawFd;
 
     #[test]
-    fn test_fetch_luks_key_success() {
-        let mock = MockCommandExecutor {
-            response: Ok("test_luks_key_12345".to_string()),
-        };
-
-        let servers = vec![Server {
-            url: "http://server1.example.com".to_string(),
-            cert: String::new(),
-        }];
-
-        let num_retries = NumRetries::Finite(3);
-        let result = fetch_luks_key(&servers, "/test/path", None, &num_retries, &mock);
-
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "test_luks_key_12345");
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
     }
 
     #[test]
-    fn test_fetch_luks_key_error() {
-        let mock = MockCommandExecutor {
-            response: Err(anyhow!("Failed to connect to server")),
-        };
-
-        let servers = vec![Server {
-            url: "http://server1.example.com".to_string(),
-            cert: String::new(),
-        }];
-
-        let num_retries = NumRetries::Finite(3);
-        let result = fetch_luks_key(&servers, "/test/path", None, &num_retries, &mock);
-
-        assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err().to_string(),
-            "Failed to fetch the LUKS key from all URLs after 3 attempts"
-        );
+    fn test_encrypt_dry_run_flag() {
+        let cli =
+            Cli::try_parse_from(["clevis-pin-trustee", "encrypt", "--dry-run", "{}"]).unwrap();
+
+        assert!(matches!(
+            cli.command,
+            Commands::Encrypt { dry_run: true, .. }
+        ));
     }
 
     #[test]
-    fn test_fetch_luks_key_infinity_retries() {
-        use std::sync::{
-            Arc,
-            atomic::{AtomicBool, Ordering},
-        };
-        use std::time::Instant;
-
-        let mock = MockCommandExecutor {
-            response: Err(anyhow!("Failed to connect to server")),
-        };
-
-        let servers = vec![Server {
-            url: "http://server1.example.com".to_string(),
-            cert: String::new(),
-        }];
-
-        let num_retries = NumRetries::Infinity;
-
-        let returned = Arc::new(AtomicBool::new(false));
-        let returned_clone = Arc::clone(&returned);
-        let handle = std::thread::spawn(move || {
-            let _ = fetch_luks_key(&servers, "/test/path", None, &num_retries, &mock);
-            returned_clone.store(true, Ordering::SeqCst);
-        });
-        let start = Instant::now();
-        let timeout = Duration::from_secs(60);
-
-        while start.elapsed() < timeout {
-            thread::sleep(Duration::from_secs(1));
-            if returned.load(Ordering::SeqCst) {
-                panic!("fetch_luks_key returned before 1 minute with infinite retries");
-            }
+    fn test_read_fd() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"secret").unwrap();
+        file.rewind().unwrap();
+        let fd = file.into_raw_fd();
+
+        assert_eq!(read_fd(fd).unwrap(), b"secret");
+        assert!(read_fd(-1).is_err());
+        for fd in 0..=2 {
+            assert_eq!(
+                read_fd(fd).unwrap_err().to_string(),
+                format!(
+                    "File descriptor {} is a standard stream, read stdin without --input-fd instead",
+                    fd
+                )
+            );
         }
-
-        assert!(
-            !returned.load(Ordering::SeqCst),
-            "fetch_luks_key should not have returned after 1 minute with infinite retries"
-        );
-
-        drop(handle);
-    }
-
-    #[test]
-    fn test_attestation_key_handle_none() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            panic!("HTTP client should not be called when attestation_key is None");
-        };
-
-        let result =
-            attestation_key_handle_with_deps(&None, &generator, http_client_factory, &filesystem);
-
-        assert!(result.is_ok());
-        assert!(filesystem.written.borrow().is_empty());
     }
 
     #[test]
-    fn test_attestation_key_handle_empty_registration_url() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            panic!("HTTP client should not be called when registration URL is empty");
-        };
+    fn test_decrypt_agent_flag() {
+        let cli = Cli::try_parse_from(["clevis-pin-trustee", "decrypt", "--agent"]).unwrap();
 
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: String::new(),
-                cert: String::new(),
-                uuid: "test-uuid".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
+        assert!(matches!(
+            cli.command,
+            Commands::Decrypt { agent: Some(socket), .. } if socket.as_os_str() == agent::AGENT_SOCKET
+        ));
+        assert!(
+            Cli::try_parse_from([
+                "clevis-pin-trustee",
+                "decrypt",
+                "--agent",
+                "--config-file",
+                "runtime.json",
+            ])
+            .is_err()
         );
-
-        assert!(result.is_ok());
-        assert!(filesystem.written.borrow().is_empty());
     }
 
     #[test]
-    fn test_attestation_key_handle_successful_registration() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            Ok(Box::new(MockHttpClient {
-                responses: vec![Ok(200)],
-                call_count: std::cell::RefCell::new(0),
-            }))
+    fn test_retries_flag() {
+        let retries = |value: &str| {
+            let cli = Cli::try_parse_from(["clevis-pin-trustee", "decrypt", "--retries", value])?;
+            let Commands::Decrypt { retries, .. } = cli.command else {
+                panic!("not a decrypt command");
+            };
+            Ok::<_, clap::Error>(retries)
         };
 
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
-        );
-
-        assert!(result.is_ok());
-        assert_eq!(filesystem.written.borrow().len(), 1);
-        assert_eq!(filesystem.written.borrow()[0], AK_REGISTERD);
+        assert_eq!(retries("3").unwrap(), Some(NumRetries::Finite(3)));
+        assert_eq!(retries("none").unwrap(), Some(NumRetries::Once));
+        assert_eq!(retries("infinity").unwrap(), Some(NumRetries::Infinity));
+        assert!(retries("0").is_err());
+        assert!(retries("forever").is_err());
     }
 
     #[test]
-    fn test_attestation_key_handle_registration_fails_with_retries() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            Ok(Box::new(MockHttpClient {
-                responses: vec![
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                    Ok(500),
-                ],
-                call_count: std::cell::RefCell::new(0),
-            }))
+    fn test_delay_flag() {
+        let cli = Cli::try_parse_from([
+            "clevis-pin-trustee",
+            "unlock",
+            "-d",
+            "/dev/vda2",
+            "-n",
+            "root",
+            "--delay",
+            "30s",
+        ])
+        .unwrap();
+        let Commands::Unlock { delay, .. } = cli.command else {
+            panic!("not an unlock command");
         };
-
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
-        );
-
-        assert!(result.is_err());
-        assert!(
-            result
-                .unwrap_err()
-                .to_string()
-                .contains("failed with status: 500")
-        );
-        assert!(filesystem.written.borrow().is_empty());
+        assert_eq!(delay.as_deref(), Some("30s"));
+        assert!(Cli::try_parse_from(["clevis-pin-trustee", "decrypt", "--delay", "30"]).is_err());
     }
 
     #[test]
-    fn test_attestation_key_handle_registration_succeeds_after_retries() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            Ok(Box::new(MockHttpClient {
-                responses: vec![Ok(500), Ok(500), Ok(200)],
-                call_count: std::cell::RefCell::new(0),
-            }))
-        };
-
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
-        );
-
-        assert!(result.is_ok());
-        assert_eq!(filesystem.written.borrow().len(), 1);
-        assert_eq!(filesystem.written.borrow()[0], AK_REGISTERD);
+    fn test_output_format_flag() {
+        let cli = Cli::try_parse_from(["clevis-pin-trustee", "status", "--output-format", "json"])
+            .unwrap();
+        assert!(cli.output_format == OutputFormat::Json);
+
+        let output = Output::new(OutputFormat::Json);
+        output.json(json!({"volumes": []})).unwrap();
+        assert!(output.printed.get());
+        assert!(output.finish(Err(anyhow::anyhow!("failed"))).is_err());
+        assert!(Cli::try_parse_from(["clevis-pin-trustee", "--output-format", "xml"]).is_err());
     }
 
     #[test]
-    fn test_attestation_key_handle_generation_fails() {
-        let generator = MockAttestationKeyGenerator {
-            response: Err(anyhow!("Failed to generate attestation key")),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            panic!("HTTP client should not be called when key generation fails");
-        };
-
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
-        );
-
-        assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err().to_string(),
-            "Failed to generate attestation key"
-        );
-        assert!(filesystem.written.borrow().is_empty());
+    fn test_progress_flag() {
+        let cli =
+            Cli::try_parse_from(["clevis-pin-trustee", "decrypt", "--progress", "ndjson"]).unwrap();
+        assert!(cli.progress == Progress::Ndjson);
+        let cli = Cli::try_parse_from(["clevis-pin-trustee", "decrypt"]).unwrap();
+        assert!(cli.progress == Progress::None);
     }
 
     #[test]
-    fn test_attestation_key_handle_http_client_error() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Ok(()),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            Ok(Box::new(MockHttpClient {
-                responses: vec![
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                    Err(anyhow!("Network error")),
-                ],
-                call_count: std::cell::RefCell::new(0),
-            }))
-        };
-
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
+    fn test_server_selection_flags() {
+        let cli = Cli::try_parse_from([
+            "clevis-pin-trustee",
+            "decrypt",
+            "--only-server",
+            "prod-kbs-1",
+            "--only-server",
+            "prod-kbs-2",
+            "--skip-server",
+            "backup",
+        ])
+        .unwrap();
 
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
+        let Commands::Decrypt { servers, .. } = cli.command else {
+            panic!("not a decrypt command");
+        };
+        let selection = ServerSelection::from(servers);
+        assert_eq!(selection.only, vec!["prod-kbs-1", "prod-kbs-2"]);
+        assert_eq!(selection.skip, vec!["backup"]);
+        assert!(
+            Cli::try_parse_from([
+                "clevis-pin-trustee",
+                "decrypt",
+                "--agent",
+                "--skip-server",
+                "a"
+            ])
+            .is_err()
         );
 
-        assert!(result.is_err());
-        assert!(result.unwrap_err().to_string().contains("Network error"));
-        assert!(filesystem.written.borrow().is_empty());
+        let cli = Cli::try_parse_from([
+            "clevis-pin-trustee",
+            "unlock",
+            "-d",
+            "/dev/vda3",
+            "-n",
+            "root",
+            "--skip-server",
+            "backup",
+        ])
+        .unwrap();
+        let Commands::Unlock { servers, .. } = cli.command else {
+            panic!("not an unlock command");
+        };
+        assert_eq!(ServerSelection::from(servers).skip, vec!["backup"]);
     }
 
     #[test]
-    fn test_attestation_key_handle_filesystem_write_fails() {
-        let generator = MockAttestationKeyGenerator {
-            response: Ok("mock_attestation_key".to_string()),
-        };
-        let filesystem = MockFileSystem {
-            write_result: Err(anyhow!("Permission denied")),
-            written: std::cell::RefCell::new(Vec::new()),
-        };
-        let http_client_factory = move |_cert: &str| -> Result<Box<dyn HttpClient>> {
-            Ok(Box::new(MockHttpClient {
-                responses: vec![Ok(200)],
-                call_count: std::cell::RefCell::new(0),
-            }))
-        };
-
-        let attestation_key = Some(AttestationKey {
-            registration: Registration {
-                url: "http://test.example.com/register".to_string(),
-                cert: String::new(),
-                uuid: "test-uuid-123".to_string(),
-            },
-        });
-
-        let result = attestation_key_handle_with_deps(
-            &attestation_key,
-            &generator,
-            http_client_factory,
-            &filesystem,
+    fn test_completions() {
+        let mut script = Vec::new();
+        clap_complete::generate(
+            Shell::Bash,
+            &mut Cli::command(),
+            "clevis-pin-trustee",
+            &mut script,
         );
 
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().to_string(), "Permission denied");
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("encrypt"));
+        assert!(script.contains("--strict"));
+        assert!(script.contains("--format"));
     }
 }
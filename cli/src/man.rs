@@ -0,0 +1,413 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Man pages for packagers, generated from the clap definitions plus a
+//! hand-written page for the configuration format
+
+use crate::Cli;
+use anyhow::{Context, Result};
+use clap::CommandFactory;
+use std::fs;
+use std::path::Path;
+
+const CONFIG_MAN_PAGE: &str = "clevis-pin-trustee-config.5";
+
+const CONFIG_MAN: &str = r#".TH CLEVIS-PIN-TRUSTEE-CONFIG 5 "" "clevis-pin-trustee" "File Formats"
+.SH NAME
+clevis-pin-trustee-config \- configuration of the Clevis PIN for Trustee
+.SH DESCRIPTION
+The configuration is a JSON, YAML or TOML object passed to
+.B clevis-pin-trustee encrypt
+(or
+.BR "clevis encrypt trustee" ).
+The syntax is detected from the content unless
+.B --format
+is given: JSON if it starts with "{", otherwise TOML, falling back to YAML.
+Fragments in
+.I /etc/clevis-trustee/config.d
+ending in .json, .yaml, .yml or .toml
+are merged into it in lexical order: servers are appended, every other
+field replaces the previous value. Everything except the attestation key
+is stored in the clevis header of the JWE and used again to decrypt.
+.B clevis-pin-trustee schema
+prints the JSON Schema of the configuration.
+.SH FIELDS
+.TP
+.B servers
+List of Trustee KBS servers, tried in order. Each entry has a
+.B url
+and optionally a
+.B cert
+(an inline PEM string, {"inline": "PEM"}, {"path": "/file.pem"} or
+empty) and
+.B policy_ids
+overriding the top-level value for this server, and a
+.B name
+labeling it for the --only-server and --skip-server flags of decrypt,
+bench and self-test.
+A bare URL string stands for a server with only a
+.BR url .
+URLs must be http or https; IPv6 addresses go in brackets, e.g.
+https://[fd00::1]:8080. They are stored in canonical form, without the
+default port or a trailing slash.
+.TP
+.B cert
+Certificate, in any of the forms above, of the servers that have none of
+their own. It is stored once in the clevis header.
+.TP
+.B path
+Resource path of the key on the KBS, e.g. default/key/root, or the same
+as a resource URI, kbs:///default/key/root. The placeholders {machine-id},
+{hostname} and {uuid} are expanded on the machine fetching the key.
+The resource holds {"key_type": "oct", "key": "..."} with a key of 32
+characters, a symmetric JWK, {"kty": "oct", "k": "..."}, used as is, or a
+key of 32 bytes, in base64, hexadecimal or as is. The format is guessed
+unless
+.B key_format
+is one of
+.BR jwk ", " key ", " base64 ", " hex " or " raw .
+.B clevis-pin-trustee set-policy
+uploads the rego resource policy releasing it to every server, signing in
+with the Ed25519 admin key of the KBS. The KBS has one resource policy for
+all its resources, so it must allow the other resources it serves as well.
+.TP
+.B key_pipeline
+List of steps applied to the resource before the key is read in it, for
+keys stored in an encoding of their own, e.g. ["base64-decode",
+"hkdf:info=luks"]:
+.BR trim ", " base64-decode ", " base64url-decode ", " hex-decode ,
+.B sha256
+and
+.B hkdf
+(32 bytes derived with HKDF-SHA256, with the optional parameters
+info=TEXT and salt=TEXT separated by commas).
+.TP
+.B resource_version
+Version or tag of the resource to fetch, sent to the KBS as the version
+query parameter of the resource request for its storage backend to select
+it. It is recorded in the clevis header, so uploading a new version of the
+key to the KBS does not change the key decrypt fetches until the binding is
+made again with the new version.
+.TP
+.B initdata
+Object converted to a Trustee initdata TOML document. Values may be
+strings or nested objects. It may also be given as a string holding a JSON
+object, or as a list of fragments merged in order, e.g. a base fleet policy
+followed by per-host additions. Each fragment is an object, a string holding
+a JSON object, or TOML: a table of data or a whole initdata document. Nested
+tables are merged key by key, any other value of a later fragment replaces
+the earlier one. Keys are sorted, so the same fragments always give the same
+document and digest. The initdata of a drop-in fragment replaces the whole
+initdata.
+.B clevis-pin-trustee initdata-digest
+prints the digest the attestation policy should expect for it.
+.TP
+.B num_retries
+Number of attempts (default 10),
+.B """infinity""",
+.B """none"""
+for a single attempt, or a retry schedule such as
+["5s", "3@10s", "infinity@300s"].
+.TP
+.B policy_ids
+Attestation policies that must evaluate the evidence.
+.TP
+.B backend
+.BR trustee-attester " (default), " native " or " attestation-agent .
+.B clevis-pin-trustee evidence
+prints the attestation request of the native backend, with the evidence of
+the attestation-agent and the initdata, without contacting a KBS.
+.TP
+.B kbs_protocol_version
+Pin the KBS protocol version instead of negotiating it (native backend
+only). A binding with initdata cannot be fetched with 0.1.1, which does not
+carry it.
+.TP
+.B attempt_timeout
+Limit on a single fetch attempt, e.g. "30s". trustee-attester is killed
+when it takes longer; the other backends abort HTTP requests that take
+longer. The attempt then counts as failed and is retried.
+.TP
+.B tls_min_version
+Oldest TLS version the servers may negotiate, "1.2" or "1.3".
+.TP
+.B tls_cipher_suites
+Cipher suites the servers may negotiate, by IANA name, e.g.
+["TLS_AES_256_GCM_SHA384"].
+.TP
+.B crl_path
+CRL file, PEM or DER, read on every fetch: a server whose certificate it
+revokes is refused. Only the pinned
+.B cert
+of the servers is trusted then, as the CRL is the one of that CA.
+.IP
+Servers may set
+.BR tls_min_version ", " tls_cipher_suites " and " crl_path
+to override these. They are enforced by the native and attestation-agent
+backends; the trustee-attester backend refuses servers with TLS settings.
+.TP
+.B user_agent
+User-Agent of the requests to the servers.
+.IP
+Servers may set
+.BR headers ,
+an object of extra request headers, e.g. {"X-Tenant-Id": "tenant-a"} for a
+multi-tenant gateway routing on it; a User-Agent there replaces the
+top-level one. Like the TLS settings, they are sent by the native and
+attestation-agent backends and refused by the trustee-attester backend.
+.TP
+.B circuit_breaker
+Object with
+.B failure_threshold
+and
+.BR cooldown_attempts :
+skip a server for a few attempts after repeated consecutive failures.
+.TP
+.B backoff
+Object growing the delay between counted or infinite retries: it is
+multiplied by
+.B multiplier
+(default 2) after each failed attempt, up to
+.B max_delay
+(default "5m"), so an infinite retry settles into polling at that cadence.
+With
+.B reset_after_success
+(default true) the delay starts again from the initial one for the next
+key once one was fetched. With
+.B jitter
+(default false) each delay is a random one between half of it and all of it,
+so machines failing together do not retry in lockstep. The delays of a retry
+schedule are not changed.
+.TP
+.B retry_on
+List of the failures worth retrying, replacing the built-in classification:
+HTTP statuses like "503", classes of them like "5xx", "timeout" for servers
+not answering in time and "network" for unreachable ones, e.g. ["5xx",
+"timeout"] to never retry a 401 or 403. Any other status, timeout or network
+failure is permanent for the server; failures of unknown cause keep their
+built-in classification.
+.TP
+.B discovery
+List of
+.BR smbios " and " cloud-init :
+where to look up servers on every key fetch, before trying
+.BR servers .
+SMBIOS OEM strings io.clevis-trustee.url=URL name a server, optionally
+followed by io.clevis-trustee.cert=BASE64 with its base64 encoded PEM
+certificate. The cloud-init metadata key clevis-trustee holds a server
+object or a list of them, possibly as a JSON string.
+.TP
+.B recipients
+List of further resources the payload is encrypted to, each with a
+.B path
+and
+.B servers
+defaulting to the top-level ones. Any of the resources decrypts the
+payload; they are tried in order, the top-level one first.
+.TP
+.B tpm2
+Config of the clevis tpm2 pin, e.g. {"pcr_ids": "7"}. A random local secret
+is sealed with the TPM and the payload key is derived from it and the
+fetched key, so decrypting needs both this machine's TPM and the servers.
+Requires the clevis tpm2 pin.
+.TP
+.B attestation_key
+Register a TPM attestation key with
+.B registration
+.RB ( url ", " cert ", " uuid )
+before fetching the key.
+.SH RUNTIME OVERRIDES
+.B clevis-pin-trustee decrypt --config-file
+reads a JSON object whose fields replace the ones of the binding for this
+run only:
+.BR servers ", " num_retries ", " backend ", " attempt_timeout ,
+.B retry_delay
+(delay between attempts without a retry schedule, e.g. "30s"),
+.B attester_path
+(the trustee-attester binary to run),
+.B cert_dir
+(where inline certificates are written for trustee-attester),
+.B cert_context
+(SELinux context of these certificates, so the attester can read them in
+enforcing mode; system_u:object_r:cert_t:s0, the one of the system
+certificates, when SELinux is enabled, and none when empty),
+.B key_cache_ttl
+(e.g. "12h": keep the fetched keys in /var/cache/clevis-trustee, or in
+$XDG_CACHE_HOME/clevis-trustee when not running as root, encrypted with a
+key local to the machine, sealed with the TPM and kept in
+/var/lib/clevis-trustee, or in $XDG_STATE_HOME/clevis-trustee, and decrypt
+without attesting until they expire)
+and
+.B network_wait
+(e.g. "60s": before the first attempt, wait up to this long for the host of
+a server to resolve to an address with a route, as the network may not be up
+yet in early boot; the attempts start anyway when the wait times out).
+.B time_sync_wait
+(e.g. "2m") waits up to this long for time sync when the system clock is
+before 2025 or the time saved by systemd-timesyncd, since certificates
+cannot be validated with it; a clock still wrong is reported as clock skew
+along with the failures.
+In builds with the mock-server feature,
+.B backend
+may also be
+.BR mock ,
+which reads the resource at
+.B path
+from the directory in
+.B mock_resources
+or
+.B CLEVIS_TRUSTEE_MOCK_RESOURCES
+without attesting nor contacting the servers, for tests only; a binding
+cannot select it.
+.B input_limits
+bounds the JWE decrypt accepts, so a corrupted or hostile token cannot
+exhaust the memory of the early boot unlock:
+.B max_jwe_size
+(default 16 MiB),
+.B max_header_size
+(the decoded protected header, default 1 MiB),
+.B max_header_depth
+(nesting of the clevis claim, default 16) and
+.B max_claim_size
+(any string of the clevis claim, default 256 KiB), all in bytes but the
+depth. Without
+.BR cert_dir ,
+the directory in
+.B CLEVIS_TRUSTEE_CERT_DIR
+is used, else /run/trustee, or $XDG_RUNTIME_DIR/trustee when not running
+as root, or $TMPDIR/trustee-UID without a runtime directory. A certificate
+directory any other user can write to is refused. Only binding and
+unlocking LUKS devices need root.
+.PP
+The
+.B --retries
+flag of decrypt and unlock takes a number of attempts,
+.B infinity
+or
+.B none
+and replaces the num_retries of both the binding and the config file, e.g.
+to give up after a single attempt in a manual recovery instead of retrying
+forever.
+.B --delay
+likewise replaces the retry_delay, e.g. "1s" in CI; a retry schedule keeps
+its own delays.
+.PP
+.B override_url
+(or
+.B --override-url
+of decrypt and unlock, or the
+.B CLEVIS_TRUSTEE_OVERRIDE_URL
+environment variable) fetches from this URL instead of the servers of the
+binding, with the certificate and settings of its first server, to recover
+when the KBS moved but still holds the resource.
+.B servers
+take precedence.
+.PP
+.B record
+(or
+.B --record
+of decrypt and unlock) writes every fetch attempt, its server, resource,
+timing and outcome, to a file as JSON lines, readable by its owner only.
+Keys are never written, only their key id, the truncated digest the binding
+records.
+.B replay
+(or
+.BR --replay )
+answers the attempts with the ones of such a recording instead of contacting
+the servers, to reproduce a failing unlock offline with the same JWE; the
+retries and failures are the same, but a fetch that succeeded fails since
+its key is not in the recording.
+.SH ALLOWED SERVERS
+When
+.I /etc/clevis-trustee/allowed-servers
+exists, decrypting only contacts the servers of the binding whose URL is
+listed in it, one per line with # starting comments, and discovers none.
+The URL may be followed by the settings pinned for the server:
+.BI cert= PATH\fR,
+.BI crl= PATH\fR,
+.BI header= NAME:VALUE
+and
+.BI policy= ID\fR,
+the last two repeatable. Servers of a doctored clevis header are refused, and
+its certificates, CRLs, request headers, policies, TLS settings and backend
+are ignored; servers given in
+.B --config-file
+are always used.
+.SH SERVER REWRITES
+When
+.I /etc/clevis-trustee/server-rewrites
+exists, the servers of the binding, including the discovered ones, are
+fetched from a new URL when theirs is listed in it, one
+.B "old-url -> new-url"
+per line with # starting comments, e.g. after a migration of the KBS to a
+new hostname. The servers keep their certificate and settings, and the
+allowed servers apply to the new URLs. Servers given in
+.B --config-file
+or
+.B --override-url
+are used as they are.
+.SH SIGNED CONFIGS
+When the minisign public key
+.I /etc/clevis-trustee/config-signing.pub
+is in the image, a config is only used with a valid detached signature made
+with it, given with
+.BR --signature ,
+and a
+.B --config-file
+only with the signature minisign writes next to it, FILE.minisig, as are
+the fragments of
+.IR /etc/clevis-trustee/config.d .
+Unsigned or tampered configs are refused, and so is
+.BR CLEVIS_TRUSTEE_OVERRIDE_URL ,
+which cannot be signed;
+.B --override-url
+is given by whoever runs the command and is used as it is. Both the default prehashed signatures of
+.B minisign -S
+and the legacy ones of
+.B minisign -S -l
+are accepted.
+.SH EXAMPLE
+.nf
+{
+    "servers": [{"url": "https://kbs.example.com:8080", "cert": ""}],
+    "path": "default/key/{machine-id}",
+    "num_retries": ["5s", "infinity@30s"]
+}
+.fi
+.SH SEE ALSO
+.BR clevis-pin-trustee (1)
+"#;
+
+/// Write the man pages of every subcommand and of the config format to `out_dir`
+pub(crate) fn generate(out_dir: &Path) -> Result<()> {
+    fs::create_dir_all(out_dir)
+        .with_context(|| format!("Failed to create {}", out_dir.display()))?;
+    clap_mangen::generate_to(Cli::command(), out_dir)
+        .with_context(|| format!("Failed to write man pages to {}", out_dir.display()))?;
+    fs::write(out_dir.join(CONFIG_MAN_PAGE), CONFIG_MAN)
+        .with_context(|| format!("Failed to write {}", CONFIG_MAN_PAGE))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let dir = tempfile::tempdir().unwrap();
+
+        generate(dir.path()).unwrap();
+
+        let page = fs::read_to_string(dir.path().join("clevis-pin-trustee-encrypt.1")).unwrap();
+        assert!(page.contains("strict"));
+        assert!(dir.path().join("clevis-pin-trustee.1").exists());
+        assert!(dir.path().join(CONFIG_MAN_PAGE).exists());
+        assert!(
+            !dir.path()
+                .join("clevis-pin-trustee-generate-man.1")
+                .exists()
+        );
+    }
+}
@@ -0,0 +1,77 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Keeping the key out of swap and core dumps. The fetched key and the JWK
+//! derived from it are copied around by the HTTP and JOSE libraries, so
+//! rather than tracking each buffer, every page of the process is locked in
+//! memory for its whole lifetime, retry loops included.
+
+use std::io;
+
+/// Whether locking every page of the process cannot starve it of memory:
+/// root is not bound by `RLIMIT_MEMLOCK`, other users only when it is
+/// unlimited, since allocations past the limit would fail
+fn can_lock_all(root: bool, memlock_limit: libc::rlim_t) -> bool {
+    root || memlock_limit == libc::RLIM_INFINITY
+}
+
+fn memlock_limit() -> io::Result<libc::rlim_t> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct passed by reference
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+fn lock_all() -> io::Result<()> {
+    // Pages are locked as they are first touched rather than all at once
+    // SAFETY: mlockall takes no pointers
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE | libc::MCL_ONFAULT) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Disable core dumps of the process and lock its memory, so secrets never
+/// reach the disk. Failures are reported and the process goes on unprotected.
+pub fn protect_secrets() {
+    // SAFETY: prctl with PR_SET_DUMPABLE takes no pointers
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        eprintln!(
+            "Warning: failed to disable core dumps: {}",
+            io::Error::last_os_error()
+        );
+    }
+    // SAFETY: geteuid cannot fail
+    let root = unsafe { libc::geteuid() } == 0;
+    let result = memlock_limit().and_then(|limit| {
+        if can_lock_all(root, limit) {
+            lock_all()
+        } else {
+            Err(io::Error::other("RLIMIT_MEMLOCK is limited"))
+        }
+    });
+    if let Err(e) = result {
+        eprintln!(
+            "Warning: the key may be swapped out, locking memory failed: {}",
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_can_lock_all() {
+        assert!(can_lock_all(true, 8 << 20));
+        assert!(can_lock_all(false, libc::RLIM_INFINITY));
+        assert!(!can_lock_all(false, 8 << 20));
+    }
+}
@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Mock backend, reading the resources from a local directory instead of
+//! fetching them from a KBS, to run encrypt/decrypt round trips in tests
+//! without attestation. The servers of the binding are not contacted.
+
+use crate::KeyFetcher;
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::{Cert, ConnectionSettings, TrusteePinError};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Directory of the resources when the runtime config sets none, e.g. for
+/// encrypt
+const MOCK_RESOURCES_ENV: &str = "CLEVIS_TRUSTEE_MOCK_RESOURCES";
+
+fn resolve_resources_dir(configured: Option<&str>, from_env: Option<OsString>) -> Option<PathBuf> {
+    configured
+        .map(PathBuf::from)
+        .or_else(|| from_env.filter(|dir| !dir.is_empty()).map(PathBuf::from))
+}
+
+/// Serves the resource at `path` from the file `dir/path`
+pub(crate) struct MockKeyFetcher {
+    dir: PathBuf,
+}
+
+impl MockKeyFetcher {
+    /// Mock backend reading the resources below `configured`, else below
+    /// the directory in `CLEVIS_TRUSTEE_MOCK_RESOURCES`
+    pub(crate) fn new(configured: Option<&str>) -> Result<Self> {
+        let dir = resolve_resources_dir(configured, env::var_os(MOCK_RESOURCES_ENV)).ok_or_else(
+            || {
+                TrusteePinError::Config(format!(
+                    "The mock backend needs mock_resources or {}",
+                    MOCK_RESOURCES_ENV
+                ))
+            },
+        )?;
+        Ok(MockKeyFetcher { dir })
+    }
+}
+
+impl KeyFetcher for MockKeyFetcher {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        _cert: &Cert,
+        _connection: &ConnectionSettings,
+        _initdata: Option<String>,
+        _policy_ids: &[String],
+    ) -> Result<String> {
+        // The version of a resource is ignored, there is a single one
+        let resource = path.split('?').next().unwrap_or_default();
+        let relative = Path::new(resource);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(TrusteePinError::Config(format!("Invalid resource path {}", path)).into());
+        }
+        let file = self.dir.join(relative);
+        let key = match fs::read(&file) {
+            Ok(key) => key,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(TrusteePinError::permanent(
+                    url,
+                    format!("No resource {} in {}", resource, self.dir.display()),
+                )
+                .into());
+            }
+            Err(e) => return Err(anyhow!("Failed to read {}: {}", file.display(), e)),
+        };
+        if key.is_empty() {
+            return Err(anyhow!("Received empty LUKS key"));
+        }
+        Ok(general_purpose::STANDARD.encode(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure_kind;
+    use clevis_pin_trustee_lib::FailureKind;
+
+    fn fetch(fetcher: &MockKeyFetcher, path: &str) -> Result<String> {
+        fetcher.try_fetch_luks_key(
+            "http://kbs:8080",
+            path,
+            &Cert::None,
+            &ConnectionSettings::default(),
+            None,
+            &[],
+        )
+    }
+
+    #[test]
+    fn test_mock_key_fetcher() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("default/key")).unwrap();
+        fs::write(dir.path().join("default/key/root"), "secret").unwrap();
+        let fetcher = MockKeyFetcher::new(dir.path().to_str()).unwrap();
+
+        assert_eq!(
+            fetch(&fetcher, "default/key/root").unwrap(),
+            general_purpose::STANDARD.encode("secret")
+        );
+        assert_eq!(
+            fetch(&fetcher, "default/key/root?version=2").unwrap(),
+            general_purpose::STANDARD.encode("secret")
+        );
+        let missing = fetch(&fetcher, "default/key/other").unwrap_err();
+        assert_eq!(failure_kind(&missing), FailureKind::Permanent);
+        assert!(fetch(&fetcher, "default/../../etc/passwd").is_err());
+    }
+
+    #[test]
+    fn test_resolve_resources_dir() {
+        assert_eq!(
+            resolve_resources_dir(Some("/a"), Some("/b".into())),
+            Some(PathBuf::from("/a"))
+        );
+        assert_eq!(
+            resolve_resources_dir(None, Some("/b".into())),
+            Some(PathBuf::from("/b"))
+        );
+        assert_eq!(resolve_resources_dir(None, Some("".into())), None);
+    }
+}
@@ -0,0 +1,546 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Mock KBS for integration tests, built with the `mock-server` feature.
+//!
+//! It speaks enough of the KBS protocol for encrypt/decrypt round trips with
+//! the native and attestation-agent backends without a Trustee deployment:
+//! every attestation is accepted and the files of a directory are served as
+//! the resources, encrypted to the TEE key of the session. The certificate
+//! is generated at startup. It checks no evidence and must never hold real
+//! keys.
+
+use crate::entropy::{OsRandom, random_bytes};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use josekit::jwe::{JweHeaderSet, RSA_OAEP};
+use josekit::jwk::Jwk;
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::x509::extension::{ExtendedKeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509, X509NameBuilder};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SESSION_COOKIE: &str = "kbs-session-id";
+/// Lifetime of the attestation tokens, as for a default KBS
+const TOKEN_LIFETIME: Duration = Duration::from_secs(300);
+const CERT_DAYS: u32 = 30;
+const CONTENT_ENCRYPTION: &str = "A256GCM";
+/// Clients stuck in the middle of a request are dropped after it
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+const ERROR_TYPE: &str = "https://github.com/confidential-containers/kbs/errors";
+
+/// HTTP request read from a client
+struct Request {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn session_id(&self) -> Option<&str> {
+        self.header("cookie")?
+            .split(';')
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == SESSION_COOKIE)
+            .map(|(_, value)| value)
+    }
+
+    fn bearer_token(&self) -> Option<&str> {
+        self.header("authorization")?.strip_prefix("Bearer ")
+    }
+}
+
+struct Response {
+    status: u16,
+    body: String,
+    session_id: Option<String>,
+}
+
+impl Response {
+    fn json(body: Value) -> Self {
+        Response {
+            status: 200,
+            body: body.to_string(),
+            session_id: None,
+        }
+    }
+
+    /// Error in the format of the KBS, `kind` being the last part of its type
+    fn error(status: u16, kind: &str, detail: impl Into<String>) -> Self {
+        Response {
+            status,
+            body: json!({
+                "type": format!("{}/{}", ERROR_TYPE, kind),
+                "detail": detail.into(),
+            })
+            .to_string(),
+            session_id: None,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Unsigned JWT carrying the TEE key, as the attestation token of the KBS
+/// does in a signed one
+fn token(tee_pubkey: &Value, now: u64) -> String {
+    let header = json!({"alg": "none", "typ": "JWT"});
+    let claims = json!({
+        "iat": now,
+        "exp": now + TOKEN_LIFETIME.as_secs(),
+        "tee-pubkey": tee_pubkey,
+    });
+    format!(
+        "{}.{}.",
+        general_purpose::URL_SAFE_NO_PAD.encode(header.to_string()),
+        general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string())
+    )
+}
+
+/// The TEE key of `token`, if it has not expired
+fn token_pubkey(token: &str, now: u64) -> Option<Value> {
+    let claims = token.split('.').nth(1)?;
+    let claims: Value =
+        serde_json::from_slice(&general_purpose::URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+    if claims["exp"].as_u64()? <= now {
+        return None;
+    }
+    Some(claims["tee-pubkey"].clone())
+}
+
+/// `resource` encrypted to the TEE public JWK, as the KBS responds
+fn encrypt_resource(resource: &[u8], tee_pubkey: &Value) -> Result<String> {
+    let jwk = tee_pubkey
+        .as_object()
+        .cloned()
+        .ok_or_else(|| anyhow!("the TEE key is not a JWK"))
+        .and_then(|map| Jwk::from_map(map).map_err(|e| anyhow!("Invalid TEE key: {}", e)))?;
+    let encrypter = RSA_OAEP
+        .encrypter_from_jwk(&jwk)
+        .map_err(|e| anyhow!("Invalid TEE key: {}", e))?;
+    let mut header = JweHeaderSet::new();
+    header.set_content_encryption(CONTENT_ENCRYPTION, true);
+    josekit::jwe::serialize_flattened_json(resource, Some(&header), None, None, &encrypter)
+        .map_err(|e| anyhow!("Failed to encrypt the resource: {}", e))
+}
+
+/// KBS protocol state: the sessions, with their TEE key once attested
+struct MockKbs {
+    resources: PathBuf,
+    sessions: RefCell<HashMap<String, Option<Value>>>,
+}
+
+impl MockKbs {
+    fn new(resources: &Path) -> Self {
+        MockKbs {
+            resources: resources.to_path_buf(),
+            sessions: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        // The version of a resource is ignored, there is a single one
+        let path = request.path.split('?').next().unwrap_or_default();
+        match (request.method.as_str(), path) {
+            ("POST", "/kbs/v0/auth") => self.auth(request),
+            ("POST", "/kbs/v0/attest") => self.attest(request),
+            ("GET", path) if path.starts_with("/kbs/v0/resource/") => {
+                self.resource(request, &path["/kbs/v0/resource/".len()..])
+            }
+            _ => Response::error(404, "NotFound", format!("No route for {}", path)),
+        }
+    }
+
+    fn auth(&self, request: &Request) -> Response {
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or_default();
+        if !body["tee"].is_string() {
+            return Response::error(400, "InvalidRequest", "The request has no tee");
+        }
+        let session_id = hex::encode(random_bytes::<16>(&OsRandom));
+        self.sessions.borrow_mut().insert(session_id.clone(), None);
+        Response {
+            session_id: Some(session_id),
+            ..Response::json(json!({
+                "nonce": general_purpose::STANDARD.encode(random_bytes::<32>(&OsRandom)),
+                "extra-params": "",
+            }))
+        }
+    }
+
+    fn attest(&self, request: &Request) -> Response {
+        let mut sessions = self.sessions.borrow_mut();
+        let Some(session) = request.session_id().and_then(|id| sessions.get_mut(id)) else {
+            return Response::error(401, "UnAuthenticated", "No session, authenticate first");
+        };
+        let body: Value = serde_json::from_slice(&request.body).unwrap_or_default();
+        // Protocol 0.4.0 binds the key to the nonce in the runtime data
+        let tee_pubkey = match &body["runtime-data"]["tee-pubkey"] {
+            Value::Null => &body["tee-pubkey"],
+            key => key,
+        };
+        if !tee_pubkey.is_object() {
+            return Response::error(400, "InvalidRequest", "The request has no tee-pubkey");
+        }
+        *session = Some(tee_pubkey.clone());
+        Response::json(json!({"token": token(tee_pubkey, now())}))
+    }
+
+    /// The file of the resource at `path`, rejecting paths out of the
+    /// directory
+    fn resource_file(&self, path: &str) -> Option<PathBuf> {
+        let path = Path::new(path);
+        path.components()
+            .all(|component| matches!(component, Component::Normal(_)))
+            .then(|| self.resources.join(path))
+    }
+
+    fn resource(&self, request: &Request, path: &str) -> Response {
+        let from_session = request
+            .session_id()
+            .and_then(|id| self.sessions.borrow().get(id).cloned().flatten());
+        let Some(tee_pubkey) =
+            from_session.or_else(|| token_pubkey(request.bearer_token()?, now()))
+        else {
+            return Response::error(401, "TokenNotFound", "Attest before fetching resources");
+        };
+        let Some(file) = self.resource_file(path) else {
+            return Response::error(400, "InvalidRequest", format!("Invalid path {}", path));
+        };
+        let resource = match fs::read(&file) {
+            Ok(resource) => resource,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Response::error(404, "ResourceNotFound", format!("No resource {}", path));
+            }
+            Err(e) => {
+                return Response::error(500, "ReadResource", format!("{}: {}", path, e));
+            }
+        };
+        match encrypt_resource(&resource, &tee_pubkey) {
+            Ok(body) => Response {
+                status: 200,
+                body,
+                session_id: None,
+            },
+            Err(e) => Response::error(400, "InvalidRequest", format!("{:#}", e)),
+        }
+    }
+}
+
+/// Read an HTTP/1.1 request, its body delimited by `Content-Length`
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(anyhow!("Invalid request line {:?}", line.trim_end()));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("Connection closed in the headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid header {:?}", header))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length = match request.header("content-length") {
+        Some(length) => length.parse().context("Invalid Content-Length")?,
+        None => 0,
+    };
+    request.body.resize(length, 0);
+    reader.read_exact(&mut request.body)?;
+    Ok(request)
+}
+
+fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Internal Server Error",
+    };
+    write!(writer, "HTTP/1.1 {} {}\r\n", response.status, reason)?;
+    if let Some(id) = &response.session_id {
+        write!(
+            writer,
+            "Set-Cookie: {}={}; Path=/kbs\r\n",
+            SESSION_COOKIE, id
+        )?;
+    }
+    write!(
+        writer,
+        "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.body.len(),
+        response.body
+    )?;
+    writer.flush()
+}
+
+/// Self-signed certificate and its PKCS#8 key, in PEM, valid for localhost
+/// and `ip`
+fn generate_cert(ip: IpAddr) -> Result<(String, String)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "clevis-pin-trustee mock KBS")?;
+    let name = name.build();
+
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = BigNum::from_slice(&random_bytes::<16>(&OsRandom))?;
+    builder.set_serial_number(&*serial.to_asn1_integer()?)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(CERT_DAYS)?)?;
+    let mut names = SubjectAlternativeName::new();
+    names.dns("localhost").ip("127.0.0.1").ip("::1");
+    if !ip.is_unspecified() && !ip.is_loopback() {
+        names.ip(&ip.to_string());
+    }
+    let names = names.build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(names)?;
+    builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+
+    let cert = String::from_utf8(builder.build().to_pem()?)?;
+    let key = String::from_utf8(key.private_key_to_pem_pkcs8()?)?;
+    Ok((cert, key))
+}
+
+fn server_config(cert: &str, key: &str) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_slice_iter(cert.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid certificate")?;
+    let key = PrivateKeyDer::from_pem_slice(key.as_bytes()).context("Invalid private key")?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Failed to configure TLS")?;
+    Ok(Arc::new(config))
+}
+
+fn handle_connection(stream: TcpStream, config: &Arc<ServerConfig>, kbs: &MockKbs) -> Result<()> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    let connection = ServerConnection::new(Arc::clone(config))?;
+    let mut stream = BufReader::new(StreamOwned::new(connection, stream));
+    let request = read_request(&mut stream)?;
+    let response = kbs.handle(&request);
+    eprintln!("{} {} {}", request.method, request.path, response.status);
+    let stream = stream.get_mut();
+    write_response(stream, &response)?;
+    stream.conn.send_close_notify();
+    stream.flush()?;
+    Ok(())
+}
+
+/// Serve the clients of `listener` until killed, one at a time
+fn serve_on(listener: &TcpListener, config: &Arc<ServerConfig>, kbs: &MockKbs) {
+    for stream in listener.incoming() {
+        let result = stream
+            .map_err(anyhow::Error::from)
+            .and_then(|stream| handle_connection(stream, config, kbs));
+        if let Err(e) = result {
+            eprintln!("Failed to serve a request: {:#}", e);
+        }
+    }
+}
+
+/// Serve the files below `resources` as KBS resources on `listen` over
+/// HTTPS until killed. The generated certificate is written to `cert_out`,
+/// or printed, for the `cert` of the server in the config.
+pub fn serve(listen: &str, resources: &Path, cert_out: Option<&Path>) -> Result<()> {
+    if !resources.is_dir() {
+        return Err(anyhow!("{} is not a directory", resources.display()));
+    }
+    let listener =
+        TcpListener::bind(listen).with_context(|| format!("Failed to listen on {}", listen))?;
+    let addr = listener.local_addr()?;
+    let (cert, key) = generate_cert(addr.ip()).context("Failed to generate the certificate")?;
+    match cert_out {
+        Some(path) => {
+            fs::write(path, &cert).with_context(|| format!("Failed to write {}", path.display()))?
+        }
+        None => print!("{}", cert),
+    }
+    io::stdout().flush()?;
+    eprintln!(
+        "Serving the resources of {} on https://{}, accepting any attestation",
+        resources.display(),
+        addr
+    );
+    serve_on(
+        &listener,
+        &server_config(&cert, &key)?,
+        &MockKbs::new(resources),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use josekit::jwk::alg::rsa::RsaKeyPair;
+    use serde_json::Map;
+    use std::thread;
+
+    fn request(method: &str, path: &str, headers: &[(&str, &str)], body: Value) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn decrypt(body: &str, key_pair: &RsaKeyPair) -> Vec<u8> {
+        let decrypter = RSA_OAEP
+            .decrypter_from_jwk(&key_pair.to_jwk_private_key())
+            .unwrap();
+        josekit::jwe::deserialize_json(body, &decrypter).unwrap().0
+    }
+
+    #[test]
+    fn test_handle() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("default/key")).unwrap();
+        fs::write(dir.path().join("default/key/root"), "secret").unwrap();
+        let kbs = MockKbs::new(dir.path());
+        let key_pair = RsaKeyPair::generate(2048).unwrap();
+        let tee_pubkey: Map<String, Value> = key_pair.to_jwk_public_key().into();
+
+        let auth = kbs.handle(&request("POST", "/kbs/v0/auth", &[], json!({})));
+        assert_eq!(auth.status, 400);
+        let auth = json!({"version": "0.4.0", "tee": "sample", "extra-params": {}});
+        let session = kbs
+            .handle(&request("POST", "/kbs/v0/auth", &[], auth))
+            .session_id
+            .unwrap();
+        let cookie = format!("{}={}", SESSION_COOKIE, session);
+        let cookie = [("Cookie", cookie.as_str())];
+
+        let resource = request(
+            "GET",
+            "/kbs/v0/resource/default/key/root",
+            &cookie,
+            json!({}),
+        );
+        assert_eq!(kbs.handle(&resource).status, 401);
+
+        let attest = json!({"runtime-data": {"nonce": "n", "tee-pubkey": tee_pubkey}});
+        assert_eq!(
+            kbs.handle(&request("POST", "/kbs/v0/attest", &[], attest.clone()))
+                .status,
+            401
+        );
+        let response = kbs.handle(&request("POST", "/kbs/v0/attest", &cookie, attest));
+        assert_eq!(response.status, 200);
+        let token: Value = serde_json::from_str(&response.body).unwrap();
+        let token = token["token"].as_str().unwrap();
+
+        let response = kbs.handle(&resource);
+        assert_eq!(response.status, 200);
+        assert_eq!(decrypt(&response.body, &key_pair), b"secret");
+
+        let bearer = format!("Bearer {}", token);
+        let bearer = [("Authorization", bearer.as_str())];
+        let versioned = "/kbs/v0/resource/default/key/root?version=2";
+        let response = kbs.handle(&request("GET", versioned, &bearer, json!({})));
+        assert_eq!(decrypt(&response.body, &key_pair), b"secret");
+        assert_eq!(token_pubkey(token, now() + 3600), None);
+
+        let missing = request(
+            "GET",
+            "/kbs/v0/resource/default/key/other",
+            &bearer,
+            json!({}),
+        );
+        assert_eq!(kbs.handle(&missing).status, 404);
+        let escape = request("GET", "/kbs/v0/resource/../key/root", &bearer, json!({}));
+        assert_eq!(kbs.handle(&escape).status, 400);
+    }
+
+    #[test]
+    fn test_serve() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("https://{}", listener.local_addr().unwrap());
+        let (cert, key) = generate_cert(listener.local_addr().unwrap().ip()).unwrap();
+        let config = server_config(&cert, &key).unwrap();
+        let resources = dir.path().to_path_buf();
+        thread::spawn(move || serve_on(&listener, &config, &MockKbs::new(&resources)));
+
+        let client = crate::build_http_client(&cert, Some(IO_TIMEOUT)).unwrap();
+        let response = client
+            .post(format!("{}/kbs/v0/auth", url))
+            .json(&json!({"version": "0.4.0", "tee": "sample"}))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let cookie = response.headers()["set-cookie"].to_str().unwrap();
+        assert!(cookie.starts_with("kbs-session-id="));
+        let challenge: Value = response.json().unwrap();
+        assert!(challenge["nonce"].is_string());
+
+        let response = client
+            .get(format!("{}/kbs/v0/resource/a/b/c", url))
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), 401);
+
+        // Without the generated certificate, the server is not trusted
+        let client = crate::build_http_client("", Some(IO_TIMEOUT)).unwrap();
+        assert!(client.post(format!("{}/kbs/v0/auth", url)).send().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Waiting for the network before the first fetch attempt. In early boot the
+//! attempts made before the interfaces are configured only fail and fill the
+//! journal, so the fetch can first wait for a server to be routable.
+
+use crate::endpoint;
+use crate::logging::{Priority, log};
+use clevis_pin_trustee_lib::{Server, format_duration};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the machine has a route to `addr`. Connecting a UDP socket sends
+/// nothing, it only selects the route, and fails without one.
+fn has_route(addr: &SocketAddr) -> bool {
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    UdpSocket::bind(local)
+        .and_then(|socket| socket.connect(addr))
+        .is_ok()
+}
+
+/// Whether the host of `url` resolves to an address the machine has a route to
+fn is_routable(url: &str) -> bool {
+    let Ok(addrs) = endpoint::socket_addrs(url) else {
+        return false;
+    };
+    addrs.iter().any(has_route)
+}
+
+/// Poll `ready` with the servers until one of them is ready or `timeout`
+/// elapses. Returns whether one was ready.
+fn wait_with(
+    servers: &[Server],
+    timeout: Duration,
+    poll_interval: Duration,
+    ready: impl Fn(&str) -> bool,
+) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if servers.iter().any(|server| ready(&server.url)) {
+            return true;
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        thread::sleep(poll_interval.min(remaining));
+    }
+}
+
+/// Wait up to `timeout` for the host of one of `servers` to resolve to a
+/// routable address. When none does, the fetch is attempted anyway: the
+/// network may only be missing a route the KBS proxy does not need.
+pub(crate) fn wait_for_network(servers: &[Server], timeout: Duration) {
+    let start = Instant::now();
+    if wait_with(servers, timeout, POLL_INTERVAL, is_routable) {
+        log(
+            Priority::Info,
+            &format!("Network ready after {}", format_duration(start.elapsed())),
+            &[],
+        );
+    } else {
+        log(
+            Priority::Warning,
+            &format!(
+                "No server reachable after waiting {} for the network, trying anyway",
+                format_duration(timeout)
+            ),
+            &[],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clevis_pin_trustee_lib::Cert;
+    use std::cell::Cell;
+
+    fn servers(urls: &[&str]) -> Vec<Server> {
+        urls.iter()
+            .map(|url| Server {
+                name: None,
+                url: url.to_string(),
+                cert: Cert::None,
+                policy_ids: None,
+                tls_min_version: None,
+                tls_cipher_suites: None,
+                crl_path: None,
+                headers: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_wait_with() {
+        let servers = servers(&["http://kbs1:8080", "http://kbs2:8080"]);
+        let probes = Cell::new(0);
+
+        let ready = wait_with(&servers, Duration::from_secs(5), Duration::ZERO, |url| {
+            probes.set(probes.get() + 1);
+            probes.get() > 4 && url == "http://kbs2:8080"
+        });
+
+        assert!(ready);
+        assert_eq!(probes.get(), 6);
+        assert!(!wait_with(
+            &servers,
+            Duration::from_millis(20),
+            Duration::from_millis(5),
+            |_| false
+        ));
+    }
+
+    #[test]
+    fn test_is_routable() {
+        assert!(is_routable("http://127.0.0.1:8080"));
+        assert!(!is_routable("not a url"));
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Random passphrases for the keyslots of `bind` and `migrate`, drawn
+//! uniformly from an alphabet with the OS RNG, so callers never have to
+//! source the randomness themselves.
+
+use crate::entropy::{OsRandom, RandomSource};
+use clevis_pin_trustee_lib::TrusteePinError;
+
+/// Characters of the default passphrase, the length of 32 random bytes in
+/// base64url like the keys of `clevis luks bind`
+const DEFAULT_LENGTH: usize = 43;
+/// Longest passphrase cryptsetup accepts interactively
+const MAX_LENGTH: usize = 512;
+/// Least entropy of a passphrase, in bits
+const MIN_ENTROPY_BITS: f64 = 128.0;
+
+/// Characters a passphrase is drawn from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Alphabet {
+    /// Letters, digits, `-` and `_`
+    #[default]
+    Base64url,
+    /// Letters and digits, e.g. to type the passphrase on a console
+    Alphanumeric,
+    /// Lowercase hexadecimal digits
+    Hex,
+    /// Printable ASCII characters but space
+    Printable,
+}
+
+impl Alphabet {
+    fn chars(self) -> Vec<u8> {
+        let alphanumeric = (b'A'..=b'Z').chain(b'a'..=b'z').chain(b'0'..=b'9');
+        match self {
+            Alphabet::Base64url => alphanumeric.chain([b'-', b'_']).collect(),
+            Alphabet::Alphanumeric => alphanumeric.collect(),
+            Alphabet::Hex => (b'0'..=b'9').chain(b'a'..=b'f').collect(),
+            Alphabet::Printable => (b'!'..=b'~').collect(),
+        }
+    }
+}
+
+/// Length and alphabet of a generated passphrase
+#[derive(Debug, Clone, clap::Args)]
+pub struct PassphraseOptions {
+    /// Characters in the generated passphrase
+    #[arg(long = "passphrase-length", value_name = "N", default_value_t = DEFAULT_LENGTH)]
+    pub length: usize,
+    /// Characters the generated passphrase is drawn from
+    #[arg(long = "passphrase-alphabet", value_enum, default_value_t)]
+    pub alphabet: Alphabet,
+}
+
+impl Default for PassphraseOptions {
+    fn default() -> Self {
+        PassphraseOptions {
+            length: DEFAULT_LENGTH,
+            alphabet: Alphabet::default(),
+        }
+    }
+}
+
+impl PassphraseOptions {
+    /// Bits of entropy of a passphrase with these options
+    pub fn entropy_bits(&self) -> f64 {
+        self.length as f64 * (self.alphabet.chars().len() as f64).log2()
+    }
+
+    fn check(&self) -> Result<(), TrusteePinError> {
+        if self.length > MAX_LENGTH {
+            return Err(TrusteePinError::Config(format!(
+                "A passphrase has at most {} characters",
+                MAX_LENGTH
+            )));
+        }
+        if self.entropy_bits() < MIN_ENTROPY_BITS {
+            return Err(TrusteePinError::Config(format!(
+                "{} characters of this alphabet hold {:.0} bits of entropy, at least {} are needed",
+                self.length,
+                self.entropy_bits(),
+                MIN_ENTROPY_BITS
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Passphrase of `options` with the characters drawn from `rng`. Bytes
+/// beyond the largest multiple of the alphabet size are dropped, so every
+/// character is equally likely.
+pub(crate) fn generate_with(
+    rng: &dyn RandomSource,
+    options: &PassphraseOptions,
+) -> Result<String, TrusteePinError> {
+    options.check()?;
+    let chars = options.alphabet.chars();
+    let limit = 256 - 256 % chars.len();
+    let mut passphrase = String::with_capacity(options.length);
+    let mut bytes = vec![0; options.length];
+    while passphrase.len() < options.length {
+        rng.fill(&mut bytes);
+        passphrase.extend(
+            bytes
+                .iter()
+                .map(|&byte| usize::from(byte))
+                .filter(|&byte| byte < limit)
+                .map(|byte| char::from(chars[byte % chars.len()]))
+                .take(options.length - passphrase.len()),
+        );
+    }
+    Ok(passphrase)
+}
+
+/// Random passphrase of `options`, from the OS RNG
+pub fn generate(options: &PassphraseOptions) -> Result<String, TrusteePinError> {
+    generate_with(&OsRandom, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_generate() {
+        let passphrase = generate(&PassphraseOptions::default()).unwrap();
+        assert_eq!(passphrase.len(), DEFAULT_LENGTH);
+        assert!(
+            passphrase
+                .bytes()
+                .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_')
+        );
+
+        let options = PassphraseOptions {
+            length: 64,
+            alphabet: Alphabet::Hex,
+        };
+        let seeded = || RefCell::new(StdRng::seed_from_u64(7));
+        let passphrase = generate_with(&seeded(), &options).unwrap();
+        assert_eq!(passphrase, generate_with(&seeded(), &options).unwrap());
+        assert_eq!(passphrase.len(), 64);
+        assert!(passphrase.bytes().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_weak_options() {
+        let options = |length, alphabet| PassphraseOptions { length, alphabet };
+
+        assert!(generate(&options(31, Alphabet::Hex)).is_err());
+        assert!(generate(&options(32, Alphabet::Hex)).is_ok());
+        assert!(generate(&options(20, Alphabet::Printable)).is_ok());
+        assert!(generate(&options(MAX_LENGTH + 1, Alphabet::Base64url)).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Pipeline of `KeyTransform`s applied to the resource fetched from the KBS
+//! before the key is read in it, for sites whose provisioning tools store
+//! keys in an encoding of their own.
+
+use anyhow::{Context, Result, anyhow};
+use aws_lc_rs::hkdf::{self, HKDF_SHA256};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::KeyTransform;
+use sha2::{Digest, Sha256};
+
+/// Bytes derived by the `hkdf` step, an A256GCM key
+const HKDF_BYTES: usize = 32;
+
+/// Length of the HKDF output, for aws-lc-rs
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-SHA256 of `secret`. No salt is the same as a salt of zeros.
+pub(crate) fn hkdf(secret: &[u8], info: &[u8], salt: Option<&[u8]>) -> Result<Vec<u8>> {
+    let mut output = vec![0; HKDF_BYTES];
+    hkdf::Salt::new(HKDF_SHA256, salt.unwrap_or_default())
+        .extract(secret)
+        .expand(&[info], OutputLen(HKDF_BYTES))
+        .and_then(|okm| okm.fill(&mut output))
+        .map_err(|_| anyhow!("HKDF cannot derive {} bytes", HKDF_BYTES))?;
+    Ok(output)
+}
+
+fn text(bytes: &[u8]) -> Result<&str> {
+    Ok(std::str::from_utf8(bytes)
+        .context("the key is not text")?
+        .trim())
+}
+
+fn apply_one(transform: &KeyTransform, key: Vec<u8>) -> Result<Vec<u8>> {
+    match transform {
+        KeyTransform::Trim => Ok(key.trim_ascii().to_vec()),
+        KeyTransform::Base64Decode => Ok(general_purpose::STANDARD.decode(text(&key)?)?),
+        KeyTransform::Base64UrlDecode => {
+            Ok(general_purpose::URL_SAFE_NO_PAD.decode(text(&key)?.trim_end_matches('='))?)
+        }
+        KeyTransform::HexDecode => Ok(hex::decode(text(&key)?)?),
+        KeyTransform::Sha256 => Ok(Sha256::digest(&key).to_vec()),
+        KeyTransform::Hkdf { info, salt } => {
+            hkdf(&key, info.as_bytes(), salt.as_deref().map(str::as_bytes))
+        }
+    }
+}
+
+/// `resource` with the steps of `pipeline` applied in order
+pub(crate) fn apply(pipeline: &[KeyTransform], resource: Vec<u8>) -> Result<Vec<u8>> {
+    pipeline.iter().try_fold(resource, |key, transform| {
+        apply_one(transform, key).with_context(|| {
+            format!(
+                "Failed to apply the key transform {}",
+                String::from(transform.clone())
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(steps: &[&str]) -> Vec<KeyTransform> {
+        steps
+            .iter()
+            .map(|step| KeyTransform::try_from(step.to_string()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(
+            apply(
+                &pipeline(&["trim", "base64-decode", "hex-decode"]),
+                b" NjE2MjYz\n".to_vec()
+            )
+            .unwrap(),
+            b"abc"
+        );
+        assert_eq!(
+            apply(&pipeline(&["base64url-decode"]), b"-_8".to_vec()).unwrap(),
+            [0xfb, 0xff]
+        );
+        assert_eq!(
+            hex::encode(apply(&pipeline(&["sha256"]), b"abc".to_vec()).unwrap()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            format!(
+                "{:#}",
+                apply(&pipeline(&["hex-decode"]), b"xy".to_vec()).unwrap_err()
+            ),
+            "Failed to apply the key transform hex-decode: Invalid character 'x' at position 0"
+        );
+    }
+
+    #[test]
+    fn test_hkdf() {
+        // RFC 5869, test case 1
+        let ikm = [0x0b; 22];
+        let salt = hex::decode("000102030405060708090a0b0c").unwrap();
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+        // The first 32 of the 42 bytes of the test case
+        assert_eq!(
+            hex::encode(hkdf(&ikm, &info, Some(&salt)).unwrap()),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        );
+        // RFC 5869, test case 3: no salt
+        assert_eq!(
+            hex::encode(hkdf(&ikm, b"", None).unwrap()),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d"
+        );
+
+        let derived = apply(
+            &pipeline(&["hkdf:info=luks,salt=site-a"]),
+            b"secret".to_vec(),
+        )
+        .unwrap();
+        assert_eq!(derived.len(), HKDF_BYTES);
+        assert_eq!(derived, hkdf(b"secret", b"luks", Some(b"site-a")).unwrap());
+        assert_ne!(derived, hkdf(b"secret", b"luks", None).unwrap());
+
+        assert_eq!(
+            String::from(KeyTransform::try_from("hkdf:salt=s,info=i".to_string()).unwrap()),
+            "hkdf:info=i,salt=s"
+        );
+        assert!(KeyTransform::try_from("hkdf:pepper=x".to_string()).is_err());
+        assert!(KeyTransform::try_from("sha256:x".to_string()).is_err());
+        assert!(KeyTransform::try_from("rot13".to_string()).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Progress of the key fetch as newline-delimited JSON on stderr, one event
+//! per line, for boot splash scripts and provisioning UIs showing the unlock
+//! as it goes rather than parsing the log.
+
+use clevis_pin_trustee_lib::{EventHandler, FailureKind};
+use serde_json::{Value, json};
+use std::cell::RefCell;
+use std::io::{self, Write};
+
+/// Writes every event as a JSON line to its writer, stderr by default
+pub struct NdjsonProgress<W: Write = io::Stderr> {
+    out: RefCell<W>,
+}
+
+impl NdjsonProgress {
+    pub fn new() -> Self {
+        Self::with_writer(io::stderr())
+    }
+}
+
+impl Default for NdjsonProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> NdjsonProgress<W> {
+    pub fn with_writer(out: W) -> Self {
+        NdjsonProgress {
+            out: RefCell::new(out),
+        }
+    }
+
+    fn emit(&self, event: Value) {
+        // A reader that went away must not fail the unlock
+        let _ = writeln!(self.out.borrow_mut(), "{}", event);
+    }
+}
+
+impl<W: Write> EventHandler for NdjsonProgress<W> {
+    fn on_attempt_start(&self, attempt: u32, max_attempts: Option<u32>) {
+        self.emit(json!({
+            "event": "attempt",
+            "attempt": attempt,
+            "max_attempts": max_attempts,
+        }));
+    }
+
+    fn on_server_failure(&self, url: &str, error: &str, kind: FailureKind) {
+        let kind = match kind {
+            FailureKind::Transient => "transient",
+            FailureKind::Permanent => "permanent",
+        };
+        self.emit(json!({
+            "event": "server-failure",
+            "url": url,
+            "error": error,
+            "kind": kind,
+        }));
+    }
+
+    fn on_success(&self, url: &str) {
+        self.emit(json!({"event": "success", "url": url}));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let progress = NdjsonProgress::with_writer(Vec::new());
+
+        progress.on_attempt_start(1, None);
+        progress.on_server_failure("http://kbs1", "timed out", FailureKind::Transient);
+        progress.on_attempt_start(2, Some(3));
+        progress.on_success("http://kbs2");
+
+        let out = progress.out.into_inner();
+        let events: Vec<Value> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                json!({"event": "attempt", "attempt": 1, "max_attempts": null}),
+                json!({
+                    "event": "server-failure",
+                    "url": "http://kbs1",
+                    "error": "timed out",
+                    "kind": "transient",
+                }),
+                json!({"event": "attempt", "attempt": 2, "max_attempts": 3}),
+                json!({"event": "success", "url": "http://kbs2"}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,365 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Recording of the key fetches of a decrypt or unlock, and their replay, to
+//! reproduce a failing unlock offline. Every attempt is written as a JSON
+//! line with its server, resource, timing and outcome as soon as it ends, so
+//! a recording survives an unlock that never completes. Keys are never
+//! written, only their key id, the one of the header: a replay reproduces
+//! the retries and failures but cannot decrypt.
+
+use crate::{KeyFetcher, key_id};
+use anyhow::{Context, Result, anyhow};
+use clevis_pin_trustee_lib::{
+    Cert, ConnectionSettings, FailureCause, FailureKind, TrusteePinError,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// A fetch attempt of the recording
+#[derive(Debug, Serialize, Deserialize)]
+struct Interaction {
+    url: String,
+    path: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    policy_ids: Vec<String>,
+    /// Since the recording started
+    offset_ms: u64,
+    elapsed_ms: u64,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+enum Outcome {
+    /// Key id of the key fetched, standing for the key
+    Key {
+        key_id: String,
+    },
+    Error(RecordedError),
+}
+
+/// What a failed attempt ran into, enough to classify it again on replay
+#[derive(Debug, Serialize, Deserialize)]
+struct RecordedError {
+    message: String,
+    error: ErrorClass,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorClass {
+    Transient,
+    Permanent,
+    Timeout,
+    Network,
+    Config,
+    Crypto,
+    ClockSkew,
+    Cancelled,
+    /// Not a failure of the pin, retried as transient
+    Other,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+impl RecordedError {
+    fn new(error: &anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        let pin_error = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<TrusteePinError>());
+        let (error, status) = match pin_error {
+            Some(TrusteePinError::Fetch { kind, cause, .. }) => match cause {
+                Some(FailureCause::Status(status)) => (ErrorClass::from(*kind), Some(*status)),
+                Some(FailureCause::Timeout) => (ErrorClass::Timeout, None),
+                Some(FailureCause::Network) => (ErrorClass::Network, None),
+                None => (ErrorClass::from(*kind), None),
+            },
+            Some(TrusteePinError::Config(_)) => (ErrorClass::Config, None),
+            Some(TrusteePinError::Crypto(_)) => (ErrorClass::Crypto, None),
+            Some(TrusteePinError::ClockSkew(_)) => (ErrorClass::ClockSkew, None),
+            Some(TrusteePinError::Cancelled) => (ErrorClass::Cancelled, None),
+            Some(TrusteePinError::GaveUp { kind, .. }) => (ErrorClass::from(*kind), None),
+            None => (ErrorClass::Other, None),
+        };
+        RecordedError {
+            message,
+            error,
+            status,
+            retry_after_ms: pin_error.and_then(TrusteePinError::retry_after).map(millis),
+        }
+    }
+
+    /// The error of the attempt again, with the classification driving the
+    /// retries
+    fn to_error(&self, url: &str) -> anyhow::Error {
+        let message = self.message.clone();
+        let error = match (self.error, self.status) {
+            (ErrorClass::Transient | ErrorClass::Permanent, Some(status)) => {
+                let mut error = TrusteePinError::from_status(url, status, message);
+                if let TrusteePinError::Fetch { kind, .. } = &mut error {
+                    *kind = self.error.into();
+                }
+                error
+            }
+            (ErrorClass::Transient | ErrorClass::Permanent, None) => TrusteePinError::Fetch {
+                server: url.to_string(),
+                kind: self.error.into(),
+                message,
+                retry_after: None,
+                cause: None,
+            },
+            (ErrorClass::Timeout, _) => {
+                TrusteePinError::transport(url, FailureCause::Timeout, message)
+            }
+            (ErrorClass::Network, _) => {
+                TrusteePinError::transport(url, FailureCause::Network, message)
+            }
+            (ErrorClass::Config, _) => TrusteePinError::Config(message),
+            (ErrorClass::Crypto, _) => TrusteePinError::Crypto(message),
+            (ErrorClass::ClockSkew, _) => TrusteePinError::ClockSkew(message),
+            (ErrorClass::Cancelled, _) => TrusteePinError::Cancelled,
+            (ErrorClass::Other, _) => return anyhow!(message),
+        };
+        error
+            .with_retry_after(self.retry_after_ms.map(Duration::from_millis))
+            .into()
+    }
+}
+
+impl From<FailureKind> for ErrorClass {
+    fn from(kind: FailureKind) -> Self {
+        match kind {
+            FailureKind::Transient => ErrorClass::Transient,
+            FailureKind::Permanent => ErrorClass::Permanent,
+        }
+    }
+}
+
+impl From<ErrorClass> for FailureKind {
+    fn from(class: ErrorClass) -> Self {
+        match class {
+            ErrorClass::Permanent => FailureKind::Permanent,
+            _ => FailureKind::Transient,
+        }
+    }
+}
+
+/// Key fetcher writing the attempts of the one it wraps to a recording
+pub(crate) struct Recorder {
+    inner: Box<dyn KeyFetcher>,
+    out: RefCell<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Record the attempts of `inner` to `path`, replacing its content. The
+    /// recording is readable by its owner only.
+    pub(crate) fn new(inner: Box<dyn KeyFetcher>, path: &Path) -> Result<Self> {
+        let out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(Recorder {
+            inner,
+            out: RefCell::new(out),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, interaction: &Interaction) {
+        let line = serde_json::to_string(interaction).unwrap_or_default();
+        // A full disk must not fail the unlock it records
+        if let Err(e) = writeln!(self.out.borrow_mut(), "{}", line) {
+            eprintln!("Warning: failed to record the fetch: {}", e);
+        }
+    }
+}
+
+impl KeyFetcher for Recorder {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        cert: &Cert,
+        connection: &ConnectionSettings,
+        initdata: Option<String>,
+        policy_ids: &[String],
+    ) -> Result<String> {
+        let started = Instant::now();
+        let result = self
+            .inner
+            .try_fetch_luks_key(url, path, cert, connection, initdata, policy_ids);
+        let outcome = match &result {
+            Ok(key) => Outcome::Key {
+                key_id: key_id(key),
+            },
+            Err(e) => Outcome::Error(RecordedError::new(e)),
+        };
+        self.record(&Interaction {
+            url: url.to_string(),
+            path: path.to_string(),
+            policy_ids: policy_ids.to_vec(),
+            offset_ms: millis(started.duration_since(self.start)),
+            elapsed_ms: millis(started.elapsed()),
+            outcome,
+        });
+        result
+    }
+}
+
+/// Key fetcher answering with the attempts of a recording, in order, without
+/// contacting any server
+pub(crate) struct Replayer {
+    interactions: RefCell<VecDeque<Interaction>>,
+}
+
+impl Replayer {
+    pub(crate) fn load(path: &Path) -> Result<Self> {
+        let recording = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let interactions = recording
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(number, line)| {
+                serde_json::from_str(line).map_err(|e| {
+                    TrusteePinError::Config(format!(
+                        "Invalid recording {} at line {}: {}",
+                        path.display(),
+                        number + 1,
+                        e
+                    ))
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Replayer {
+            interactions: RefCell::new(interactions),
+        })
+    }
+}
+
+impl KeyFetcher for Replayer {
+    fn try_fetch_luks_key(
+        &self,
+        url: &str,
+        path: &str,
+        _cert: &Cert,
+        _connection: &ConnectionSettings,
+        _initdata: Option<String>,
+        _policy_ids: &[String],
+    ) -> Result<String> {
+        let Some(interaction) = self.interactions.borrow_mut().pop_front() else {
+            return Err(TrusteePinError::Config(format!(
+                "The recording ends before the fetch of {} from {}",
+                path, url
+            ))
+            .into());
+        };
+        if interaction.url != url || interaction.path != path {
+            return Err(TrusteePinError::Config(format!(
+                "The replay diverged from the recording: fetch of {} from {}, recorded {} from {}",
+                path, url, interaction.path, interaction.url
+            ))
+            .into());
+        }
+        eprintln!(
+            "Replaying the fetch of {} from {}, which took {} ms",
+            path, url, interaction.elapsed_ms
+        );
+        match &interaction.outcome {
+            Outcome::Key { key_id } => Err(TrusteePinError::Config(format!(
+                "The key fetched from {} is not in the recording, only its key id {}",
+                url, key_id
+            ))
+            .into()),
+            Outcome::Error(error) => Err(error.to_error(url)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockCommandExecutor, failure_kind, retry_after};
+    use sha2::{Digest, Sha256};
+
+    fn fetch(executor: &dyn KeyFetcher, url: &str) -> Result<String> {
+        executor.try_fetch_luks_key(
+            url,
+            "default/key/root",
+            &Cert::default(),
+            &ConnectionSettings::default(),
+            None,
+            &[],
+        )
+    }
+
+    fn record(path: &Path, response: Result<String>) {
+        let recorder = Recorder::new(Box::new(MockCommandExecutor { response }), path).unwrap();
+        let _ = fetch(&recorder, "http://kbs:8080");
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        let denied = TrusteePinError::from_status("http://kbs:8080", 401, "Attestation denied")
+            .with_retry_after(Some(Duration::from_secs(3)));
+        record(&path, Err(denied.into()));
+
+        let replayer = Replayer::load(&path).unwrap();
+        let error = fetch(&replayer, "http://kbs:8080").unwrap_err();
+        assert_eq!(error.to_string(), "Attestation denied");
+        assert_eq!(failure_kind(&error), FailureKind::Permanent);
+        assert_eq!(retry_after(&error), Some(Duration::from_secs(3)));
+
+        let error = fetch(&replayer, "http://kbs:8080").unwrap_err();
+        assert!(error.to_string().contains("The recording ends"));
+    }
+
+    #[test]
+    fn test_record_redacts_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        record(&path, Ok("secret-key".to_string()));
+
+        let recording = fs::read_to_string(&path).unwrap();
+        assert!(!recording.contains("secret-key"));
+        assert!(recording.contains(&format!("\"key_id\":\"{}\"", key_id("secret-key"))));
+        assert!(!recording.contains(&hex::encode(Sha256::digest(b"secret-key"))));
+
+        let replayer = Replayer::load(&path).unwrap();
+        let error = fetch(&replayer, "http://kbs:8080").unwrap_err();
+        assert!(error.to_string().contains("not in the recording"));
+    }
+
+    #[test]
+    fn test_replay_diverged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.jsonl");
+        record(&path, Err(anyhow!("Connection refused")));
+
+        let replayer = Replayer::load(&path).unwrap();
+        let error = fetch(&replayer, "http://other:8080").unwrap_err();
+        assert!(error.to_string().contains("diverged"));
+    }
+}
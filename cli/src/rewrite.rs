@@ -0,0 +1,127 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Local map of server URLs rewritten before fetching, so a fleet-wide
+//! migration of the KBS to a new hostname does not need every volume to be
+//! bound again. The servers keep the certificate and settings of the
+//! binding; the allowlist, if any, applies to the rewritten URLs.
+
+use crate::endpoint;
+use anyhow::{Context, Result};
+use clevis_pin_trustee_lib::{Server, TrusteePinError};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// One `old-url -> new-url` per line, `#` starting comments
+pub(crate) const SERVER_REWRITES_PATH: &str = "/etc/clevis-trustee/server-rewrites";
+
+pub(crate) struct RewriteMap {
+    /// Comparable old URL and the URL replacing it
+    rewrites: Vec<(String, String)>,
+}
+
+impl RewriteMap {
+    /// The map at `path`, `None` when there is no map
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(Self::parse(&content).with_context(|| {
+                format!("Invalid server rewrites in {}", path.display())
+            })?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, TrusteePinError> {
+        let mut rewrites = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (old, new) = match words[..] {
+                [] => continue,
+                [old, "->", new] | [old, new] => (old, new),
+                _ => {
+                    return Err(TrusteePinError::Config(format!(
+                        "line {}: expected old-url -> new-url",
+                        number + 1
+                    )));
+                }
+            };
+            rewrites.push((endpoint::comparable(old), endpoint::normalize_url(new)?));
+        }
+        Ok(RewriteMap { rewrites })
+    }
+
+    /// `servers`, with the URLs of the map rewritten
+    pub(crate) fn apply(&self, servers: Vec<Server>) -> Vec<Server> {
+        servers
+            .into_iter()
+            .map(|server| {
+                let url = endpoint::comparable(&server.url);
+                match self.rewrites.iter().find(|(old, _)| *old == url) {
+                    Some((_, new)) => {
+                        eprintln!("Rewriting server {} to {}", server.url, new);
+                        Server {
+                            url: new.clone(),
+                            ..server
+                        }
+                    }
+                    None => server,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(url: &str) -> Server {
+        serde_json::from_value(serde_json::json!(url)).unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let map = RewriteMap::parse(
+            "# Migration to the new domain\nhttps://kbs.old.example:8080/ -> https://kbs.new.example\n\nhttp://kbs2:8080  https://kbs2.new.example # fallback\n",
+        )
+        .unwrap();
+
+        let servers = map.apply(vec![
+            server("https://KBS.old.example:8080"),
+            server("https://other:8080"),
+            server("http://kbs2:8080"),
+        ]);
+
+        let urls: Vec<&str> = servers.iter().map(|server| server.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            [
+                "https://kbs.new.example",
+                "https://other:8080",
+                "https://kbs2.new.example"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_map() {
+        assert!(RewriteMap::parse("https://old -> \n").is_err());
+        assert!(RewriteMap::parse("https://old -> kbs.new:8080\n").is_err());
+        assert!(RewriteMap::parse("https://old => https://new\n").is_err());
+    }
+
+    #[test]
+    fn test_missing_map() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(
+            RewriteMap::load(&dir.path().join("server-rewrites"))
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,314 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Detached minisign signatures of the configs. When the public key of the
+//! provisioning pipeline is baked into the image, configs and runtime config
+//! files reaching the machine over an untrusted channel are only used if
+//! signed with it.
+
+use anyhow::{Context, Result};
+use aws_lc_rs::signature::{ED25519, UnparsedPublicKey};
+use base64::{Engine as _, engine::general_purpose};
+use blake2::{Blake2b512, Digest};
+use clevis_pin_trustee_lib::TrusteePinError;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// minisign public key the configs must be signed with, if present
+pub(crate) const SIGNING_KEY_PATH: &str = "/etc/clevis-trustee/config-signing.pub";
+/// Extension minisign gives the signature of a file
+const SIGNATURE_EXTENSION: &str = "minisig";
+
+const TRUSTED_COMMENT: &str = "trusted comment: ";
+
+/// minisign key ids are little-endian numbers shown in hexadecimal
+fn key_id_string(id: &[u8]) -> String {
+    id.iter()
+        .rev()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+fn verify_ed25519(key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, key)
+        .verify(message, signature)
+        .is_ok()
+}
+
+/// Decode a base64 line of a minisign file
+fn decode_line(line: Option<&str>, what: &str) -> Result<Vec<u8>, TrusteePinError> {
+    line.and_then(|line| general_purpose::STANDARD.decode(line.trim()).ok())
+        .ok_or_else(|| TrusteePinError::Config(format!("Invalid minisign {}", what)))
+}
+
+pub(crate) struct PublicKey {
+    id: [u8; 8],
+    key: [u8; 32],
+}
+
+impl PublicKey {
+    /// The key at `path`, `None` when there is none and configs need no
+    /// signature
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(
+                Self::parse(&content).with_context(|| format!("Invalid {}", path.display()))?,
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self, TrusteePinError> {
+        let line = content
+            .lines()
+            .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"));
+        let decoded = decode_line(line, "public key")?;
+        match decoded.as_slice() {
+            [b'E', b'd', rest @ ..] if rest.len() == 40 => Ok(PublicKey {
+                id: rest[..8].try_into().expect("8 bytes"),
+                key: rest[8..].try_into().expect("32 bytes"),
+            }),
+            _ => Err(TrusteePinError::Config(
+                "Invalid minisign public key".to_string(),
+            )),
+        }
+    }
+
+    /// Check that the minisign `signature` is the one of `data` made with
+    /// this key
+    pub(crate) fn verify(&self, data: &[u8], signature: &str) -> Result<(), TrusteePinError> {
+        let mut lines = signature.lines();
+        let _untrusted_comment = lines.next();
+        let decoded = decode_line(lines.next(), "signature")?;
+        let (algorithm, id, signature) = match decoded.as_slice() {
+            [a, b, rest @ ..] if rest.len() == 72 => (&[*a, *b], &rest[..8], &rest[8..]),
+            _ => return Err(TrusteePinError::Config("Invalid minisign signature".into())),
+        };
+        // minisign signs the BLAKE2b-512 hash of the data by default, and the
+        // data itself in legacy mode, with -l
+        let prehashed = match algorithm {
+            b"ED" => true,
+            b"Ed" => false,
+            _ => return Err(TrusteePinError::Config("Invalid minisign signature".into())),
+        };
+        if id != self.id {
+            return Err(TrusteePinError::Config(format!(
+                "The config is signed with the key {}, not with {}",
+                key_id_string(id),
+                key_id_string(&self.id)
+            )));
+        }
+        let comment = lines
+            .next()
+            .and_then(|line| line.strip_prefix(TRUSTED_COMMENT))
+            .ok_or_else(|| {
+                TrusteePinError::Config("The minisign signature has no trusted comment".into())
+            })?;
+        let global_signature = decode_line(lines.next(), "signature")?;
+        let signed_comment = [signature, comment.as_bytes()].concat();
+        let data = if prehashed {
+            Blake2b512::digest(data).to_vec()
+        } else {
+            data.to_vec()
+        };
+        if !verify_ed25519(&self.key, &data, signature)
+            || !verify_ed25519(&self.key, &signed_comment, &global_signature)
+        {
+            return Err(TrusteePinError::Config(
+                "The config signature is invalid, the config was tampered with".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Check the `signature` of `config` with the key at `key_path`. Without a
+/// key, configs are used as they are.
+fn check_with(key_path: &Path, config: &[u8], signature: Option<&Path>) -> Result<()> {
+    let Some(key) = PublicKey::load(key_path)? else {
+        if let Some(signature) = signature.filter(|signature| signature.exists()) {
+            eprintln!(
+                "Warning: ignoring {}, there is no {} to check it with",
+                signature.display(),
+                key_path.display()
+            );
+        }
+        return Ok(());
+    };
+    let signature = signature.ok_or_else(|| {
+        TrusteePinError::Config(format!(
+            "The config is not signed, {} requires a signature",
+            key_path.display()
+        ))
+    })?;
+    let content = match fs::read_to_string(signature) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(TrusteePinError::Config(format!(
+                "The config is not signed, {} is missing",
+                signature.display()
+            ))
+            .into());
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read {}", signature.display()));
+        }
+    };
+    key.verify(config, &content)
+        .with_context(|| format!("Failed to check {}", signature.display()))
+}
+
+/// Check the detached `signature` of the `config` text given to encrypt
+pub(crate) fn check_config(config: &str, signature: Option<&Path>) -> Result<()> {
+    check_with(Path::new(SIGNING_KEY_PATH), config.as_bytes(), signature)
+}
+
+/// Check the signature minisign writes next to the config file at `path`
+pub(crate) fn check_config_file(path: &Path, content: &str) -> Result<()> {
+    check_with(
+        Path::new(SIGNING_KEY_PATH),
+        content.as_bytes(),
+        Some(&signature_path(path)),
+    )
+}
+
+/// Refuse the setting `what`, which comes with no signature, when configs
+/// must be signed with the key at `key_path`
+fn check_unsigned_with(key_path: &Path, what: &str) -> Result<()> {
+    if key_path.exists() {
+        return Err(TrusteePinError::Config(format!(
+            "{what} cannot be signed, it is refused while {} is present",
+            key_path.display()
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Refuse the unsigned setting `what` when configs must be signed
+pub(crate) fn check_unsigned(what: &str) -> Result<()> {
+    check_unsigned_with(Path::new(SIGNING_KEY_PATH), what)
+}
+
+/// `path.minisig`, where minisign writes the signature of `path`
+fn signature_path(path: &Path) -> PathBuf {
+    let mut signature = path.as_os_str().to_owned();
+    signature.push(".");
+    signature.push(SIGNATURE_EXTENSION);
+    PathBuf::from(signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key 8877665544332211\n\
+        RWQRIjNEVWZ3iAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4\n";
+    const CONFIG: &str = r#"{"servers": [], "path": "default/key/root"}"#;
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key\n\
+        RWQRIjNEVWZ3iHIL0PYzdqg9UxQ91OJS/KcBGshFKOduzUIvFaVrQPlvoR0HlMM/jQ3kNC47zy4iCLE1pbnhED32BEoQwnYg8gQ=\n\
+        trusted comment: timestamp:1800000000\tfile:config.json\n\
+        W2SETBB2ex7jCrf3SZ3HXOCas328NT9RhkI6xfUWXMgappmVz2m7nRIZdGe4IMXWZogILvVsvgLiciN4HQTcDQ==\n";
+
+    #[test]
+    fn test_verify() {
+        let key = PublicKey::parse(PUBLIC_KEY).unwrap();
+        key.verify(CONFIG.as_bytes(), SIGNATURE).unwrap();
+
+        let tampered = CONFIG.replace("root", "other");
+        assert_eq!(
+            key.verify(tampered.as_bytes(), SIGNATURE)
+                .unwrap_err()
+                .to_string(),
+            "The config signature is invalid, the config was tampered with"
+        );
+        let comment = SIGNATURE.replace("config.json", "other.json");
+        assert!(key.verify(CONFIG.as_bytes(), &comment).is_err());
+
+        let other = PublicKey {
+            id: [0; 8],
+            ..PublicKey::parse(PUBLIC_KEY).unwrap()
+        };
+        assert_eq!(
+            other
+                .verify(CONFIG.as_bytes(), SIGNATURE)
+                .unwrap_err()
+                .to_string(),
+            "The config is signed with the key 8877665544332211, not with 0000000000000000"
+        );
+        assert!(PublicKey::parse("untrusted comment: x\nAAAA\n").is_err());
+    }
+
+    #[test]
+    fn test_verify_prehashed() {
+        let public_key = "untrusted comment: minisign public key 1122334455667788\n\
+            RWSId2ZVRDMiEWaHOVj4BTi36nIMggxoziHXQotV9BmH+0a5kQJ0SqiM\n";
+        // Default mode of minisign 0.11+, without -l
+        let signature = "untrusted comment: signature from minisign secret key\n\
+            RUSId2ZVRDMiEZuUM6dofOEP8wyV2myaO7c2cemWSg1tOg8+YyJv8YcNdvfI1smb8Tlubx0zEk9R13Ul9UD5iw5/xBteQl6tqg0=\n\
+            trusted comment: timestamp:1800000000\tfile:config.json\tprehashed\n\
+            pwv+S0feUHI4lYgl5z67+qXsnCeset65gg9gFvCfZaHuv/xVm23CIf0F3F5Z+so3tuIIZS6EXWoxOLHttG6gDA==\n";
+        let key = PublicKey::parse(public_key).unwrap();
+        key.verify(CONFIG.as_bytes(), signature).unwrap();
+
+        let tampered = CONFIG.replace("root", "other");
+        assert!(key.verify(tampered.as_bytes(), signature).is_err());
+        // The same signature read as a legacy one does not match
+        let legacy = signature.replacen("RUS", "RWS", 1);
+        assert!(key.verify(CONFIG.as_bytes(), &legacy).is_err());
+    }
+
+    #[test]
+    fn test_check_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("config-signing.pub");
+        let config_path = dir.path().join("config.json");
+        let signature = signature_path(&config_path);
+        assert_eq!(signature, dir.path().join("config.json.minisig"));
+
+        // Without a key, nothing is checked
+        check_with(&key_path, b"anything", None).unwrap();
+
+        fs::write(&key_path, PUBLIC_KEY).unwrap();
+        let error = check_with(&key_path, CONFIG.as_bytes(), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "The config is not signed, {} requires a signature",
+                key_path.display()
+            )
+        );
+        let error = check_with(&key_path, CONFIG.as_bytes(), Some(&signature)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "The config is not signed, {} is missing",
+                signature.display()
+            )
+        );
+
+        fs::write(&signature, SIGNATURE).unwrap();
+        check_with(&key_path, CONFIG.as_bytes(), Some(&signature)).unwrap();
+        assert!(check_with(&key_path, b"{}", Some(&signature)).is_err());
+    }
+
+    #[test]
+    fn test_check_unsigned_with() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_path = dir.path().join("config-signing.pub");
+        check_unsigned_with(&key_path, "CLEVIS_TRUSTEE_OVERRIDE_URL").unwrap();
+
+        fs::write(&key_path, PUBLIC_KEY).unwrap();
+        let error = check_unsigned_with(&key_path, "CLEVIS_TRUSTEE_OVERRIDE_URL").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "CLEVIS_TRUSTEE_OVERRIDE_URL cannot be signed, it is refused while {} is present",
+                key_path.display()
+            )
+        );
+    }
+}
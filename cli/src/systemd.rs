@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Exit codes and sd_notify status telling a unit whether a failure is
+//! worth a restart: giving up after transient failures exits with
+//! EX_TEMPFAIL, a denied attestation or another permanent failure with
+//! EX_NOPERM, which the units list in `RestartPreventExitStatus=`.
+
+use clevis_pin_trustee_lib::{FailureKind, TrusteePinError};
+use std::env;
+use std::ffi::OsString;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+/// Exit code of a failure that may go away, sysexits EX_TEMPFAIL
+pub const EXIT_TRANSIENT: u8 = 75;
+/// Exit code of a failure that retrying cannot fix, sysexits EX_NOPERM
+pub const EXIT_PERMANENT: u8 = 77;
+/// Exit code of any other failure
+pub const EXIT_FAILURE: u8 = 1;
+
+/// How a command failed, as far as restarting it is concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The servers were unreachable or failing until the retries ran out
+    Transient,
+    /// Attestation denied, missing resource or bad configuration
+    Permanent,
+    /// Not a failure of the key fetch, e.g. cryptsetup failing
+    Other,
+}
+
+impl Failure {
+    /// The failure of the pin in the chain of `error`
+    pub fn of(error: &anyhow::Error) -> Self {
+        match error
+            .chain()
+            .find_map(|e| e.downcast_ref::<TrusteePinError>())
+            .map(TrusteePinError::kind)
+        {
+            Some(FailureKind::Transient) => Failure::Transient,
+            Some(FailureKind::Permanent) => Failure::Permanent,
+            None => Failure::Other,
+        }
+    }
+
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::Transient => EXIT_TRANSIENT,
+            Failure::Permanent => EXIT_PERMANENT,
+            Failure::Other => EXIT_FAILURE,
+        }
+    }
+
+    /// sd_notify status of the failure
+    pub fn status(self, error: &anyhow::Error) -> String {
+        let what = match self {
+            Failure::Transient => "Gave up, the servers may recover",
+            Failure::Permanent => "Denied, retrying will not help",
+            Failure::Other => "Failed",
+        };
+        // STATUS= is a single line
+        let error = format!("{:#}", error).replace('\n', "; ");
+        format!("STATUS={}: {}", what, error)
+    }
+}
+
+/// Send `state` to the service manager, if the process runs under one that
+/// listens on `NOTIFY_SOCKET`. The status is informational, so failing to
+/// send it is not an error.
+pub fn notify(state: &str) {
+    notify_socket(env::var_os("NOTIFY_SOCKET"), state);
+}
+
+fn notify_socket(socket: Option<OsString>, state: &str) {
+    let Some(socket) = socket else {
+        return;
+    };
+    let socket = socket.to_string_lossy();
+    // A leading @ stands for the abstract namespace
+    let address = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+        None => SocketAddr::from_pathname(socket.as_ref()),
+    };
+    let sent = UnixDatagram::unbound()
+        .and_then(|datagram| datagram.send_to_addr(state.as_bytes(), &address?));
+    if let Err(e) = sent {
+        eprintln!("Warning: failed to notify the service manager: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_failure_of() {
+        let gave_up = |kind| -> anyhow::Error {
+            anyhow::Error::from(TrusteePinError::GaveUp {
+                kind,
+                message: "http://kbs:8080: connection refused".to_string(),
+            })
+            .context("Failed to fetch the LUKS key")
+        };
+
+        let transient = gave_up(FailureKind::Transient);
+        assert_eq!(Failure::of(&transient), Failure::Transient);
+        assert_eq!(Failure::of(&transient).exit_code(), EXIT_TRANSIENT);
+        let permanent = gave_up(FailureKind::Permanent);
+        assert_eq!(Failure::of(&permanent).exit_code(), EXIT_PERMANENT);
+        let config = anyhow::Error::from(TrusteePinError::Config("No URLs".to_string()));
+        assert_eq!(Failure::of(&config), Failure::Permanent);
+        assert_eq!(Failure::of(&anyhow!("cryptsetup failed")), Failure::Other);
+
+        assert_eq!(
+            Failure::Transient.status(&transient),
+            "STATUS=Gave up, the servers may recover: Failed to fetch the LUKS key: \
+             http://kbs:8080: connection refused"
+        );
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(None, "STATUS=Ignored");
+        notify_socket(Some(path.into_os_string()), "STATUS=Denied");
+
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STATUS=Denied");
+    }
+}
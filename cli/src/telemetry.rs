@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! OpenTelemetry spans around the key fetch and the JWE operations.
+//!
+//! With the `otel` feature, the spans are exported with OTLP over HTTP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is
+//! set, so unlock latency can be correlated with the KBS traces. Without the
+//! feature, or without an endpoint, spans cost nothing.
+
+use anyhow::Result;
+
+#[cfg(feature = "otel")]
+use {
+    anyhow::Context as _,
+    opentelemetry::trace::{Status, TraceContextExt, Tracer, get_active_span},
+    opentelemetry::{Context, ContextGuard, KeyValue, global},
+    opentelemetry_otlp::SpanExporter,
+    opentelemetry_sdk::{Resource, trace::SdkTracerProvider},
+};
+
+#[cfg(feature = "otel")]
+const TRACER: &str = "clevis-pin-trustee";
+#[cfg(feature = "otel")]
+const ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+/// Flushes the exported spans when dropped, keep it alive until exit
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<SdkTracerProvider>,
+}
+
+/// Install the OTLP exporter if an endpoint is configured
+#[cfg(feature = "otel")]
+pub fn init() -> Result<Telemetry> {
+    if !ENDPOINT_VARS
+        .iter()
+        .any(|var| std::env::var_os(var).is_some())
+    {
+        return Ok(Telemetry { provider: None });
+    }
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .build()
+        .context("Failed to create the OTLP exporter")?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(TRACER).build())
+        .build();
+    global::set_tracer_provider(provider.clone());
+    Ok(Telemetry {
+        provider: Some(provider),
+    })
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init() -> Result<Telemetry> {
+    Ok(Telemetry {})
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Some(provider) = &self.provider
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("Failed to export traces: {}", e);
+        }
+    }
+}
+
+/// Span ending when dropped. Spans started while it is alive are its
+/// children, so keep it in a named binding, not `_`.
+pub(crate) struct Span {
+    #[cfg(feature = "otel")]
+    _guard: ContextGuard,
+}
+
+#[cfg(feature = "otel")]
+pub(crate) fn span(name: &'static str) -> Span {
+    let span = global::tracer(TRACER).start(name);
+    Span {
+        _guard: Context::current_with_span(span).attach(),
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn span(_name: &'static str) -> Span {
+    Span {}
+}
+
+#[cfg(feature = "otel")]
+impl Span {
+    pub(crate) fn set_attribute(&self, key: &'static str, value: impl ToString) {
+        get_active_span(|span| span.set_attribute(KeyValue::new(key, value.to_string())));
+    }
+
+    pub(crate) fn set_error(&self, error: &anyhow::Error) {
+        get_active_span(|span| span.set_status(Status::error(format!("{:#}", error))));
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+impl Span {
+    pub(crate) fn set_attribute(&self, _key: &'static str, _value: impl ToString) {}
+
+    pub(crate) fn set_error(&self, _error: &anyhow::Error) {}
+}
+
+#[cfg(feature = "otel")]
+impl Drop for Span {
+    fn drop(&mut self) {
+        // Still the active span: the guard is only dropped after this
+        get_active_span(|span| span.end());
+    }
+}
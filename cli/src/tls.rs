@@ -0,0 +1,246 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! TLS configuration of the HTTP client enforcing the baseline of a server.
+//! The platform TLS library used by default cannot restrict the cipher
+//! suites nor check a CRL, so servers with a baseline are reached with
+//! rustls instead.
+
+use anyhow::{Context, Result};
+use clevis_pin_trustee_lib::{TlsPolicy, TlsVersion, TrusteePinError};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::{CryptoProvider, aws_lc_rs};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, CertificateRevocationListDer};
+use rustls::{ClientConfig, RootCertStore, SupportedCipherSuite, SupportedProtocolVersion};
+use std::fs;
+use std::sync::Arc;
+
+/// IANA name of `suite`: rustls prefixes the TLS 1.3 ones with `TLS13_`
+fn iana_name(suite: &SupportedCipherSuite) -> Option<String> {
+    suite
+        .suite()
+        .as_str()
+        .map(|name| name.replacen("TLS13_", "TLS_", 1))
+}
+
+/// Crypto provider offering only the cipher suites `policy` allows
+fn provider(policy: &TlsPolicy) -> Result<CryptoProvider, TrusteePinError> {
+    let mut provider = aws_lc_rs::default_provider();
+    if let Some(names) = &policy.cipher_suites {
+        if let Some(unknown) = names.iter().find(|name| {
+            !provider
+                .cipher_suites
+                .iter()
+                .any(|suite| iana_name(suite).as_ref() == Some(*name))
+        }) {
+            return Err(TrusteePinError::Config(format!(
+                "Unsupported TLS cipher suite {}",
+                unknown
+            )));
+        }
+        provider
+            .cipher_suites
+            .retain(|suite| iana_name(suite).is_some_and(|name| names.contains(&name)));
+    }
+    if policy.min_version == Some(TlsVersion::Tls13) {
+        provider
+            .cipher_suites
+            .retain(|suite| suite.tls13().is_some());
+    }
+    if provider.cipher_suites.is_empty() {
+        return Err(TrusteePinError::Config(
+            "None of the allowed TLS cipher suites is usable with the minimum TLS version"
+                .to_string(),
+        ));
+    }
+    Ok(provider)
+}
+
+/// The CRLs of the PEM or DER file at `path`
+fn read_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+    let content = fs::read(path).with_context(|| format!("Failed to read the CRL {}", path))?;
+    if !content.starts_with(b"-----BEGIN") {
+        return Ok(vec![CertificateRevocationListDer::from(content)]);
+    }
+    CertificateRevocationListDer::pem_slice_iter(&content)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Failed to parse the CRL {}", path))
+}
+
+fn root_store(roots: Vec<CertificateDer<'static>>) -> RootCertStore {
+    let mut store = RootCertStore::empty();
+    store.add_parsable_certificates(roots);
+    store
+}
+
+/// Verifier trusting the roots of `store` only, checking the certificates
+/// against `crls`
+fn webpki_verifier(
+    store: RootCertStore,
+    crls: Vec<CertificateRevocationListDer<'static>>,
+    provider: &Arc<CryptoProvider>,
+) -> Result<Arc<dyn ServerCertVerifier>, rustls::client::VerifierBuilderError> {
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(store), Arc::clone(provider))
+            .with_crls(crls)
+            // The CRL is the one of the pinned CA, which does not cover itself
+            .only_check_end_entity_revocation()
+            .build()?;
+    Ok(verifier)
+}
+
+/// Verifier of the certificate of the server, checked against the CRL of
+/// `policy`, if any. With a CRL, only the pinned `roots` are trusted: the
+/// revocation status of certificates issued by a system root is unknown.
+fn verifier(
+    roots: Vec<CertificateDer<'static>>,
+    policy: &TlsPolicy,
+    provider: &Arc<CryptoProvider>,
+) -> Result<Arc<dyn ServerCertVerifier>> {
+    let Some(crl_path) = &policy.crl_path else {
+        #[cfg(feature = "vendored-roots")]
+        {
+            let mut store = root_store(roots);
+            store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            return webpki_verifier(store, Vec::new(), provider)
+                .context("Failed to load the trusted certificates");
+        }
+        #[cfg(not(feature = "vendored-roots"))]
+        {
+            let verifier = rustls_platform_verifier::Verifier::new_with_extra_roots(
+                roots,
+                Arc::clone(provider),
+            )
+            .context("Failed to load the trusted certificates")?;
+            return Ok(Arc::new(verifier));
+        }
+    };
+    if roots.is_empty() {
+        return Err(TrusteePinError::Config(format!(
+            "Checking the CRL {} needs the certificate of the server",
+            crl_path
+        ))
+        .into());
+    }
+    webpki_verifier(root_store(roots), read_crls(crl_path)?, provider)
+        .with_context(|| format!("Invalid CRL {}", crl_path))
+}
+
+/// Client config meeting `policy`, trusting the system roots and the
+/// certificates of `pem`
+pub(crate) fn client_config(pem: Option<&str>, policy: &TlsPolicy) -> Result<ClientConfig> {
+    let provider = Arc::new(provider(policy)?);
+    let versions: &[&SupportedProtocolVersion] = match policy.min_version {
+        Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+        Some(TlsVersion::Tls12) | None => rustls::DEFAULT_VERSIONS,
+    };
+    let roots = match pem {
+        Some(pem) => CertificateDer::pem_slice_iter(pem.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .context("Failed to parse TLS certificate")?,
+        None => Vec::new(),
+    };
+    let verifier = verifier(roots, policy, &provider)?;
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(versions)
+        .context("Invalid TLS settings")?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(provider: &CryptoProvider) -> Vec<String> {
+        provider
+            .cipher_suites
+            .iter()
+            .filter_map(iana_name)
+            .collect()
+    }
+
+    #[test]
+    fn test_provider() {
+        let policy = TlsPolicy {
+            cipher_suites: Some(vec![
+                "TLS_AES_256_GCM_SHA384".to_string(),
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_string(),
+            ]),
+            ..TlsPolicy::default()
+        };
+        assert_eq!(
+            names(&provider(&policy).unwrap()),
+            [
+                "TLS_AES_256_GCM_SHA384",
+                "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384"
+            ]
+        );
+
+        let tls13 = TlsPolicy {
+            min_version: Some(TlsVersion::Tls13),
+            ..policy
+        };
+        assert_eq!(
+            names(&provider(&tls13).unwrap()),
+            ["TLS_AES_256_GCM_SHA384"]
+        );
+
+        let only_tls12 = TlsPolicy {
+            cipher_suites: Some(vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".to_string()]),
+            ..tls13
+        };
+        assert!(provider(&only_tls12).is_err());
+
+        let unknown = TlsPolicy {
+            cipher_suites: Some(vec!["TLS_RSA_WITH_RC4_128_MD5".to_string()]),
+            ..TlsPolicy::default()
+        };
+        assert_eq!(
+            provider(&unknown).unwrap_err().to_string(),
+            "Unsupported TLS cipher suite TLS_RSA_WITH_RC4_128_MD5"
+        );
+    }
+    #[test]
+    fn test_read_crls() {
+        let dir = tempfile::tempdir().unwrap();
+        let pem = dir.path().join("ca.crl");
+        std::fs::write(
+            &pem,
+            "-----BEGIN X509 CRL-----\nAQID\n-----END X509 CRL-----\n\
+             -----BEGIN X509 CRL-----\nBAUG\n-----END X509 CRL-----\n",
+        )
+        .unwrap();
+        let der = dir.path().join("ca.der");
+        std::fs::write(&der, [1, 2, 3]).unwrap();
+
+        let crls = read_crls(pem.to_str().unwrap()).unwrap();
+        assert_eq!(crls.len(), 2);
+        assert_eq!(crls[1].as_ref(), [4, 5, 6]);
+        assert_eq!(
+            read_crls(der.to_str().unwrap()).unwrap()[0].as_ref(),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_crl_needs_pinned_cert() {
+        let policy = TlsPolicy {
+            crl_path: Some("/etc/clevis-trustee/kbs.crl".to_string()),
+            ..TlsPolicy::default()
+        };
+
+        let error = client_config(None, &policy).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Checking the CRL /etc/clevis-trustee/kbs.crl needs the certificate of the server"
+        );
+    }
+}
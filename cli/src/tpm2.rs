@@ -0,0 +1,216 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Split-key bindings: the payload key is derived from the key fetched from
+//! the servers and a local secret sealed with the TPM by the clevis tpm2
+//! pin, so neither a leaked KBS resource nor the TPM of the machine alone
+//! decrypts the payload.
+
+use crate::entropy::{RandomSource, random_bytes};
+use crate::{oct_key, pipeline};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use clevis_pin_trustee_lib::ClevisHeader;
+use serde_json::Value;
+use std::io::Write;
+use std::process::{Command as StdCommand, Stdio};
+
+const CLEVIS: &str = "clevis";
+const SPLIT_KEY_CONTEXT: &[u8] = b"clevis-pin-trustee split key";
+/// Size of the local secret sealed with the TPM
+const LOCAL_SECRET_BYTES: usize = 32;
+/// Settings of the tpm2 pin config that are recorded in its JWE header
+const TPM2_CONFIG_FIELDS: [&str; 3] = ["key", "pcr_bank", "pcr_ids"];
+
+/// Trait for sealing secrets to the TPM
+pub(crate) trait Sealer {
+    /// Compact JWE of `secret`, sealed as the tpm2 pin `config` says
+    fn seal(&self, config: &Value, secret: &[u8]) -> Result<String>;
+    fn unseal(&self, jwe: &str) -> Result<Vec<u8>>;
+}
+
+/// Seals through `clevis encrypt tpm2` and `clevis decrypt`
+pub(crate) struct ClevisTpm2;
+
+impl ClevisTpm2 {
+    fn run(&self, command: &mut StdCommand, stdin: &[u8]) -> Result<Vec<u8>> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to execute {}", CLEVIS))?;
+        if let Some(mut pipe) = child.stdin.take() {
+            pipe.write_all(stdin)?;
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(anyhow!("{} failed: {}", CLEVIS, stderr.trim()));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl Sealer for ClevisTpm2 {
+    fn seal(&self, config: &Value, secret: &[u8]) -> Result<String> {
+        let jwe = self
+            .run(
+                StdCommand::new(CLEVIS)
+                    .args(["encrypt", "tpm2"])
+                    .arg(config.to_string()),
+                secret,
+            )
+            .context("Failed to seal the local secret with the TPM")?;
+        let jwe = String::from_utf8(jwe).context("Invalid JWE from the tpm2 pin")?;
+        Ok(jwe.trim().to_string())
+    }
+
+    fn unseal(&self, jwe: &str) -> Result<Vec<u8>> {
+        self.run(StdCommand::new(CLEVIS).arg("decrypt"), jwe.as_bytes())
+            .context("Failed to unseal the local secret with the TPM")
+    }
+}
+
+/// Payload key of a split-key binding, in the form the servers hand out
+/// keys: HKDF of the fetched `key`, salted with the local secret
+pub(crate) fn split_key(key: &str, local_secret: &[u8]) -> Result<String> {
+    let derived = pipeline::hkdf(key.as_bytes(), SPLIT_KEY_CONTEXT, Some(local_secret))?;
+    Ok(oct_key(&derived))
+}
+
+/// Seal a new local secret as the tpm2 pin `config` says, record it in
+/// `header` and return the payload key derived from it and `key`
+pub(crate) fn seal_split_key(
+    sealer: &dyn Sealer,
+    rng: &dyn RandomSource,
+    header: &mut ClevisHeader,
+    config: &Value,
+    key: &str,
+) -> Result<String> {
+    let local_secret = random_bytes::<LOCAL_SECRET_BYTES>(rng);
+    header.tpm2_jwe = Some(sealer.seal(config, &local_secret)?);
+    split_key(key, &local_secret)
+}
+
+/// Local secret of a split-key binding, `None` for other bindings
+pub(crate) fn unseal_local_secret(
+    sealer: &dyn Sealer,
+    header: &ClevisHeader,
+) -> Result<Option<Vec<u8>>> {
+    header
+        .tpm2_jwe
+        .as_deref()
+        .map(|jwe| sealer.unseal(jwe))
+        .transpose()
+}
+
+/// The tpm2 pin config a local secret was sealed with, as far as the clevis
+/// header of its JWE records it
+pub(crate) fn sealed_config(jwe: &str) -> Result<Value> {
+    let protected = jwe
+        .split('.')
+        .next()
+        .ok_or_else(|| anyhow!("Invalid tpm2 JWE"))?;
+    let protected = general_purpose::URL_SAFE_NO_PAD
+        .decode(protected)
+        .context("The header of the tpm2 JWE is not base64url")?;
+    let protected: Value =
+        serde_json::from_slice(&protected).context("The header of the tpm2 JWE is not JSON")?;
+    let tpm2 = &protected["clevis"]["tpm2"];
+    let config = TPM2_CONFIG_FIELDS
+        .iter()
+        .filter_map(|field| Some((field.to_string(), tpm2.get(field)?.clone())))
+        .collect();
+    Ok(Value::Object(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entropy::OsRandom;
+    use crate::{KeyEncoding, prepare_jwk};
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    /// Seals by storing the secret, the JWE being its index
+    #[derive(Default)]
+    struct MockSealer {
+        sealed: RefCell<Vec<(Value, Vec<u8>)>>,
+    }
+
+    impl Sealer for MockSealer {
+        fn seal(&self, config: &Value, secret: &[u8]) -> Result<String> {
+            let mut sealed = self.sealed.borrow_mut();
+            sealed.push((config.clone(), secret.to_vec()));
+            Ok((sealed.len() - 1).to_string())
+        }
+
+        fn unseal(&self, jwe: &str) -> Result<Vec<u8>> {
+            let index: usize = jwe.parse()?;
+            self.sealed
+                .borrow()
+                .get(index)
+                .map(|(_, secret)| secret.clone())
+                .ok_or_else(|| anyhow!("Cannot unseal {}", jwe))
+        }
+    }
+
+    #[test]
+    fn test_split_key() {
+        let sealer = MockSealer::default();
+        let mut header = ClevisHeader::from_claim(json!({
+            "pin": "trustee",
+            "servers": [],
+            "path": "default/key/root",
+            "initdata": null,
+        }))
+        .unwrap();
+
+        let key = seal_split_key(
+            &sealer,
+            &OsRandom,
+            &mut header,
+            &json!({"pcr_ids": "7"}),
+            "kbs",
+        )
+        .unwrap();
+
+        let local_secret = unseal_local_secret(&sealer, &header).unwrap().unwrap();
+        assert_eq!(local_secret.len(), LOCAL_SECRET_BYTES);
+        assert_eq!(sealer.sealed.borrow()[0].0, json!({"pcr_ids": "7"}));
+        assert_eq!(split_key("kbs", &local_secret).unwrap(), key);
+        assert_ne!(split_key("other", &local_secret).unwrap(), key);
+
+        let jwk = prepare_jwk(&key, KeyEncoding::DERIVED).unwrap();
+        assert_eq!(
+            jwk.key_value().unwrap(),
+            pipeline::hkdf(b"kbs", SPLIT_KEY_CONTEXT, Some(&local_secret)).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sealed_config() {
+        let protected = json!({
+            "alg": "dir",
+            "clevis": {"pin": "tpm2", "tpm2": {
+                "hash": "sha256",
+                "key": "ecc",
+                "pcr_bank": "sha256",
+                "pcr_ids": "7",
+                "jwk_pub": "AAAA",
+                "jwk_priv": "AAAA",
+            }},
+        });
+        let jwe = format!(
+            "{}..iv.ciphertext.tag",
+            general_purpose::URL_SAFE_NO_PAD.encode(protected.to_string())
+        );
+
+        assert_eq!(
+            sealed_config(&jwe).unwrap(),
+            json!({"key": "ecc", "pcr_bank": "sha256", "pcr_ids": "7"})
+        );
+    }
+}
@@ -0,0 +1,251 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Minimal ttrpc client used to talk to the CoCo guest components over their
+//! unix sockets. Only unary calls with single-field protobuf messages are
+//! needed, so the wire format is encoded by hand instead of pulling in a
+//! protobuf toolchain.
+
+use anyhow::{Context, Result, anyhow};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const MESSAGE_HEADER_LENGTH: usize = 10;
+const MESSAGE_LENGTH_MAX: usize = 4 << 20;
+const MESSAGE_TYPE_REQUEST: u8 = 0x1;
+const MESSAGE_TYPE_RESPONSE: u8 = 0x2;
+const STREAM_ID: u32 = 1;
+
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LENGTH_DELIMITED: u8 = 2;
+const WIRE_FIXED32: u8 = 5;
+
+/// Decoded protobuf field value
+#[derive(Debug, PartialEq)]
+pub(crate) enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Append a length-delimited (string/bytes/message) field
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, field: u32, data: &[u8]) {
+    put_varint(buf, ((field as u64) << 3) | WIRE_LENGTH_DELIMITED as u64);
+    put_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+/// Append a varint-encoded integer field
+pub(crate) fn put_int64(buf: &mut Vec<u8>, field: u32, value: i64) {
+    put_varint(buf, ((field as u64) << 3) | WIRE_VARINT as u64);
+    put_varint(buf, value as u64);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data
+            .get(*pos)
+            .ok_or_else(|| anyhow!("Truncated protobuf varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Protobuf varint too long"))
+}
+
+/// Decode the top-level fields of a protobuf message, skipping fixed-width ones
+pub(crate) fn fields(data: &[u8]) -> Result<Vec<(u32, Field<'_>)>> {
+    let mut pos = 0;
+    let mut fields = Vec::new();
+    while pos < data.len() {
+        let key = get_varint(data, &mut pos)?;
+        let number = (key >> 3) as u32;
+        match (key & 0x7) as u8 {
+            WIRE_VARINT => fields.push((number, Field::Varint(get_varint(data, &mut pos)?))),
+            WIRE_LENGTH_DELIMITED => {
+                let len = get_varint(data, &mut pos)? as usize;
+                let end = pos
+                    .checked_add(len)
+                    .filter(|end| *end <= data.len())
+                    .ok_or_else(|| anyhow!("Truncated protobuf field {}", number))?;
+                fields.push((number, Field::Bytes(&data[pos..end])));
+                pos = end;
+            }
+            WIRE_FIXED64 => pos += 8,
+            WIRE_FIXED32 => pos += 4,
+            other => return Err(anyhow!("Unsupported protobuf wire type {}", other)),
+        }
+    }
+    if pos > data.len() {
+        return Err(anyhow!("Truncated protobuf message"));
+    }
+    Ok(fields)
+}
+
+/// Return the first length-delimited field with the given number, if any
+pub(crate) fn bytes_field(data: &[u8], number: u32) -> Result<Option<&[u8]>> {
+    Ok(fields(data)?
+        .into_iter()
+        .find_map(|(n, field)| match field {
+            Field::Bytes(bytes) if n == number => Some(bytes),
+            _ => None,
+        }))
+}
+
+/// Client for unary ttrpc calls over a unix socket
+pub(crate) struct TtrpcClient {
+    socket: PathBuf,
+    timeout: Duration,
+}
+
+impl TtrpcClient {
+    pub(crate) fn new(socket: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Self {
+            socket: socket.into(),
+            timeout,
+        }
+    }
+
+    /// Invoke `service/method` with an encoded request and return the encoded response
+    pub(crate) fn call(&self, service: &str, method: &str, payload: &[u8]) -> Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(&self.socket)
+            .with_context(|| format!("Failed to connect to {}", self.socket.display()))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        stream
+            .write_all(&encode_request(service, method, payload, self.timeout))
+            .with_context(|| format!("Failed to send ttrpc request {}/{}", service, method))?;
+
+        let mut header = [0u8; MESSAGE_HEADER_LENGTH];
+        stream
+            .read_exact(&mut header)
+            .with_context(|| format!("Failed to read ttrpc response for {}", method))?;
+        let (length, stream_id, message_type) = parse_header(&header)?;
+        if stream_id != STREAM_ID || message_type != MESSAGE_TYPE_RESPONSE {
+            return Err(anyhow!(
+                "Unexpected ttrpc message (stream {}, type {})",
+                stream_id,
+                message_type
+            ));
+        }
+        let mut body = vec![0u8; length];
+        stream
+            .read_exact(&mut body)
+            .with_context(|| format!("Failed to read ttrpc response for {}", method))?;
+
+        decode_response(&body).with_context(|| format!("ttrpc call {}/{} failed", service, method))
+    }
+}
+
+fn encode_request(service: &str, method: &str, payload: &[u8], timeout: Duration) -> Vec<u8> {
+    let mut request = Vec::new();
+    put_bytes(&mut request, 1, service.as_bytes());
+    put_bytes(&mut request, 2, method.as_bytes());
+    put_bytes(&mut request, 3, payload);
+    put_int64(&mut request, 4, timeout.as_nanos() as i64);
+
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_LENGTH + request.len());
+    message.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    message.extend_from_slice(&STREAM_ID.to_be_bytes());
+    message.push(MESSAGE_TYPE_REQUEST);
+    message.push(0);
+    message.extend_from_slice(&request);
+    message
+}
+
+fn parse_header(header: &[u8; MESSAGE_HEADER_LENGTH]) -> Result<(usize, u32, u8)> {
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let stream_id = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+    if length > MESSAGE_LENGTH_MAX {
+        return Err(anyhow!("ttrpc message too large: {} bytes", length));
+    }
+    Ok((length, stream_id, header[8]))
+}
+
+fn decode_response(body: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for (number, field) in fields(body)? {
+        match (number, field) {
+            (1, Field::Bytes(status)) => {
+                let mut code = 0;
+                let mut message = String::new();
+                for (n, f) in fields(status)? {
+                    match (n, f) {
+                        (1, Field::Varint(c)) => code = c,
+                        (2, Field::Bytes(m)) => message = String::from_utf8_lossy(m).to_string(),
+                        _ => {}
+                    }
+                }
+                if code != 0 {
+                    return Err(anyhow!("status {}: {}", code, message));
+                }
+            }
+            (2, Field::Bytes(p)) => payload = p.to_vec(),
+            _ => {}
+        }
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields_roundtrip() {
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, 1, b"runtime-data");
+        put_int64(&mut buf, 4, 300);
+        put_bytes(&mut buf, 2, &[0u8; 200]);
+
+        let decoded = fields(&buf).unwrap();
+
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], (1, Field::Bytes(b"runtime-data")));
+        assert_eq!(decoded[1], (4, Field::Varint(300)));
+        assert_eq!(decoded[2], (2, Field::Bytes(&[0u8; 200])));
+        assert_eq!(bytes_field(&buf, 2).unwrap(), Some(&[0u8; 200][..]));
+        assert_eq!(bytes_field(&buf, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn test_fields_truncated() {
+        let mut buf = Vec::new();
+        put_bytes(&mut buf, 1, b"evidence");
+        buf.truncate(buf.len() - 1);
+
+        assert!(fields(&buf).is_err());
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let mut ok = Vec::new();
+        put_bytes(&mut ok, 2, b"payload");
+        assert_eq!(decode_response(&ok).unwrap(), b"payload");
+
+        let mut status = Vec::new();
+        put_int64(&mut status, 1, 5);
+        put_bytes(&mut status, 2, b"resource not found");
+        let mut err = Vec::new();
+        put_bytes(&mut err, 1, &status);
+        assert_eq!(
+            decode_response(&err).unwrap_err().to_string(),
+            "status 5: resource not found"
+        );
+    }
+}
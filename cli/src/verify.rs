@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Comparison of an existing binding with the current site config, to find
+//! the bindings left behind by a change of the servers before they fail to
+//! decrypt.
+
+use crate::endpoint::comparable;
+use crate::{ConfigOptions, armor, read_config};
+use anyhow::{Context, Result};
+use clevis_pin_trustee_lib::{ClevisHeader, Server};
+use serde_json::json;
+use std::fmt;
+
+/// A difference between a binding and the site config
+#[derive(Debug, PartialEq)]
+pub enum Drift {
+    /// The binding uses a server the config no longer lists
+    StaleServer(String),
+    /// The config lists a server the binding does not know
+    MissingServer(String),
+    /// The binding trusts another certificate for the server
+    CertChanged(String),
+    PathChanged {
+        binding: String,
+        config: String,
+    },
+    InitdataChanged,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Drift::StaleServer(url) => write!(f, "server {} is no longer in the config", url),
+            Drift::MissingServer(url) => write!(f, "server {} is missing from the binding", url),
+            Drift::CertChanged(url) => {
+                write!(f, "the certificate of {} differs from the config", url)
+            }
+            Drift::PathChanged { binding, config } => {
+                write!(f, "path {} differs from {} in the config", binding, config)
+            }
+            Drift::InitdataChanged => f.write_str("the initdata differs from the config"),
+        }
+    }
+}
+
+impl Drift {
+    /// The difference for `--output-format json`
+    pub fn to_json(&self) -> serde_json::Value {
+        let (kind, mut drift) = match self {
+            Drift::StaleServer(url) => ("stale-server", json!({"url": url})),
+            Drift::MissingServer(url) => ("missing-server", json!({"url": url})),
+            Drift::CertChanged(url) => ("cert-changed", json!({"url": url})),
+            Drift::PathChanged { binding, config } => (
+                "path-changed",
+                json!({"binding": binding, "config": config}),
+            ),
+            Drift::InitdataChanged => ("initdata-changed", json!({})),
+        };
+        drift["kind"] = json!(kind);
+        drift["message"] = json!(self.to_string());
+        drift
+    }
+}
+
+fn cert_pem(server: &Server) -> Result<Option<String>> {
+    server
+        .cert
+        .pem()
+        .with_context(|| format!("Failed to read the certificate of {}", server.url))
+}
+
+fn compare_servers(binding: &[Server], config: &[Server], drifts: &mut Vec<Drift>) -> Result<()> {
+    for server in binding {
+        let Some(configured) = config
+            .iter()
+            .find(|configured| comparable(&configured.url) == comparable(&server.url))
+        else {
+            drifts.push(Drift::StaleServer(server.url.clone()));
+            continue;
+        };
+        if cert_pem(server)? != cert_pem(configured)? {
+            drifts.push(Drift::CertChanged(server.url.clone()));
+        }
+    }
+    for server in config {
+        if !binding
+            .iter()
+            .any(|bound| comparable(&bound.url) == comparable(&server.url))
+        {
+            drifts.push(Drift::MissingServer(server.url.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Differences between the binding of `header` and the header `config` of
+/// a binding made now
+fn compare(header: &ClevisHeader, config: &ClevisHeader) -> Result<Vec<Drift>> {
+    let mut drifts = Vec::new();
+    compare_servers(
+        &Server::with_default_cert(&header.servers, header.cert.as_ref()),
+        &Server::with_default_cert(&config.servers, config.cert.as_ref()),
+        &mut drifts,
+    )?;
+    if header.path != config.path {
+        drifts.push(Drift::PathChanged {
+            binding: header.path.clone(),
+            config: config.path.clone(),
+        });
+    }
+    if header.initdata != config.initdata {
+        drifts.push(Drift::InitdataChanged);
+    }
+    Ok(drifts)
+}
+
+/// Compare the binding in the clevis header of the compact JWE `input` with
+/// the one `config`, merged with the drop-in fragments, would make now.
+/// Nothing is fetched: discovered servers and rotated keys are not checked.
+pub fn verify_binding(input: &str, config: &str, options: ConfigOptions) -> Result<Vec<Drift>> {
+    let header = ClevisHeader::from_compact_jwe(&armor::dearmor(input))?;
+    let (config, initdata) = read_config(config, options)?;
+    compare(&header, &ClevisHeader::new(config, initdata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn header(claim: serde_json::Value) -> ClevisHeader {
+        ClevisHeader::from_claim(claim).unwrap()
+    }
+
+    #[test]
+    fn test_compare() {
+        let binding = header(json!({
+            "pin": "trustee",
+            "servers": [
+                {"url": "https://kbs1:8080", "cert": "OLD"},
+                "https://old:8080",
+            ],
+            "path": "default/key/root",
+            "initdata": null,
+        }));
+        let config = header(json!({
+            "pin": "trustee",
+            "servers": ["https://kbs1:8080/", "https://new:8080"],
+            "cert": "NEW",
+            "path": "default/key/other",
+            "initdata": "version = \"0.1.0\"",
+        }));
+
+        let drifts = compare(&binding, &config).unwrap();
+
+        assert_eq!(
+            drifts,
+            [
+                Drift::CertChanged("https://kbs1:8080".to_string()),
+                Drift::StaleServer("https://old:8080".to_string()),
+                Drift::MissingServer("https://new:8080".to_string()),
+                Drift::PathChanged {
+                    binding: "default/key/root".to_string(),
+                    config: "default/key/other".to_string(),
+                },
+                Drift::InitdataChanged,
+            ]
+        );
+        assert_eq!(
+            drifts[3].to_json(),
+            json!({
+                "kind": "path-changed",
+                "binding": "default/key/root",
+                "config": "default/key/other",
+                "message": "path default/key/root differs from default/key/other in the config",
+            })
+        );
+        assert!(compare(&binding, &binding).unwrap().is_empty());
+    }
+}
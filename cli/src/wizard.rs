@@ -0,0 +1,249 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! Interactive creation of a config. The certificate of every HTTPS server
+//! is fetched and shown for confirmation before it is trusted, on first use.
+
+use crate::{normalize_initdata, parse_config};
+use anyhow::{Context, Result, anyhow};
+use base64::{Engine as _, engine::general_purpose};
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+const DEFAULT_PATH: &str = "default/key/root";
+const CERT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Trait for asking the user questions
+trait Prompter {
+    /// The answer to `question`, trimmed
+    fn ask(&mut self, question: &str) -> Result<String>;
+    fn say(&mut self, message: &str);
+}
+
+/// Asks on the terminal: questions on stderr, answers from stdin
+struct Terminal;
+
+impl Prompter for Terminal {
+    fn ask(&mut self, question: &str) -> Result<String> {
+        eprint!("{} ", question);
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        if io::stdin().lock().read_line(&mut answer)? == 0 {
+            return Err(anyhow!("No answer to '{}'", question));
+        }
+        Ok(answer.trim().to_string())
+    }
+
+    fn say(&mut self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+/// Trait for retrieving the certificate a server presents
+trait CertFetcher {
+    /// DER certificate of the HTTPS server at `url`
+    fn peer_certificate(&self, url: &str) -> Result<Vec<u8>>;
+}
+
+struct HttpsCertFetcher;
+
+impl CertFetcher for HttpsCertFetcher {
+    fn peer_certificate(&self, url: &str) -> Result<Vec<u8>> {
+        // The certificate is not trusted yet, it is shown for confirmation
+        let client = reqwest::blocking::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .tls_info(true)
+            .timeout(CERT_FETCH_TIMEOUT)
+            .build()?;
+        let response = client
+            .get(url)
+            .send()
+            .with_context(|| format!("Failed to connect to {}", url))?;
+        response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| anyhow!("{} presented no certificate", url))
+    }
+}
+
+fn to_pem(der: &[u8]) -> String {
+    let encoded = general_purpose::STANDARD.encode(der);
+    let mut pem = "-----BEGIN CERTIFICATE-----\n".to_string();
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str("-----END CERTIFICATE-----\n");
+    pem
+}
+
+/// SHA-256 fingerprint as `AB:CD:...`, as `openssl x509 -fingerprint` prints it
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Ask for a server, `None` once the user is done
+fn ask_server<P: Prompter, F: CertFetcher>(
+    prompter: &mut P,
+    fetcher: &F,
+    first: bool,
+) -> Result<Option<Value>> {
+    let url = if first {
+        prompter.ask("KBS URL:")?
+    } else {
+        prompter.ask("Another KBS URL (empty when done):")?
+    };
+    if url.is_empty() {
+        return if first {
+            Err(anyhow!("At least one server is needed"))
+        } else {
+            Ok(None)
+        };
+    }
+    if !url.starts_with("https://") {
+        return Ok(Some(json!({"url": url})));
+    }
+
+    let der = fetcher.peer_certificate(&url)?;
+    prompter.say(&format!(
+        "{} presented a certificate with SHA-256 fingerprint\n  {}",
+        url,
+        fingerprint(&der)
+    ));
+    let answer = prompter.ask("Trust this certificate? [y/N]")?;
+    if !is_yes(&answer) {
+        prompter.say("Not trusting the certificate: relying on the system roots.");
+        return Ok(Some(json!({"url": url})));
+    }
+    Ok(Some(json!({"url": url, "cert": to_pem(&der)})))
+}
+
+fn generate_config_with<P: Prompter, F: CertFetcher>(
+    prompter: &mut P,
+    fetcher: &F,
+) -> Result<Value> {
+    let mut servers = Vec::new();
+    while let Some(server) = ask_server(prompter, fetcher, servers.is_empty())? {
+        servers.push(server);
+    }
+
+    let path = prompter.ask(&format!("Resource path [{}]:", DEFAULT_PATH))?;
+    let path = if path.is_empty() {
+        DEFAULT_PATH.to_string()
+    } else {
+        path
+    };
+
+    let mut config = Map::new();
+    config.insert("servers".to_string(), Value::Array(servers));
+    config.insert("path".to_string(), Value::String(path));
+    let initdata = prompter.ask("Initdata as a JSON object (empty for none):")?;
+    if !initdata.is_empty() {
+        let initdata: Value =
+            serde_json::from_str(&initdata).context("The initdata is not valid JSON")?;
+        config.insert("initdata".to_string(), initdata);
+    }
+
+    let config = Value::Object(config);
+    let mut normalized = config.clone();
+    normalize_initdata(&mut normalized)?;
+    parse_config(normalized, true).context("The generated config is invalid")?;
+    Ok(config)
+}
+
+/// Ask on the terminal for the servers, trusting their certificates on
+/// confirmation, the resource path and the initdata, and return the config
+pub fn generate_config() -> Result<Value> {
+    generate_config_with(&mut Terminal, &HttpsCertFetcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    struct MockPrompter {
+        answers: VecDeque<&'static str>,
+    }
+
+    impl Prompter for MockPrompter {
+        fn ask(&mut self, question: &str) -> Result<String> {
+            self.answers
+                .pop_front()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Unexpected question '{}'", question))
+        }
+
+        fn say(&mut self, _: &str) {}
+    }
+
+    struct MockCertFetcher;
+
+    impl CertFetcher for MockCertFetcher {
+        fn peer_certificate(&self, _: &str) -> Result<Vec<u8>> {
+            Ok(b"certificate".to_vec())
+        }
+    }
+
+    #[test]
+    fn test_generate_config() {
+        let mut prompter = MockPrompter {
+            answers: VecDeque::from([
+                "https://kbs1:8080",
+                "y",
+                "https://kbs2:8080",
+                "n",
+                "http://kbs3:8080",
+                "",
+                "",
+                r#"{"aa.toml": "x"}"#,
+            ]),
+        };
+
+        let config = generate_config_with(&mut prompter, &MockCertFetcher).unwrap();
+
+        assert_eq!(
+            config,
+            json!({
+                "servers": [
+                    {"url": "https://kbs1:8080", "cert": to_pem(b"certificate")},
+                    {"url": "https://kbs2:8080"},
+                    {"url": "http://kbs3:8080"},
+                ],
+                "path": DEFAULT_PATH,
+                "initdata": {"aa.toml": "x"},
+            })
+        );
+        assert!(prompter.answers.is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint_and_pem() {
+        assert_eq!(
+            fingerprint(b""),
+            "E3:B0:C4:42:98:FC:1C:14:9A:FB:F4:C8:99:6F:B9:24:\
+             27:AE:41:E4:64:9B:93:4C:A4:95:99:1B:78:52:B8:55"
+        );
+        assert_eq!(
+            to_pem(&[0; 60]),
+            format!(
+                "-----BEGIN CERTIFICATE-----\n{}\n{}\n-----END CERTIFICATE-----\n",
+                "A".repeat(64),
+                "A".repeat(16)
+            )
+        );
+    }
+}
@@ -0,0 +1,61 @@
+/*
+ * SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+ *
+ * SPDX-License-Identifier: MIT
+ */
+
+#ifndef CLEVIS_TRUSTEE_H
+#define CLEVIS_TRUSTEE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * All functions return 0 on success and -1 on failure. After a failure,
+ * clevis_trustee_last_error() describes the error of the calling thread.
+ */
+
+/*
+ * Fetch the key described by the JSON config and encrypt data_len bytes of
+ * data with it. On success *jwe_out holds the compact JWE, to be released
+ * with clevis_trustee_free_string().
+ */
+int clevis_trustee_encrypt(const char *config, const uint8_t *data,
+                           size_t data_len, char **jwe_out);
+
+/*
+ * Fetch the key bound in the compact JWE and decrypt it. On success
+ * *data_out holds *data_len_out bytes, to be released with
+ * clevis_trustee_free_buffer().
+ */
+int clevis_trustee_decrypt(const char *jwe, uint8_t **data_out,
+                           size_t *data_len_out);
+
+/*
+ * Fetch the key described by the JSON config without binding anything.
+ * On success *key_out holds the base64 encoded key, to be released with
+ * clevis_trustee_free_string().
+ */
+int clevis_trustee_fetch_luks_key(const char *config, char **key_out);
+
+/* Release a string returned by this library. NULL is ignored. */
+void clevis_trustee_free_string(char *string);
+
+/* Release a buffer returned by this library. NULL is ignored. */
+void clevis_trustee_free_buffer(uint8_t *buffer, size_t len);
+
+/*
+ * Message of the last failure on the calling thread, or NULL. The string is
+ * owned by the library and valid until the next call on the same thread.
+ */
+const char *clevis_trustee_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CLEVIS_TRUSTEE_H */
@@ -0,0 +1,232 @@
+// SPDX-FileCopyrightText: Alice Frosi <afrosi@redhat.com>
+//
+// SPDX-License-Identifier: MIT
+
+//! C bindings for the Trustee pin, declared in `include/clevis_trustee.h`.
+//!
+//! Every function returns 0 on success and -1 on failure, with the error
+//! message of the calling thread available from `clevis_trustee_last_error`.
+
+use anyhow::{Context, Result, anyhow};
+use clevis_pin_trustee::{ConfigOptions, NoEvents};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Run `f`, keeping its error for `clevis_trustee_last_error`. Panics must
+/// not unwind into C, so they are reported as errors too.
+fn ffi_call(f: impl FnOnce() -> Result<()>) -> c_int {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(format!("{:#}", e));
+            -1
+        }
+        Err(_) => {
+            set_last_error("Unexpected panic in clevis-pin-trustee".to_string());
+            -1
+        }
+    }
+}
+
+/// # Safety
+///
+/// `value` must be NULL or a NUL-terminated string valid for `'a`.
+unsafe fn str_arg<'a>(name: &str, value: *const c_char) -> Result<&'a str> {
+    if value.is_null() {
+        return Err(anyhow!("{} must not be NULL", name));
+    }
+    unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .with_context(|| format!("{} is not valid UTF-8", name))
+}
+
+/// # Safety
+///
+/// `data` must be NULL or point to `len` readable bytes valid for `'a`.
+unsafe fn bytes_arg<'a>(name: &str, data: *const u8, len: usize) -> Result<&'a [u8]> {
+    if data.is_null() {
+        return match len {
+            0 => Ok(&[]),
+            _ => Err(anyhow!("{} must not be NULL", name)),
+        };
+    }
+    Ok(unsafe { std::slice::from_raw_parts(data, len) })
+}
+
+fn out_arg<T>(name: &str, out: *mut T) -> Result<*mut T> {
+    if out.is_null() {
+        return Err(anyhow!("{} must not be NULL", name));
+    }
+    Ok(out)
+}
+
+/// Fetch the key described by `config` and encrypt `data` with it.
+///
+/// # Safety
+///
+/// `config` must be a NUL-terminated string, `data` must point to `data_len`
+/// bytes (or be NULL if `data_len` is 0) and `jwe_out` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clevis_trustee_encrypt(
+    config: *const c_char,
+    data: *const u8,
+    data_len: usize,
+    jwe_out: *mut *mut c_char,
+) -> c_int {
+    ffi_call(|| {
+        let config = unsafe { str_arg("config", config)? };
+        let data = unsafe { bytes_arg("data", data, data_len)? };
+        let jwe_out = out_arg("jwe_out", jwe_out)?;
+        let jwe = CString::new(clevis_pin_trustee::encrypt(
+            config,
+            ConfigOptions::default(),
+            data,
+            &NoEvents,
+        )?)?;
+        unsafe { *jwe_out = jwe.into_raw() };
+        Ok(())
+    })
+}
+
+/// Fetch the key bound in the compact JWE `jwe` and decrypt it.
+///
+/// # Safety
+///
+/// `jwe` must be a NUL-terminated string, `data_out` and `data_len_out` must
+/// be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clevis_trustee_decrypt(
+    jwe: *const c_char,
+    data_out: *mut *mut u8,
+    data_len_out: *mut usize,
+) -> c_int {
+    ffi_call(|| {
+        let jwe = unsafe { str_arg("jwe", jwe)? };
+        let data_out = out_arg("data_out", data_out)?;
+        let data_len_out = out_arg("data_len_out", data_len_out)?;
+        let data =
+            clevis_pin_trustee::decrypt(jwe, &Default::default(), &NoEvents)?.into_boxed_slice();
+        unsafe {
+            *data_len_out = data.len();
+            *data_out = Box::into_raw(data).cast();
+        }
+        Ok(())
+    })
+}
+
+/// Fetch the key described by `config` without binding anything to it.
+///
+/// # Safety
+///
+/// `config` must be a NUL-terminated string and `key_out` must be writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clevis_trustee_fetch_luks_key(
+    config: *const c_char,
+    key_out: *mut *mut c_char,
+) -> c_int {
+    ffi_call(|| {
+        let config = unsafe { str_arg("config", config)? };
+        let key_out = out_arg("key_out", key_out)?;
+        let key = CString::new(clevis_pin_trustee::fetch_key(
+            config,
+            ConfigOptions::default(),
+            &NoEvents,
+        )?)?;
+        unsafe { *key_out = key.into_raw() };
+        Ok(())
+    })
+}
+
+/// Release a string returned by this library.
+///
+/// # Safety
+///
+/// `string` must be NULL or a string returned by this library that was not
+/// released yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clevis_trustee_free_string(string: *mut c_char) {
+    if !string.is_null() {
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Release a buffer returned by this library.
+///
+/// # Safety
+///
+/// `buffer` must be NULL or a buffer of `len` bytes returned by this library
+/// that was not released yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clevis_trustee_free_buffer(buffer: *mut u8, len: usize) {
+    if !buffer.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len)) });
+    }
+}
+
+/// Message of the last failure on the calling thread, or NULL
+#[unsafe(no_mangle)]
+pub extern "C" fn clevis_trustee_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let error = clevis_trustee_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_null_arguments() {
+        let mut key = ptr::null_mut();
+        let result = unsafe { clevis_trustee_fetch_luks_key(ptr::null(), &mut key) };
+
+        assert_eq!(result, -1);
+        assert!(key.is_null());
+        assert_eq!(last_error(), "config must not be NULL");
+
+        let jwe = c"header.key.iv.ciphertext.tag";
+        let result =
+            unsafe { clevis_trustee_decrypt(jwe.as_ptr(), ptr::null_mut(), ptr::null_mut()) };
+        assert_eq!(result, -1);
+        assert_eq!(last_error(), "data_out must not be NULL");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let mut jwe = ptr::null_mut();
+        let config = c"{\"servers\": []";
+        let result = unsafe { clevis_trustee_encrypt(config.as_ptr(), ptr::null(), 0, &mut jwe) };
+
+        assert_eq!(result, -1);
+        assert!(jwe.is_null());
+        assert!(last_error().starts_with("Failed to parse config JSON"));
+    }
+
+    #[test]
+    fn test_free_buffer() {
+        let data = vec![1u8, 2, 3].into_boxed_slice();
+        let len = data.len();
+        unsafe {
+            clevis_trustee_free_buffer(Box::into_raw(data).cast(), len);
+            clevis_trustee_free_buffer(ptr::null_mut(), 0);
+            clevis_trustee_free_string(ptr::null_mut());
+        }
+    }
+}
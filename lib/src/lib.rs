@@ -3,13 +3,394 @@
 //
 // SPDX-License-Identifier: MIT
 
+use base64::{Engine as _, engine::general_purpose};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::ser::SerializeSeq;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Characters of a certificate shown by `Debug` unless verbose output is on
+const CERT_DEBUG_PREFIX: usize = 32;
+
+static VERBOSE_DEBUG: AtomicBool = AtomicBool::new(false);
+
+/// Show certificates, initdata and keys in full in `Debug` output. They are
+/// truncated or redacted by default so they do not end up in logs.
+pub fn set_verbose_debug(verbose: bool) {
+    VERBOSE_DEBUG.store(verbose, Ordering::Relaxed);
+}
+
+fn verbose_debug() -> bool {
+    VERBOSE_DEBUG.load(Ordering::Relaxed)
+}
+
+/// `Debug` wrapper hiding a sensitive value unless verbose output is on
+pub struct Redacted<'a, T>(pub &'a T);
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if verbose_debug() {
+            self.0.fmt(f)
+        } else {
+            f.write_str("<redacted>")
+        }
+    }
+}
+
+/// `Debug` wrapper showing only the beginning of a PEM certificate
+struct TruncatedCert<'a>(&'a str);
+
+impl fmt::Debug for TruncatedCert<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if verbose_debug() || self.0.chars().count() <= CERT_DEBUG_PREFIX {
+            return self.0.fmt(f);
+        }
+        let prefix: String = self.0.chars().take(CERT_DEBUG_PREFIX).collect();
+        write!(f, "{:?}... ({} bytes)", prefix, self.0.len())
+    }
+}
+
+/// Whether a failed fetch is worth retrying
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// Network errors, timeouts and server-side failures
+    Transient,
+    /// Attestation denied, missing resource or bad configuration
+    Permanent,
+}
+
+/// What a failed fetch ran into, for the retry policies naming it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureCause {
+    /// The server answered with this HTTP status
+    Status(u16),
+    /// The server did not answer in time
+    Timeout,
+    /// The server could not be reached
+    Network,
+}
+
+/// Failures of the Trustee pin that callers may want to tell apart
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum TrusteePinError {
+    /// The configuration or clevis header is invalid
+    #[error("{0}")]
+    Config(String),
+    /// Fetching the key from `server` failed
+    #[error("{message}")]
+    Fetch {
+        server: String,
+        kind: FailureKind,
+        message: String,
+        /// How long the server asked to wait before trying it again
+        retry_after: Option<Duration>,
+        /// What the fetch ran into, when known
+        cause: Option<FailureCause>,
+    },
+    /// Encrypting or decrypting the secret failed
+    #[error("{0}")]
+    Crypto(String),
+    /// The system clock is implausible, so certificates cannot be validated
+    #[error("{0}")]
+    ClockSkew(String),
+    /// The fetch was aborted through its `CancellationToken`
+    #[error("The key fetch was cancelled")]
+    Cancelled,
+    /// Every server failed: on a permanent error each, or after the retries
+    /// when some failure was transient
+    #[error("{message}")]
+    GaveUp { kind: FailureKind, message: String },
+}
+
+impl TrusteePinError {
+    /// Fetch failure that retrying cannot fix
+    pub fn permanent(server: impl Into<String>, message: impl Into<String>) -> Self {
+        TrusteePinError::Fetch {
+            server: server.into(),
+            kind: FailureKind::Permanent,
+            message: message.into(),
+            retry_after: None,
+            cause: None,
+        }
+    }
+
+    /// Fetch failure that may go away: the server was unreachable or did
+    /// not answer in time
+    pub fn transport(
+        server: impl Into<String>,
+        cause: FailureCause,
+        message: impl Into<String>,
+    ) -> Self {
+        TrusteePinError::Fetch {
+            server: server.into(),
+            kind: FailureKind::Transient,
+            message: message.into(),
+            retry_after: None,
+            cause: Some(cause),
+        }
+    }
+
+    /// Classify a failed HTTP request: 5xx, 408 and 429 may go away, other
+    /// client errors (401, 403, 404, ...) will not.
+    pub fn from_status(server: impl Into<String>, status: u16, message: impl Into<String>) -> Self {
+        let kind = match status {
+            408 | 429 | 500..=599 => FailureKind::Transient,
+            400..=499 => FailureKind::Permanent,
+            _ => FailureKind::Transient,
+        };
+        TrusteePinError::Fetch {
+            server: server.into(),
+            kind,
+            message: message.into(),
+            retry_after: None,
+            cause: Some(FailureCause::Status(status)),
+        }
+    }
+
+    /// The same failure, with the delay the server asked for before the
+    /// next attempt, e.g. in a `Retry-After` header
+    pub fn with_retry_after(mut self, delay: Option<Duration>) -> Self {
+        if let TrusteePinError::Fetch { retry_after, .. } = &mut self {
+            *retry_after = delay;
+        }
+        self
+    }
+
+    /// Delay the server asked for before the next attempt
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            TrusteePinError::Fetch { retry_after, .. } => *retry_after,
+            TrusteePinError::Config(_)
+            | TrusteePinError::Crypto(_)
+            | TrusteePinError::ClockSkew(_)
+            | TrusteePinError::Cancelled
+            | TrusteePinError::GaveUp { .. } => None,
+        }
+    }
+
+    /// What the fetch ran into, when known
+    pub fn cause(&self) -> Option<FailureCause> {
+        match self {
+            TrusteePinError::Fetch { cause, .. } => *cause,
+            TrusteePinError::Config(_)
+            | TrusteePinError::Crypto(_)
+            | TrusteePinError::ClockSkew(_)
+            | TrusteePinError::Cancelled
+            | TrusteePinError::GaveUp { .. } => None,
+        }
+    }
+
+    /// Configuration and crypto errors are the same on every attempt, time
+    /// sync may fix the clock meanwhile
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            TrusteePinError::Fetch { kind, .. } | TrusteePinError::GaveUp { kind, .. } => *kind,
+            TrusteePinError::Config(_)
+            | TrusteePinError::Crypto(_)
+            | TrusteePinError::Cancelled => FailureKind::Permanent,
+            TrusteePinError::ClockSkew(_) => FailureKind::Transient,
+        }
+    }
+}
+
+/// Progress of a key fetch, for embedders showing it in their own UI
+/// instead of parsing the log. Every method does nothing by default.
+pub trait EventHandler {
+    /// A round over the servers starts; `max_attempts` is `None` when
+    /// retrying forever
+    fn on_attempt_start(&self, _attempt: u32, _max_attempts: Option<u32>) {}
+
+    /// Fetching from `url` failed with `error`
+    fn on_server_failure(&self, _url: &str, _error: &str, _kind: FailureKind) {}
+
+    /// The key was fetched from `url`
+    fn on_success(&self, _url: &str) {}
+}
+
+/// Handler ignoring every event
+pub struct NoEvents;
+
+impl EventHandler for NoEvents {}
+
+/// Flag aborting a key fetch from another thread. The fetch stops before its
+/// next attempt, or right away while waiting between attempts, and fails
+/// with `TrusteePinError::Cancelled`. Clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancellationToken {
+    /// Abort the fetches using this token
+    pub fn cancel(&self) {
+        let (cancelled, condvar) = &*self.state;
+        *cancelled.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        condvar.notify_all();
+    }
+
+    /// Whether `cancel` was called
+    pub fn is_cancelled(&self) -> bool {
+        *self.state.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Wait up to `timeout` for the token to be cancelled. Returns whether
+    /// it was.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let (cancelled, condvar) = &*self.state;
+        let guard = cancelled.lock().unwrap_or_else(PoisonError::into_inner);
+        let (guard, _) = condvar
+            .wait_timeout_while(guard, timeout, |cancelled| !*cancelled)
+            .unwrap_or_else(PoisonError::into_inner);
+        *guard
+    }
+}
+
+/// Parse a duration such as `500ms`, `5s`, `2m` or `1h`
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in duration '{}'", value))?;
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", value))?;
+    let millis = match unit {
+        "ms" => Some(number),
+        "s" => number.checked_mul(1000),
+        "m" => number.checked_mul(60 * 1000),
+        "h" => number.checked_mul(60 * 60 * 1000),
+        _ => return Err(format!("unknown unit '{}' in duration '{}'", unit, value)),
+    };
+    millis
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("duration too large: '{}'", value))
+}
+
+/// Parse the duration setting `name`, if set
+pub fn duration_setting(
+    name: &str,
+    value: Option<&str>,
+) -> Result<Option<Duration>, TrusteePinError> {
+    value
+        .map(parse_duration)
+        .transpose()
+        .map_err(|e| TrusteePinError::Config(format!("Invalid {}: {}", name, e)))
+}
+
+/// Format a duration with the largest unit that represents it exactly
+pub fn format_duration(duration: Duration) -> String {
+    let millis = duration.as_millis();
+    [(3_600_000, "h"), (60_000, "m"), (1000, "s")]
+        .iter()
+        .find(|(unit, _)| millis > 0 && millis.is_multiple_of(*unit))
+        .map(|(unit, suffix)| format!("{}{}", millis / unit, suffix))
+        .unwrap_or_else(|| format!("{}ms", millis))
+}
+
+/// One entry of a retry schedule: `count` retries, each after `delay`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleStep {
+    /// Number of retries, `None` to keep retrying forever
+    pub count: Option<u32>,
+    pub delay: Duration,
+}
+
+impl ScheduleStep {
+    /// Parse `<delay>`, `<count>@<delay>` or `infinity@<delay>`
+    fn parse(value: &str) -> Result<Self, String> {
+        let (count, delay) = match value.split_once('@') {
+            None => (Some(1), value),
+            Some(("infinity", delay)) => (None, delay),
+            Some((count, delay)) => match count.parse::<u32>() {
+                Ok(count) if count >= 1 => (Some(count), delay),
+                _ => return Err(format!("invalid retry count in '{}'", value)),
+            },
+        };
+        Ok(ScheduleStep {
+            count,
+            delay: parse_duration(delay)?,
+        })
+    }
+}
+
+impl std::fmt::Display for ScheduleStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.count {
+            Some(1) => write!(f, "{}", format_duration(self.delay)),
+            Some(count) => write!(f, "{}@{}", count, format_duration(self.delay)),
+            None => write!(f, "infinity@{}", format_duration(self.delay)),
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum NumRetries {
     Finite(u32),
     Infinity,
+    /// A single attempt without any retry delay, written as `"none"`
+    Once,
+    /// Explicit delays between attempts, e.g. `["5s", "3@10s", "infinity@300s"]`
+    Schedule(Vec<ScheduleStep>),
+}
+
+impl NumRetries {
+    /// Total number of attempts, `None` when retrying forever
+    pub fn max_attempts(&self) -> Option<u32> {
+        match self {
+            NumRetries::Finite(n) => Some(*n),
+            NumRetries::Once => Some(1),
+            NumRetries::Infinity => None,
+            NumRetries::Schedule(steps) => steps
+                .iter()
+                .try_fold(1u32, |total, step| Some(total.saturating_add(step.count?))),
+        }
+    }
+
+    /// Delay before the attempt following `attempt` (1-based), or `None` when
+    /// `attempt` was the last one. Counted retries use `default_delay`.
+    pub fn retry_delay(&self, attempt: u32, default_delay: Duration) -> Option<Duration> {
+        match self {
+            NumRetries::Finite(n) => (attempt < *n).then_some(default_delay),
+            NumRetries::Once => None,
+            NumRetries::Infinity => Some(default_delay),
+            NumRetries::Schedule(steps) => {
+                let mut remaining = attempt;
+                for step in steps {
+                    match step.count {
+                        Some(count) if remaining > count => remaining -= count,
+                        _ => return Some(step.delay),
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+impl JsonSchema for NumRetries {
+    fn schema_name() -> Cow<'static, str> {
+        "NumRetries".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "anyOf": [
+                {"type": "integer", "minimum": 1, "maximum": u32::MAX},
+                {"enum": ["infinity", "none"]},
+                {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {"type": "string"},
+                    "description": "Retry schedule, e.g. [\"5s\", \"3@10s\", \"infinity@300s\"]",
+                },
+            ]
+        })
+    }
 }
 
 impl Serialize for NumRetries {
@@ -20,6 +401,14 @@ impl Serialize for NumRetries {
         match self {
             NumRetries::Finite(n) => serializer.serialize_u32(*n),
             NumRetries::Infinity => serializer.serialize_str("infinity"),
+            NumRetries::Once => serializer.serialize_str("none"),
+            NumRetries::Schedule(steps) => {
+                let mut seq = serializer.serialize_seq(Some(steps.len()))?;
+                for step in steps {
+                    seq.serialize_element(&step.to_string())?;
+                }
+                seq.end()
+            }
         }
     }
 }
@@ -35,7 +424,9 @@ impl<'de> Deserialize<'de> for NumRetries {
             type Value = NumRetries;
 
             fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                formatter.write_str("a positive number (>= 1) or the string 'infinity'")
+                formatter.write_str(
+                    "a positive number (>= 1), the string 'infinity' or 'none', or a retry schedule",
+                )
             }
 
             fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
@@ -71,56 +462,1234 @@ impl<'de> Deserialize<'de> for NumRetries {
             where
                 E: serde::de::Error,
             {
-                if value == "infinity" {
-                    Ok(NumRetries::Infinity)
-                } else {
-                    Err(E::custom(format!("expected 'infinity', got: '{}'", value)))
+                match value {
+                    "infinity" => Ok(NumRetries::Infinity),
+                    "none" => Ok(NumRetries::Once),
+                    _ => Err(E::custom(format!(
+                        "expected 'infinity' or 'none', got: '{}'",
+                        value
+                    ))),
                 }
             }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                use serde::de::Error;
+
+                let mut steps: Vec<ScheduleStep> = Vec::new();
+                while let Some(value) = seq.next_element::<String>()? {
+                    if steps.last().is_some_and(|step| step.count.is_none()) {
+                        return Err(A::Error::custom(
+                            "'infinity@' must be the last entry of a retry schedule",
+                        ));
+                    }
+                    steps.push(ScheduleStep::parse(&value).map_err(A::Error::custom)?);
+                }
+                if steps.is_empty() {
+                    return Err(A::Error::custom("retry schedule must not be empty"));
+                }
+                Ok(NumRetries::Schedule(steps))
+            }
         }
 
         deserializer.deserialize_any(NumRetriesVisitor)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// How the LUKS key is fetched from the servers
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Spawn `trustee-attester` for every attempt
+    #[default]
+    TrusteeAttester,
+    /// Run the KBS protocol in-process with evidence from the attestation-agent
+    Native,
+    /// Reuse the attestation token held by a running attestation-agent
+    AttestationAgent,
+    /// Read the resources from a local directory without attesting, for
+    /// tests only. Only chosen at runtime, a binding cannot select it.
+    #[cfg(feature = "mock")]
+    Mock,
+}
+
+impl Backend {
+    /// Whether only the runtime config may choose the backend, as a doctored
+    /// binding could otherwise skip attestation with it
+    pub fn runtime_only(self) -> bool {
+        match self {
+            #[cfg(feature = "mock")]
+            Backend::Mock => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Backend::TrusteeAttester => "trustee-attester",
+            Backend::Native => "native",
+            Backend::AttestationAgent => "attestation-agent",
+            #[cfg(feature = "mock")]
+            Backend::Mock => "mock",
+        })
+    }
+}
+
+/// Where the servers of the platform are discovered when fetching the key
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiscoverySource {
+    /// SMBIOS OEM strings (type 11) set by the hypervisor
+    Smbios,
+    /// Instance metadata collected by cloud-init
+    CloudInit,
+}
+
+impl fmt::Display for DiscoverySource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DiscoverySource::Smbios => "smbios",
+            DiscoverySource::CloudInit => "cloud-init",
+        })
+    }
+}
+
+/// TLS certificate trusted for a server in addition to the system roots
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(from = "CertRepr", into = "CertRepr")]
+pub enum Cert {
+    #[default]
+    None,
+    /// PEM certificate embedded in the config
+    Inline(String),
+    /// File holding the PEM certificate, read when fetching
+    Path(String),
+}
+
+impl Cert {
+    /// The PEM certificate, reading it from disk for `Cert::Path`
+    pub fn pem(&self) -> std::io::Result<Option<String>> {
+        match self {
+            Cert::None => Ok(None),
+            Cert::Inline(pem) => Ok(Some(pem.clone())),
+            Cert::Path(path) => read_pem(path).map(Some),
+        }
+    }
+}
+
+#[cfg(feature = "fs")]
+fn read_pem(path: &str) -> std::io::Result<String> {
+    std::fs::read_to_string(path)
+}
+
+/// Builds without a filesystem, e.g. wasm32, can only use inline certificates
+#[cfg(not(feature = "fs"))]
+fn read_pem(path: &str) -> std::io::Result<String> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "cannot read certificate {}: built without the fs feature",
+            path
+        ),
+    ))
+}
+
+impl fmt::Debug for Cert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Cert::None => f.write_str("None"),
+            Cert::Inline(pem) => f.debug_tuple("Inline").field(&TruncatedCert(pem)).finish(),
+            Cert::Path(path) => f.debug_tuple("Path").field(path).finish(),
+        }
+    }
+}
+
+/// Serialized forms of `Cert`. A bare string is an inline PEM certificate, or
+/// no certificate when empty, as written by older versions.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+enum CertRepr {
+    Pem(String),
+    Tagged(TaggedCert),
+    Null(()),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+enum TaggedCert {
+    Inline(String),
+    Path(String),
+}
+
+impl From<CertRepr> for Cert {
+    fn from(repr: CertRepr) -> Self {
+        match repr {
+            CertRepr::Pem(pem) if pem.is_empty() => Cert::None,
+            CertRepr::Pem(pem) | CertRepr::Tagged(TaggedCert::Inline(pem)) => Cert::Inline(pem),
+            CertRepr::Tagged(TaggedCert::Path(path)) => Cert::Path(path),
+            CertRepr::Null(()) => Cert::None,
+        }
+    }
+}
+
+// Keep writing a plain string unless a path is used, so headers stay
+// readable by older versions
+impl From<Cert> for CertRepr {
+    fn from(cert: Cert) -> Self {
+        match cert {
+            Cert::None => CertRepr::Pem(String::new()),
+            Cert::Inline(pem) => CertRepr::Pem(pem),
+            Cert::Path(path) => CertRepr::Tagged(TaggedCert::Path(path)),
+        }
+    }
+}
+
+/// Oldest TLS version a server may negotiate
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, JsonSchema)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// TLS baseline the connections to a server must meet
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct TlsPolicy {
+    pub min_version: Option<TlsVersion>,
+    /// Cipher suites allowed, by IANA name, e.g. `TLS_AES_256_GCM_SHA384`
+    pub cipher_suites: Option<Vec<String>>,
+    /// CRL file the certificate of the server is checked against
+    pub crl_path: Option<String>,
+}
+
+impl TlsPolicy {
+    /// Whether the policy leaves every setting to the TLS library
+    pub fn is_default(&self) -> bool {
+        self.min_version.is_none() && self.cipher_suites.is_none() && self.crl_path.is_none()
+    }
+}
+
+/// How the requests to a server are made: the TLS baseline of the
+/// connections and the extra headers
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ConnectionSettings {
+    pub tls: TlsPolicy,
+    /// Extra request headers, by name; a `User-Agent` replaces the default one
+    pub headers: BTreeMap<String, String>,
+}
+
+/// A server, deserialized from an object or from a bare URL string
+#[derive(Serialize, Clone)]
 pub struct Server {
     pub url: String,
-    pub cert: String,
+    /// Label of the server, e.g. `prod-kbs-1`, to select it by
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub cert: Cert,
+    /// Attestation policies that must evaluate the evidence, overriding
+    /// `Config::policy_ids` for this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_ids: Option<Vec<String>>,
+    /// Overrides `Config::tls_min_version` for this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_min_version: Option<TlsVersion>,
+    /// Overrides `Config::tls_cipher_suites` for this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cipher_suites: Option<Vec<String>>,
+    /// Overrides `Config::crl_path` for this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crl_path: Option<String>,
+    /// Extra request headers, e.g. `X-Tenant-Id` for a multi-tenant gateway
+    /// routing on it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeMap<String, String>>,
+}
+
+/// Object form of `Server`
+#[derive(Deserialize, JsonSchema)]
+struct ServerObject {
+    url: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    cert: Cert,
+    #[serde(default)]
+    policy_ids: Option<Vec<String>>,
+    #[serde(default)]
+    tls_min_version: Option<TlsVersion>,
+    #[serde(default)]
+    tls_cipher_suites: Option<Vec<String>>,
+    #[serde(default)]
+    crl_path: Option<String>,
+    #[serde(default)]
+    headers: Option<BTreeMap<String, String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl<'de> Deserialize<'de> for Server {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct ServerVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ServerVisitor {
+            type Value = Server;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a server object or a URL string")
+            }
+
+            fn visit_str<E>(self, url: &str) -> Result<Self::Value, E>
+            where
+                E: serde::de::Error,
+            {
+                Ok(Server {
+                    url: url.to_string(),
+                    name: None,
+                    cert: Cert::None,
+                    policy_ids: None,
+                    tls_min_version: None,
+                    tls_cipher_suites: None,
+                    crl_path: None,
+                    headers: None,
+                })
+            }
+
+            // Through the map access, unknown fields still reach the caller
+            fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::MapAccess<'de>,
+            {
+                let server =
+                    ServerObject::deserialize(serde::de::value::MapAccessDeserializer::new(map))?;
+                Ok(Server {
+                    url: server.url,
+                    name: server.name,
+                    cert: server.cert,
+                    policy_ids: server.policy_ids,
+                    tls_min_version: server.tls_min_version,
+                    tls_cipher_suites: server.tls_cipher_suites,
+                    crl_path: server.crl_path,
+                    headers: server.headers,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(ServerVisitor)
+    }
+}
+
+impl JsonSchema for Server {
+    fn schema_name() -> Cow<'static, str> {
+        "Server".into()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "anyOf": [
+                {"type": "string", "description": "URL of a server without a certificate"},
+                generator.subschema_for::<ServerObject>(),
+            ]
+        })
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("url", &self.url)
+            .field("name", &self.name)
+            .field("cert", &self.cert)
+            .field("policy_ids", &self.policy_ids)
+            .field("tls_min_version", &self.tls_min_version)
+            .field("tls_cipher_suites", &self.tls_cipher_suites)
+            .field("crl_path", &self.crl_path)
+            // The values may be credentials of the gateway
+            .field(
+                "headers",
+                &self.headers.as_ref().map(|h| h.keys().collect::<Vec<_>>()),
+            )
+            .finish()
+    }
+}
+
+impl Server {
+    /// TLS baseline of the server, `defaults` for what it does not set
+    pub fn tls_policy(&self, defaults: &TlsPolicy) -> TlsPolicy {
+        TlsPolicy {
+            min_version: self.tls_min_version.or(defaults.min_version),
+            cipher_suites: self
+                .tls_cipher_suites
+                .clone()
+                .or_else(|| defaults.cipher_suites.clone()),
+            crl_path: self.crl_path.clone().or_else(|| defaults.crl_path.clone()),
+        }
+    }
+
+    /// Connection settings of the server, `defaults` for what it does not
+    /// set. Its headers are added to the default ones.
+    pub fn connection(&self, defaults: &ConnectionSettings) -> ConnectionSettings {
+        let mut headers = defaults.headers.clone();
+        headers.extend(self.headers.clone().unwrap_or_default());
+        ConnectionSettings {
+            tls: self.tls_policy(&defaults.tls),
+            headers,
+        }
+    }
+
+    /// `servers`, with `cert` for the ones without a certificate of their own
+    pub fn with_default_cert(servers: &[Server], cert: Option<&Cert>) -> Vec<Server> {
+        servers
+            .iter()
+            .map(|server| match (&server.cert, cert) {
+                (Cert::None, Some(cert)) => Server {
+                    cert: cert.clone(),
+                    ..server.clone()
+                },
+                _ => server.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether `label` is the name or the URL of the server
+    pub fn is_labeled(&self, label: &str) -> bool {
+        self.name.as_deref() == Some(label) || self.url == label
+    }
+}
+
+/// Servers picked by name or URL, e.g. to find out which replica misbehaves
+#[derive(Debug, Default, Clone)]
+pub struct ServerSelection {
+    /// Only use these servers, all of them when empty
+    pub only: Vec<String>,
+    /// Never use these servers
+    pub skip: Vec<String>,
+}
+
+impl ServerSelection {
+    /// The labels naming none of `servers`, likely mistyped
+    pub fn unmatched<'a>(&'a self, servers: &[Server]) -> Vec<&'a str> {
+        self.only
+            .iter()
+            .chain(&self.skip)
+            .filter(|label| !servers.iter().any(|server| server.is_labeled(label)))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The selected ones of `servers`, in order
+    pub fn select(&self, servers: Vec<Server>) -> Result<Vec<Server>, TrusteePinError> {
+        // Having no servers at all is reported by the fetch
+        if servers.is_empty() {
+            return Ok(servers);
+        }
+        let selected: Vec<Server> = servers
+            .into_iter()
+            .filter(|server| {
+                (self.only.is_empty() || self.only.iter().any(|label| server.is_labeled(label)))
+                    && !self.skip.iter().any(|label| server.is_labeled(label))
+            })
+            .collect();
+        if selected.is_empty() {
+            return Err(TrusteePinError::Config(
+                "No server is left after the server selection".to_string(),
+            ));
+        }
+        Ok(selected)
+    }
+}
+
+/// Skip a server for a few attempts after repeated consecutive failures
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct CircuitBreaker {
+    /// Consecutive failed attempts after which the server is skipped
+    pub failure_threshold: u32,
+    /// Attempts to skip the server before probing it again
+    pub cooldown_attempts: u32,
+}
+
+/// Failures a config may declare retryable: an HTTP status, e.g. `503`, a
+/// class of statuses, e.g. `5xx`, `timeout` or `network`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RetryClass {
+    Status(u16),
+    /// The hundreds digit of the statuses
+    StatusClass(u16),
+    Timeout,
+    Network,
+}
+
+impl RetryClass {
+    /// Whether a failure caused by `cause` is of this class
+    pub fn matches(&self, cause: FailureCause) -> bool {
+        match (self, cause) {
+            (RetryClass::Status(expected), FailureCause::Status(status)) => *expected == status,
+            (RetryClass::StatusClass(class), FailureCause::Status(status)) => {
+                status / 100 == *class
+            }
+            (RetryClass::Timeout, FailureCause::Timeout) => true,
+            (RetryClass::Network, FailureCause::Network) => true,
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for RetryClass {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "invalid retryable failure {:?}, expected a status like 503 or 5xx, timeout or network",
+                value
+            )
+        };
+        match value.as_str() {
+            "timeout" => return Ok(RetryClass::Timeout),
+            "network" => return Ok(RetryClass::Network),
+            _ => {}
+        }
+        let class = match value.strip_suffix("xx") {
+            Some(digit) => digit
+                .parse()
+                .map(RetryClass::StatusClass)
+                .map_err(|_| invalid())?,
+            None => value
+                .parse::<u16>()
+                .map(RetryClass::Status)
+                .map_err(|_| invalid())?,
+        };
+        match class {
+            RetryClass::Status(100..=599) | RetryClass::StatusClass(1..=5) => Ok(class),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<RetryClass> for String {
+    fn from(class: RetryClass) -> Self {
+        match class {
+            RetryClass::Status(status) => status.to_string(),
+            RetryClass::StatusClass(class) => format!("{}xx", class),
+            RetryClass::Timeout => "timeout".to_string(),
+            RetryClass::Network => "network".to_string(),
+        }
+    }
+}
+
+impl JsonSchema for RetryClass {
+    fn schema_name() -> Cow<'static, str> {
+        "RetryClass".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "pattern": "^([1-5][0-9][0-9]|[1-5]xx|timeout|network)$",
+        })
+    }
+}
+
+/// Cap on the delay of a backoff without a `max_delay`
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Growth of the delay between counted or infinite retries after each failed
+/// attempt, so an outage is polled less and less often
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, JsonSchema)]
+pub struct Backoff {
+    /// Factor the delay is multiplied by after each failed attempt
+    #[serde(default = "default_backoff_multiplier")]
+    #[schemars(range(min = 1))]
+    pub multiplier: u32,
+    /// Cap on the delay, e.g. `5m` (the default), after which the attempts
+    /// settle into polling at that cadence
+    #[serde(default)]
+    pub max_delay: Option<String>,
+    /// Start again from the initial delay for the next key once one was
+    /// fetched, rather than keep the grown delay
+    #[serde(default = "default_reset_after_success")]
+    pub reset_after_success: bool,
+    /// Wait a random delay between half of the grown one and all of it, so
+    /// machines failing together do not retry in lockstep
+    #[serde(default)]
+    pub jitter: bool,
+}
+
+fn default_backoff_multiplier() -> u32 {
+    2
+}
+
+fn default_reset_after_success() -> bool {
+    true
+}
+
+impl Backoff {
+    /// Parsed `max_delay`
+    pub fn max_delay(&self) -> Result<Duration, TrusteePinError> {
+        Ok(
+            duration_setting("backoff.max_delay", self.max_delay.as_deref())?
+                .unwrap_or(DEFAULT_MAX_BACKOFF),
+        )
+    }
+
+    /// `delay` grown after `failures` previous failed attempts
+    pub fn delay(&self, delay: Duration, failures: u32) -> Result<Duration, TrusteePinError> {
+        if self.multiplier == 0 {
+            return Err(TrusteePinError::Config(
+                "Invalid backoff.multiplier: must be at least 1".to_string(),
+            ));
+        }
+        let factor = self.multiplier.saturating_pow(failures);
+        Ok(delay.saturating_mul(factor).min(self.max_delay()?))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct AttestationKey {
     pub registration: Registration,
 }
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, JsonSchema)]
 pub struct Registration {
     pub url: String,
     pub cert: String,
     pub uuid: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl fmt::Debug for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registration")
+            .field("url", &self.url)
+            .field("cert", &TruncatedCert(&self.cert))
+            .field("uuid", &self.uuid)
+            .finish()
+    }
+}
+
+/// Another resource the payload is encrypted to, see `Config::recipients`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Recipient {
+    /// Servers of the resource, the ones of the config by default
+    pub servers: Option<Vec<Server>>,
+    /// Path of the resource, the one of the config by default
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Config {
     pub servers: Vec<Server>,
+    /// Certificate of the servers that have none of their own
+    #[serde(default)]
+    pub cert: Option<Cert>,
     pub path: String,
+    /// Trustee initdata, as an object or a JSON string
+    #[serde(default)]
+    #[schemars(schema_with = "initdata_schema")]
     pub initdata: Option<String>,
     pub num_retries: Option<NumRetries>,
     pub attestation_key: Option<AttestationKey>,
+    /// Attestation policies that must evaluate the evidence on every server
+    pub policy_ids: Option<Vec<String>>,
+    pub backend: Option<Backend>,
+    /// Pin the KBS protocol version instead of negotiating it (native backend only)
+    pub kbs_protocol_version: Option<String>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Growth of the delay between attempts, e.g. `{"max_delay": "5m"}`
+    pub backoff: Option<Backoff>,
+    /// Failures retried, e.g. `["5xx", "timeout"]`, instead of the built-in
+    /// classification; other HTTP statuses and transport failures are not
+    pub retry_on: Option<Vec<RetryClass>>,
+    /// Limit on a single fetch attempt, e.g. `30s`, after which the backend
+    /// is aborted and the attempt counts as failed
+    pub attempt_timeout: Option<String>,
+    /// Sources of servers tried before `servers`, looked up on every fetch
+    pub discovery: Option<Vec<DiscoverySource>>,
+    /// Oldest TLS version the servers may negotiate, enforced by the native
+    /// and attestation-agent backends
+    pub tls_min_version: Option<TlsVersion>,
+    /// Cipher suites the servers may negotiate, by IANA name, enforced by
+    /// the native and attestation-agent backends
+    pub tls_cipher_suites: Option<Vec<String>>,
+    /// CRL file, PEM or DER, read on every fetch to check that the
+    /// certificate of the servers was not revoked. Needs the certificate
+    /// of the servers to be pinned.
+    pub crl_path: Option<String>,
+    /// User-Agent of the requests to the servers, made by the native and
+    /// attestation-agent backends
+    pub user_agent: Option<String>,
+    /// Format of the resource holding the key, guessed when not set
+    pub key_format: Option<KeyFormat>,
+    /// Steps applied to the fetched resource before reading the key in it,
+    /// e.g. `["base64-decode", "hkdf:info=luks"]`
+    pub key_pipeline: Option<Vec<KeyTransform>>,
+    /// Version or tag of the resource at `path` to fetch, recorded in the
+    /// header so a new version uploaded to the KBS is not picked up by
+    /// decrypt. Sent as the `version` query parameter of the resource.
+    pub resource_version: Option<String>,
+    /// Resources the payload is encrypted to besides the one of `servers`
+    /// and `path`. Any of them is enough to decrypt.
+    pub recipients: Option<Vec<Recipient>>,
+    /// Config of the clevis tpm2 pin, e.g. `{"pcr_ids": "7"}`. When set, the
+    /// payload key is derived from the fetched key and a local secret
+    /// sealed with the TPM, so the key of the servers alone is not enough.
+    pub tpm2: Option<serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("servers", &self.servers)
+            .field("cert", &self.cert)
+            .field("path", &self.path)
+            .field("initdata", &self.initdata.as_ref().map(Redacted))
+            .field("num_retries", &self.num_retries)
+            .field("attestation_key", &self.attestation_key)
+            .field("policy_ids", &self.policy_ids)
+            .field("backend", &self.backend)
+            .field("kbs_protocol_version", &self.kbs_protocol_version)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("backoff", &self.backoff)
+            .field("retry_on", &self.retry_on)
+            .field("attempt_timeout", &self.attempt_timeout)
+            .field("discovery", &self.discovery)
+            .field("tls_min_version", &self.tls_min_version)
+            .field("tls_cipher_suites", &self.tls_cipher_suites)
+            .field("crl_path", &self.crl_path)
+            .field("user_agent", &self.user_agent)
+            .field("key_format", &self.key_format)
+            .field("key_pipeline", &self.key_pipeline)
+            .field("resource_version", &self.resource_version)
+            .field("recipients", &self.recipients)
+            .field("tpm2", &self.tpm2)
+            .finish()
+    }
+}
+
+fn initdata_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({"type": ["object", "string", "array", "null"]})
+}
+
+/// JSON Schema of `Config`, as read by `encrypt`
+pub fn config_schema() -> serde_json::Value {
+    schemars::schema_for!(Config).to_value()
+}
+
+/// JSON Schema of `RuntimeConfig`, as read by `decrypt --config-file`
+pub fn runtime_config_schema() -> serde_json::Value {
+    schemars::schema_for!(RuntimeConfig).to_value()
+}
+
+/// JSON Schema of the clevis header stored in the JWE
+pub fn header_schema() -> serde_json::Value {
+    schemars::schema_for!(ClevisHeader).to_value()
+}
+
+/// Bounds on the JWE accepted by decrypt, so a corrupted or hostile token
+/// cannot exhaust the memory of the early boot unlock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputLimits {
+    /// Size of the whole JWE, in bytes
+    pub max_jwe_size: usize,
+    /// Size of the decoded protected header, in bytes
+    pub max_header_size: usize,
+    /// Nesting of the objects and arrays of the clevis claim
+    pub max_header_depth: usize,
+    /// Size of any string of the clevis claim, in bytes
+    pub max_claim_size: usize,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        InputLimits {
+            max_jwe_size: 16 << 20,
+            // Bindings with recipients embed a JWE per recipient
+            max_header_size: 1 << 20,
+            max_header_depth: 16,
+            max_claim_size: 256 << 10,
+        }
+    }
+}
+
+impl InputLimits {
+    /// Refuse a JWE larger than `max_jwe_size`
+    pub fn check_jwe(&self, jwe: &str) -> Result<(), TrusteePinError> {
+        if jwe.len() > self.max_jwe_size {
+            return Err(TrusteePinError::Config(format!(
+                "Invalid JWE: {} bytes exceed the limit of {}",
+                jwe.len(),
+                self.max_jwe_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse a clevis claim nested deeper than `max_header_depth` or with a
+    /// string longer than `max_claim_size`
+    fn check_claim(&self, claim: &serde_json::Value) -> Result<(), String> {
+        let mut pending = vec![(claim, 1)];
+        while let Some((value, depth)) = pending.pop() {
+            if depth > self.max_header_depth {
+                return Err(format!(
+                    "the clevis claim is nested deeper than {} levels",
+                    self.max_header_depth
+                ));
+            }
+            match value {
+                serde_json::Value::String(string) if string.len() > self.max_claim_size => {
+                    return Err(format!(
+                        "a value of the clevis claim exceeds {} bytes",
+                        self.max_claim_size
+                    ));
+                }
+                serde_json::Value::Array(values) => {
+                    pending.extend(values.iter().map(|value| (value, depth + 1)))
+                }
+                serde_json::Value::Object(map) => {
+                    pending.extend(map.values().map(|value| (value, depth + 1)))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings for fetching the key of an existing binding that are read at
+/// runtime instead of being stored in the clevis header
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Servers to use instead of the ones of the binding
+    pub servers: Option<Vec<Server>>,
+    /// URL the server of the binding moved to, used with its certificate
+    /// and settings instead of the URLs stored in the header
+    pub override_url: Option<String>,
+    pub num_retries: Option<NumRetries>,
+    /// Delay between attempts when `num_retries` has no schedule, e.g. `10s`
+    pub retry_delay: Option<String>,
+    pub backend: Option<Backend>,
+    /// trustee-attester binary used by the trustee-attester backend
+    pub attester_path: Option<String>,
+    /// Directory where trustee-attester is given inline certificates
+    pub cert_dir: Option<String>,
+    /// SELinux context of these certificates, the one of the system
+    /// certificates by default when SELinux is enabled, none if empty
+    pub cert_context: Option<String>,
+    /// Limit on a single fetch attempt, e.g. `30s`
+    pub attempt_timeout: Option<String>,
+    /// Keep the keys fetched to decrypt in a local cache for this long,
+    /// e.g. `12h`, skipping attestation meanwhile
+    pub key_cache_ttl: Option<String>,
+    /// Wait up to this long, e.g. `60s`, for a server to be routable before
+    /// the first attempt
+    pub network_wait: Option<String>,
+    /// Wait up to this long, e.g. `2m`, for time sync when the system clock
+    /// is implausible
+    pub time_sync_wait: Option<String>,
+    /// Bounds on the JWE to decrypt, the defaults of `InputLimits` when
+    /// missing
+    pub input_limits: Option<InputLimits>,
+    /// Record every fetch attempt, its server, timing and outcome but not
+    /// the key, as JSON lines to this file, to debug a failing unlock offline
+    pub record: Option<String>,
+    /// Answer the fetch attempts with the ones of a recording instead of
+    /// contacting the servers
+    pub replay: Option<String>,
+    /// Directory of the resources of the mock backend
+    pub mock_resources: Option<String>,
+    /// Servers to restrict the fetch to, given on the command line
+    #[serde(skip)]
+    pub selection: ServerSelection,
+    /// Aborts the fetch, e.g. when the embedder shuts down
+    #[serde(skip)]
+    pub cancellation: CancellationToken,
+}
+
+impl RuntimeConfig {
+    /// Parsed `retry_delay`
+    pub fn retry_delay(&self) -> Result<Option<Duration>, TrusteePinError> {
+        duration_setting("retry_delay", self.retry_delay.as_deref())
+    }
+
+    /// Parsed `attempt_timeout`
+    pub fn attempt_timeout(&self) -> Result<Option<Duration>, TrusteePinError> {
+        duration_setting("attempt_timeout", self.attempt_timeout.as_deref())
+    }
+
+    /// Parsed `key_cache_ttl`
+    pub fn key_cache_ttl(&self) -> Result<Option<Duration>, TrusteePinError> {
+        duration_setting("key_cache_ttl", self.key_cache_ttl.as_deref())
+    }
+
+    /// Parsed `network_wait`
+    pub fn network_wait(&self) -> Result<Option<Duration>, TrusteePinError> {
+        duration_setting("network_wait", self.network_wait.as_deref())
+    }
+
+    /// Parsed `time_sync_wait`
+    pub fn time_sync_wait(&self) -> Result<Option<Duration>, TrusteePinError> {
+        duration_setting("time_sync_wait", self.time_sync_wait.as_deref())
+    }
+}
+
+/// Metadata of a trustee binding, stored as the `clevis` claim in the
+/// protected header of the JWE
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ClevisHeader {
+    pub pin: String,
+    pub servers: Vec<Server>,
+    /// Certificate of the servers that have none of their own, stored once
+    /// rather than in every server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<Cert>,
+    pub path: String,
+    pub initdata: Option<String>,
+    #[serde(default)]
+    pub num_retries: Option<NumRetries>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy_ids: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<Backend>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kbs_protocol_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreaker>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Backoff>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_on: Option<Vec<RetryClass>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt_timeout: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discovery: Option<Vec<DiscoverySource>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_min_version: Option<TlsVersion>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cipher_suites: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crl_path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_format: Option<KeyFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_pipeline: Option<Vec<KeyTransform>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource_version: Option<String>,
+    /// Identifies the key the JWE was encrypted with, to detect that it
+    /// was rotated on the KBS
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    /// With several recipients, the key of the JWE encrypted to each of
+    /// them, as compact JWEs with their own clevis header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipients: Option<Vec<String>>,
+    /// In split-key mode, the local secret sealed by the clevis tpm2 pin,
+    /// as a compact JWE
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tpm2_jwe: Option<String>,
+}
+
+impl ClevisHeader {
+    /// Name of the pin in the `pin` field
+    pub const PIN: &str = "trustee";
+
+    /// Header for a binding made with `config`. `initdata` is the TOML
+    /// document derived from `config.initdata`.
+    pub fn new(config: Config, initdata: Option<String>) -> Self {
+        ClevisHeader {
+            pin: Self::PIN.to_string(),
+            servers: config.servers,
+            cert: config.cert,
+            path: config.path,
+            initdata,
+            num_retries: config.num_retries,
+            policy_ids: config.policy_ids,
+            backend: config.backend,
+            kbs_protocol_version: config.kbs_protocol_version,
+            circuit_breaker: config.circuit_breaker,
+            backoff: config.backoff,
+            retry_on: config.retry_on,
+            attempt_timeout: config.attempt_timeout,
+            discovery: config.discovery,
+            tls_min_version: config.tls_min_version,
+            tls_cipher_suites: config.tls_cipher_suites,
+            crl_path: config.crl_path,
+            user_agent: config.user_agent,
+            key_format: config.key_format,
+            key_pipeline: config.key_pipeline,
+            resource_version: config.resource_version,
+            key_id: None,
+            recipients: None,
+            tpm2_jwe: None,
+        }
+    }
+
+    /// Header of the binding to `recipient`, which uses the settings of
+    /// this one
+    pub fn for_recipient(&self, recipient: &Recipient) -> Self {
+        ClevisHeader {
+            servers: recipient
+                .servers
+                .clone()
+                .unwrap_or_else(|| self.servers.clone()),
+            path: recipient.path.clone().unwrap_or_else(|| self.path.clone()),
+            key_id: None,
+            recipients: None,
+            tpm2_jwe: None,
+            ..self.clone()
+        }
+    }
+
+    /// TLS baseline of the servers that do not set their own
+    pub fn tls_policy(&self) -> TlsPolicy {
+        TlsPolicy {
+            min_version: self.tls_min_version,
+            cipher_suites: self.tls_cipher_suites.clone(),
+            crl_path: self.crl_path.clone(),
+        }
+    }
+
+    /// Connection settings of the servers that do not set their own
+    pub fn connection(&self) -> ConnectionSettings {
+        ConnectionSettings {
+            tls: self.tls_policy(),
+            headers: self
+                .user_agent
+                .iter()
+                .map(|agent| ("User-Agent".to_string(), agent.clone()))
+                .collect(),
+        }
+    }
+
+    /// Value of the `clevis` claim
+    pub fn to_claim(&self) -> Result<serde_json::Value, TrusteePinError> {
+        serde_json::to_value(self)
+            .map_err(|e| TrusteePinError::Config(format!("Error serializing clevis header: {}", e)))
+    }
+
+    /// Parse the `clevis` claim of a JWE header
+    pub fn from_claim(claim: serde_json::Value) -> Result<Self, TrusteePinError> {
+        let header: ClevisHeader = serde_json::from_value(claim).map_err(|e| {
+            TrusteePinError::Config(format!("Error deserializing clevis header: {}", e))
+        })?;
+        if header.pin != Self::PIN {
+            return Err(TrusteePinError::Config(format!(
+                "JWE was bound with the {} pin, not {}",
+                header.pin,
+                Self::PIN
+            )));
+        }
+        if let Some(backend) = header.backend.filter(|backend| backend.runtime_only()) {
+            return Err(TrusteePinError::Config(format!(
+                "The {} backend can only be chosen at runtime, not by a binding",
+                backend
+            )));
+        }
+        Ok(header)
+    }
+
+    /// Read the header from the protected header of a compact JWE, without
+    /// decrypting anything
+    pub fn from_compact_jwe(jwe: &str) -> Result<Self, TrusteePinError> {
+        Self::from_compact_jwe_within(jwe, &InputLimits::default())
+    }
+
+    /// `from_compact_jwe`, refusing a JWE beyond `limits`
+    pub fn from_compact_jwe_within(
+        jwe: &str,
+        limits: &InputLimits,
+    ) -> Result<Self, TrusteePinError> {
+        let invalid = |reason: String| TrusteePinError::Config(format!("Invalid JWE: {}", reason));
+        limits.check_jwe(jwe)?;
+        let protected = jwe
+            .split('.')
+            .next()
+            .filter(|protected| !protected.is_empty())
+            .ok_or_else(|| invalid("missing protected header".to_string()))?;
+        // Checked before decoding, which takes 3 bytes per 4 characters
+        if protected.trim().len() / 4 * 3 > limits.max_header_size {
+            return Err(invalid(format!(
+                "the protected header exceeds {} bytes",
+                limits.max_header_size
+            )));
+        }
+        let protected = general_purpose::URL_SAFE_NO_PAD
+            .decode(protected.trim())
+            .map_err(|e| invalid(format!("protected header is not base64url: {}", e)))?;
+        let mut protected: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&protected)
+                .map_err(|e| invalid(format!("protected header is not a JSON object: {}", e)))?;
+        let claim = protected
+            .remove("clevis")
+            .ok_or_else(|| invalid("no clevis claim in the header".to_string()))?;
+        limits.check_claim(&claim).map_err(invalid)?;
+        Self::from_claim(claim)
+    }
+}
+
+impl fmt::Debug for ClevisHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClevisHeader")
+            .field("pin", &self.pin)
+            .field("servers", &self.servers)
+            .field("cert", &self.cert)
+            .field("path", &self.path)
+            .field("initdata", &self.initdata.as_ref().map(Redacted))
+            .field("num_retries", &self.num_retries)
+            .field("policy_ids", &self.policy_ids)
+            .field("backend", &self.backend)
+            .field("kbs_protocol_version", &self.kbs_protocol_version)
+            .field("circuit_breaker", &self.circuit_breaker)
+            .field("backoff", &self.backoff)
+            .field("retry_on", &self.retry_on)
+            .field("attempt_timeout", &self.attempt_timeout)
+            .field("discovery", &self.discovery)
+            .field("tls_min_version", &self.tls_min_version)
+            .field("tls_cipher_suites", &self.tls_cipher_suites)
+            .field("crl_path", &self.crl_path)
+            .field("user_agent", &self.user_agent)
+            .field("key_format", &self.key_format)
+            .field("key_pipeline", &self.key_pipeline)
+            .field("resource_version", &self.resource_version)
+            .field("key_id", &self.key_id)
+            .field("recipients", &self.recipients.as_ref().map(Vec::len))
+            .field("tpm2_jwe", &self.tpm2_jwe.as_ref().map(Redacted))
+            .finish()
+    }
+}
+
+/// Format of the resource holding the key on the KBS
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyFormat {
+    /// A symmetric JWK, `{"kty": "oct", "k": "..."}`
+    Jwk,
+    /// A `Key`, `{"key_type": "oct", "key": "..."}`
+    Key,
+    /// The bytes of the key in base64
+    Base64,
+    /// The bytes of the key in hexadecimal
+    Hex,
+    /// The bytes of the key
+    Raw,
+}
+
+/// Step of the pipeline turning the fetched resource into the key, e.g.
+/// `base64-decode` or `hkdf:info=luks,salt=site-a`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum KeyTransform {
+    /// Strip leading and trailing whitespace
+    Trim,
+    Base64Decode,
+    Base64UrlDecode,
+    HexDecode,
+    /// SHA-256 digest
+    Sha256,
+    /// 32 bytes derived with HKDF-SHA256
+    Hkdf {
+        info: String,
+        salt: Option<String>,
+    },
+}
+
+impl TryFrom<String> for KeyTransform {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (name, params) = value.split_once(':').unwrap_or((&value, ""));
+        let simple = match name {
+            "trim" => Some(KeyTransform::Trim),
+            "base64-decode" => Some(KeyTransform::Base64Decode),
+            "base64url-decode" => Some(KeyTransform::Base64UrlDecode),
+            "hex-decode" => Some(KeyTransform::HexDecode),
+            "sha256" => Some(KeyTransform::Sha256),
+            "hkdf" => None,
+            _ => return Err(format!("unknown key transform {:?}", value)),
+        };
+        if let Some(transform) = simple {
+            if !params.is_empty() {
+                return Err(format!("key transform {} takes no parameters", name));
+            }
+            return Ok(transform);
+        }
+        let (mut info, mut salt) = (String::new(), None);
+        for param in params.split(',').filter(|param| !param.is_empty()) {
+            match param.split_once('=') {
+                Some(("info", value)) => info = value.to_string(),
+                Some(("salt", value)) => salt = Some(value.to_string()),
+                _ => {
+                    return Err(format!(
+                        "invalid hkdf parameter {:?}, expected info=... or salt=...",
+                        param
+                    ));
+                }
+            }
+        }
+        Ok(KeyTransform::Hkdf { info, salt })
+    }
+}
+
+impl From<KeyTransform> for String {
+    fn from(transform: KeyTransform) -> Self {
+        match transform {
+            KeyTransform::Trim => "trim".to_string(),
+            KeyTransform::Base64Decode => "base64-decode".to_string(),
+            KeyTransform::Base64UrlDecode => "base64url-decode".to_string(),
+            KeyTransform::HexDecode => "hex-decode".to_string(),
+            KeyTransform::Sha256 => "sha256".to_string(),
+            KeyTransform::Hkdf { info, salt } => match salt {
+                Some(salt) => format!("hkdf:info={},salt={}", info, salt),
+                None => format!("hkdf:info={}", info),
+            },
+        }
+    }
+}
+
+impl JsonSchema for KeyTransform {
+    fn schema_name() -> Cow<'static, str> {
+        "KeyTransform".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "pattern": "^(trim|base64-decode|base64url-decode|hex-decode|sha256|hkdf(:.*)?)$",
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Key {
     pub key_type: String,
     pub key: String,
 }
 
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Key")
+            .field("key_type", &self.key_type)
+            .field("key", &Redacted(&self.key))
+            .finish()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// Sync with Trustee attestation_service::Initdata
 pub struct Initdata {
     pub version: String,
     pub algorithm: String,
-    pub data: HashMap<String, String>,
+    /// Plain strings or nested tables, e.g. configuration for guest components.
+    /// Sorted, so the same data always gives the same document and digest.
+    pub data: BTreeMap<String, toml::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]